@@ -30,6 +30,15 @@ pub trait Writer: AsyncWrite + Unpin + Send {
     async fn tell(&mut self) -> Result<usize>;
 }
 
+/// In-memory [Writer], for tests.
+#[cfg(any(test, feature = "testing"))]
+#[async_trait]
+impl Writer for std::io::Cursor<Vec<u8>> {
+    async fn tell(&mut self) -> Result<usize> {
+        Ok(self.position() as usize)
+    }
+}
+
 /// Lance Write Extension.
 #[async_trait]
 pub trait WriteExt {
@@ -0,0 +1,72 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark of computing PQ codes, with and without SIMD.
+
+use arrow_array::types::Float32Type;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lance_index::vector::pq::compute_pq_codes;
+use lance_linalg::distance::MetricType;
+use lance_linalg::simd::is_simd_supported;
+use lance_testing::datagen::generate_random_array_with_seed;
+
+#[cfg(target_os = "linux")]
+use pprof::criterion::{Output, PProfProfiler};
+
+const PQ: usize = 16;
+const DIM: usize = 128;
+const NUM_VECTORS: usize = 16 * 1024;
+
+fn pq_encode(c: &mut Criterion) {
+    let codebook = generate_random_array_with_seed::<Float32Type>(256 * DIM, [88; 32]);
+    let data = generate_random_array_with_seed::<Float32Type>(NUM_VECTORS * DIM, [32; 32]);
+
+    let mut modes = vec![("scalar", false)];
+    if is_simd_supported() {
+        modes.push(("simd", true));
+    }
+    for (name, use_simd) in modes {
+        c.bench_function(format!("PQ{},L2,{}", PQ, name).as_str(), |b| {
+            b.iter(|| {
+                black_box(
+                    compute_pq_codes::<Float32Type>(
+                        data.values(),
+                        codebook.values(),
+                        DIM,
+                        PQ,
+                        8,
+                        MetricType::L2,
+                        use_simd,
+                    )
+                    .unwrap(),
+                );
+            })
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+criterion_group!(
+    name=benches;
+    config = Criterion::default().significance_level(0.1).sample_size(10)
+        .with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
+    targets = pq_encode);
+
+#[cfg(not(target_os = "linux"))]
+criterion_group!(
+    name=benches;
+    config = Criterion::default().significance_level(0.1).sample_size(10);
+    targets = pq_encode);
+
+criterion_main!(benches);
@@ -18,21 +18,43 @@
 use arrow_array::ArrayRef;
 use lance_linalg::distance::MetricType;
 
+pub mod codec;
 pub mod flat;
 pub mod ivf;
 pub mod kmeans;
+pub mod pca;
 pub mod pq;
 pub mod residual;
+pub mod stats;
 pub mod transform;
 pub mod utils;
+pub mod weight;
 
 // TODO: Make these crate private once the migration from lance to lance-index is done.
 pub const PQ_CODE_COLUMN: &str = "__pq_code";
 pub const PART_ID_COLUMN: &str = "__ivf_part_id";
+pub const VALID_COLUMN: &str = "__valid";
+pub const CENTROID_DISTANCE_COLUMN: &str = "__centroid_distance";
+/// Float32 margin in `[0, 1]` of the assignment of a vector to its partition:
+/// `1 - d1 / d2`, of the distances to the nearest and second nearest centroids.
+/// A low margin means the vector is almost as close to another partition.
+pub const ASSIGNMENT_MARGIN_COLUMN: &str = "__assignment_margin";
+/// FixedSizeList<UInt32> of the next nearest partitions of a vector after the one it
+/// is assigned to, nearest first, padded with `u32::MAX`.
+pub const ALTERNATIVE_PARTITIONS_COLUMN: &str = "__alternative_partitions";
+/// FixedSizeList<Float32> of the distances to the [ALTERNATIVE_PARTITIONS_COLUMN],
+/// padded with infinity.
+pub const ALTERNATIVE_DISTANCES_COLUMN: &str = "__alternative_distances";
+/// Nullable UInt32 partition of an input row of [`ivf::Ivf::partition_transform`].
+/// The rows with a value are assigned to that partition, once, instead of their
+/// nearest ones.
+pub const PINNED_PARTITION_COLUMN: &str = "__ivf_pinned_partition";
+/// Utf8 value of [`ivf::IvfBuildParams::subgroup_column`] of each row.
+pub const SUBGROUP_COLUMN: &str = "__ivf_subgroup";
 pub const DIST_COL: &str = "_distance";
 
 use super::pb;
-pub use residual::RESIDUAL_COLUMN;
+pub use residual::{ResidualPrecision, RESIDUAL_COLUMN};
 
 /// Query parameters for the vector indices
 #[derive(Debug, Clone)]
@@ -0,0 +1,178 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Vectors stored in a binary column with a custom encoding.
+//!
+
+use std::sync::Arc;
+
+use arrow_array::{
+    cast::AsArray, Array, FixedSizeListArray, Float32Array, GenericBinaryArray, OffsetSizeTrait,
+    RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema};
+use async_trait::async_trait;
+use lance_arrow::FixedSizeListArrayExt;
+use lance_core::{Error, Result};
+use snafu::{location, Location};
+
+use super::transform::Transformer;
+
+/// Decodes the vectors stored as blobs in a binary column.
+pub trait VectorCodec: std::fmt::Debug + Send + Sync {
+    /// Decode one encoded vector.
+    fn decode(&self, data: &[u8]) -> Vec<f32>;
+}
+
+/// Replace a binary column of encoded vectors with the float32 vectors decoded by a
+/// [`VectorCodec`], so it can be transformed like a `FixedSizeList` vector column.
+#[derive(Debug, Clone)]
+pub struct DecodeTransform {
+    codec: Arc<dyn VectorCodec>,
+
+    /// Vector Column
+    column: String,
+
+    /// Dimension of the decoded vectors.
+    dimension: usize,
+}
+
+impl DecodeTransform {
+    pub fn new(codec: Arc<dyn VectorCodec>, column: &str, dimension: usize) -> Self {
+        Self {
+            codec,
+            column: column.to_owned(),
+            dimension,
+        }
+    }
+
+    /// Data type of the decoded vector column.
+    pub fn data_type(&self) -> DataType {
+        DataType::FixedSizeList(
+            Arc::new(Field::new("item", DataType::Float32, true)),
+            self.dimension as i32,
+        )
+    }
+
+    fn decode<O: OffsetSizeTrait>(&self, blobs: &GenericBinaryArray<O>) -> Result<Float32Array> {
+        let mut values = Vec::with_capacity(blobs.len() * self.dimension);
+        for (i, blob) in blobs.iter().enumerate() {
+            let blob = blob.ok_or_else(|| Error::Index {
+                message: format!("Decode transform: row {} of {} is null", i, self.column),
+                location: location!(),
+            })?;
+            let vector = self.codec.decode(blob);
+            if vector.len() != self.dimension {
+                return Err(Error::Index {
+                    message: format!(
+                        "Decode transform: decoded vector of dimension {}, expected {}",
+                        vector.len(),
+                        self.dimension
+                    ),
+                    location: location!(),
+                });
+            }
+            values.extend(vector);
+        }
+        Ok(Float32Array::from(values))
+    }
+}
+
+#[async_trait]
+impl Transformer for DecodeTransform {
+    async fn transform(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let (index, field) = batch
+            .schema()
+            .column_with_name(&self.column)
+            .map(|(i, f)| (i, f.clone()))
+            .ok_or(Error::Index {
+                message: format!("Decode transform: column {} not found", self.column),
+                location: location!(),
+            })?;
+        let arr = batch.column(index);
+        let values = match arr.data_type() {
+            DataType::Binary => self.decode(arr.as_binary::<i32>())?,
+            DataType::LargeBinary => self.decode(arr.as_binary::<i64>())?,
+            _ => {
+                return Err(Error::Index {
+                    message: format!(
+                        "Decode transform: column {} is not binary: {}",
+                        self.column,
+                        arr.data_type()
+                    ),
+                    location: location!(),
+                })
+            }
+        };
+        let vectors = FixedSizeListArray::try_new_from_values(values, self.dimension as i32)?;
+
+        let mut fields = batch.schema().fields().to_vec();
+        fields[index] = Arc::new(Field::new(
+            field.name(),
+            self.data_type(),
+            field.is_nullable(),
+        ));
+        let mut columns = batch.columns().to_vec();
+        columns[index] = Arc::new(vectors);
+        let schema = Schema::new_with_metadata(fields, batch.schema().metadata().clone());
+        Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow_array::{types::Float32Type, BinaryArray};
+
+    /// Vectors stored as little-endian f32 bytes.
+    #[derive(Debug)]
+    struct LeBytesCodec;
+
+    impl VectorCodec for LeBytesCodec {
+        fn decode(&self, data: &[u8]) -> Vec<f32> {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_transform() {
+        let blobs = [[1.0_f32, 2.0], [3.0, 4.0]]
+            .iter()
+            .map(|v| v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_from_iter([(
+            "blob",
+            Arc::new(BinaryArray::from_iter_values(blobs)) as _,
+        )])
+        .unwrap();
+
+        let transform = DecodeTransform::new(Arc::new(LeBytesCodec), "blob", 2);
+        let decoded = transform.transform(&batch).await.unwrap();
+        assert_eq!(
+            decoded.schema().field(0).data_type(),
+            &transform.data_type()
+        );
+        let vectors = decoded["blob"].as_fixed_size_list();
+        assert_eq!(
+            vectors.values().as_primitive::<Float32Type>().values(),
+            &[1.0, 2.0, 3.0, 4.0]
+        );
+
+        let transform = DecodeTransform::new(Arc::new(LeBytesCodec), "blob", 3);
+        assert!(transform.transform(&batch).await.is_err());
+    }
+}
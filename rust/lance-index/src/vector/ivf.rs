@@ -16,13 +16,15 @@
 
 use std::collections::HashMap;
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use arrow_array::builder::UInt32Builder;
 use arrow_array::types::{Float16Type, Float32Type, Float64Type};
 use arrow_array::UInt64Array;
 use arrow_array::{
-    cast::AsArray, types::UInt32Type, Array, FixedSizeListArray, RecordBatch, UInt32Array,
+    cast::AsArray, types::UInt32Type, Array, FixedSizeListArray, Float32Array, RecordBatch,
+    UInt32Array,
 };
 use arrow_schema::{DataType, Field};
 use arrow_select::take::take;
@@ -35,37 +37,108 @@ use lance_linalg::{
     MatrixView,
 };
 use log::{debug, info};
+use serde::Serialize;
 use snafu::{location, Location};
 use tracing::{instrument, Instrument};
 
 mod builder;
 pub mod shuffler;
+pub mod tree;
 
-use super::{PART_ID_COLUMN, PQ_CODE_COLUMN, RESIDUAL_COLUMN};
+use super::{
+    ALTERNATIVE_DISTANCES_COLUMN, ALTERNATIVE_PARTITIONS_COLUMN, ASSIGNMENT_MARGIN_COLUMN,
+    CENTROID_DISTANCE_COLUMN, PART_ID_COLUMN, PINNED_PARTITION_COLUMN, PQ_CODE_COLUMN,
+    RESIDUAL_COLUMN,
+};
 use crate::vector::{
     pq::{transform::PQTransformer, ProductQuantizer},
-    residual::ResidualTransform,
+    residual::{ResidualPrecision, ResidualTransform},
     transform::Transformer,
 };
-pub use builder::IvfBuildParams;
+pub use builder::{
+    BuildControl, BuildProgressCallback, BytesPerSec, IvfBuildParams, PackageFormat,
+    PartitionWrittenCallback, PostShuffleTransform, PrometheusMetricsCallback, RowIdMap, Tier,
+    TierFn, TimeWindow, ZeroNormPolicy,
+};
 use lance_linalg::kmeans::KMeans;
+use tree::{IvfTree, TreeAssigner};
+
+/// Margin of an assignment from the distances to the `nearest` and the `second`
+/// nearest centroids: `1 - nearest / second`, clamped to `[0, 1]`.
+///
+/// It is 1 for a vector on its centroid and 0 for a vector equally close to two
+/// centroids, or if there is only one centroid.
+fn assignment_margin(nearest: f32, second: f32) -> f32 {
+    if second.is_finite() && second > 0.0 {
+        (1.0 - nearest.max(0.0) / second).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Options of the partition assignment of an IVF created by [`new_ivf_with_options`]
+/// or [`new_ivf_with_pq_and_options`], see the builders of [`IvfImpl`].
+#[derive(Debug, Clone, Default)]
+pub struct IvfAssignOptions<'a> {
+    /// Only covers a range of partitions.
+    pub range: Option<Range<u32>>,
+
+    /// Partition of each row id, instead of the nearest one.
+    pub precomputed_partitions: Option<HashMap<u64, u32>>,
+
+    /// Assign vectors to partitions with a coarse quantizer tree.
+    pub tree: Option<&'a IvfTree>,
+
+    /// Add the [ASSIGNMENT_MARGIN_COLUMN] in `partition_transform`.
+    pub assignment_margin: bool,
+
+    /// Add the [CENTROID_DISTANCE_COLUMN] in `partition_transform`.
+    pub centroid_distance: bool,
+
+    /// Add the `num_alternatives` next nearest partitions of each vector in
+    /// `partition_transform`, see [`IvfImpl::with_alternatives`].
+    pub num_alternatives: usize,
+
+    /// Assign each vector to its `multi_assign` nearest partitions, the nearest one only
+    /// if 0 or 1.
+    pub multi_assign: usize,
+
+    /// Precision of the PQ codes of the residuals, only used with PQ.
+    pub residual_precision: ResidualPrecision,
+}
+
+impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
+    /// Configure the assignment with the `options` besides the range and the
+    /// precomputed partitions, which are given when the IVF is created.
+    fn with_assign_options(self, options: &IvfAssignOptions) -> Result<Self> {
+        let ivf = match options.tree {
+            Some(tree) => self.with_tree(tree)?,
+            None => self,
+        };
+        Ok(ivf
+            .with_assignment_margin(options.assignment_margin)
+            .with_centroid_distance(options.centroid_distance)
+            .with_alternatives(options.num_alternatives)
+            .with_multi_assign(options.multi_assign))
+    }
+}
 
 fn new_ivf_impl<T: ArrowFloatType + Dot + Cosine + L2 + 'static>(
     centroids: &T::ArrayType,
     dimension: usize,
     metric_type: MetricType,
     transforms: Vec<Arc<dyn Transformer>>,
-    range: Option<Range<u32>>,
-    precomputed_partitions: Option<HashMap<u64, u32>>,
-) -> Arc<dyn Ivf> {
+    options: IvfAssignOptions,
+) -> Result<Arc<dyn Ivf>> {
     let mat = MatrixView::<T>::new(Arc::new(centroids.clone()), dimension);
-    Arc::new(IvfImpl::<T>::new(
+    let ivf = IvfImpl::<T>::new(
         mat,
         metric_type,
         transforms,
-        range,
-        precomputed_partitions,
-    ))
+        options.range.clone(),
+        options.precomputed_partitions.clone(),
+    );
+    Ok(Arc::new(ivf.with_assign_options(&options)?))
 }
 
 /// Create an IVF from the flatten centroids.
@@ -84,32 +157,50 @@ pub fn new_ivf(
     transforms: Vec<Arc<dyn Transformer>>,
     range: Option<Range<u32>>,
     precomputed_partitions: Option<HashMap<u64, u32>>,
+) -> Result<Arc<dyn Ivf>> {
+    new_ivf_with_options(
+        centroids,
+        dimension,
+        metric_type,
+        transforms,
+        IvfAssignOptions {
+            range,
+            precomputed_partitions,
+            ..Default::default()
+        },
+    )
+}
+
+/// Create an IVF from the flatten centroids, assigning the vectors with `options`.
+pub fn new_ivf_with_options(
+    centroids: &dyn Array,
+    dimension: usize,
+    metric_type: MetricType,
+    transforms: Vec<Arc<dyn Transformer>>,
+    options: IvfAssignOptions,
 ) -> Result<Arc<dyn Ivf>> {
     match centroids.data_type() {
-        DataType::Float16 => Ok(new_ivf_impl::<Float16Type>(
+        DataType::Float16 => new_ivf_impl::<Float16Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             transforms,
-            range,
-            precomputed_partitions,
-        )),
-        DataType::Float32 => Ok(new_ivf_impl::<Float32Type>(
+            options,
+        ),
+        DataType::Float32 => new_ivf_impl::<Float32Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             transforms,
-            range,
-            precomputed_partitions,
-        )),
-        DataType::Float64 => Ok(new_ivf_impl::<Float64Type>(
+            options,
+        ),
+        DataType::Float64 => new_ivf_impl::<Float64Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             transforms,
-            range,
-            precomputed_partitions,
-        )),
+            options,
+        ),
         _ => Err(Error::Index {
             message: format!(
                 "new_ivf: centroids is not expected type: {}",
@@ -126,18 +217,19 @@ fn new_ivf_with_pq_impl<T: ArrowFloatType + Dot + Cosine + L2 + 'static>(
     metric_type: MetricType,
     vector_column: &str,
     pq: Arc<dyn ProductQuantizer>,
-    range: Option<Range<u32>>,
-    precomputed_partitions: Option<HashMap<u64, u32>>,
-) -> Arc<dyn Ivf> {
+    options: IvfAssignOptions,
+) -> Result<Arc<dyn Ivf>> {
     let mat = MatrixView::<T>::new(Arc::new(centroids.clone()), dimension);
-    Arc::new(IvfImpl::<T>::new_with_pq(
+    let ivf = IvfImpl::<T>::new_with_pq(
         mat,
         metric_type,
         vector_column,
         pq,
-        range,
-        precomputed_partitions,
-    ))
+        options.range.clone(),
+        options.precomputed_partitions.clone(),
+        options.residual_precision,
+    );
+    Ok(Arc::new(ivf.with_assign_options(&options)?))
 }
 
 pub fn new_ivf_with_pq(
@@ -148,35 +240,55 @@ pub fn new_ivf_with_pq(
     pq: Arc<dyn ProductQuantizer>,
     range: Option<Range<u32>>,
     precomputed_partitions: Option<HashMap<u64, u32>>,
+) -> Result<Arc<dyn Ivf>> {
+    new_ivf_with_pq_and_options(
+        centroids,
+        dimension,
+        metric_type,
+        vector_column,
+        pq,
+        IvfAssignOptions {
+            range,
+            precomputed_partitions,
+            ..Default::default()
+        },
+    )
+}
+
+/// Create an IVF with PQ from the flatten centroids, assigning the vectors with `options`.
+pub fn new_ivf_with_pq_and_options(
+    centroids: &dyn Array,
+    dimension: usize,
+    metric_type: MetricType,
+    vector_column: &str,
+    pq: Arc<dyn ProductQuantizer>,
+    options: IvfAssignOptions,
 ) -> Result<Arc<dyn Ivf>> {
     match centroids.data_type() {
-        DataType::Float16 => Ok(new_ivf_with_pq_impl::<Float16Type>(
+        DataType::Float16 => new_ivf_with_pq_impl::<Float16Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             vector_column,
             pq,
-            range,
-            precomputed_partitions,
-        )),
-        DataType::Float32 => Ok(new_ivf_with_pq_impl::<Float32Type>(
+            options,
+        ),
+        DataType::Float32 => new_ivf_with_pq_impl::<Float32Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             vector_column,
             pq,
-            range,
-            precomputed_partitions,
-        )),
-        DataType::Float64 => Ok(new_ivf_with_pq_impl::<Float64Type>(
+            options,
+        ),
+        DataType::Float64 => new_ivf_with_pq_impl::<Float64Type>(
             centroids.as_primitive(),
             dimension,
             metric_type,
             vector_column,
             pq,
-            range,
-            precomputed_partitions,
-        )),
+            options,
+        ),
         _ => Err(Error::Index {
             message: format!(
                 "new_ivf_with_pq: centroids is not expected type: {}",
@@ -187,6 +299,25 @@ pub fn new_ivf_with_pq(
     }
 }
 
+/// Distances computed to assign vectors to partitions, see [`Ivf::assignment_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AssignmentStats {
+    /// Number of distances computed between a vector and a centroid, including those
+    /// to the groups of a coarse quantizer tree.
+    pub distance_computations: u64,
+
+    /// Time spent computing the partitions, summed over the batches assigned in parallel.
+    pub duration: Duration,
+}
+
+impl AssignmentStats {
+    /// Distance computations per second of assignment time, `None` if no time was spent.
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.duration.as_secs_f64();
+        (secs > 0.0).then(|| self.distance_computations as f64 / secs)
+    }
+}
+
 /// IVF - IVF file partition
 ///
 #[async_trait]
@@ -229,9 +360,19 @@ pub trait Ivf: Send + Sync + std::fmt::Debug {
     /// schema `(PART_ID_COLUMN, ...)`, where [PART_ID_COLUMN] has the partition id for each vector.
     ///
     /// Note that the vector column might be transformed by the `transforms` in the IVF.
+    /// If the IVF is built with an assignment margin, the batch also has the
+    /// [ASSIGNMENT_MARGIN_COLUMN] of each vector, and likewise for the
+    /// [CENTROID_DISTANCE_COLUMN] and the alternative partitions. If it assigns each
+    /// vector to several partitions, the row of a vector is repeated for each of them.
     ///
     /// **Warning**: unstable API.
     async fn partition_transform(&self, batch: &RecordBatch, column: &str) -> Result<RecordBatch>;
+
+    /// Distances computed to assign the vectors of all the calls of
+    /// [`Self::partition_transform`] so far, not counting precomputed partitions.
+    fn assignment_stats(&self) -> AssignmentStats {
+        AssignmentStats::default()
+    }
 }
 
 /// IVF - IVF file partition
@@ -254,6 +395,24 @@ pub struct IvfImpl<T: ArrowFloatType + Dot + L2 + Cosine> {
     partition_range: Option<Range<u32>>,
 
     precomputed_partitions: Option<HashMap<u64, u32>>,
+
+    /// Coarse quantizer tree to assign vectors to partitions.
+    tree: Option<Arc<TreeAssigner<T>>>,
+
+    /// Add the [ASSIGNMENT_MARGIN_COLUMN] in `partition_transform`.
+    assignment_margin: bool,
+
+    /// Add the [CENTROID_DISTANCE_COLUMN] in `partition_transform`.
+    centroid_distance: bool,
+
+    /// Number of next nearest partitions of each vector added in `partition_transform`.
+    num_alternatives: usize,
+
+    /// Number of nearest partitions each vector is assigned to.
+    multi_assign: usize,
+
+    /// Distances computed by `partition_transform`, shared by the clones.
+    assignment_stats: Arc<Mutex<AssignmentStats>>,
 }
 
 impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
@@ -270,7 +429,208 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
             transforms,
             partition_range: range,
             precomputed_partitions,
+            tree: None,
+            assignment_margin: false,
+            centroid_distance: false,
+            num_alternatives: 0,
+            multi_assign: 1,
+            assignment_stats: Arc::default(),
+        }
+    }
+
+    /// Assign vectors to partitions with the coarse quantizer `tree`.
+    pub fn with_tree(mut self, tree: &IvfTree) -> Result<Self> {
+        self.tree = Some(Arc::new(TreeAssigner::try_new(
+            tree,
+            self.centroids.data().as_slice(),
+            self.dimension(),
+            self.metric_type,
+        )?));
+        Ok(self)
+    }
+
+    /// Add the [ASSIGNMENT_MARGIN_COLUMN] to the batches of `partition_transform`.
+    pub fn with_assignment_margin(mut self, assignment_margin: bool) -> Self {
+        self.assignment_margin = assignment_margin;
+        self
+    }
+
+    /// Add the [CENTROID_DISTANCE_COLUMN] to the batches of `partition_transform`, the
+    /// distance from each vector to the centroid of its partition found when assigning it.
+    pub fn with_centroid_distance(mut self, centroid_distance: bool) -> Self {
+        self.centroid_distance = centroid_distance;
+        self
+    }
+
+    /// Add the `num_alternatives` next nearest partitions of each vector after the one
+    /// it is assigned to, and the distances to them, to the batches of
+    /// `partition_transform`, as [ALTERNATIVE_PARTITIONS_COLUMN] and
+    /// [ALTERNATIVE_DISTANCES_COLUMN].
+    ///
+    /// They are found with the partition of the vector, so it can be moved to another
+    /// partition later without the vector. Not supported with `multi_assign`.
+    pub fn with_alternatives(mut self, num_alternatives: usize) -> Self {
+        self.num_alternatives = num_alternatives;
+        self
+    }
+
+    /// Assign each vector to its `multi_assign` nearest partitions in
+    /// `partition_transform`, which repeats the row of the vector for each of them.
+    ///
+    /// The partitions are searched like the nearest partition, with the tree if there
+    /// is one.
+    pub fn with_multi_assign(mut self, multi_assign: usize) -> Self {
+        self.multi_assign = multi_assign.max(1);
+        self
+    }
+
+    /// Number of nearest partitions of each vector needed for the assignment margin
+    /// and the alternative partitions, 0 if neither is added.
+    fn num_nearest(&self) -> usize {
+        if self.num_alternatives > 0 {
+            self.num_alternatives + 1
+        } else if self.assignment_margin {
+            2
+        } else {
+            0
+        }
+    }
+
+    /// Assign the rows with a [PINNED_PARTITION_COLUMN] value to that partition, with
+    /// the assigned `part_ids` of all the copies of the rows in `batch`. Only the first
+    /// copy of a pinned row is kept.
+    fn pin_partitions(
+        part_ids: &UInt32Array,
+        batch: RecordBatch,
+        pinned: &UInt32Array,
+    ) -> Result<(UInt32Array, RecordBatch)> {
+        if pinned.null_count() == pinned.len() {
+            return Ok((part_ids.clone(), batch));
         }
+        let num_copies = part_ids.len() / pinned.len();
+        let (indices, part_ids): (Vec<u32>, Vec<u32>) = part_ids
+            .values()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &part_id)| {
+                let row = idx / num_copies;
+                if pinned.is_null(row) {
+                    Some((idx as u32, part_id))
+                } else if idx % num_copies == 0 {
+                    Some((idx as u32, pinned.value(row)))
+                } else {
+                    None
+                }
+            })
+            .unzip();
+        let batch = batch.take(&UInt32Array::from(indices))?;
+        Ok((UInt32Array::from(part_ids), batch))
+    }
+
+    /// The [ASSIGNMENT_MARGIN_COLUMN] of each vector, from its `k` nearest partitions
+    /// in `nearest`.
+    fn margins_of(nearest: &[(u32, f32)], k: usize) -> Float32Array {
+        Float32Array::from_iter_values(
+            nearest
+                .chunks_exact(k)
+                .map(|n| assignment_margin(n[0].1, n.get(1).map_or(f32::INFINITY, |n| n.1))),
+        )
+    }
+
+    /// The [ALTERNATIVE_PARTITIONS_COLUMN] and [ALTERNATIVE_DISTANCES_COLUMN] of each
+    /// vector: its `k` nearest partitions in `nearest` other than its partition in
+    /// `part_ids`.
+    fn alternatives_of(
+        &self,
+        part_ids: &UInt32Array,
+        nearest: &[(u32, f32)],
+        k: usize,
+    ) -> Result<(FixedSizeListArray, FixedSizeListArray)> {
+        let num_alternatives = self.num_alternatives;
+        let mut alt_ids = Vec::with_capacity(part_ids.len() * num_alternatives);
+        let mut alt_distances = Vec::with_capacity(part_ids.len() * num_alternatives);
+        for (nearest, &part_id) in nearest.chunks_exact(k).zip(part_ids.values()) {
+            let start = alt_ids.len();
+            for &(id, distance) in nearest.iter().filter(|(id, _)| *id != part_id) {
+                if alt_ids.len() - start == num_alternatives {
+                    break;
+                }
+                alt_ids.push(id);
+                alt_distances.push(distance);
+            }
+            alt_ids.resize(start + num_alternatives, u32::MAX);
+            alt_distances.resize(start + num_alternatives, f32::INFINITY);
+        }
+        Ok((
+            FixedSizeListArray::try_new_from_values(
+                UInt32Array::from(alt_ids),
+                num_alternatives as i32,
+            )?,
+            FixedSizeListArray::try_new_from_values(
+                Float32Array::from(alt_distances),
+                num_alternatives as i32,
+            )?,
+        ))
+    }
+
+    /// The distance from each vector of `data` to the centroid of its partition in
+    /// `part_ids`.
+    fn assigned_distances(
+        &self,
+        data: &FixedSizeListArray,
+        part_ids: &UInt32Array,
+    ) -> Result<Float32Array> {
+        let dim = self.dimension();
+        let distance = tree::distance_fn::<T>(self.metric_type);
+        let values = self.native_values(data)?.as_slice();
+        Ok(Float32Array::from_iter_values(
+            values
+                .chunks_exact(dim)
+                .zip(part_ids.values())
+                .map(|(vector, &part_id)| {
+                    distance(vector, self.centroids.row(part_id as usize).unwrap())
+                }),
+        ))
+    }
+
+    /// The `k` nearest partitions of each vector, as `k` (partition id, distance)
+    /// pairs per vector, nearest first, and the number of distances computed to find
+    /// them. With a tree, they are searched with the tree.
+    fn compute_nearest_counted(
+        &self,
+        data: &FixedSizeListArray,
+        k: usize,
+    ) -> Result<(Vec<(u32, f32)>, u64)> {
+        let dim = data.value_length() as usize;
+        let values = self.native_values(data)?.as_slice();
+        if let Some(tree) = self.tree.as_ref() {
+            return Ok(tree.compute_nearest(values, dim, k));
+        }
+        let distance = tree::distance_fn::<T>(self.metric_type);
+        let centroids = self.centroids.data();
+        let mut nearest = vec![(u32::MAX, f32::INFINITY); data.len() * k];
+        for (vector, nearest) in values.chunks_exact(dim).zip(nearest.chunks_exact_mut(k)) {
+            for (part_id, centroid) in centroids.as_slice().chunks_exact(dim).enumerate() {
+                tree::push_nearest(nearest, part_id as u32, distance(vector, centroid));
+            }
+        }
+        let num_distances = (data.len() * self.centroids.num_rows()) as u64;
+        Ok((nearest, num_distances))
+    }
+
+    /// The values of `data`, of float type `T`.
+    fn native_values<'a>(&self, data: &'a FixedSizeListArray) -> Result<&'a T::ArrayType> {
+        data.values()
+            .as_any()
+            .downcast_ref::<T::ArrayType>()
+            .ok_or(Error::Index {
+                message: format!(
+                    "Ivf::compute_partitions: data is not expected type: {} got {}",
+                    T::FLOAT_TYPE,
+                    data.values().data_type()
+                ),
+                location: Default::default(),
+            })
     }
 
     fn new_with_pq(
@@ -280,6 +640,7 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
         pq: Arc<dyn ProductQuantizer>,
         range: Option<Range<u32>>,
         precomputed_partitions: Option<HashMap<u64, u32>>,
+        residual_precision: ResidualPrecision,
     ) -> Self {
         let transforms: Vec<Arc<dyn Transformer>> = if pq.use_residual() {
             vec![
@@ -288,18 +649,16 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
                     PART_ID_COLUMN,
                     vector_column,
                 )),
-                Arc::new(PQTransformer::new(
-                    pq.clone(),
-                    RESIDUAL_COLUMN,
-                    PQ_CODE_COLUMN,
-                )),
+                Arc::new(
+                    PQTransformer::new(pq.clone(), RESIDUAL_COLUMN, PQ_CODE_COLUMN)
+                        .with_precision(residual_precision),
+                ),
             ]
         } else {
-            vec![Arc::new(PQTransformer::new(
-                pq.clone(),
-                vector_column,
-                PQ_CODE_COLUMN,
-            ))]
+            vec![Arc::new(
+                PQTransformer::new(pq.clone(), vector_column, PQ_CODE_COLUMN)
+                    .with_precision(residual_precision),
+            )]
         };
         Self {
             centroids: centroids.clone(),
@@ -307,6 +666,12 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
             transforms,
             partition_range: range,
             precomputed_partitions,
+            tree: None,
+            assignment_margin: false,
+            centroid_distance: false,
+            num_alternatives: 0,
+            multi_assign: 1,
+            assignment_stats: Arc::default(),
         }
     }
 
@@ -314,10 +679,22 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
         self.centroids.ndim()
     }
 
-    /// Compute the partition for each row in the input Matrix.
+    /// The partition of each vector of `data`, and the number of distances computed
+    /// to find them.
+    async fn compute_partitions_counted(
+        &self,
+        data: &FixedSizeListArray,
+    ) -> Result<(UInt32Array, u64)> {
+        let array = self.native_values(data)?;
+        let mat = MatrixView::<T>::new(Arc::new(array.clone()), data.value_length());
+        Ok(self.do_compute_partitions(&mat).await)
+    }
+
+    /// Compute the partition for each row in the input Matrix, and the number of
+    /// distances computed to find them.
     ///
     #[instrument(level = "debug", skip(data))]
-    async fn do_compute_partitions(&self, data: &MatrixView<T>) -> UInt32Array {
+    async fn do_compute_partitions(&self, data: &MatrixView<T>) -> (UInt32Array, u64) {
         use lance_linalg::kmeans::compute_partitions;
 
         let dimension = data.ndim();
@@ -338,7 +715,7 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
         let chunk_size = num_rows / chunks + if num_rows % chunks > 0 { 1 } else { 0 };
         let stride = chunk_size * dimension;
 
-        let result: Vec<Vec<u32>> = stream::iter(0..chunks)
+        let result: Vec<(Vec<u32>, u64)> = stream::iter(0..chunks)
             .map(|chunk_id| stride * chunk_id..std::cmp::min(stride * (chunk_id + 1), data.len()))
             // When there are a large number of CPUs and a small number of rows,
             // it's possible there isn't an split of rows that there isn't
@@ -351,40 +728,37 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> IvfImpl<T> {
                 let centroids = centroids.clone();
                 let data = data.clone();
 
-                compute_partitions::<T>(
+                if let Some(tree) = self.tree.as_ref() {
+                    return tree
+                        .compute_partitions(&data.as_slice()[range], dimension)
+                        .in_current_span()
+                        .await;
+                }
+                let num_distances = (range.len() / dimension * num_centroids) as u64;
+                let part_ids = compute_partitions::<T>(
                     centroids.as_slice(),
                     &data.as_slice()[range],
                     dimension,
                     metric_type,
                 )
                 .in_current_span()
-                .await
+                .await;
+                (part_ids, num_distances)
             })
             .buffered(chunks)
             .collect::<Vec<_>>()
             .await;
 
-        UInt32Array::from_iter(result.iter().flatten().copied())
+        let num_distances = result.iter().map(|(_, n)| n).sum();
+        let part_ids = UInt32Array::from_iter(result.into_iter().flat_map(|(ids, _)| ids));
+        (part_ids, num_distances)
     }
 }
 
 #[async_trait]
 impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> Ivf for IvfImpl<T> {
     async fn compute_partitions(&self, data: &FixedSizeListArray) -> Result<UInt32Array> {
-        let array = data
-            .values()
-            .as_any()
-            .downcast_ref::<T::ArrayType>()
-            .ok_or(Error::Index {
-                message: format!(
-                    "Ivf::compute_partitions: data is not expected type: {} got {}",
-                    T::FLOAT_TYPE,
-                    data.values().data_type()
-                ),
-                location: Default::default(),
-            })?;
-        let mat = MatrixView::<T>::new(Arc::new(array.clone()), data.value_length());
-        Ok(self.do_compute_partitions(&mat).await)
+        Ok(self.compute_partitions_counted(data).await?.0)
     }
 
     async fn compute_residual(
@@ -459,32 +833,150 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> Ivf for IvfImpl<T> {
             location: location!(),
         })?;
 
-        let part_ids = match (&self.precomputed_partitions, batch.column_by_name(ROW_ID)) {
-            (Some(partitions), Some(row_ids)) => {
-                debug!("Using precomputed partitions for partitions");
-                let mut builder = UInt32Builder::new();
-                for row in row_ids
-                    .as_any()
-                    .downcast_ref::<UInt64Array>()
-                    .unwrap()
-                    .values()
-                    .iter()
-                {
-                    if let Some(part_id) = partitions.get(row) {
-                        builder.append_value(*part_id);
-                    } else {
-                        return Err(Error::Index {
-                            message: format!(
-                                "Row ID {} does not exist in the precomputed partitions",
-                                row
-                            ),
-                            location: location!(),
-                        });
+        if self.multi_assign > 1 && self.num_alternatives > 0 {
+            return Err(Error::Index {
+                message: "Alternative partitions are not supported with multi_assign".to_string(),
+                location: location!(),
+            });
+        }
+        let k = self.num_nearest();
+        // The `k` nearest partitions of each vector, and the distance from each output
+        // row to the centroid of its partition.
+        let (part_ids, nearest, margins, distances) =
+            match (&self.precomputed_partitions, batch.column_by_name(ROW_ID)) {
+                (Some(partitions), Some(row_ids)) => {
+                    debug!("Using precomputed partitions for partitions");
+                    let mut builder = UInt32Builder::new();
+                    for row in row_ids
+                        .as_any()
+                        .downcast_ref::<UInt64Array>()
+                        .unwrap()
+                        .values()
+                        .iter()
+                    {
+                        if let Some(part_id) = partitions.get(row) {
+                            builder.append_value(*part_id);
+                        } else {
+                            return Err(Error::Index {
+                                message: format!(
+                                    "Row ID {} does not exist in the precomputed partitions",
+                                    row
+                                ),
+                                location: location!(),
+                            });
+                        }
                     }
+                    let part_ids = builder.finish();
+                    let nearest = if k > 0 {
+                        Some((self.compute_nearest_counted(data, k)?.0, k))
+                    } else {
+                        None
+                    };
+                    let distances = if self.centroid_distance {
+                        Some(self.assigned_distances(data, &part_ids)?)
+                    } else {
+                        None
+                    };
+                    (part_ids, nearest, None, distances)
                 }
-                builder.finish()
+                _ => {
+                    let start = Instant::now();
+                    let (part_ids, nearest, margins, distances, num_distances) = if self
+                        .multi_assign
+                        > 1
+                    {
+                        let k = self.multi_assign.min(self.centroids.num_rows());
+                        let (nearest, num_distances) = self.compute_nearest_counted(data, k)?;
+                        let part_ids =
+                            UInt32Array::from_iter_values(nearest.iter().map(|(id, _)| *id));
+                        let distances =
+                            Float32Array::from_iter_values(nearest.iter().map(|(_, d)| *d));
+                        (
+                            part_ids,
+                            None,
+                            Some(Self::margins_of(&nearest, k)),
+                            Some(distances),
+                            num_distances,
+                        )
+                    } else if k > 0 || self.centroid_distance {
+                        let k = k.max(1);
+                        let (nearest, num_distances) = self.compute_nearest_counted(data, k)?;
+                        let part_ids =
+                            UInt32Array::from_iter_values(nearest.chunks_exact(k).map(|n| n[0].0));
+                        let distances =
+                            Float32Array::from_iter_values(nearest.chunks_exact(k).map(|n| n[0].1));
+                        (
+                            part_ids,
+                            Some((nearest, k)),
+                            None,
+                            Some(distances),
+                            num_distances,
+                        )
+                    } else {
+                        let (part_ids, num_distances) =
+                            self.compute_partitions_counted(data).await?;
+                        (part_ids, None, None, None, num_distances)
+                    };
+                    let mut stats = self.assignment_stats.lock().unwrap();
+                    stats.distance_computations += num_distances;
+                    stats.duration += start.elapsed();
+                    (part_ids, nearest, margins, distances)
+                }
+            };
+
+        let mut batch = batch.clone();
+        let pinned = match batch.column_by_name(PINNED_PARTITION_COLUMN) {
+            Some(pinned) => {
+                let pinned = pinned.as_primitive::<UInt32Type>().clone();
+                batch = batch.drop_column(PINNED_PARTITION_COLUMN)?;
+                Some(pinned)
             }
-            _ => self.compute_partitions(data).await?,
+            None => None,
+        };
+        let margins = margins.or_else(|| {
+            nearest
+                .as_ref()
+                .map(|(nearest, k)| Self::margins_of(nearest, *k))
+        });
+        if let Some(margins) = margins.filter(|_| self.assignment_margin) {
+            let field = Field::new(ASSIGNMENT_MARGIN_COLUMN, DataType::Float32, false);
+            batch = batch.try_with_column(field, Arc::new(margins))?;
+        }
+        if let Some((nearest, k)) = nearest.filter(|_| self.num_alternatives > 0) {
+            let (alt_ids, alt_distances) = self.alternatives_of(&part_ids, &nearest, k)?;
+            let field = Field::new(
+                ALTERNATIVE_PARTITIONS_COLUMN,
+                alt_ids.data_type().clone(),
+                false,
+            );
+            batch = batch.try_with_column(field, Arc::new(alt_ids))?;
+            let field = Field::new(
+                ALTERNATIVE_DISTANCES_COLUMN,
+                alt_distances.data_type().clone(),
+                false,
+            );
+            batch = batch.try_with_column(field, Arc::new(alt_distances))?;
+        }
+        // Repeat the row of each vector for each of its partitions.
+        let batch = if part_ids.len() > batch.num_rows() {
+            let num_copies = part_ids.len() / batch.num_rows();
+            let indices = UInt32Array::from_iter_values(
+                (0..batch.num_rows() as u32).flat_map(|i| std::iter::repeat(i).take(num_copies)),
+            );
+            batch.take(&indices)?
+        } else {
+            batch
+        };
+        let batch = if let Some(distances) = distances.filter(|_| self.centroid_distance) {
+            let field = Field::new(CENTROID_DISTANCE_COLUMN, DataType::Float32, false);
+            batch.try_with_column(field, Arc::new(distances))?
+        } else {
+            batch
+        };
+
+        let (part_ids, batch) = match pinned.as_ref() {
+            Some(pinned) => Self::pin_partitions(&part_ids, batch, pinned)?,
+            None => (part_ids, batch),
         };
 
         let (part_ids, batch) = if let Some(part_range) = self.partition_range.as_ref() {
@@ -501,7 +993,7 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> Ivf for IvfImpl<T> {
             let batch = batch.take(&idx_in_range)?;
             (part_ids, batch)
         } else {
-            (part_ids, batch.clone())
+            (part_ids, batch)
         };
 
         let field = Field::new(PART_ID_COLUMN, part_ids.data_type().clone(), false);
@@ -514,4 +1006,8 @@ impl<T: ArrowFloatType + Dot + L2 + Cosine + 'static> Ivf for IvfImpl<T> {
 
         Ok(batch)
     }
+
+    fn assignment_stats(&self) -> AssignmentStats {
+        *self.assignment_stats.lock().unwrap()
+    }
 }
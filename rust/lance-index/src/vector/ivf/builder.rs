@@ -14,15 +14,131 @@
 
 //! Build IVF model
 
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use arrow_array::{Array, FixedSizeListArray};
+use arrow_array::{Array, FixedSizeListArray, RecordBatch};
+use datafusion::execution::memory_pool::MemoryPool;
+use datafusion_expr::Expr;
 use snafu::{location, Location};
+use tokio::sync::Notify;
 
 use lance_core::error::{Error, Result};
 
+use super::shuffler::{SpillCallback, SpillCodec, SpillFileNameFn};
+use crate::pb;
+use crate::vector::codec::VectorCodec;
+use crate::vector::pca::PcaMatrix;
+use crate::vector::pq::{CodeStorageOrder, PQBuildParams};
+use crate::vector::residual::ResidualPrecision;
+
+/// Callback invoked with the id and the number of rows of each IVF partition once it
+/// is written, see [`IvfBuildParams::on_partition_written`].
+pub type PartitionWrittenCallback = Arc<dyn Fn(u32, usize) + Send + Sync>;
+
+/// Storage tier of an IVF partition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Tier {
+    /// Kept with the rest of the index.
+    #[default]
+    Hot,
+
+    /// Written to a separate file, e.g. on cheaper storage, for rarely probed partitions.
+    Cold,
+}
+
+impl From<pb::PartitionTier> for Tier {
+    fn from(proto: pb::PartitionTier) -> Self {
+        match proto {
+            pb::PartitionTier::Hot => Self::Hot,
+            pb::PartitionTier::Cold => Self::Cold,
+        }
+    }
+}
+
+impl From<Tier> for pb::PartitionTier {
+    fn from(tier: Tier) -> Self {
+        match tier {
+            Tier::Hot => Self::Hot,
+            Tier::Cold => Self::Cold,
+        }
+    }
+}
+
+/// Function assigning the [Tier] of each partition id.
+pub type TierFn = Arc<dyn Fn(u32) -> Tier + Send + Sync>;
+
+/// Callback invoked with the percentage of the rows written to the index file, see
+/// [`IvfBuildParams::on_build_progress`].
+pub type BuildProgressCallback = Arc<dyn Fn(f64) + Send + Sync>;
+
+/// Mapping of the input ROW_IDs to the ROW_IDs stored in the index, see
+/// [`IvfBuildParams::row_id_map`].
+pub type RowIdMap = Arc<dyn Fn(u64) -> u64 + Send + Sync>;
+
+/// Callback invoked with the metrics of a build in the Prometheus text format, see
+/// [`IvfBuildParams::on_prometheus_metrics`].
+pub type PrometheusMetricsCallback = Arc<dyn Fn(String) + Send + Sync>;
+
+/// Transform of each batch of a shuffled partition, with the partition id, see
+/// [`IvfBuildParams::post_shuffle_transform`].
+pub type PostShuffleTransform = Arc<dyn Fn(u32, RecordBatch) -> Result<RecordBatch> + Send + Sync>;
+
+/// Handle to pause and resume a running IVF build, see [`IvfBuildParams::control`].
+///
+/// Clones share the same state, so the handle kept by the caller controls the build
+/// running with its clone.
+#[derive(Debug, Clone, Default)]
+pub struct BuildControl {
+    state: Arc<BuildControlState>,
+}
+
+#[derive(Debug, Default)]
+struct BuildControlState {
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl BuildControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pause the build before its next input batch is transformed or its next
+    /// partition is written. The work in flight is finished first.
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume a paused build where it stopped.
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+        self.state.resumed.notify_waiters();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the build is not paused, called by the build between units of work.
+    pub async fn wait_if_paused(&self) {
+        loop {
+            // Registered before checking the flag, so a resume in between is not missed.
+            let resumed = self.state.resumed.notified();
+            if !self.is_paused() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+}
+
 /// Parameters to build IVF partitions
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct IvfBuildParams {
     /// Number of partitions to build.
     pub num_partitions: usize,
@@ -36,7 +152,543 @@ pub struct IvfBuildParams {
 
     pub sample_rate: usize,
 
+    /// Minimum number of training vectors per partition. Training the centroids on
+    /// fewer vectors than `num_partitions * min_training_rows_per_partition` fails,
+    /// instead of producing degenerate centroids. At most [`Self::sample_rate`], as no
+    /// more vectors are sampled for training.
+    pub min_training_rows_per_partition: usize,
+
     pub precomputed_partitons_file: Option<String>,
+
+    /// Only index the rows where `hash(ROW_ID) % n == r`, given as `(n, r)`.
+    ///
+    /// This gives a reproducible, roughly `1/n` sample of the input without
+    /// a scan-side filter.
+    pub sample_mod: Option<(u64, u64)>,
+
+    /// Seed of the random sampling of the build, e.g. of the training data, of the
+    /// k-means initialization, of the coarse quantizer tree and of the self-recall
+    /// check, so the build can be reproduced. `None` seeds from entropy.
+    pub seed: Option<u64>,
+
+    /// Only index the rows of one hash shard of `ROW_ID`, given as
+    /// `(num_shards, shard_id)`, so that each node of a cluster builds an index over
+    /// all the centroids for a disjoint part of the rows. The shards built with the
+    /// same centroids and PQ codebook can then be merged partition by partition.
+    ///
+    /// It filters by the same hash as [`Self::sample_mod`], so the two can not be
+    /// combined.
+    pub hash_shard: Option<(u64, u64)>,
+
+    /// Store the ROW_ID of each row mapped by this function, e.g. to build an index
+    /// over a copy of the data whose rows were renumbered by a compaction.
+    ///
+    /// The rows are sampled, sharded and assigned from precomputed partitions by their
+    /// input ROW_IDs, and mapped right before the shuffle.
+    pub row_id_map: Option<RowIdMap>,
+
+    /// Called each time the shuffler finalizes a spill file, for example, to
+    /// start uploading it while the build continues.
+    pub on_spill_finalized: Option<SpillCallback>,
+
+    /// Called with the id and the number of rows of each partition as soon as it is
+    /// written to the index file, e.g. to show the progress of the write stage.
+    pub on_partition_written: Option<PartitionWrittenCallback>,
+
+    /// Called each time a partition is written with the rows written so far, as a
+    /// percentage from 0 to 100 of all the rows to write.
+    ///
+    /// Unlike counting the partitions, the percentage advances by the size of each
+    /// partition, so it is smooth even if the partition sizes are skewed.
+    pub on_build_progress: Option<BuildProgressCallback>,
+
+    /// Namespace and callback invoked once the partitions are built with the rows,
+    /// partitions and duration of the build, as gauges in the Prometheus text format
+    /// with names prefixed by the namespace, for simple scraping.
+    pub on_prometheus_metrics: Option<(String, PrometheusMetricsCallback)>,
+
+    /// Pauses the transform and the write stages of the build while paused, e.g. to
+    /// yield resources to an interactive workload, see [`BuildControl`].
+    pub control: Option<BuildControl>,
+
+    /// Transform each batch of the shuffled partitions right before it is written,
+    /// e.g. to add a derived column. The columns added by the transform are stored
+    /// in each partition after its other columns, so they must be of fixed-width
+    /// primitive types, and be added to every batch.
+    pub post_shuffle_transform: Option<PostShuffleTransform>,
+
+    /// Serialize the spill files of the shuffle with this codec, e.g. to merge them
+    /// with an external tool, instead of the Lance file format.
+    pub spill_codec: Option<Arc<dyn SpillCodec>>,
+
+    /// Name the spill files of the shuffle with this function, e.g. to include a job
+    /// id, instead of `sorted_{id}.lance`, see [`SpillFileNameFn`].
+    pub spill_file_name_fn: Option<SpillFileNameFn>,
+
+    /// Reserve the memory of the shuffle buffers from this pool, and spill the largest
+    /// buffers when it runs out, instead of spilling a fixed number of batches.
+    pub shuffle_memory_pool: Option<Arc<dyn MemoryPool>>,
+
+    /// Bytes to keep available in [`Self::shuffle_memory_pool`] for the rest of the
+    /// build, e.g. the index writer, when buffering the shuffle.
+    pub shuffle_memory_min_available: usize,
+
+    /// If set, also store the vectors reduced by this PCA projection in each
+    /// partition, so the candidates can be reranked with approximately
+    /// reconstructed vectors.
+    pub pca: Option<PcaMatrix>,
+
+    /// Train a PQ codebook for each partition from its own residuals, instead
+    /// of encoding all partitions with the shared codebook.
+    ///
+    /// Partitions with fewer rows than PQ centroids keep the shared codebook.
+    pub per_partition_pq: bool,
+
+    /// Parameters to train the codebook of each partition with, if
+    /// [`Self::per_partition_pq`], e.g. the number of kmeans iterations. The number of
+    /// sub-vectors and bits are always the ones of the shared codebook.
+    ///
+    /// `build_ivf_pq_index` uses its PQ build parameters if not set.
+    pub per_partition_pq_params: Option<PQBuildParams>,
+
+    /// Maximum number of shuffle files to keep open at the same time, for
+    /// systems with a low limit of open file descriptors.
+    pub max_open_files: Option<usize>,
+
+    /// Count the partition sizes of the shuffle with up to this many tasks in
+    /// parallel, each over a part of the shuffled batches, instead of one.
+    pub shuffle_count_concurrency: Option<usize>,
+
+    /// Storage order of the PQ codes of each partition, to match the scan kernel.
+    pub code_storage_order: CodeStorageOrder,
+
+    /// Tombstone column of the input, carried into each partition as a boolean
+    /// valid column, so deleted rows can be masked at query time.
+    ///
+    /// A row is valid if its tombstone value is null, or false for a boolean column.
+    pub valid_column: Option<String>,
+
+    /// Categorical column to group the rows of each partition by, e.g. a language, so
+    /// a query filtering on one of its values reads only the rows of that value. The
+    /// values are cast to strings, and the offset of each group is stored in the index.
+    ///
+    /// Requires [`CodeStorageOrder::Interleaved`] codes.
+    pub subgroup_column: Option<String>,
+
+    /// Store the ROW_ID of the medoid of each partition, that is the assigned vector
+    /// closest to the centroid.
+    pub compute_medoids: bool,
+
+    /// Skip up to this many input batches that fail to be transformed, instead of
+    /// failing the build on the first error.
+    pub max_bad_batches: Option<usize>,
+
+    /// Assign vectors to partitions with a two-level tree, that clusters the
+    /// partitions into this many groups, instead of comparing each vector with
+    /// every centroid. About `sqrt(num_partitions)` is a good choice.
+    pub num_coarse_partitions: Option<usize>,
+
+    /// Decode the vector column from binary blobs with this codec, for datasets
+    /// that store their vectors in a custom encoding instead of a `FixedSizeList`.
+    pub vector_codec: Option<Arc<dyn VectorCodec>>,
+
+    /// Produce the vector column by evaluating this DataFusion expression over each
+    /// input batch before the transform, e.g. a UDF computing the embeddings of a text
+    /// column, so the input only carries the columns the expression reads.
+    ///
+    /// A dataset build scans the columns the expression reads, and trains the models
+    /// on the vectors computed from the sampled rows. Not supported with
+    /// [`Self::pipelined_training_rows`].
+    pub vector_expr: Option<Expr>,
+
+    /// Log the ids and sizes of this many largest partitions once they are built,
+    /// to diagnose skewed partitions without the full size histogram.
+    pub log_top_partitions: Option<usize>,
+
+    /// Pass the transformed batches to the shuffle through a channel of this many
+    /// batches, so no more transform tasks are spawned while the shuffle is behind.
+    pub transform_channel_capacity: Option<usize>,
+
+    /// Weight of each dimension in the distance used to assign vectors to
+    /// partitions and to compute their residuals, one per dimension.
+    ///
+    /// The PQ codebook should be trained on the vectors scaled by the square roots
+    /// of the weights, see [`crate::vector::weight::WeightTransform`].
+    pub weights: Option<Vec<f32>>,
+
+    /// Only index the rows whose timestamp is in this window.
+    pub time_window: Option<TimeWindow>,
+
+    /// Store the assignment margin of each vector in its partition, see
+    /// [`crate::vector::ASSIGNMENT_MARGIN_COLUMN`], so that queries close to
+    /// low-margin vectors can probe more partitions.
+    pub assignment_margin: bool,
+
+    /// Also store the shared PQ codebook laid out dimension-major, see
+    /// [`crate::vector::pq::lookup::TransposedCodebook`], to build the distance
+    /// tables of queries faster.
+    pub transposed_codebook: bool,
+
+    /// Train the IVF and PQ models on the first this many rows of the scan, and
+    /// assign and encode all the rows in the same scan, instead of sampling the
+    /// training data in a separate pass over the dataset.
+    ///
+    /// The first rows should be representative of the dataset.
+    pub pipelined_training_rows: Option<usize>,
+
+    /// Scan each fragment of the dataset separately, with up to this many fragments
+    /// scanned at the same time, instead of a single scan of the dataset, to keep
+    /// more reads in flight on datasets with many fragments.
+    pub parallel_fragment_scans: Option<usize>,
+
+    /// Reassign the rows of the partitions with fewer than this many rows to the
+    /// nearest partition that has enough rows, so no tiny partitions are written.
+    ///
+    /// Partitions are kept or folded by their size before reassignment, so a kept
+    /// partition never drops below the threshold.
+    pub min_partition_rows: Option<usize>,
+
+    /// Assign each vector to its `multi_assign` nearest partitions, rather than only the
+    /// nearest, for a higher recall at the cost of a larger index. 1 by default.
+    ///
+    /// A row can then be found in several probed partitions, so searches keep the
+    /// nearest copy of each row.
+    pub multi_assign: usize,
+
+    /// Precision of the product quantization of the residuals of the vectors to their
+    /// centroids. [`ResidualPrecision::F64`] accumulates the distances to the sub-vector
+    /// centroids in f64, for stabler codes in near-degenerate clusters. Applies to the
+    /// shared product quantizer, not to [`Self::per_partition_pq`].
+    pub residual_precision: ResidualPrecision,
+
+    /// Sort the input rows by this column before the shuffle, e.g. by a cluster hint,
+    /// so that similar rows are next to each other in their partition and the spill
+    /// files and the partitions compress better.
+    pub presort_by: Option<String>,
+
+    /// Store the coarse quantizer tree with the covering radius of each group, see
+    /// [`super::tree::IvfTree::with_radii`], so that appends assign new vectors to their
+    /// closest partition exactly, without comparing with every centroid. Requires the
+    /// L2 metric.
+    ///
+    /// The tree has `num_coarse_partitions` groups if set, `sqrt(num_partitions)`
+    /// otherwise.
+    pub store_assignment_accelerator: bool,
+
+    /// Write the spill files of the shuffle one at a time, in increasing id order, so
+    /// that the intermediate files of a build are reproducible.
+    pub deterministic_spill_order: bool,
+
+    /// Count the rows of each partition by the L2 norm of their residual to the
+    /// partition centroid, into the coarse log-scale bins of the build report, e.g. to
+    /// spot partitions whose centroid fits its vectors poorly.
+    ///
+    /// The rows are counted as they are assigned, before partitions are folded under
+    /// `min_partition_rows`.
+    pub collect_residual_histograms: bool,
+
+    /// Train the IVF centroids on a sample that over-samples the sparse regions of the
+    /// training data, rather than on a uniform one, so that small, isolated clusters
+    /// get centroids of their own.
+    ///
+    /// The k-means sample is drawn from a uniform sample of many more vectors, see
+    /// `lance::index::vector::utils::maybe_sample_training_data`, and its density is
+    /// estimated on a coarse grid over the dimensions of largest variance. The PQ is
+    /// still trained on a uniform sample.
+    pub density_sampling: bool,
+
+    /// Also write a bit-packed copy of the PQ codes of each partition, after its other
+    /// columns, so that the raw and the bit-packed layouts can be compared on the same
+    /// index, see [`crate::vector::pq::CodeLayout`].
+    pub dual_code_layout: bool,
+
+    /// Fail the build if the L2 norms of the input vectors fall into two well separated
+    /// groups, which often means that embeddings of different models were mixed in the
+    /// column, see [`crate::vector::stats::NormHistogram::bimodality`].
+    pub reject_bimodal_norms: bool,
+
+    /// Query the built partitions for a random sample of this many input vectors, and
+    /// report how many of them find their own row among their nearest neighbors.
+    /// Nearly every vector of a correct build finds itself, so a low self-recall
+    /// points to a bug in the build rather than in the data.
+    pub self_recall_check: Option<usize>,
+
+    /// Time budget of building the partitions, once the IVF and PQ models are trained,
+    /// for best-effort background builds. When it runs out, no further input batches
+    /// are read, the rows read so far are written as usual and the index is marked as
+    /// partial, so it is valid but does not cover all the rows.
+    pub max_duration: Option<Duration>,
+
+    /// Cap on the number of rows stored in each partition. The rows assigned to a full
+    /// partition are dropped, so which rows are kept depends on the order the input is
+    /// transformed in. Once every partition of the range being built is full, no
+    /// further input batches are read.
+    ///
+    /// The cap applies before [`Self::min_partition_rows`] folds partitions together.
+    pub max_partition_rows: Option<usize>,
+
+    /// Cap on how fast the input batches are read, e.g. to stay within the throughput
+    /// quota of a shared storage. Reads are paced by a token bucket over the in-memory
+    /// size of the batches, which allows bursts of up to a tenth of a second of reads.
+    pub read_rate_limit: Option<BytesPerSec>,
+
+    /// Store the centroids as bfloat16 in the index metadata, halving their size. The
+    /// centroids are rounded to bfloat16 once trained, so the coarse quantizer, the PQ
+    /// residuals and the assignment are all computed in float32 with the upcast values
+    /// the index is read back with. Requires float32 centroids.
+    pub bf16_centroids: bool,
+
+    /// Order to write the partitions in the index file, a permutation of the partition
+    /// ids, e.g. by centroid proximity so that the partitions probed together are
+    /// adjacent on disk. The partitions are still looked up by their id.
+    ///
+    /// Partitions are built in id order, and each one is held in memory until the
+    /// partitions before it in the order are written. So the build holds the bytes of
+    /// all the partitions built ahead of their turn: the whole index for an order that
+    /// starts with the last partition id. Orders that mostly follow the ids, e.g. with
+    /// neighbours swapped, hold little. The peak is logged after the partitions are
+    /// written.
+    pub output_order: Option<Vec<u32>>,
+
+    /// Storage tier of each partition id. The [`Tier::Cold`] partitions are written to
+    /// a separate `cold.idx` file next to the index file, e.g. to keep the rarely
+    /// probed partitions on cheaper storage. Can not be used with [`Self::package`].
+    pub tier_fn: Option<TierFn>,
+
+    /// Gather the per-dimension mean and variance of the input vectors while building
+    /// the partitions, see [`crate::vector::stats::VectorStats`], and store them in the
+    /// index, so queries can be standardized consistently.
+    pub compute_global_stats: bool,
+
+    /// Key-value metadata to store in the index file as is, e.g. for governance, see
+    /// `lance::index::read_index_toc`.
+    pub custom_metadata: HashMap<String, String>,
+
+    /// Also write the index file into a single archive next to it, to ship the built
+    /// index as one artifact. The archive is written along with the index file, which
+    /// is split into several members of the archive to do so.
+    pub package: Option<PackageFormat>,
+
+    /// Write a human-readable summary of the build to this local file once the
+    /// partitions are written, e.g. `index.summary.txt`: the metric, the dimension,
+    /// the number of partitions and rows, the non-empty and the largest partitions,
+    /// and the build duration. For debugging only, the format is not stable.
+    pub write_summary: Option<PathBuf>,
+
+    /// What to do with the vectors of zero norm under the Cosine metric, which have no
+    /// direction to assign or encode, in the training sample as well as in the indexed
+    /// rows. Ignored by the other metrics.
+    pub on_zero_norm: ZeroNormPolicy,
+
+    /// Cap the estimated size of the index file in bytes: the build uses the largest
+    /// number of PQ sub-vectors, up to the requested one and dividing the dimension,
+    /// whose estimate fits, and fails if none does. Can not be used with a given PQ
+    /// codebook.
+    pub target_index_bytes: Option<usize>,
+
+    /// Write the partitions. Without it, the rows are still transformed and grouped
+    /// into the partitions, and the build report and the partition lengths are filled,
+    /// but nothing is written, to profile the cost of the assignment and the encoding
+    /// without IO. No index file is created, so the index can not be committed; the
+    /// report is returned by `build_ivf_pq_index`.
+    pub write: bool,
+}
+
+/// A rate in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BytesPerSec(pub u64);
+
+/// Single-file archive of a built index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageFormat {
+    /// Uncompressed tar, which can be queried in place.
+    Tar,
+
+    /// Gzip-compressed tar, which is decompressed into a local temporary file to be
+    /// queried.
+    TarGz,
+}
+
+impl PackageFormat {
+    /// File name of the archive in the index directory.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Tar => "index.tar",
+            Self::TarGz => "index.tar.gz",
+        }
+    }
+}
+
+/// Handling of the vectors of zero norm in a Cosine build, see
+/// [`IvfBuildParams::on_zero_norm`].
+///
+/// The rows handled are counted in the `zero_norm_rows` of the build report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ZeroNormPolicy {
+    /// Leave the rows out of the index.
+    #[default]
+    Skip,
+
+    /// Fail the build.
+    Error,
+
+    /// Index the rows in partition 0, encoded as the centroid of the partition,
+    /// whatever the partition nearest to it. They are left out of the training
+    /// sample.
+    AssignToPartitionZero,
+}
+
+/// Rows whose value of a timestamp column is in `[start, end)`.
+///
+/// The bounds are in the unit of the column, e.g. microseconds for a
+/// `Timestamp(Microsecond, _)` column. Rows with a null timestamp are not in any window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Timestamp column.
+    pub column: String,
+
+    /// Inclusive lower bound.
+    pub start: i64,
+
+    /// Exclusive upper bound.
+    pub end: i64,
+}
+
+impl TimeWindow {
+    pub fn new(column: &str, range: Range<i64>) -> Self {
+        Self {
+            column: column.to_owned(),
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    /// The window of `duration` that ends at `end`, e.g. the last N days.
+    pub fn last(column: &str, end: i64, duration: i64) -> Self {
+        Self::new(column, end.saturating_sub(duration)..end)
+    }
+
+    pub fn contains(&self, timestamp: i64) -> bool {
+        (self.start..self.end).contains(&timestamp)
+    }
+}
+
+impl From<&TimeWindow> for pb::TimeWindow {
+    fn from(window: &TimeWindow) -> Self {
+        Self {
+            column: window.column.clone(),
+            start: window.start,
+            end: window.end,
+        }
+    }
+}
+
+impl From<&pb::TimeWindow> for TimeWindow {
+    fn from(proto: &pb::TimeWindow) -> Self {
+        Self::new(&proto.column, proto.start..proto.end)
+    }
+}
+
+impl std::fmt::Debug for IvfBuildParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IvfBuildParams")
+            .field("num_partitions", &self.num_partitions)
+            .field("max_iters", &self.max_iters)
+            .field("centroids", &self.centroids)
+            .field("sample_rate", &self.sample_rate)
+            .field(
+                "min_training_rows_per_partition",
+                &self.min_training_rows_per_partition,
+            )
+            .field(
+                "precomputed_partitons_file",
+                &self.precomputed_partitons_file,
+            )
+            .field("sample_mod", &self.sample_mod)
+            .field("seed", &self.seed)
+            .field("hash_shard", &self.hash_shard)
+            .field("row_id_map", &self.row_id_map.is_some())
+            .field("on_spill_finalized", &self.on_spill_finalized.is_some())
+            .field("on_partition_written", &self.on_partition_written.is_some())
+            .field("on_build_progress", &self.on_build_progress.is_some())
+            .field(
+                "on_prometheus_metrics",
+                &self
+                    .on_prometheus_metrics
+                    .as_ref()
+                    .map(|(namespace, _)| namespace),
+            )
+            .field("control", &self.control)
+            .field(
+                "post_shuffle_transform",
+                &self.post_shuffle_transform.is_some(),
+            )
+            .field("spill_codec", &self.spill_codec)
+            .field("spill_file_name_fn", &self.spill_file_name_fn.is_some())
+            .field("shuffle_memory_pool", &self.shuffle_memory_pool)
+            .field(
+                "shuffle_memory_min_available",
+                &self.shuffle_memory_min_available,
+            )
+            .field("pca", &self.pca)
+            .field("per_partition_pq", &self.per_partition_pq)
+            .field("per_partition_pq_params", &self.per_partition_pq_params)
+            .field("max_open_files", &self.max_open_files)
+            .field("shuffle_count_concurrency", &self.shuffle_count_concurrency)
+            .field("code_storage_order", &self.code_storage_order)
+            .field("valid_column", &self.valid_column)
+            .field("subgroup_column", &self.subgroup_column)
+            .field("compute_medoids", &self.compute_medoids)
+            .field("max_bad_batches", &self.max_bad_batches)
+            .field("num_coarse_partitions", &self.num_coarse_partitions)
+            .field("vector_codec", &self.vector_codec)
+            .field(
+                "vector_expr",
+                &self.vector_expr.as_ref().map(ToString::to_string),
+            )
+            .field("log_top_partitions", &self.log_top_partitions)
+            .field(
+                "transform_channel_capacity",
+                &self.transform_channel_capacity,
+            )
+            .field("weights", &self.weights)
+            .field("time_window", &self.time_window)
+            .field("assignment_margin", &self.assignment_margin)
+            .field("transposed_codebook", &self.transposed_codebook)
+            .field("pipelined_training_rows", &self.pipelined_training_rows)
+            .field("parallel_fragment_scans", &self.parallel_fragment_scans)
+            .field("min_partition_rows", &self.min_partition_rows)
+            .field("multi_assign", &self.multi_assign)
+            .field("residual_precision", &self.residual_precision)
+            .field("presort_by", &self.presort_by)
+            .field(
+                "store_assignment_accelerator",
+                &self.store_assignment_accelerator,
+            )
+            .field("deterministic_spill_order", &self.deterministic_spill_order)
+            .field(
+                "collect_residual_histograms",
+                &self.collect_residual_histograms,
+            )
+            .field("density_sampling", &self.density_sampling)
+            .field("dual_code_layout", &self.dual_code_layout)
+            .field("reject_bimodal_norms", &self.reject_bimodal_norms)
+            .field("self_recall_check", &self.self_recall_check)
+            .field("max_duration", &self.max_duration)
+            .field("max_partition_rows", &self.max_partition_rows)
+            .field("read_rate_limit", &self.read_rate_limit)
+            .field("bf16_centroids", &self.bf16_centroids)
+            .field("output_order", &self.output_order)
+            .field("tier_fn", &self.tier_fn.is_some())
+            .field("compute_global_stats", &self.compute_global_stats)
+            .field("custom_metadata", &self.custom_metadata)
+            .field("package", &self.package)
+            .field("write_summary", &self.write_summary)
+            .field("on_zero_norm", &self.on_zero_norm)
+            .field("target_index_bytes", &self.target_index_bytes)
+            .field("write", &self.write)
+            .finish()
+    }
 }
 
 impl Default for IvfBuildParams {
@@ -46,7 +698,67 @@ impl Default for IvfBuildParams {
             max_iters: 50,
             centroids: None,
             sample_rate: 256, // See faiss
+            min_training_rows_per_partition: 1,
             precomputed_partitons_file: None,
+            sample_mod: None,
+            seed: None,
+            hash_shard: None,
+            row_id_map: None,
+            on_spill_finalized: None,
+            on_partition_written: None,
+            on_build_progress: None,
+            on_prometheus_metrics: None,
+            control: None,
+            post_shuffle_transform: None,
+            spill_codec: None,
+            spill_file_name_fn: None,
+            shuffle_memory_pool: None,
+            shuffle_memory_min_available: 0,
+            pca: None,
+            per_partition_pq: false,
+            per_partition_pq_params: None,
+            max_open_files: None,
+            shuffle_count_concurrency: None,
+            code_storage_order: CodeStorageOrder::default(),
+            valid_column: None,
+            subgroup_column: None,
+            compute_medoids: false,
+            max_bad_batches: None,
+            num_coarse_partitions: None,
+            vector_codec: None,
+            vector_expr: None,
+            log_top_partitions: None,
+            transform_channel_capacity: None,
+            weights: None,
+            time_window: None,
+            assignment_margin: false,
+            transposed_codebook: false,
+            pipelined_training_rows: None,
+            parallel_fragment_scans: None,
+            min_partition_rows: None,
+            multi_assign: 1,
+            residual_precision: ResidualPrecision::default(),
+            presort_by: None,
+            store_assignment_accelerator: false,
+            deterministic_spill_order: false,
+            collect_residual_histograms: false,
+            density_sampling: false,
+            dual_code_layout: false,
+            reject_bimodal_norms: false,
+            self_recall_check: None,
+            max_duration: None,
+            max_partition_rows: None,
+            read_rate_limit: None,
+            bf16_centroids: false,
+            output_order: None,
+            tier_fn: None,
+            compute_global_stats: false,
+            custom_metadata: HashMap::new(),
+            package: None,
+            write_summary: None,
+            on_zero_norm: ZeroNormPolicy::default(),
+            target_index_bytes: None,
+            write: true,
         }
     }
 }
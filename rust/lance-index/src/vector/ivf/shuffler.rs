@@ -18,27 +18,42 @@
 //! 2. count the number of rows in each partition
 //! 3. read the data back into memory and shuffle into grouped vectors
 //!
+//! All columns of the shuffle schema are carried through, so extra per-row data
+//! (e.g. reduced vectors) can be grouped along with the row ids and PQ codes.
+//!
 //! Problems for the future:
-//! 1. while groupby column will stay the same, we may want to include extra data columns in the future
-//! 2. shuffling into memory is fast but we should add disk buffer to support bigger datasets
+//! 1. shuffling into memory is fast but we should add disk buffer to support bigger datasets
 
-use std::sync::Arc;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use arrow_arith::boolean::not;
 use arrow_array::cast::AsArray;
-use arrow_array::{FixedSizeListArray, RecordBatch, UInt32Array, UInt64Array, UInt8Array};
-use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema};
-use futures::{stream, Stream, StreamExt, TryStreamExt};
-use lance_arrow::FixedSizeListArrayExt;
+use arrow_array::types::UInt32Type;
+use arrow_array::{BooleanArray, RecordBatch, UInt32Array};
+use arrow_schema::Schema as ArrowSchema;
+use arrow_select::{concat::concat_batches, filter::filter_record_batch};
+use async_trait::async_trait;
+use datafusion::execution::memory_pool::{MemoryConsumer, MemoryPool, MemoryReservation};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, FuturesUnordered},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use lance_arrow::RecordBatchExt;
 use lance_core::datatypes::Schema;
+use lance_core::format::{MAGIC, MAJOR_VERSION, MINOR_VERSION};
 use lance_core::io::{FileReader, FileWriter, ReadBatchParams, RecordBatchStream};
 
-use crate::vector::{PART_ID_COLUMN, PQ_CODE_COLUMN};
+use crate::vector::PART_ID_COLUMN;
 use lance_core::io::object_store::ObjectStore;
-use lance_core::{Error, Result, ROW_ID, ROW_ID_FIELD};
-use log::info;
+use lance_core::{Error, Result};
+use log::{info, warn};
 use object_store::path::Path;
 use snafu::{location, Location};
 use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
 
 const UNSORTED_BUFFER: &str = "unsorted.lance";
 
@@ -51,23 +66,347 @@ fn get_temp_dir() -> Result<Path> {
     Ok(tmp_dir_path)
 }
 
+/// Describes a spill file written by [`IvfShuffler::write_partitioned_shuffles`].
+#[derive(Debug, Clone)]
+pub struct SpillFileInfo {
+    /// File name, relative to the shuffler's output directory.
+    pub file_name: String,
+
+    /// Full path of the spill file.
+    pub path: Path,
+
+    /// Number of rows in the spill file.
+    pub num_rows: usize,
+
+    /// Size of the spill file in bytes.
+    pub size_bytes: usize,
+}
+
+/// Callback invoked once a spill file is finalized.
+pub type SpillCallback = Arc<dyn Fn(&SpillFileInfo) + Send + Sync>;
+
+/// Callback invoked with the number of batches counted so far and the number of batches
+/// of a counting pass, see [`IvfShuffler::with_count_progress`].
+pub type CountProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// Number of rows of each partition, counted by a pass over batches of the unsorted
+/// buffer of [`IvfShuffler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionCounts {
+    /// Number of rows of each partition.
+    pub sizes: Vec<u64>,
+
+    /// Number of batches counted.
+    pub num_batches: usize,
+
+    /// Whether the pass was cancelled before counting all its batches, in which case
+    /// `sizes` only has the rows of the `num_batches` batches counted, the first ones
+    /// unless counted in parallel, see [`IvfShuffler::with_count_concurrency`].
+    pub cancelled: bool,
+}
+
+/// Names a spill file from its id, i.e., the index of its first batch in the unsorted buffer,
+/// or the sequence number of the flush when the shuffler has a memory pool, and the ids
+/// of the partitions of its rows, in increasing order.
+pub type SpillFileNameFn = Arc<dyn Fn(u32, &[u32]) -> String + Send + Sync>;
+
+fn default_spill_file_name(id: u32) -> String {
+    format!("sorted_{}.lance", id)
+}
+
+/// Serialization format of the spill files of [`IvfShuffler`], e.g. to write them in a
+/// format an external merge tool can read.
+#[async_trait]
+pub trait SpillCodec: std::fmt::Debug + Send + Sync {
+    /// Write `batches` of the shuffle `schema` to a new local file at `path`.
+    ///
+    /// Each batch holds the rows of one partition, in increasing partition order.
+    /// Returns the number of rows written.
+    async fn encode(
+        &self,
+        path: &Path,
+        schema: &Schema,
+        batches: Vec<RecordBatch>,
+    ) -> Result<usize>;
+
+    /// Read back the batches written by [`Self::encode`] to `path`, in the same order.
+    async fn decode(
+        &self,
+        path: &Path,
+        schema: &Schema,
+    ) -> Result<BoxStream<'static, Result<RecordBatch>>>;
+
+    /// Check, without decoding the batches, that the file at `path` is a complete file
+    /// written by [`Self::encode`], see [`IvfShuffler::validate_spill_files`].
+    ///
+    /// By default every file is accepted, for codecs that can only tell by decoding.
+    async fn validate(&self, _path: &Path, _schema: &Schema) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Length of the footer of a Lance file: the metadata offset, the format version and
+/// the magic number.
+const LANCE_FOOTER_LEN: usize = 16;
+
+/// Spill files in the Lance file format, the default [`SpillCodec`].
+#[derive(Debug, Default, Clone)]
+pub struct LanceSpillCodec {
+    /// Reopen the file for each batch being decoded, instead of holding a file handle.
+    reopen: bool,
+}
+
+#[async_trait]
+impl SpillCodec for LanceSpillCodec {
+    async fn encode(
+        &self,
+        path: &Path,
+        schema: &Schema,
+        batches: Vec<RecordBatch>,
+    ) -> Result<usize> {
+        let writer = ObjectStore::local().create(path).await?;
+        let mut file_writer =
+            FileWriter::with_object_writer(writer, schema.clone(), &Default::default())?;
+        for batch in batches {
+            file_writer.write(&[batch]).await?;
+        }
+        file_writer.finish().await
+    }
+
+    async fn decode(
+        &self,
+        path: &Path,
+        _schema: &Schema,
+    ) -> Result<BoxStream<'static, Result<RecordBatch>>> {
+        let reader = FileReader::try_new(&ObjectStore::local(), path).await?;
+        let num_batches = reader.num_batches();
+        let reader = if self.reopen {
+            drop(reader);
+            None
+        } else {
+            Some(Arc::new(reader))
+        };
+
+        Ok(stream::iter(0..num_batches)
+            .zip(stream::repeat((reader, path.clone())))
+            .map(|(i, (reader, path))| async move {
+                match reader {
+                    Some(reader) => {
+                        reader
+                            .read_batch(i as i32, ReadBatchParams::RangeFull, reader.schema())
+                            .await
+                    }
+                    None => {
+                        let reader = FileReader::try_new(&ObjectStore::local(), &path).await?;
+                        reader
+                            .read_batch(i as i32, ReadBatchParams::RangeFull, reader.schema())
+                            .await
+                    }
+                }
+            })
+            // Do not read ahead while reopening, so no handle outlives the read.
+            .buffered(if self.reopen { 1 } else { 16 })
+            .boxed())
+    }
+
+    async fn validate(&self, path: &Path, schema: &Schema) -> Result<()> {
+        let invalid = |reason: String| Error::IO {
+            message: format!("Invalid spill file {}: {}", path, reason),
+            location: location!(),
+        };
+        let object_store = ObjectStore::local();
+        let reader = object_store.open(path).await?;
+        let size = reader.size().await?;
+        if size < LANCE_FOOTER_LEN {
+            return Err(invalid(format!(
+                "{} bytes is smaller than the footer, the file may be truncated",
+                size
+            )));
+        }
+        let footer = reader.get_range(size - LANCE_FOOTER_LEN..size).await?;
+        if &footer[12..] != MAGIC {
+            return Err(invalid(
+                "the magic number does not match, the file may be truncated".to_string(),
+            ));
+        }
+        let version = (
+            i16::from_le_bytes([footer[8], footer[9]]),
+            i16::from_le_bytes([footer[10], footer[11]]),
+        );
+        if version != (MAJOR_VERSION, MINOR_VERSION) {
+            return Err(invalid(format!(
+                "format version {}.{} is not {}.{}",
+                version.0, version.1, MAJOR_VERSION, MINOR_VERSION
+            )));
+        }
+        let metadata_pos = i64::from_le_bytes(footer[..8].try_into().unwrap());
+        if metadata_pos < 0 || metadata_pos as usize >= size - LANCE_FOOTER_LEN {
+            return Err(invalid(format!(
+                "metadata offset {} is out of the {} bytes of the file",
+                metadata_pos, size
+            )));
+        }
+
+        let file_reader = FileReader::try_new(&object_store, path)
+            .await
+            .map_err(|err| invalid(err.to_string()))?;
+        let fields = |schema: &Schema| {
+            schema
+                .fields
+                .iter()
+                .map(|f| f.name.clone())
+                .collect::<Vec<_>>()
+        };
+        if fields(file_reader.schema()) != fields(schema) {
+            return Err(invalid(format!(
+                "columns {:?} are not the shuffle columns {:?}",
+                fields(file_reader.schema()),
+                fields(schema)
+            )));
+        }
+        let num_rows = (0..file_reader.num_batches())
+            .map(|i| file_reader.num_rows_in_batch(i as i32))
+            .sum::<usize>();
+        if num_rows != file_reader.len() {
+            return Err(invalid(format!(
+                "the batches have {} rows but the file has {}",
+                num_rows,
+                file_reader.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The partition sizes of `counts`, or an error with the partial counts if cancelled.
+fn check_counts(counts: PartitionCounts) -> Result<Vec<u64>> {
+    if counts.cancelled {
+        warn!(
+            "Shuffle cancelled after counting {} batches, partition sizes so far: {:?}",
+            counts.num_batches, counts.sizes
+        );
+        return Err(Error::Index {
+            message: format!(
+                "Shuffle cancelled after counting {} batches, partition sizes so far: {:?}",
+                counts.num_batches, counts.sizes
+            ),
+            location: location!(),
+        });
+    }
+    Ok(counts.sizes)
+}
+
+/// Count the rows of each of `num_partitions` partitions in the `batches` of the
+/// unsorted buffer at `path`, stopping early if `cancellation` is cancelled.
+///
+/// `progress` is the callback to call after each batch, with the number of batches
+/// counted by all the tasks of the pass and its number of batches.
+async fn count_batches(
+    path: Path,
+    num_partitions: usize,
+    batches: Range<usize>,
+    cancellation: Option<CancellationToken>,
+    progress: Option<(CountProgressCallback, Arc<AtomicUsize>, usize)>,
+) -> Result<PartitionCounts> {
+    let object_store = ObjectStore::local();
+    let reader = FileReader::try_new(&object_store, &path).await?;
+
+    let mut partition_sizes = vec![0; num_partitions];
+
+    let lance_schema = reader
+        .schema()
+        .project(&[PART_ID_COLUMN])
+        .expect("part id should exist");
+
+    let num_to_count = batches.len();
+    let mut stream = stream::iter(batches)
+        .map(|i| reader.read_batch(i as i32, ReadBatchParams::RangeFull, &lance_schema))
+        .buffered(64);
+
+    let mut num_batches = 0;
+    loop {
+        if cancellation.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Ok(PartitionCounts {
+                sizes: partition_sizes,
+                num_batches,
+                cancelled: num_batches < num_to_count,
+            });
+        }
+        let Some(batch) = stream.next().await else {
+            break;
+        };
+        let batch = batch?;
+        let part_ids: &UInt32Array = batch.column(0).as_primitive();
+        for part_id in part_ids.values() {
+            partition_sizes[*part_id as usize] += 1;
+        }
+        num_batches += 1;
+        if let Some((callback, counted, total)) = progress.as_ref() {
+            callback(counted.fetch_add(1, Ordering::Relaxed) + 1, *total);
+        }
+    }
+
+    Ok(PartitionCounts {
+        sizes: partition_sizes,
+        num_batches,
+        cancelled: false,
+    })
+}
+
+/// Reassigns the rows of the partitions that are too small to be kept, see
+/// [`IvfShuffler::with_partition_fold`].
+#[async_trait]
+pub trait PartitionFold: Send + Sync {
+    /// Move the rows of `batch` to one of the partitions marked in `targets`, updating
+    /// the partition id and any other column that depends on the partition.
+    ///
+    /// The returned batch has the same schema and number of rows as `batch`.
+    async fn fold(&self, batch: &RecordBatch, targets: &[bool]) -> Result<RecordBatch>;
+}
+
 pub struct IvfShuffler {
     num_partitions: u32,
 
-    pq_width: usize,
-
     output_dir: Path,
 
     schema: Schema,
+
+    on_spill_finalized: Option<SpillCallback>,
+
+    max_open_files: Option<usize>,
+
+    file_name_fn: Option<SpillFileNameFn>,
+
+    spill_codec: Option<Arc<dyn SpillCodec>>,
+
+    /// Memory pool of the partition buffers, and the bytes to keep available in it.
+    memory_pool: Option<(Arc<dyn MemoryPool>, usize)>,
+
+    /// Minimum number of rows of a partition, and the fold of the smaller partitions.
+    partition_fold: Option<(usize, Arc<dyn PartitionFold>)>,
+
+    cancellation: Option<CancellationToken>,
+
+    on_count_progress: Option<CountProgressCallback>,
+
+    /// Number of tasks counting the partition sizes in parallel.
+    count_concurrency: usize,
+
+    /// Write the spill files one at a time, in increasing id order.
+    deterministic_spill_order: bool,
+
+    /// Partition sizes of the unsorted buffer, counted while writing it with a
+    /// partition fold.
+    unsorted_sizes: Mutex<Option<Vec<u64>>>,
 }
 
 impl IvfShuffler {
-    pub fn try_new(
-        num_partitions: u32,
-        pq_width: usize,
-        output_dir: Option<Path>,
-        schema: Schema,
-    ) -> Result<Self> {
+    /// Create a shuffler of `num_partitions` partitions, spilling to `output_dir` (a
+    /// temporary directory by default).
+    ///
+    /// The rows are grouped with every column of `schema`, so the shuffler no longer
+    /// takes the width of the PQ codes: it is the width of the PQ code column in `schema`.
+    pub fn try_new(num_partitions: u32, output_dir: Option<Path>, schema: Schema) -> Result<Self> {
         let output_dir = match output_dir {
             Some(output_dir) => output_dir,
             None => get_temp_dir()?,
@@ -75,12 +414,212 @@ impl IvfShuffler {
 
         Ok(Self {
             num_partitions,
-            pq_width,
             output_dir,
             schema,
+            on_spill_finalized: None,
+            max_open_files: None,
+            file_name_fn: None,
+            spill_codec: None,
+            memory_pool: None,
+            partition_fold: None,
+            cancellation: None,
+            on_count_progress: None,
+            count_concurrency: 1,
+            deterministic_spill_order: false,
+            unsorted_sizes: Mutex::new(None),
         })
     }
 
+    /// Call `callback` each time a spill file is finalized, i.e., it is ready to be
+    /// read or uploaded while the rest of the shuffle is still running.
+    pub fn with_spill_callback(&mut self, callback: SpillCallback) -> &mut Self {
+        self.on_spill_finalized = Some(callback);
+        self
+    }
+
+    /// Keep at most `max_open_files` shuffle files open at the same time.
+    ///
+    /// Fewer spill files are written concurrently, and if there are more spill files
+    /// than the limit, they are reopened for each batch when being loaded instead of
+    /// holding a file handle per spill file.
+    pub fn with_max_open_files(&mut self, max_open_files: usize) -> &mut Self {
+        self.max_open_files = Some(max_open_files.max(1));
+        self
+    }
+
+    /// Name the spill files with `file_name_fn` instead of `sorted_{id}.lance`, e.g. to
+    /// include a job id and the partition ids so external orchestration can find the
+    /// files of each partition.
+    ///
+    /// The names must be unique per spill file id.
+    pub fn with_file_name_fn(&mut self, file_name_fn: SpillFileNameFn) -> &mut Self {
+        self.file_name_fn = Some(file_name_fn);
+        self
+    }
+
+    /// Serialize the spill files with `codec` instead of [`LanceSpillCodec`].
+    ///
+    /// The unsorted buffer is still a Lance file.
+    pub fn with_spill_codec(&mut self, codec: Arc<dyn SpillCodec>) -> &mut Self {
+        self.spill_codec = Some(codec);
+        self
+    }
+
+    /// Buffer the rows of each partition in memory reserved from `pool`, and flush the
+    /// largest buffers to a spill file when the pool can not fit the next batch with
+    /// `min_available` bytes to spare, instead of spilling a fixed number of batches.
+    ///
+    /// The shuffle never reserves more than the pool allows: it fails if the pool can
+    /// not fit a single batch. See [`Self::write_partitioned_shuffles`].
+    pub fn with_memory_pool(
+        &mut self,
+        pool: Arc<dyn MemoryPool>,
+        min_available: usize,
+    ) -> &mut Self {
+        self.memory_pool = Some((pool, min_available));
+        self
+    }
+
+    /// Fold the rows of the partitions with fewer than `min_partition_rows` rows into
+    /// the partitions with at least as many rows with `fold`, before they are grouped.
+    ///
+    /// If no partition has enough rows, the largest partition is kept. The partition
+    /// sizes are counted while writing the unsorted buffer.
+    pub fn with_partition_fold(
+        &mut self,
+        min_partition_rows: usize,
+        fold: Arc<dyn PartitionFold>,
+    ) -> &mut Self {
+        self.partition_fold = Some((min_partition_rows, fold));
+        self
+    }
+
+    /// Create the spill files of [`Self::write_partitioned_shuffles`] one at a time, in
+    /// increasing id order, e.g. to reproduce the intermediate files byte for byte.
+    ///
+    /// The partitions of the next spill files are still shuffled concurrently, only the
+    /// writes are serialized.
+    pub fn with_deterministic_spill_order(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic_spill_order = deterministic;
+        self
+    }
+
+    /// Stop counting partition sizes once `token` is cancelled, keeping the counts of the
+    /// batches counted so far, see [`Self::count_partitions`].
+    ///
+    /// A cancelled [`Self::write_partitioned_shuffles`] fails with the partial counts in
+    /// its error.
+    pub fn with_cancellation(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Call `callback` after each batch counted by a counting pass, e.g. to report the
+    /// progress of a long pass.
+    pub fn with_count_progress(&mut self, callback: CountProgressCallback) -> &mut Self {
+        self.on_count_progress = Some(callback);
+        self
+    }
+
+    /// Count the partition sizes of each counting pass with up to `concurrency` tasks,
+    /// each counting a contiguous range of the batches, instead of one. The partial
+    /// counts are merged once all the tasks are done, so the counts are the same.
+    pub fn with_count_concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.count_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Count the rows of each partition in the whole unsorted buffer.
+    ///
+    /// If cancelled, see [`Self::with_cancellation`], the counts of the batches counted
+    /// so far are returned.
+    pub async fn count_partitions(&self) -> Result<PartitionCounts> {
+        self.count_partition_size(0, self.total_batches().await?, self.count_tasks(1))
+            .await
+    }
+
+    /// The number of tasks counting the batches of each of `concurrent_jobs` jobs.
+    ///
+    /// Each task opens the unsorted buffer, so with [`Self::with_max_open_files`] the
+    /// tasks of all the jobs share the budget left after each job's own two files.
+    fn count_tasks(&self, concurrent_jobs: usize) -> usize {
+        match self.max_open_files {
+            Some(max_open_files) => self
+                .count_concurrency
+                .min(max_open_files / (2 * concurrent_jobs))
+                .max(1),
+            None => self.count_concurrency,
+        }
+    }
+
+    /// The partitions kept by the partition fold, none if there is no fold.
+    async fn fold_targets(&self) -> Result<Vec<bool>> {
+        let Some((min_partition_rows, _)) = self.partition_fold.as_ref() else {
+            return Ok(vec![]);
+        };
+        let counted = self.unsorted_sizes.lock().unwrap().clone();
+        let sizes = match counted {
+            Some(sizes) => sizes,
+            None => check_counts(self.count_partitions().await?)?,
+        };
+        let mut targets = sizes
+            .iter()
+            .map(|s| *s >= *min_partition_rows as u64)
+            .collect::<Vec<_>>();
+        if !targets.iter().any(|t| *t) {
+            if let Some(largest) = sizes
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(&a.0)))
+                .map(|(i, _)| i)
+            {
+                targets[largest] = true;
+            }
+        }
+        info!(
+            "Folding {} partitions with fewer than {} rows",
+            sizes
+                .iter()
+                .zip(targets.iter())
+                .filter(|(s, t)| **s > 0 && !**t)
+                .count(),
+            min_partition_rows
+        );
+        Ok(targets)
+    }
+
+    /// Fold the rows of `batch` outside of the partitions kept in `targets`.
+    async fn fold_partitions(&self, batch: RecordBatch, targets: &[bool]) -> Result<RecordBatch> {
+        let Some((_, fold)) = self.partition_fold.as_ref() else {
+            return Ok(batch);
+        };
+        let part_ids: &UInt32Array = batch
+            .column_by_name(PART_ID_COLUMN)
+            .expect("Partition ID column not found")
+            .as_primitive();
+        let folded = BooleanArray::from_iter(
+            part_ids
+                .values()
+                .iter()
+                .map(|part_id| Some(!targets[*part_id as usize])),
+        );
+        if folded.true_count() == 0 {
+            return Ok(batch);
+        }
+        let kept = filter_record_batch(&batch, &not(&folded)?)?;
+        let moved = fold
+            .fold(&filter_record_batch(&batch, &folded)?, targets)
+            .await?;
+        Ok(concat_batches(&batch.schema(), &[kept, moved])?)
+    }
+
+    /// The codec of the spill files, reopening Lance files for each batch if `reopen`.
+    fn spill_codec(&self, reopen: bool) -> Arc<dyn SpillCodec> {
+        self.spill_codec
+            .clone()
+            .unwrap_or_else(|| Arc::new(LanceSpillCodec { reopen }))
+    }
+
     pub async fn write_unsorted_stream(
         &self,
         data: impl RecordBatchStream + Unpin + 'static,
@@ -94,11 +633,27 @@ impl IvfShuffler {
 
         let mut data = Box::pin(data);
 
+        // The partition fold needs the sizes of the whole buffer, count them on the way.
+        let mut sizes = self
+            .partition_fold
+            .as_ref()
+            .map(|_| vec![0; self.num_partitions as usize]);
         while let Some(batch) = data.next().await {
-            file_writer.write(&[batch?]).await?;
+            let batch = batch?;
+            if let Some(sizes) = sizes.as_mut() {
+                let part_ids: &UInt32Array = batch
+                    .column_by_name(PART_ID_COLUMN)
+                    .expect("Partition ID column not found")
+                    .as_primitive();
+                for part_id in part_ids.values() {
+                    sizes[*part_id as usize] += 1;
+                }
+            }
+            file_writer.write(&[batch]).await?;
         }
 
         file_writer.finish().await?;
+        *self.unsorted_sizes.lock().unwrap() = sizes;
 
         Ok(())
     }
@@ -110,48 +665,85 @@ impl IvfShuffler {
         Ok(reader.num_batches())
     }
 
-    async fn count_partition_size(&self, start: usize, end: usize) -> Result<Vec<u64>> {
-        let object_store = ObjectStore::local();
+    /// Count the rows of each partition in batches `[start, end)` with up to `num_tasks`
+    /// tasks, stopping early if cancelled.
+    async fn count_partition_size(
+        &self,
+        start: usize,
+        end: usize,
+        num_tasks: usize,
+    ) -> Result<PartitionCounts> {
         let path = self.output_dir.child(UNSORTED_BUFFER);
-        let reader = FileReader::try_new(&object_store, &path).await?;
-
-        let mut partition_sizes = vec![0; self.num_partitions as usize];
-
-        let lance_schema = reader
-            .schema()
-            .project(&[PART_ID_COLUMN])
-            .expect("part id should exist");
-
-        let mut stream = stream::iter(start..end)
-            .map(|i| reader.read_batch(i as i32, ReadBatchParams::RangeFull, &lance_schema))
-            .buffered(64);
+        let num_partitions = self.num_partitions as usize;
+        let num_tasks = num_tasks.min(end - start).max(1);
+        let progress = self
+            .on_count_progress
+            .clone()
+            .map(|callback| (callback, Arc::new(AtomicUsize::new(0)), end - start));
+        if num_tasks == 1 {
+            return count_batches(
+                path,
+                num_partitions,
+                start..end,
+                self.cancellation.clone(),
+                progress,
+            )
+            .await;
+        }
 
-        while let Some(batch) = stream.next().await {
-            let batch = batch?;
-            let part_ids: &UInt32Array = batch.column(0).as_primitive();
-            for part_id in part_ids.values() {
-                partition_sizes[*part_id as usize] += 1;
+        let chunk_size = (end - start + num_tasks - 1) / num_tasks;
+        let mut tasks = (start..end)
+            .step_by(chunk_size)
+            .map(|chunk_start| {
+                tokio::spawn(count_batches(
+                    path.clone(),
+                    num_partitions,
+                    chunk_start..(chunk_start + chunk_size).min(end),
+                    self.cancellation.clone(),
+                    progress.clone(),
+                ))
+            })
+            .collect::<FuturesUnordered<_>>();
+        let mut counts = PartitionCounts {
+            sizes: vec![0; num_partitions],
+            num_batches: 0,
+            cancelled: false,
+        };
+        while let Some(partial) = tasks.next().await {
+            let partial = partial
+                .map_err(|err| Error::IO {
+                    message: format!("failed to count partition sizes: {}", err),
+                    location: location!(),
+                })
+                .and_then(|partial| partial);
+            let partial = match partial {
+                Ok(partial) => partial,
+                Err(err) => {
+                    // The other tasks would keep reading the buffer after the failure.
+                    tasks.iter().for_each(|task| task.abort());
+                    return Err(err);
+                }
+            };
+            for (size, partial_size) in counts.sizes.iter_mut().zip(partial.sizes) {
+                *size += partial_size;
             }
+            counts.num_batches += partial.num_batches;
+            counts.cancelled |= partial.cancelled;
         }
-
-        Ok(partition_sizes)
+        Ok(counts)
     }
 
+    /// Group the rows of batches `[start, end)` by partition id.
+    ///
+    /// Every column of the shuffle schema is carried along, so transforms may attach extra
+    /// per-row data (besides row id and PQ code) that should end up next to each partition.
     async fn shuffle_to_partitions(
         &self,
         partition_size: Vec<u64>,
         start: usize,
         end: usize,
-    ) -> Result<(Vec<Vec<u64>>, Vec<Vec<u8>>)> {
-        let mut row_id_buffers = partition_size
-            .iter()
-            .map(|s| Vec::with_capacity(*s as usize))
-            .collect::<Vec<_>>();
-        let mut pq_code_buffers = partition_size
-            .iter()
-            .map(|s| Vec::with_capacity((*s as usize) * self.pq_width))
-            .collect::<Vec<_>>();
-
+        fold_targets: &[bool],
+    ) -> Result<Vec<Option<RecordBatch>>> {
         let object_store = ObjectStore::local();
         let path = self.output_dir.child(UNSORTED_BUFFER);
         let reader = FileReader::try_new(&object_store, &path).await?;
@@ -164,151 +756,986 @@ impl IvfShuffler {
             .buffered(16)
             .enumerate();
 
+        // Each batch is split by partition as soon as it is read, so the rows are only held
+        // once, as the slices of their partition, instead of a concatenated copy next to the
+        // taken partitions.
+        let mut pieces = partition_size
+            .iter()
+            .map(|_| Vec::new())
+            .collect::<Vec<Vec<RecordBatch>>>();
         while let Some((idx, batch)) = stream.next().await {
             if idx % 100 == 0 {
                 info!("Shuffle Progress {}/{}", idx, total_batch);
             }
-
-            let batch = batch?;
-
-            let row_ids: &UInt64Array = batch
-                .column_by_name(ROW_ID)
-                .expect("Row ID column not found")
-                .as_primitive();
-
+            let batch = self.fold_partitions(batch?, fold_targets).await?;
             let part_ids: &UInt32Array = batch
                 .column_by_name(PART_ID_COLUMN)
                 .expect("Partition ID column not found")
                 .as_primitive();
 
-            let pq_codes: &UInt8Array = batch
-                .column_by_name(PQ_CODE_COLUMN)
-                .expect("PQ Code column not found")
-                .as_fixed_size_list()
-                .values()
-                .as_primitive();
-
-            let num_sub_vectors = pq_codes.len() / row_ids.len();
-
-            row_ids
+            let mut indices = vec![Vec::new(); partition_size.len()];
+            part_ids
                 .values()
                 .iter()
-                .zip(part_ids.values().iter())
                 .enumerate()
-                .for_each(|(i, (row_id, part_id))| {
-                    row_id_buffers[*part_id as usize].push(*row_id);
-                    pq_code_buffers[*part_id as usize].extend(
-                        pq_codes.values()[i * num_sub_vectors..(i + 1) * num_sub_vectors].iter(),
-                    );
-                });
+                .for_each(|(i, part_id)| indices[*part_id as usize].push(i as u32));
+            for (part_id, idx) in indices.into_iter().enumerate() {
+                if !idx.is_empty() {
+                    pieces[part_id].push(batch.take(&UInt32Array::from(idx))?);
+                }
+            }
         }
 
-        Ok((row_id_buffers, pq_code_buffers))
+        let schema = Arc::new(ArrowSchema::from(&self.schema));
+        pieces
+            .into_iter()
+            .map(|batches| match batches.len() {
+                0 => Ok(None),
+                1 => Ok(batches.into_iter().next()),
+                _ => Ok(Some(concat_batches(&schema, &batches)?)),
+            })
+            .collect()
     }
 
+    /// Write the rows of the unsorted buffer to spill files, each sorted by partition.
+    ///
+    /// Each spill file holds the rows of `batches_per_partition` batches of the buffer,
+    /// and up to `concurrent_jobs` are written at the same time. With a memory pool, see
+    /// [`Self::with_memory_pool`], the size of the spill files is instead driven by the
+    /// memory available in the pool.
+    ///
+    /// With [`Self::with_max_open_files`], at most half as many jobs as open files run
+    /// at the same time, and the batches of each job are counted with fewer tasks than
+    /// [`Self::with_count_concurrency`] if the open files would not fit the budget.
+    ///
+    /// With a partition fold, see [`Self::with_partition_fold`], the partitions to fold
+    /// are found from the partition sizes counted by [`Self::write_unsorted_stream`], or
+    /// by counting the whole buffer first if it was written by another shuffler.
     pub async fn write_partitioned_shuffles(
         &self,
         batches_per_partition: usize,
         concurrent_jobs: usize,
     ) -> Result<Vec<String>> {
+        let fold_targets = self.fold_targets().await?;
+        // Each job reads the unsorted buffer and writes one spill file.
+        let concurrent_jobs = match self.max_open_files {
+            Some(max_open_files) => concurrent_jobs.min(max_open_files / 2).max(1),
+            None => concurrent_jobs,
+        };
+        if let Some((pool, min_available)) = self.memory_pool.as_ref() {
+            return self
+                .write_shuffles_with_memory_pool(
+                    pool,
+                    *min_available,
+                    concurrent_jobs,
+                    &fold_targets,
+                )
+                .await;
+        }
         let total_batches = self.total_batches().await?;
+        let count_tasks = self.count_tasks(concurrent_jobs);
 
-        stream::iter((0..total_batches).step_by(batches_per_partition))
-            .map(|i| async move {
-                let start = i;
-                let end = std::cmp::min(i + batches_per_partition, total_batches);
-
-                let size_counts = self.count_partition_size(start, end).await?;
-
-                let (row_id_buffers, pq_code_buffers) =
-                    self.shuffle_to_partitions(size_counts, start, end).await?;
-
-                let object_store = ObjectStore::local();
-                let output_file = format!("sorted_{}.lance", i);
-                let path = self.output_dir.child(output_file.clone());
-                let writer = object_store.create(&path).await?;
-
-                // TODO: dynamically detect schema from the transforms.
-                let schema = Arc::new(ArrowSchema::new(vec![
-                    ROW_ID_FIELD.clone(),
-                    ArrowField::new(PART_ID_COLUMN, DataType::UInt32, false),
-                    ArrowField::new(
-                        PQ_CODE_COLUMN,
-                        DataType::FixedSizeList(
-                            Arc::new(ArrowField::new("item", DataType::UInt8, true)),
-                            self.pq_width as i32,
-                        ),
-                        false,
-                    ),
-                ]));
-
-                let mut file_writer = FileWriter::with_object_writer(
-                    writer,
-                    self.schema.clone(),
-                    &Default::default(),
-                )?;
-
-                let shuffled = row_id_buffers
-                    .into_iter()
-                    .zip(pq_code_buffers.into_iter())
-                    .enumerate()
-                    .filter(|(_, (row_ids, _))| !row_ids.is_empty())
-                    .map(|(part_id, (row_ids, pq_codes))| {
-                        let length = row_ids.len();
-                        let batch = RecordBatch::try_new(
-                            schema.clone(),
-                            vec![
-                                Arc::new(UInt64Array::from(row_ids)),
-                                Arc::new(UInt32Array::from_iter_values(
-                                    std::iter::repeat(part_id as u32).take(length),
-                                )),
-                                Arc::new(FixedSizeListArray::try_new_from_values(
-                                    UInt8Array::from(pq_codes),
-                                    self.pq_width as i32,
-                                )?),
-                            ],
-                        )?;
-
-                        Ok(batch) as Result<_>
-                    });
-
-                for batch in shuffled {
-                    file_writer.write(&[batch?]).await?;
-                }
+        let fold_targets = &fold_targets;
+        let shuffle = |i: usize| async move {
+            let start = i;
+            let end = std::cmp::min(i + batches_per_partition, total_batches);
 
-                file_writer.finish().await?;
+            let size_counts =
+                check_counts(self.count_partition_size(start, end, count_tasks).await?)?;
 
-                Ok(output_file) as Result<String>
+            let shuffled = self
+                .shuffle_to_partitions(size_counts, start, end, fold_targets)
+                .await?;
+            Ok::<_, Error>((i, shuffled.into_iter().flatten().collect::<Vec<_>>()))
+        };
+        let jobs = stream::iter((0..total_batches).step_by(batches_per_partition));
+        if self.deterministic_spill_order {
+            // `buffered` yields the shuffles in order, and each file is written before
+            // the next one is pulled.
+            jobs.map(shuffle)
+                .buffered(concurrent_jobs)
+                .and_then(|(i, batches)| self.write_spill_file(i as u32, batches))
+                .try_collect()
+                .await
+        } else {
+            jobs.map(|i| async move {
+                let (i, batches) = shuffle(i).await?;
+                self.write_spill_file(i as u32, batches).await
             })
             .buffered(concurrent_jobs)
             .try_collect()
             .await
+        }
+    }
+
+    /// Write the `batches`, one per partition in increasing partition order, to the spill
+    /// file of `id`. Returns the file name.
+    async fn write_spill_file(&self, id: u32, batches: Vec<RecordBatch>) -> Result<String> {
+        let output_file = match self.file_name_fn.as_ref() {
+            Some(file_name_fn) => {
+                let part_ids = batches
+                    .iter()
+                    .filter(|batch| batch.num_rows() > 0)
+                    .map(|batch| batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0))
+                    .collect::<Vec<_>>();
+                file_name_fn(id, &part_ids)
+            }
+            None => default_spill_file_name(id),
+        };
+        let path = self.output_dir.child(output_file.clone());
+        let num_rows = self
+            .spill_codec(false)
+            .encode(&path, &self.schema, batches)
+            .await?;
+
+        if let Some(callback) = self.on_spill_finalized.as_ref() {
+            let size_bytes = ObjectStore::local().size(&path).await?;
+            callback(&SpillFileInfo {
+                file_name: output_file.clone(),
+                path,
+                num_rows,
+                size_bytes,
+            });
+        }
+
+        Ok(output_file)
+    }
+
+    /// Group the rows of the unsorted buffer into per-partition buffers, and flush the
+    /// largest buffers whenever `pool` can not fit the next batch with `min_available`
+    /// bytes to spare.
+    ///
+    /// The batches are read one at a time, with the memory of each reserved before it
+    /// is read from the size of the previous one. Up to `concurrent_jobs` spill files
+    /// are written at the same time, one when the spill order is deterministic, and
+    /// their buffers stay reserved until they are written.
+    async fn write_shuffles_with_memory_pool(
+        &self,
+        pool: &Arc<dyn MemoryPool>,
+        min_available: usize,
+        concurrent_jobs: usize,
+        fold_targets: &[bool],
+    ) -> Result<Vec<String>> {
+        let object_store = ObjectStore::local();
+        let path = self.output_dir.child(UNSORTED_BUFFER);
+        let reader = FileReader::try_new(&object_store, &path).await?;
+
+        let num_partitions = self.num_partitions as usize;
+        let mut buffers = PooledBuffers {
+            shuffler: self,
+            pool: pool.clone(),
+            reservation: MemoryConsumer::new("IvfShuffler").register(pool),
+            min_available,
+            buffers: vec![vec![]; num_partitions],
+            sizes: vec![0; num_partitions],
+            writing: FuturesUnordered::new(),
+            max_writing: if self.deterministic_spill_order {
+                1
+            } else {
+                concurrent_jobs
+            },
+            files: vec![],
+        };
+
+        // Bytes reserved for the next batch.
+        let mut expected = 0;
+        for i in 0..reader.num_batches() {
+            buffers.reserve(expected).await?;
+            let batch = reader
+                .read_batch(i as i32, ReadBatchParams::RangeFull, reader.schema())
+                .await?;
+            let batch = self.fold_partitions(batch, fold_targets).await?;
+            let parts = split_by_partition(&batch, num_partitions)?;
+            let size = parts
+                .iter()
+                .map(|(_, b)| b.get_array_memory_size())
+                .sum::<usize>();
+            if size > expected {
+                buffers.reserve(size - expected).await?;
+            } else {
+                buffers.reservation.shrink(expected - size);
+            }
+            for (part_id, part) in parts {
+                buffers.sizes[part_id] += part.get_array_memory_size();
+                buffers.buffers[part_id].push(part);
+            }
+            expected = size;
+        }
+        buffers.finish().await
+    }
+
+    /// Check that each of the spill `files` is complete and consistent, e.g. not
+    /// truncated, without decoding it, to fail before [`Self::load_partitioned_shuffles`]
+    /// starts the merge. See [`SpillCodec::validate`].
+    pub async fn validate_spill_files(&self, files: &[String]) -> Result<()> {
+        let codec = self.spill_codec(false);
+        for file in files {
+            codec
+                .validate(&self.output_dir.child(file.as_str()), &self.schema)
+                .await?;
+        }
+        Ok(())
     }
 
     pub async fn load_partitioned_shuffles(
         &self,
         files: Vec<String>,
-    ) -> Result<Vec<impl Stream<Item = Result<RecordBatch>>>> {
+    ) -> Result<Vec<BoxStream<'static, Result<RecordBatch>>>> {
         // impl RecordBatchStream
         let mut streams = vec![];
+        let reopen = self
+            .max_open_files
+            .map(|max_open_files| files.len() > max_open_files)
+            .unwrap_or(false);
+        let codec = self.spill_codec(reopen);
 
         for file in files {
-            let object_store = ObjectStore::local();
             let path = self.output_dir.child(file);
-            let reader = FileReader::try_new(&object_store, &path).await?;
-            let reader = Arc::new(reader);
-
-            let stream = stream::iter(0..reader.num_batches())
-                .zip(stream::repeat(reader))
-                .map(|(i, reader)| async move {
-                    reader
-                        .read_batch(i as i32, ReadBatchParams::RangeFull, reader.schema())
-                        .await
-                })
-                .buffered(16);
-            streams.push(stream);
+            streams.push(codec.decode(&path, &self.schema).await?);
         }
 
         Ok(streams)
     }
 }
+
+/// Split the rows of `batch` by partition id, in increasing partition order.
+fn split_by_partition(
+    batch: &RecordBatch,
+    num_partitions: usize,
+) -> Result<Vec<(usize, RecordBatch)>> {
+    let part_ids: &UInt32Array = batch
+        .column_by_name(PART_ID_COLUMN)
+        .expect("Partition ID column not found")
+        .as_primitive();
+    let mut indices = vec![vec![]; num_partitions];
+    part_ids
+        .values()
+        .iter()
+        .enumerate()
+        .for_each(|(i, part_id)| indices[*part_id as usize].push(i as u32));
+    indices
+        .into_iter()
+        .enumerate()
+        .filter(|(_, idx)| !idx.is_empty())
+        .map(|(part_id, idx)| Ok((part_id, batch.take(&UInt32Array::from(idx))?)))
+        .collect()
+}
+
+/// Per-partition buffers of the shuffle with a memory pool, see
+/// [`IvfShuffler::with_memory_pool`].
+struct PooledBuffers<'a> {
+    shuffler: &'a IvfShuffler,
+
+    pool: Arc<dyn MemoryPool>,
+
+    /// Memory of the buffers, and of the spill files being written.
+    reservation: MemoryReservation,
+
+    /// Bytes to keep available in the pool.
+    min_available: usize,
+
+    /// Rows of each partition.
+    buffers: Vec<Vec<RecordBatch>>,
+
+    /// Bytes of the rows of each partition.
+    sizes: Vec<usize>,
+
+    /// The spill files being written: spill file id, file name and bytes of their rows.
+    writing: FuturesUnordered<BoxFuture<'a, Result<(u32, String, usize)>>>,
+
+    /// Maximum number of spill files written at the same time.
+    max_writing: usize,
+
+    /// The spill files written, with their ids.
+    files: Vec<(u32, String)>,
+}
+
+impl<'a> PooledBuffers<'a> {
+    /// Reserve `bytes` more with `min_available` bytes to spare, flushing the largest
+    /// buffers and waiting for the spill files being written until they fit.
+    async fn reserve(&mut self, bytes: usize) -> Result<()> {
+        loop {
+            if self
+                .reservation
+                .try_grow(bytes + self.min_available)
+                .is_ok()
+            {
+                self.reservation.shrink(self.min_available);
+                return Ok(());
+            }
+            if self.writing.len() < self.max_writing && self.sizes.iter().any(|s| *s > 0) {
+                let flushed = largest_buffers(&self.sizes);
+                info!(
+                    "Memory pool pressure, flushing {} partition buffers",
+                    flushed.len()
+                );
+                self.flush(&flushed)?;
+            } else if !self.writing.is_empty() {
+                self.wait_for_one().await?;
+            } else {
+                return Err(Error::IO {
+                    message: format!(
+                        "IvfShuffler: the memory pool can not fit a batch of {} bytes with {} bytes to spare, {} bytes are reserved",
+                        bytes,
+                        self.min_available,
+                        self.pool.reserved()
+                    ),
+                    location: location!(),
+                });
+            }
+        }
+    }
+
+    /// Start writing the buffers of `part_ids`, in increasing partition order, to the
+    /// next spill file.
+    fn flush(&mut self, part_ids: &[usize]) -> Result<()> {
+        let schema = Arc::new(ArrowSchema::from(&self.shuffler.schema));
+        let mut bytes = 0;
+        let batches = part_ids
+            .iter()
+            .map(|&part_id| {
+                bytes += std::mem::take(&mut self.sizes[part_id]);
+                Ok(concat_batches(
+                    &schema,
+                    &std::mem::take(&mut self.buffers[part_id]),
+                )?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let id = (self.files.len() + self.writing.len()) as u32;
+        let shuffler = self.shuffler;
+        self.writing.push(
+            async move {
+                let file = shuffler.write_spill_file(id, batches).await?;
+                Ok((id, file, bytes))
+            }
+            .boxed(),
+        );
+        Ok(())
+    }
+
+    /// Wait for one of the spill files being written, and release its memory.
+    async fn wait_for_one(&mut self) -> Result<()> {
+        if let Some(written) = self.writing.next().await {
+            let (id, file, bytes) = written?;
+            self.reservation.shrink(bytes);
+            self.files.push((id, file));
+        }
+        Ok(())
+    }
+
+    /// Write the remaining buffers, and return the spill files in increasing id order.
+    async fn finish(mut self) -> Result<Vec<String>> {
+        let remaining = (0..self.sizes.len())
+            .filter(|&part_id| !self.buffers[part_id].is_empty())
+            .collect::<Vec<_>>();
+        if !remaining.is_empty() {
+            while self.writing.len() >= self.max_writing {
+                self.wait_for_one().await?;
+            }
+            self.flush(&remaining)?;
+        }
+        while !self.writing.is_empty() {
+            self.wait_for_one().await?;
+        }
+        self.files.sort();
+        Ok(self.files.into_iter().map(|(_, file)| file).collect())
+    }
+}
+
+/// The partitions of the largest buffers, holding at least half of the buffered bytes,
+/// in increasing partition order.
+fn largest_buffers(buffer_sizes: &[usize]) -> Vec<usize> {
+    let total = buffer_sizes.iter().sum::<usize>();
+    let mut by_size = (0..buffer_sizes.len())
+        .filter(|p| buffer_sizes[*p] > 0)
+        .collect::<Vec<_>>();
+    by_size.sort_by_key(|p| std::cmp::Reverse(buffer_sizes[*p]));
+    let mut flushed = 0;
+    let mut partitions = by_size
+        .into_iter()
+        .take_while(|p| {
+            let take = flushed * 2 < total;
+            flushed += buffer_sizes[*p];
+            take
+        })
+        .collect::<Vec<_>>();
+    partitions.sort();
+    partitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use arrow::ipc::{reader::StreamReader, writer::StreamWriter};
+    use arrow_array::{
+        types::{UInt32Type, UInt8Type},
+        FixedSizeListArray, UInt64Array, UInt8Array,
+    };
+    use arrow_schema::{DataType, Field as ArrowField};
+    use datafusion::execution::memory_pool::GreedyMemoryPool;
+    use lance_arrow::FixedSizeListArrayExt;
+    use lance_core::io::{local::to_local_path, RecordBatchStreamAdapter};
+    use lance_core::ROW_ID_FIELD;
+
+    use crate::vector::PQ_CODE_COLUMN;
+
+    const PQ_WIDTH: usize = 4;
+
+    fn shuffle_schema() -> Arc<ArrowSchema> {
+        Arc::new(ArrowSchema::new(vec![
+            ROW_ID_FIELD.clone(),
+            ArrowField::new(PART_ID_COLUMN, DataType::UInt32, false),
+            ArrowField::new(
+                PQ_CODE_COLUMN,
+                DataType::FixedSizeList(
+                    Arc::new(ArrowField::new("item", DataType::UInt8, true)),
+                    PQ_WIDTH as i32,
+                ),
+                false,
+            ),
+        ]))
+    }
+
+    /// Generate `num_batches` batches of `rows_per_batch` rows, where row `i`
+    /// belongs to partition `i % num_partitions`.
+    fn make_stream(
+        num_batches: usize,
+        rows_per_batch: usize,
+        num_partitions: u32,
+    ) -> impl RecordBatchStream + Unpin + 'static {
+        let schema = shuffle_schema();
+        let batches = (0..num_batches)
+            .map(|b| {
+                let row_ids = (b * rows_per_batch..(b + 1) * rows_per_batch).map(|i| i as u64);
+                let part_ids = row_ids.clone().map(|i| (i % num_partitions as u64) as u32);
+                let codes = row_ids
+                    .clone()
+                    .flat_map(|i| std::iter::repeat(i as u8).take(PQ_WIDTH));
+                Ok(RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(UInt64Array::from_iter_values(row_ids)),
+                        Arc::new(UInt32Array::from_iter_values(part_ids)),
+                        Arc::new(
+                            FixedSizeListArray::try_new_from_values(
+                                UInt8Array::from_iter_values(codes),
+                                PQ_WIDTH as i32,
+                            )
+                            .unwrap(),
+                        ),
+                    ],
+                )
+                .unwrap())
+            })
+            .collect::<Vec<_>>();
+        RecordBatchStreamAdapter::new(schema, stream::iter(batches))
+    }
+
+    fn make_shuffler(num_partitions: u32, output_dir: &TempDir) -> IvfShuffler {
+        IvfShuffler::try_new(
+            num_partitions,
+            Some(Path::from_filesystem_path(output_dir.path()).unwrap()),
+            Schema::try_from(shuffle_schema().as_ref()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Number of files under `dir` this process holds open.
+    #[cfg(target_os = "linux")]
+    fn count_open_files(dir: &std::path::Path) -> usize {
+        let dir = dir.canonicalize().unwrap();
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| target.starts_with(&dir))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_max_open_files() {
+        const NUM_PARTITIONS: u32 = 64;
+        const MAX_OPEN_FILES: usize = 4;
+
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        shuffler
+            .with_max_open_files(MAX_OPEN_FILES)
+            .with_count_concurrency(8);
+        // The files open while the jobs count their batches.
+        let peak_open_files = Arc::new(AtomicUsize::new(0));
+        #[cfg(target_os = "linux")]
+        {
+            let peak_open_files = peak_open_files.clone();
+            let dir = output_dir.path().to_path_buf();
+            shuffler.with_count_progress(Arc::new(move |_, _| {
+                peak_open_files.fetch_max(count_open_files(&dir), Ordering::Relaxed);
+            }));
+        }
+
+        shuffler
+            .write_unsorted_stream(make_stream(30, 100, NUM_PARTITIONS))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(3, 8).await.unwrap();
+        assert_eq!(files.len(), 10);
+        assert!(peak_open_files.load(Ordering::Relaxed) <= MAX_OPEN_FILES);
+
+        // Peek every stream at once, like the k-way merge of the index writer does.
+        let mut streams = shuffler
+            .load_partitioned_shuffles(files)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| Box::pin(s.peekable()))
+            .collect::<Vec<_>>();
+        for stream in streams.iter_mut() {
+            assert!(stream.as_mut().peek().await.is_some());
+        }
+        #[cfg(target_os = "linux")]
+        assert!(count_open_files(output_dir.path()) <= MAX_OPEN_FILES);
+
+        let mut row_ids = vec![];
+        for stream in streams {
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            for batch in batches {
+                let part_ids: &UInt32Array = batch[PART_ID_COLUMN].as_primitive();
+                assert!(part_ids.values().iter().all(|p| *p == part_ids.value(0)));
+                let ids: &UInt64Array = batch[ROW_ID_FIELD.name().as_str()].as_primitive();
+                row_ids.extend(ids.values().iter().copied());
+            }
+        }
+        row_ids.sort();
+        assert_eq!(row_ids, (0..3000).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_spill_callback() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(3, &output_dir);
+
+        let spilled = Arc::new(Mutex::new(vec![]));
+        let spilled_ref = spilled.clone();
+        shuffler.with_spill_callback(Arc::new(move |info: &SpillFileInfo| {
+            spilled_ref.lock().unwrap().push(info.clone());
+        }));
+
+        shuffler
+            .write_unsorted_stream(make_stream(5, 10, 3))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(2, 1).await.unwrap();
+
+        let spilled = spilled.lock().unwrap();
+        assert_eq!(spilled.len(), 3);
+        assert_eq!(
+            spilled
+                .iter()
+                .map(|s| s.file_name.clone())
+                .collect::<Vec<_>>(),
+            files
+        );
+        assert_eq!(
+            spilled.iter().map(|s| s.num_rows).collect::<Vec<_>>(),
+            vec![20, 20, 10]
+        );
+        for info in spilled.iter() {
+            let on_disk = std::fs::metadata(output_dir.path().join(&info.file_name)).unwrap();
+            assert_eq!(info.size_bytes, on_disk.len() as usize);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_spill_order() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(4, &output_dir);
+        let created = Arc::new(Mutex::new(vec![]));
+        let created_ref = created.clone();
+        shuffler
+            .with_deterministic_spill_order(true)
+            .with_spill_callback(Arc::new(move |info: &SpillFileInfo| {
+                created_ref.lock().unwrap().push(info.file_name.clone());
+            }));
+
+        shuffler
+            .write_unsorted_stream(make_stream(32, 10, 4))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(1, 8).await.unwrap();
+        assert_eq!(
+            files,
+            (0..32).map(default_spill_file_name).collect::<Vec<_>>()
+        );
+        assert_eq!(*created.lock().unwrap(), files);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_counting_pass() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(4, &output_dir);
+        shuffler
+            .write_unsorted_stream(make_stream(20, 10, 4))
+            .await
+            .unwrap();
+        assert_eq!(
+            shuffler.count_partitions().await.unwrap(),
+            PartitionCounts {
+                sizes: vec![50; 4],
+                num_batches: 20,
+                cancelled: false,
+            }
+        );
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        shuffler
+            .with_cancellation(token)
+            .with_count_progress(Arc::new(move |counted, total| {
+                assert_eq!(total, 20);
+                if counted == 8 {
+                    cancel.cancel();
+                }
+            }));
+        let counts = shuffler.count_partitions().await.unwrap();
+        assert!(counts.cancelled);
+        assert_eq!(counts.num_batches, 8);
+        assert_eq!(counts.sizes, vec![20; 4]);
+
+        let err = shuffler
+            .write_partitioned_shuffles(20, 1)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{}", err);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parallel_counting_pass() {
+        const NUM_PARTITIONS: u32 = 64;
+        const NUM_BATCHES: usize = 400;
+        const ROWS_PER_BATCH: usize = 5000;
+
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        shuffler
+            .write_unsorted_stream(make_stream(NUM_BATCHES, ROWS_PER_BATCH, NUM_PARTITIONS))
+            .await
+            .unwrap();
+
+        let serial = shuffler.count_partitions().await.unwrap();
+        assert_eq!(
+            serial.sizes,
+            vec![(NUM_BATCHES * ROWS_PER_BATCH) as u64 / NUM_PARTITIONS as u64; 64]
+        );
+
+        let progress = Arc::new(std::sync::Mutex::new(vec![]));
+        let calls = progress.clone();
+        shuffler
+            .with_count_concurrency(4)
+            .with_count_progress(Arc::new(move |counted, total| {
+                calls.lock().unwrap().push((counted, total));
+            }));
+        let parallel = shuffler.count_partitions().await.unwrap();
+        assert_eq!(parallel, serial);
+        let mut calls = std::mem::take(&mut *progress.lock().unwrap());
+        calls.sort();
+        assert_eq!(
+            calls,
+            (1..=NUM_BATCHES)
+                .map(|counted| (counted, NUM_BATCHES))
+                .collect::<Vec<_>>()
+        );
+
+        // The failure of a task fails the count.
+        assert!(shuffler
+            .count_partition_size(0, NUM_BATCHES + 1, 4)
+            .await
+            .is_err());
+
+        // Spill files are counted in parallel too.
+        let files = shuffler.write_partitioned_shuffles(100, 2).await.unwrap();
+        assert_eq!(files.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_custom_file_names() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(3, &output_dir);
+        shuffler.with_file_name_fn(Arc::new(|id, part_ids| {
+            let part_ids = part_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+            format!("job-42-{}-part-{}.lance", id, part_ids.join("-"))
+        }));
+
+        shuffler
+            .write_unsorted_stream(make_stream(5, 10, 3))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(2, 2).await.unwrap();
+        assert_eq!(
+            files,
+            vec![
+                "job-42-0-part-0-1-2.lance",
+                "job-42-2-part-0-1-2.lance",
+                "job-42-4-part-0-1-2.lance"
+            ]
+        );
+        for file in files.iter() {
+            assert!(output_dir.path().join(file).exists());
+        }
+
+        let streams = shuffler.load_partitioned_shuffles(files).await.unwrap();
+        let mut num_rows = 0;
+        for stream in streams {
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            num_rows += batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        }
+        assert_eq!(num_rows, 50);
+    }
+
+    #[tokio::test]
+    async fn test_validate_truncated_spill_file() {
+        let output_dir = TempDir::new().unwrap();
+        let shuffler = make_shuffler(3, &output_dir);
+        shuffler
+            .write_unsorted_stream(make_stream(5, 10, 3))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(2, 2).await.unwrap();
+        shuffler.validate_spill_files(&files).await.unwrap();
+
+        // A file of another format version.
+        let path = output_dir.path().join(&files[1]);
+        let bytes = std::fs::read(&path).unwrap();
+        let mut other_version = bytes.clone();
+        let len = other_version.len();
+        other_version[len - 8..len - 6].copy_from_slice(&(MAJOR_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, other_version).unwrap();
+        let err = shuffler.validate_spill_files(&files).await.unwrap_err();
+        assert!(err.to_string().contains("format version"), "{}", err);
+
+        // Cut the footer, as if the spill was interrupted.
+        std::fs::write(&path, &bytes[..len - 4]).unwrap();
+        let err = shuffler.validate_spill_files(&files).await.unwrap_err();
+        assert!(err.to_string().contains(&files[1]), "{}", err);
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+
+    /// Spill files in the Arrow IPC stream format.
+    #[derive(Debug, Default)]
+    struct IpcSpillCodec {
+        num_encoded: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl SpillCodec for IpcSpillCodec {
+        async fn encode(
+            &self,
+            path: &Path,
+            schema: &Schema,
+            batches: Vec<RecordBatch>,
+        ) -> Result<usize> {
+            let file = std::fs::File::create(to_local_path(path))?;
+            let mut writer = StreamWriter::try_new(file, &ArrowSchema::from(schema))?;
+            for batch in batches.iter() {
+                writer.write(batch)?;
+            }
+            writer.finish()?;
+            *self.num_encoded.lock().unwrap() += 1;
+            Ok(batches.iter().map(|b| b.num_rows()).sum())
+        }
+
+        async fn decode(
+            &self,
+            path: &Path,
+            _schema: &Schema,
+        ) -> Result<BoxStream<'static, Result<RecordBatch>>> {
+            let file = std::fs::File::open(to_local_path(path))?;
+            let batches = StreamReader::try_new(file, None)?
+                .map(|b| b.map_err(Error::from))
+                .collect::<Vec<_>>();
+            Ok(stream::iter(batches).boxed())
+        }
+    }
+
+    /// Rows of the loaded spill streams, sorted by row id.
+    async fn load_rows(shuffler: &IvfShuffler, files: Vec<String>) -> Vec<(u64, u32, Vec<u8>)> {
+        let mut rows = vec![];
+        for stream in shuffler.load_partitioned_shuffles(files).await.unwrap() {
+            for batch in stream.try_collect::<Vec<_>>().await.unwrap() {
+                let row_ids: &UInt64Array = batch[ROW_ID_FIELD.name().as_str()].as_primitive();
+                let part_ids: &UInt32Array = batch[PART_ID_COLUMN].as_primitive();
+                let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+                for i in 0..batch.num_rows() {
+                    rows.push((
+                        row_ids.value(i),
+                        part_ids.value(i),
+                        codes.value(i).as_primitive::<UInt8Type>().values().to_vec(),
+                    ));
+                }
+            }
+        }
+        rows.sort();
+        rows
+    }
+
+    /// Moves every row to the first kept partition.
+    struct FirstTargetFold;
+
+    #[async_trait]
+    impl PartitionFold for FirstTargetFold {
+        async fn fold(&self, batch: &RecordBatch, targets: &[bool]) -> Result<RecordBatch> {
+            let target = targets.iter().position(|t| *t).unwrap() as u32;
+            Ok(batch.replace_column_by_name(
+                PART_ID_COLUMN,
+                Arc::new(UInt32Array::from(vec![target; batch.num_rows()])),
+            )?)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fold_without_counting_pass() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(4, &output_dir);
+        let counted = Arc::new(AtomicUsize::new(0));
+        let counted_ref = counted.clone();
+        shuffler
+            .with_memory_pool(Arc::new(GreedyMemoryPool::new(1024 * 1024)), 0)
+            .with_count_progress(Arc::new(move |_, _| {
+                counted_ref.fetch_add(1, Ordering::Relaxed);
+            }))
+            // Every partition has 20 rows, so only the first one is kept.
+            .with_partition_fold(30, Arc::new(FirstTargetFold));
+        shuffler
+            .write_unsorted_stream(make_stream(8, 10, 4))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(10000, 2).await.unwrap();
+        let rows = load_rows(&shuffler, files).await;
+        assert_eq!(rows.len(), 80);
+        assert!(rows.iter().all(|(_, part_id, _)| *part_id == 0));
+        // The partition sizes were counted while writing the unsorted buffer.
+        assert_eq!(counted.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_custom_spill_codec() {
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(3, &output_dir);
+        let codec = Arc::new(IpcSpillCodec::default());
+        shuffler.with_spill_codec(codec.clone());
+
+        shuffler
+            .write_unsorted_stream(make_stream(5, 10, 3))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(2, 2).await.unwrap();
+        assert_eq!(*codec.num_encoded.lock().unwrap(), files.len());
+        // Spill files are readable by any Arrow IPC reader.
+        for file in files.iter() {
+            let file = std::fs::File::open(output_dir.path().join(file)).unwrap();
+            let batches = StreamReader::try_new(file, None)
+                .unwrap()
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(batches.len(), 3);
+        }
+        let rows = load_rows(&shuffler, files).await;
+
+        // The same rows as with the default codec.
+        let default_dir = TempDir::new().unwrap();
+        let shuffler = make_shuffler(3, &default_dir);
+        shuffler
+            .write_unsorted_stream(make_stream(5, 10, 3))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(2, 2).await.unwrap();
+        assert_eq!(rows, load_rows(&shuffler, files).await);
+        assert_eq!(rows.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_flush_under_memory_pressure() {
+        const NUM_PARTITIONS: u32 = 8;
+        let output_dir = TempDir::new().unwrap();
+        let shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        shuffler
+            .write_unsorted_stream(make_stream(30, 100, NUM_PARTITIONS))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(10000, 2).await.unwrap();
+        assert_eq!(files.len(), 1);
+        let expected = load_rows(&shuffler, files).await;
+
+        // Room for a few batches only.
+        let pool: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(16 * 1024));
+        let spilled = Arc::new(Mutex::new(0));
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        let spilled_ref = spilled.clone();
+        shuffler
+            .with_memory_pool(pool.clone(), 1024)
+            .with_spill_callback(Arc::new(move |_: &SpillFileInfo| {
+                *spilled_ref.lock().unwrap() += 1;
+            }));
+        shuffler
+            .write_unsorted_stream(make_stream(30, 100, NUM_PARTITIONS))
+            .await
+            .unwrap();
+        let files = shuffler.write_partitioned_shuffles(10000, 2).await.unwrap();
+        assert!(files.len() > 2, "{} spill files", files.len());
+        assert_eq!(*spilled.lock().unwrap(), files.len());
+        // All the buffered memory is returned to the pool.
+        assert_eq!(pool.reserved(), 0);
+
+        // Each spill file is sorted by partition.
+        for stream in shuffler
+            .load_partitioned_shuffles(files.clone())
+            .await
+            .unwrap()
+        {
+            let part_ids = stream
+                .map_ok(|b| b[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0))
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert!(part_ids.windows(2).all(|w| w[0] < w[1]), "{:?}", part_ids);
+        }
+        assert_eq!(load_rows(&shuffler, files.clone()).await, expected);
+
+        // The spill files are written one at a time with few open files.
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        shuffler
+            .with_memory_pool(pool.clone(), 1024)
+            .with_max_open_files(2);
+        shuffler
+            .write_unsorted_stream(make_stream(30, 100, NUM_PARTITIONS))
+            .await
+            .unwrap();
+        let serial_files = shuffler.write_partitioned_shuffles(10000, 2).await.unwrap();
+        assert!(serial_files.len() > 2, "{} spill files", serial_files.len());
+        assert_eq!(load_rows(&shuffler, serial_files).await, expected);
+
+        // A pool too small for a single batch fails the shuffle, instead of reserving
+        // more than it allows.
+        let pool: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(512));
+        let output_dir = TempDir::new().unwrap();
+        let mut shuffler = make_shuffler(NUM_PARTITIONS, &output_dir);
+        shuffler.with_memory_pool(pool.clone(), 0);
+        shuffler
+            .write_unsorted_stream(make_stream(30, 100, NUM_PARTITIONS))
+            .await
+            .unwrap();
+        let err = shuffler
+            .write_partitioned_shuffles(10000, 2)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("memory pool can not fit a batch"),
+            "{}",
+            err
+        );
+        assert_eq!(pool.reserved(), 0);
+    }
+}
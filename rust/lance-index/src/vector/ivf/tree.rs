@@ -0,0 +1,987 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Two-level coarse quantizer to assign vectors to a large number of IVF partitions.
+//!
+
+use std::sync::Arc;
+
+use arrow_array::{
+    cast::AsArray,
+    types::{Float16Type, Float32Type, Float64Type},
+    Array, FixedSizeListArray,
+};
+use arrow_schema::DataType;
+use lance_arrow::{ArrowFloatType, FixedSizeListArrayExt, FloatArray};
+use lance_core::{Error, Result};
+use lance_linalg::{
+    distance::{Cosine, Dot, MetricType, L2},
+    kmeans::{compute_partitions, KMeans},
+};
+use rand::{rngs::SmallRng, SeedableRng};
+use snafu::{location, Location};
+
+use crate::pb;
+use crate::vector::kmeans::train_kmeans;
+
+/// A tree over the IVF centroids, that clusters the IVF partitions into groups.
+///
+/// A vector is assigned to the closest IVF centroid within the group of its closest
+/// group centroid. With `sqrt(num_partitions)` groups, this takes
+/// `O(sqrt(num_partitions))` distance computations per vector instead of
+/// `O(num_partitions)`, at the cost of occasionally missing the closest partition.
+///
+/// With the covering radius of each group, see [`IvfTree::with_radii`], the L2
+/// assignment is exact instead: every group that may hold a closer partition than the
+/// closest one found so far is searched, and the others are skipped.
+#[derive(Debug, Clone)]
+pub struct IvfTree {
+    /// `num_groups * dimension` centroids of the groups, of the same type as the
+    /// IVF centroids.
+    centroids: Arc<FixedSizeListArray>,
+
+    /// Group of each IVF partition.
+    group_ids: Vec<u32>,
+
+    /// L2 covering radius of each group.
+    radii: Option<Vec<f32>>,
+}
+
+impl IvfTree {
+    pub fn try_new(centroids: FixedSizeListArray, group_ids: Vec<u32>) -> Result<Self> {
+        let mut has_partitions = vec![false; centroids.len()];
+        for group_id in group_ids.iter() {
+            match has_partitions.get_mut(*group_id as usize) {
+                Some(has_partition) => *has_partition = true,
+                None => {
+                    return Err(Error::Index {
+                        message: format!(
+                            "IVF tree: group id {} out of range of {} groups",
+                            group_id,
+                            centroids.len()
+                        ),
+                        location: location!(),
+                    })
+                }
+            }
+        }
+        if has_partitions.iter().any(|has_partition| !has_partition) {
+            return Err(Error::Index {
+                message: "IVF tree: every group must have at least one partition".to_string(),
+                location: location!(),
+            });
+        }
+        Ok(Self {
+            centroids: Arc::new(centroids),
+            group_ids,
+            radii: None,
+        })
+    }
+
+    /// Compute the L2 covering radius of each group over the `ivf_centroids`, the
+    /// largest distance from the group centroid to the centroids of its partitions.
+    pub fn with_radii(mut self, ivf_centroids: &FixedSizeListArray) -> Result<Self> {
+        if ivf_centroids.len() != self.group_ids.len()
+            || ivf_centroids.value_length() != self.centroids.value_length()
+        {
+            return Err(Error::Index {
+                message: format!(
+                    "IVF tree of {} partitions of dimension {} does not match {} centroids of dimension {}",
+                    self.group_ids.len(),
+                    self.centroids.value_length(),
+                    ivf_centroids.len(),
+                    ivf_centroids.value_length()
+                ),
+                location: location!(),
+            });
+        }
+        let dim = ivf_centroids.value_length() as usize;
+        let values = ivf_centroids.values();
+        let radii = match (values.data_type(), self.centroids.value_type()) {
+            (DataType::Float16, DataType::Float16) => {
+                covering_radii::<Float16Type>(&self, values.as_primitive(), dim)
+            }
+            (DataType::Float32, DataType::Float32) => {
+                covering_radii::<Float32Type>(&self, values.as_primitive(), dim)
+            }
+            (DataType::Float64, DataType::Float64) => {
+                covering_radii::<Float64Type>(&self, values.as_primitive(), dim)
+            }
+            (ivf_type, tree_type) => {
+                return Err(Error::Index {
+                    message: format!(
+                        "IVF tree: centroids of type {} do not match the tree of type {}",
+                        ivf_type, tree_type
+                    ),
+                    location: location!(),
+                })
+            }
+        };
+        self.radii = Some(radii);
+        Ok(self)
+    }
+
+    /// Train a tree of `num_groups` groups over the IVF centroids, with the KMeans
+    /// initialization seeded by `seed`.
+    pub async fn train(
+        ivf_centroids: &FixedSizeListArray,
+        num_groups: usize,
+        metric_type: MetricType,
+        seed: u64,
+    ) -> Result<Self> {
+        let values = ivf_centroids.values();
+        match values.data_type() {
+            DataType::Float16 => {
+                do_train::<Float16Type>(
+                    values.as_primitive(),
+                    ivf_centroids.value_length(),
+                    num_groups,
+                    metric_type,
+                    seed,
+                )
+                .await
+            }
+            DataType::Float32 => {
+                do_train::<Float32Type>(
+                    values.as_primitive(),
+                    ivf_centroids.value_length(),
+                    num_groups,
+                    metric_type,
+                    seed,
+                )
+                .await
+            }
+            DataType::Float64 => {
+                do_train::<Float64Type>(
+                    values.as_primitive(),
+                    ivf_centroids.value_length(),
+                    num_groups,
+                    metric_type,
+                    seed,
+                )
+                .await
+            }
+            _ => Err(Error::Index {
+                message: format!(
+                    "IVF tree: centroids is not expected type: {}",
+                    values.data_type()
+                ),
+                location: location!(),
+            }),
+        }
+    }
+
+    pub fn num_groups(&self) -> usize {
+        self.centroids.len()
+    }
+
+    pub fn centroids(&self) -> &FixedSizeListArray {
+        &self.centroids
+    }
+
+    pub fn group_ids(&self) -> &[u32] {
+        &self.group_ids
+    }
+
+    /// L2 covering radius of each group, if computed.
+    pub fn radii(&self) -> Option<&[f32]> {
+        self.radii.as_deref()
+    }
+}
+
+fn covering_radii<T: ArrowFloatType + L2>(
+    tree: &IvfTree,
+    ivf_centroids: &T::ArrayType,
+    dim: usize,
+) -> Vec<f32> {
+    let group_centroids = tree
+        .centroids
+        .values()
+        .as_any()
+        .downcast_ref::<T::ArrayType>()
+        .expect("IVF tree centroids match the type of the IVF centroids");
+    let group_centroids = group_centroids.as_slice();
+    let mut radii = vec![0.0_f32; tree.num_groups()];
+    for (centroid, group_id) in ivf_centroids
+        .as_slice()
+        .chunks_exact(dim)
+        .zip(tree.group_ids.iter())
+    {
+        let group_id = *group_id as usize;
+        let group_centroid = &group_centroids[group_id * dim..(group_id + 1) * dim];
+        radii[group_id] = radii[group_id].max(T::l2(centroid, group_centroid).sqrt());
+    }
+    radii
+}
+
+async fn do_train<T: ArrowFloatType + Dot + L2 + Cosine>(
+    ivf_centroids: &T::ArrayType,
+    dimension: i32,
+    num_groups: usize,
+    metric_type: MetricType,
+    seed: u64,
+) -> Result<IvfTree> {
+    const MAX_ITERS: u32 = 50;
+    const REDOS: usize = 1;
+    const SAMPLE_RATE: usize = 256;
+    let dim = dimension as usize;
+    let centroids = train_kmeans::<T>(
+        ivf_centroids,
+        None,
+        dim,
+        num_groups,
+        MAX_ITERS,
+        REDOS,
+        SmallRng::seed_from_u64(seed),
+        metric_type,
+        SAMPLE_RATE,
+    )
+    .await?;
+    let group_ids = compute_partitions::<T>(
+        centroids.as_slice(),
+        ivf_centroids.as_slice(),
+        dim,
+        metric_type,
+    )
+    .await;
+
+    // KMeans may leave some groups empty, which are dropped.
+    let mut new_ids = vec![None; num_groups];
+    let mut values = Vec::with_capacity(centroids.len());
+    for group_id in group_ids.iter() {
+        let new_id = &mut new_ids[*group_id as usize];
+        if new_id.is_none() {
+            *new_id = Some(values.len() / dim);
+            let start = *group_id as usize * dim;
+            values.extend_from_slice(&centroids.as_slice()[start..start + dim]);
+        }
+    }
+    let group_ids = group_ids
+        .iter()
+        .map(|group_id| new_ids[*group_id as usize].unwrap() as u32)
+        .collect();
+    IvfTree::try_new(
+        FixedSizeListArray::try_new_from_values(T::ArrayType::from(values), dimension)?,
+        group_ids,
+    )
+}
+
+impl TryFrom<&IvfTree> for pb::IvfTree {
+    type Error = Error;
+
+    fn try_from(tree: &IvfTree) -> Result<Self> {
+        Ok(Self {
+            centroids: Some(tree.centroids.as_ref().try_into()?),
+            group_ids: tree.group_ids.clone(),
+            radii: tree.radii.clone().unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<&pb::IvfTree> for IvfTree {
+    type Error = Error;
+
+    fn try_from(proto: &pb::IvfTree) -> Result<Self> {
+        let Some(centroids) = proto.centroids.as_ref() else {
+            return Err(Error::Index {
+                message: "IVF tree is missing centroids".to_string(),
+                location: location!(),
+            });
+        };
+        let mut tree = Self::try_new(
+            FixedSizeListArray::try_from(centroids)?,
+            proto.group_ids.clone(),
+        )?;
+        if !proto.radii.is_empty() {
+            if proto.radii.len() != tree.num_groups() {
+                return Err(Error::Index {
+                    message: format!(
+                        "IVF tree has {} radii for {} groups",
+                        proto.radii.len(),
+                        tree.num_groups()
+                    ),
+                    location: location!(),
+                });
+            }
+            tree.radii = Some(proto.radii.clone());
+        }
+        Ok(tree)
+    }
+}
+
+/// Distance between two vectors of native float type `N`.
+pub(super) type DistanceFn<N> = fn(&[N], &[N]) -> f32;
+
+/// Distance function of `metric_type` for vectors of float type `T`, so that the
+/// closest vector has the smallest distance.
+pub(super) fn distance_fn<T: ArrowFloatType + Dot + L2 + Cosine>(
+    metric_type: MetricType,
+) -> DistanceFn<T::Native> {
+    match metric_type {
+        MetricType::L2 => T::l2,
+        MetricType::Cosine => T::cosine,
+        MetricType::Dot => |x, y| -T::dot(x, y),
+    }
+}
+
+/// Insert the partition `part_id` at `distance` into `nearest`, the closest
+/// (partition id, distance) pairs found so far sorted by distance, if it is closer
+/// than the last one. Ties go to the lowest partition id.
+pub(super) fn push_nearest(nearest: &mut [(u32, f32)], part_id: u32, distance: f32) {
+    let Some(pos) = nearest
+        .iter()
+        .position(|&(id, d)| distance < d || (distance == d && part_id < id))
+    else {
+        return;
+    };
+    nearest[pos..].rotate_right(1);
+    nearest[pos] = (part_id, distance);
+}
+
+/// Number of closest groups searched for the IVF partition of a vector, so that
+/// vectors close to the boundary of two groups are still assigned correctly.
+const NUM_PROBES: usize = 3;
+
+/// [IvfTree] laid out to assign vectors of float type `T`.
+#[derive(Debug)]
+pub(super) struct TreeAssigner<T: ArrowFloatType + Dot + L2 + Cosine> {
+    /// KMeans model of the groups.
+    groups: KMeans<T>,
+
+    /// IVF partition ids of each group, and the KMeans model of their centroids.
+    partitions: Vec<(Vec<u32>, KMeans<T>)>,
+
+    /// L2 covering radius of each group, to assign vectors exactly.
+    radii: Option<Vec<f32>>,
+
+    /// Distance to the IVF centroids.
+    distance: DistanceFn<T::Native>,
+}
+
+impl<T: ArrowFloatType + Dot + L2 + Cosine> TreeAssigner<T> {
+    pub(super) fn try_new(
+        tree: &IvfTree,
+        ivf_centroids: &[T::Native],
+        dim: usize,
+        metric_type: MetricType,
+    ) -> Result<Self> {
+        if tree.group_ids.len() * dim != ivf_centroids.len() {
+            return Err(Error::Index {
+                message: format!(
+                    "IVF tree has {} partitions, but the IVF model has {}",
+                    tree.group_ids.len(),
+                    ivf_centroids.len() / dim
+                ),
+                location: location!(),
+            });
+        }
+        let centroids = tree
+            .centroids
+            .values()
+            .as_any()
+            .downcast_ref::<T::ArrayType>()
+            .ok_or(Error::Index {
+                message: format!(
+                    "IVF tree: centroids is not expected type: expect {}, got {}",
+                    T::FLOAT_TYPE,
+                    tree.centroids.value_type()
+                ),
+                location: location!(),
+            })?;
+        let mut partitions = vec![(vec![], vec![]); tree.num_groups()];
+        for (part_id, group_id) in tree.group_ids.iter().enumerate() {
+            let (part_ids, values) = &mut partitions[*group_id as usize];
+            part_ids.push(part_id as u32);
+            values.extend_from_slice(&ivf_centroids[part_id * dim..(part_id + 1) * dim]);
+        }
+        Ok(Self {
+            groups: KMeans::with_centroids(Arc::new(centroids.clone()), dim, metric_type),
+            partitions: partitions
+                .into_iter()
+                .map(|(part_ids, values)| {
+                    let centroids = Arc::new(T::ArrayType::from(values));
+                    (
+                        part_ids,
+                        KMeans::with_centroids(centroids, dim, metric_type),
+                    )
+                })
+                .collect(),
+            radii: tree.radii.clone().filter(|_| metric_type == MetricType::L2),
+            distance: distance_fn::<T>(metric_type),
+        })
+    }
+
+    /// Compute the IVF partition of each vector in `data`, and the number of distances
+    /// computed to find them.
+    pub(super) async fn compute_partitions(
+        &self,
+        data: &[T::Native],
+        dim: usize,
+    ) -> (Vec<u32>, u64) {
+        if let Some(radii) = self.radii.as_ref() {
+            let mut num_distances = 0;
+            let part_ids = data
+                .chunks_exact(dim)
+                .map(|vector| {
+                    let mut closest = [(u32::MAX, f32::INFINITY)];
+                    num_distances += self.find_nearest_exact(vector, radii, dim, &mut closest);
+                    closest[0].0
+                })
+                .collect();
+            return (part_ids, num_distances);
+        }
+        let num_rows = data.len() / dim;
+        let mut num_distances = (num_rows * self.partitions.len()) as u64;
+        let mut rows = vec![vec![]; self.partitions.len()];
+        for (row, vector) in data.chunks_exact(dim).enumerate() {
+            let group_ids = self
+                .groups
+                .find_partitions(vector, NUM_PROBES.min(self.partitions.len()))
+                .expect("vector dimension matches the IVF tree");
+            for group_id in group_ids.values() {
+                rows[*group_id as usize].push(row);
+            }
+        }
+
+        // Closest (partition id, distance) of each vector among the probed groups.
+        let mut closest = vec![(0, f32::INFINITY); num_rows];
+        for ((part_ids, kmeans), rows) in self.partitions.iter().zip(rows.iter()) {
+            if rows.is_empty() {
+                continue;
+            }
+            let mut vectors = Vec::with_capacity(rows.len() * dim);
+            for row in rows.iter() {
+                vectors.extend_from_slice(&data[row * dim..(row + 1) * dim]);
+            }
+            num_distances += (rows.len() * part_ids.len()) as u64;
+            let membership = kmeans
+                .compute_membership(Arc::new(T::ArrayType::from(vectors)))
+                .await;
+            for (row, (local_id, distance)) in
+                rows.iter().zip(membership.cluster_id_and_distances.iter())
+            {
+                if *distance < closest[*row].1 {
+                    closest[*row] = (part_ids[*local_id as usize], *distance);
+                }
+            }
+        }
+        let part_ids = closest.into_iter().map(|(part_id, _)| part_id).collect();
+        (part_ids, num_distances)
+    }
+
+    /// The `k` nearest IVF partitions of each vector in `data`, as `k`
+    /// (partition id, distance) pairs per vector, nearest first, and the number of
+    /// distances computed to find them.
+    ///
+    /// The nearest partitions are searched the same way as in `compute_partitions`.
+    /// If fewer than `k` partitions are searched, the pairs are padded with
+    /// `(u32::MAX, f32::INFINITY)`.
+    pub(super) fn compute_nearest(
+        &self,
+        data: &[T::Native],
+        dim: usize,
+        k: usize,
+    ) -> (Vec<(u32, f32)>, u64) {
+        let mut nearest = vec![(u32::MAX, f32::INFINITY); data.len() / dim * k];
+        let mut num_distances = 0;
+        for (vector, nearest) in data.chunks_exact(dim).zip(nearest.chunks_exact_mut(k)) {
+            num_distances += match self.radii.as_ref() {
+                Some(radii) => self.find_nearest_exact(vector, radii, dim, nearest),
+                None => self.find_nearest_probed(vector, dim, nearest),
+            };
+        }
+        (nearest, num_distances)
+    }
+
+    /// Find the `nearest.len()` closest IVF partitions of `vector` by L2 distance,
+    /// skipping the groups that are too far to hold any of them, and return the
+    /// number of distances computed to find them.
+    fn find_nearest_exact(
+        &self,
+        vector: &[T::Native],
+        radii: &[f32],
+        dim: usize,
+        nearest: &mut [(u32, f32)],
+    ) -> u64 {
+        // Slack for the rounding errors of the distances, so that a group is only
+        // skipped if it is clearly too far.
+        const SLACK: f32 = 1e-4;
+
+        let mut groups = self
+            .groups
+            .centroids
+            .as_slice()
+            .chunks_exact(dim)
+            .map(|centroid| T::l2(vector, centroid).sqrt())
+            .enumerate()
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let mut num_distances = groups.len() as u64;
+        for (group_id, distance) in groups {
+            let furthest = nearest[nearest.len() - 1].1;
+            let lower_bound = distance - radii[group_id];
+            if lower_bound > 0.0 && lower_bound * lower_bound > furthest * (1.0 + SLACK) + SLACK {
+                continue;
+            }
+            let (part_ids, kmeans) = &self.partitions[group_id];
+            num_distances += part_ids.len() as u64;
+            for (part_id, centroid) in part_ids
+                .iter()
+                .zip(kmeans.centroids.as_slice().chunks_exact(dim))
+            {
+                push_nearest(nearest, *part_id, T::l2(vector, centroid));
+            }
+        }
+        num_distances
+    }
+
+    /// Find the `nearest.len()` closest IVF partitions of `vector` within its
+    /// closest groups, and return the number of distances computed to find them.
+    fn find_nearest_probed(
+        &self,
+        vector: &[T::Native],
+        dim: usize,
+        nearest: &mut [(u32, f32)],
+    ) -> u64 {
+        let group_ids = self
+            .groups
+            .find_partitions(vector, NUM_PROBES.min(self.partitions.len()))
+            .expect("vector dimension matches the IVF tree");
+        let mut num_distances = self.partitions.len() as u64;
+        for group_id in group_ids.values() {
+            let (part_ids, kmeans) = &self.partitions[*group_id as usize];
+            num_distances += part_ids.len() as u64;
+            for (part_id, centroid) in part_ids
+                .iter()
+                .zip(kmeans.centroids.as_slice().chunks_exact(dim))
+            {
+                push_nearest(nearest, *part_id, (self.distance)(vector, centroid));
+            }
+        }
+        num_distances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow_array::{types::UInt32Type, Float32Array, RecordBatch};
+    use arrow_schema::{Field, Schema};
+    use lance_testing::datagen::generate_random_array_with_seed;
+    use rand::Rng;
+
+    use crate::vector::ivf::{assignment_margin, new_ivf, new_ivf_with_options, IvfAssignOptions};
+    use crate::vector::{
+        ALTERNATIVE_DISTANCES_COLUMN, ALTERNATIVE_PARTITIONS_COLUMN, ASSIGNMENT_MARGIN_COLUMN,
+        CENTROID_DISTANCE_COLUMN, PART_ID_COLUMN,
+    };
+
+    #[tokio::test]
+    async fn test_tree_assignment_matches_flat() {
+        const DIM: usize = 16;
+        const NUM_PARTITIONS: usize = 1024;
+        const NUM_ROWS: usize = 5000;
+
+        let centroids =
+            generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [1; 32]);
+        // Vectors scattered around random centroids.
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut values = Vec::with_capacity(NUM_ROWS * DIM);
+        for _ in 0..NUM_ROWS {
+            let part_id = rng.gen_range(0..NUM_PARTITIONS);
+            values.extend(
+                centroids.values()[part_id * DIM..(part_id + 1) * DIM]
+                    .iter()
+                    .map(|c| c + rng.gen_range(-0.1..0.1)),
+            );
+        }
+        let data = FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+            .unwrap();
+        let centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+
+        let tree = IvfTree::train(&centroids, 32, MetricType::L2, 42)
+            .await
+            .unwrap();
+        assert!(tree.num_groups() <= 32);
+        assert_eq!(tree.group_ids().len(), NUM_PARTITIONS);
+        let proto = pb::IvfTree::try_from(&tree).unwrap();
+        let tree = IvfTree::try_from(&proto).unwrap();
+
+        let flat_ivf =
+            new_ivf(centroids.values(), DIM, MetricType::L2, vec![], None, None).unwrap();
+        let tree_ivf = new_ivf_with_options(
+            centroids.values(),
+            DIM,
+            MetricType::L2,
+            vec![],
+            IvfAssignOptions {
+                tree: Some(&tree),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let flat = flat_ivf.compute_partitions(&data).await.unwrap();
+        let partitions = tree_ivf.compute_partitions(&data).await.unwrap();
+
+        let num_mismatches = flat
+            .values()
+            .iter()
+            .zip(partitions.values().iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert!(
+            num_mismatches * 100 < NUM_ROWS,
+            "{} out of {} vectors are assigned differently",
+            num_mismatches,
+            NUM_ROWS
+        );
+
+        // The flat assignment computes the distance to every centroid.
+        let assigner = TreeAssigner::<Float32Type>::try_new(
+            &tree,
+            centroids.values().as_primitive::<Float32Type>().values(),
+            DIM,
+            MetricType::L2,
+        )
+        .unwrap();
+        let (tree_partitions, num_distances) = assigner
+            .compute_partitions(data.values().as_primitive::<Float32Type>().values(), DIM)
+            .await;
+        assert_eq!(tree_partitions, partitions.values().to_vec());
+        let flat_distances = (NUM_ROWS * NUM_PARTITIONS) as u64;
+        assert!(
+            num_distances * 4 < flat_distances,
+            "tree: {}, flat: {}",
+            num_distances,
+            flat_distances
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tree_with_radii_is_exact() {
+        const DIM: usize = 8;
+        const NUM_PARTITIONS: usize = 256;
+
+        let centroids =
+            generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [2; 32]);
+        let centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let data = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(2000 * DIM, [3; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+
+        let tree = IvfTree::train(&centroids, 16, MetricType::L2, 42)
+            .await
+            .unwrap()
+            .with_radii(&centroids)
+            .unwrap();
+        assert_eq!(tree.radii().unwrap().len(), tree.num_groups());
+        let proto = pb::IvfTree::try_from(&tree).unwrap();
+        let tree = IvfTree::try_from(&proto).unwrap();
+        assert!(tree.radii().is_some());
+
+        let ivf = |tree| {
+            new_ivf_with_options(
+                centroids.values(),
+                DIM,
+                MetricType::L2,
+                vec![],
+                IvfAssignOptions {
+                    tree,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let flat = ivf(None).compute_partitions(&data).await.unwrap();
+        let exact = ivf(Some(&tree)).compute_partitions(&data).await.unwrap();
+        assert_eq!(flat, exact);
+
+        let other = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(4 * DIM, [4; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        assert!(tree.with_radii(&other).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tree_assignment_margins() {
+        const DIM: usize = 8;
+        const NUM_PARTITIONS: usize = 256;
+        const NUM_ROWS: usize = 2000;
+
+        let centroids =
+            generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [5; 32]);
+        let centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let data = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [6; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "vector",
+            data.data_type().clone(),
+            false,
+        )]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(data.clone())]).unwrap();
+
+        let tree = IvfTree::train(&centroids, 16, MetricType::L2, 42)
+            .await
+            .unwrap();
+        let exact_tree = tree.clone().with_radii(&centroids).unwrap();
+        let ivf = |tree, range| {
+            new_ivf_with_options(
+                centroids.values(),
+                DIM,
+                MetricType::L2,
+                vec![],
+                IvfAssignOptions {
+                    range,
+                    tree,
+                    assignment_margin: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let margins_of = |batch: &RecordBatch| {
+            let part_ids = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().clone();
+            let margins = batch[ASSIGNMENT_MARGIN_COLUMN]
+                .as_primitive::<Float32Type>()
+                .clone();
+            (part_ids, margins)
+        };
+
+        // The exact tree finds the same two nearest partitions as the flat search,
+        // over the partition range too.
+        let flat = ivf(None, None)
+            .partition_transform(&batch, "vector")
+            .await
+            .unwrap();
+        let (flat_part_ids, flat_margins) = margins_of(&flat);
+        let exact = ivf(Some(&exact_tree), Some(0..128))
+            .partition_transform(&batch, "vector")
+            .await
+            .unwrap();
+        let (part_ids, margins) = margins_of(&exact);
+        let in_range = flat_part_ids
+            .values()
+            .iter()
+            .zip(flat_margins.values().iter())
+            .filter(|(part_id, _)| **part_id < 128)
+            .collect::<Vec<_>>();
+        assert_eq!(in_range.len(), part_ids.len());
+        for ((flat_part_id, flat_margin), (part_id, margin)) in in_range
+            .into_iter()
+            .zip(part_ids.values().iter().zip(margins.values().iter()))
+        {
+            assert_eq!(flat_part_id, part_id);
+            assert!((flat_margin - margin).abs() < 1e-5);
+        }
+
+        // The margins of the probed tree come from the partitions it searched, so
+        // they match its assignment.
+        let assigner = TreeAssigner::<Float32Type>::try_new(
+            &tree,
+            centroids.values().as_primitive::<Float32Type>().values(),
+            DIM,
+            MetricType::L2,
+        )
+        .unwrap();
+        let (nearest, _) =
+            assigner.compute_nearest(data.values().as_primitive::<Float32Type>().values(), DIM, 2);
+        let probed = ivf(Some(&tree), None)
+            .partition_transform(&batch, "vector")
+            .await
+            .unwrap();
+        let (part_ids, margins) = margins_of(&probed);
+        for ((nearest, part_id), margin) in nearest
+            .chunks_exact(2)
+            .zip(part_ids.values().iter())
+            .zip(margins.values().iter())
+        {
+            assert_eq!(nearest[0].0, *part_id);
+            assert_eq!(assignment_margin(nearest[0].1, nearest[1].1), *margin);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alternative_partitions() {
+        const DIM: usize = 8;
+        const NUM_PARTITIONS: usize = 64;
+        const NUM_ALTERNATIVES: usize = 3;
+
+        let centroids =
+            generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [5; 32]);
+        let centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let data = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(500 * DIM, [6; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "vector",
+            data.data_type().clone(),
+            false,
+        )]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(data.clone())]).unwrap();
+        let tree = IvfTree::train(&centroids, 8, MetricType::L2, 42)
+            .await
+            .unwrap()
+            .with_radii(&centroids)
+            .unwrap();
+        let ivf = |tree| {
+            new_ivf_with_options(
+                centroids.values(),
+                DIM,
+                MetricType::L2,
+                vec![],
+                IvfAssignOptions {
+                    tree,
+                    centroid_distance: true,
+                    num_alternatives: NUM_ALTERNATIVES,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let centroid_values = centroids.values().as_primitive::<Float32Type>().values();
+        for tree in [None, Some(&tree)] {
+            let transformed = ivf(tree)
+                .partition_transform(&batch, "vector")
+                .await
+                .unwrap();
+            let part_ids = transformed[PART_ID_COLUMN].as_primitive::<UInt32Type>();
+            let distances = transformed[CENTROID_DISTANCE_COLUMN].as_primitive::<Float32Type>();
+            let alt_ids = transformed[ALTERNATIVE_PARTITIONS_COLUMN].as_fixed_size_list();
+            let alt_distances = transformed[ALTERNATIVE_DISTANCES_COLUMN].as_fixed_size_list();
+            assert_eq!(alt_ids.value_length(), NUM_ALTERNATIVES as i32);
+
+            // The assigned partition and the alternatives are the nearest partitions,
+            // nearest first, with the distances to their centroids.
+            for (i, vector) in data
+                .values()
+                .as_primitive::<Float32Type>()
+                .values()
+                .chunks_exact(DIM)
+                .enumerate()
+            {
+                let mut expected = centroid_values
+                    .chunks_exact(DIM)
+                    .enumerate()
+                    .map(|(part_id, c)| (part_id as u32, Float32Type::l2(vector, c)))
+                    .collect::<Vec<_>>();
+                expected.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+                assert_eq!(part_ids.value(i), expected[0].0);
+                assert!((distances.value(i) - expected[0].1).abs() < 1e-5);
+                let ids = alt_ids.value(i);
+                let dists = alt_distances.value(i);
+                for (j, (part_id, distance)) in expected[1..=NUM_ALTERNATIVES].iter().enumerate() {
+                    assert_eq!(ids.as_primitive::<UInt32Type>().value(j), *part_id);
+                    assert!((dists.as_primitive::<Float32Type>().value(j) - distance).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tree_multi_assign() {
+        const DIM: usize = 8;
+        const NUM_PARTITIONS: usize = 256;
+        const NUM_ROWS: usize = 1000;
+        const MULTI_ASSIGN: usize = 3;
+
+        let centroids =
+            generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [5; 32]);
+        let centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let data = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [6; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "vector",
+            data.data_type().clone(),
+            false,
+        )]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(data.clone())]).unwrap();
+        let tree = IvfTree::train(&centroids, 16, MetricType::L2, 42)
+            .await
+            .unwrap();
+        let ivf = |tree| {
+            new_ivf_with_options(
+                centroids.values(),
+                DIM,
+                MetricType::L2,
+                vec![],
+                IvfAssignOptions {
+                    tree,
+                    centroid_distance: true,
+                    multi_assign: MULTI_ASSIGN,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        // Each vector is assigned to the nearest partitions found by the tree, with the
+        // distances computed by the tree.
+        let assigner = TreeAssigner::<Float32Type>::try_new(
+            &tree,
+            centroids.values().as_primitive::<Float32Type>().values(),
+            DIM,
+            MetricType::L2,
+        )
+        .unwrap();
+        let (nearest, num_distances) = assigner.compute_nearest(
+            data.values().as_primitive::<Float32Type>().values(),
+            DIM,
+            MULTI_ASSIGN,
+        );
+        let tree_ivf = ivf(Some(&tree));
+        let transformed = tree_ivf
+            .partition_transform(&batch, "vector")
+            .await
+            .unwrap();
+        assert_eq!(transformed.num_rows(), NUM_ROWS * MULTI_ASSIGN);
+        let part_ids = transformed[PART_ID_COLUMN].as_primitive::<UInt32Type>();
+        let distances = transformed[CENTROID_DISTANCE_COLUMN].as_primitive::<Float32Type>();
+        for (i, (part_id, distance)) in nearest.iter().enumerate() {
+            assert_eq!(part_ids.value(i), *part_id);
+            assert_eq!(distances.value(i), *distance);
+        }
+        assert_eq!(
+            tree_ivf.assignment_stats().distance_computations,
+            num_distances
+        );
+        assert!(num_distances < (NUM_ROWS * NUM_PARTITIONS) as u64);
+
+        // Without the tree, every centroid is compared.
+        let flat_ivf = ivf(None);
+        flat_ivf
+            .partition_transform(&batch, "vector")
+            .await
+            .unwrap();
+        assert_eq!(
+            flat_ivf.assignment_stats().distance_computations,
+            (NUM_ROWS * NUM_PARTITIONS) as u64
+        );
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PCA dimension reduction of vectors.
+//!
+
+use std::sync::Arc;
+
+use arrow::compute::cast;
+use arrow_array::{
+    cast::AsArray, types::Float32Type, Array, FixedSizeListArray, Float32Array, RecordBatch,
+};
+use arrow_schema::{DataType, Field};
+use async_trait::async_trait;
+use lance_arrow::{FixedSizeListArrayExt, RecordBatchExt};
+use lance_core::{Error, Result};
+use lance_linalg::distance::dot;
+use snafu::{location, Location};
+
+use super::pb;
+use super::transform::Transformer;
+
+/// Column of the PCA-reduced vectors, populated by [`PcaTransform`].
+pub const PCA_VECTOR_COLUMN: &str = "__pca_vector";
+
+/// A linear projection `y = components * (x - mean)` that reduces vectors of `dimension`
+/// to `num_components`.
+#[derive(Debug, Clone)]
+pub struct PcaMatrix {
+    /// `num_components * dimension` matrix, one principal component per row.
+    components: Arc<FixedSizeListArray>,
+
+    /// Mean of the training vectors, `dimension` values.
+    mean: Arc<Float32Array>,
+}
+
+impl PcaMatrix {
+    /// Create a PCA projection from float32 `components` and `mean`.
+    pub fn try_new(components: FixedSizeListArray, mean: Float32Array) -> Result<Self> {
+        if components.value_type() != DataType::Float32 {
+            return Err(Error::Index {
+                message: format!(
+                    "PCA components must be float32, got {}",
+                    components.value_type()
+                ),
+                location: location!(),
+            });
+        }
+        if components.value_length() as usize != mean.len() {
+            return Err(Error::Index {
+                message: format!(
+                    "PCA components dimension {} does not match mean dimension {}",
+                    components.value_length(),
+                    mean.len()
+                ),
+                location: location!(),
+            });
+        }
+        if components.is_empty() || components.len() > mean.len() {
+            return Err(Error::Index {
+                message: format!(
+                    "PCA requires 0 < num_components <= dimension, got num_components={}, dimension={}",
+                    components.len(),
+                    mean.len()
+                ),
+                location: location!(),
+            });
+        }
+        Ok(Self {
+            components: Arc::new(components),
+            mean: Arc::new(mean),
+        })
+    }
+
+    /// Dimension of the original vectors.
+    pub fn dimension(&self) -> usize {
+        self.mean.len()
+    }
+
+    /// Dimension of the reduced vectors.
+    pub fn num_components(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn components(&self) -> &FixedSizeListArray {
+        &self.components
+    }
+
+    pub fn mean(&self) -> &Float32Array {
+        &self.mean
+    }
+
+    fn component(&self, i: usize) -> &[f32] {
+        let dim = self.dimension();
+        let values = self
+            .components
+            .values()
+            .as_primitive::<Float32Type>()
+            .values();
+        &values[i * dim..(i + 1) * dim]
+    }
+
+    /// Project a vector of [`Self::dimension`] to [`Self::num_components`].
+    ///
+    /// Query vectors must be projected the same way to be compared with the stored
+    /// reduced vectors.
+    pub fn project(&self, vector: &[f32]) -> Vec<f32> {
+        let centered = vector
+            .iter()
+            .zip(self.mean.values().iter())
+            .map(|(v, m)| v - m)
+            .collect::<Vec<_>>();
+        (0..self.num_components())
+            .map(|i| dot(&centered, self.component(i)))
+            .collect()
+    }
+
+    /// Approximately reconstruct the original vector from a reduced vector.
+    pub fn reconstruct(&self, reduced: &[f32]) -> Vec<f32> {
+        let mut vector = self.mean.values().to_vec();
+        reduced.iter().enumerate().for_each(|(i, y)| {
+            vector
+                .iter_mut()
+                .zip(self.component(i).iter())
+                .for_each(|(v, c)| *v += y * c);
+        });
+        vector
+    }
+}
+
+impl TryFrom<&PcaMatrix> for pb::Pca {
+    type Error = Error;
+
+    fn try_from(pca: &PcaMatrix) -> Result<Self> {
+        let mean = FixedSizeListArray::try_new_from_values(
+            pca.mean.as_ref().clone(),
+            pca.dimension() as i32,
+        )?;
+        Ok(Self {
+            components: Some(pca.components.as_ref().try_into()?),
+            mean: Some((&mean).try_into()?),
+        })
+    }
+}
+
+impl TryFrom<&pb::Pca> for PcaMatrix {
+    type Error = Error;
+
+    fn try_from(proto: &pb::Pca) -> Result<Self> {
+        let (Some(components), Some(mean)) = (proto.components.as_ref(), proto.mean.as_ref())
+        else {
+            return Err(Error::Index {
+                message: "PCA projection is missing components or mean".to_string(),
+                location: location!(),
+            });
+        };
+        let components = FixedSizeListArray::try_from(components)?;
+        let mean = FixedSizeListArray::try_from(mean)?;
+        Self::try_new(
+            components,
+            mean.values().as_primitive::<Float32Type>().clone(),
+        )
+    }
+}
+
+/// Add the PCA-reduced vectors as [`PCA_VECTOR_COLUMN`], keeping the original vectors.
+#[derive(Debug, Clone)]
+pub struct PcaTransform {
+    pca: PcaMatrix,
+
+    /// Vector Column
+    column: String,
+}
+
+impl PcaTransform {
+    pub fn new(pca: PcaMatrix, column: &str) -> Self {
+        Self {
+            pca,
+            column: column.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Transformer for PcaTransform {
+    async fn transform(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let arr = batch.column_by_name(&self.column).ok_or(Error::Index {
+            message: format!("PCA transform: column {} not found", self.column),
+            location: location!(),
+        })?;
+        let fsl = arr.as_fixed_size_list_opt().ok_or(Error::Index {
+            message: format!(
+                "PCA transform: column {} is not fixed size list: {}",
+                self.column,
+                arr.data_type()
+            ),
+            location: location!(),
+        })?;
+        let dim = fsl.value_length() as usize;
+        if dim != self.pca.dimension() {
+            return Err(Error::Index {
+                message: format!(
+                    "PCA transform: vector dimension {} does not match PCA dimension {}",
+                    dim,
+                    self.pca.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        let values = cast(fsl.values(), &DataType::Float32)?;
+        let values = values.as_primitive::<Float32Type>();
+
+        let mut reduced = Vec::with_capacity(fsl.len() * self.pca.num_components());
+        values
+            .values()
+            .chunks_exact(dim)
+            .for_each(|vector| reduced.extend(self.pca.project(vector)));
+        let reduced = FixedSizeListArray::try_new_from_values(
+            Float32Array::from(reduced),
+            self.pca.num_components() as i32,
+        )?;
+
+        let field = Field::new(PCA_VECTOR_COLUMN, reduced.data_type().clone(), false);
+        Ok(batch.try_with_column(field, Arc::new(reduced))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_project_and_reconstruct() {
+        // Project onto the first two axes of a 3-D space.
+        let components = FixedSizeListArray::try_new_from_values(
+            Float32Array::from(vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+            3,
+        )
+        .unwrap();
+        let pca = PcaMatrix::try_new(components, Float32Array::from(vec![1.0, 1.0, 1.0])).unwrap();
+
+        let reduced = pca.project(&[2.0, 3.0, 4.0]);
+        assert_eq!(reduced, vec![1.0, 2.0]);
+        let reconstructed = pca.reconstruct(&reduced);
+        assert_relative_eq!(reconstructed.as_slice(), [2.0, 3.0, 1.0].as_slice());
+
+        let proto = pb::Pca::try_from(&pca).unwrap();
+        let loaded = PcaMatrix::try_from(&proto).unwrap();
+        assert_eq!(loaded.components().values(), pca.components().values());
+        assert_eq!(loaded.mean(), pca.mean());
+    }
+}
@@ -18,26 +18,35 @@
 use std::any::Any;
 use std::sync::Arc;
 
-use arrow_array::{cast::AsArray, Array, FixedSizeListArray, UInt8Array};
+use arrow::compute::cast;
+use arrow_array::{cast::AsArray, types::Float32Type, Array, FixedSizeListArray, UInt8Array};
 use arrow_array::{ArrayRef, Float32Array};
+use arrow_schema::DataType;
 use async_trait::async_trait;
 use lance_arrow::floats::FloatArray;
 use lance_arrow::*;
 use lance_core::{Error, Result};
 use lance_linalg::distance::{
-    cosine_distance_batch, dot_distance_batch, l2_distance_batch, Cosine, Dot, L2,
+    cosine_distance_batch, dot::dot_scalar, dot_distance_batch, l2::l2_scalar, l2_distance_batch,
+    Cosine, Dot, L2,
 };
-use lance_linalg::kernels::{argmin, argmin_value_float, normalize};
+use lance_linalg::kernels::{argmin_value_float, normalize};
 use lance_linalg::{distance::MetricType, MatrixView};
+use num_traits::Float;
 use snafu::{location, Location};
 pub mod builder;
+pub mod lookup;
 pub mod transform;
 pub(crate) mod utils;
 
-pub use self::utils::num_centroids;
+pub use self::utils::{
+    num_centroids, pack_codes, packed_codes_len, transpose_pq_codes, unpack_codes, CodeLayout,
+    CodeStorageOrder,
+};
 use super::pb;
+use super::residual::ResidualPrecision;
 pub use builder::PQBuildParams;
-use lance_linalg::simd::{f32::f32x8, SIMD};
+use lance_linalg::simd::{f32::f32x8, is_simd_supported, SIMD};
 
 /// Product Quantization
 
@@ -56,6 +65,14 @@ pub trait ProductQuantizer: Send + Sync + std::fmt::Debug {
     ///   PQ code column
     async fn transform(&self, data: &dyn Array) -> Result<ArrayRef>;
 
+    /// Transform a vector column to PQ code column, like [`Self::transform`], with the
+    /// distances to the sub-vector centroids accumulated in `precision`.
+    async fn transform_with_precision(
+        &self,
+        data: &dyn Array,
+        precision: ResidualPrecision,
+    ) -> Result<ArrayRef>;
+
     /// Build the distance lookup in `f32`.
     fn build_distance_table(&self, query: &dyn Array, code: &UInt8Array) -> Result<Float32Array>;
 
@@ -129,6 +146,184 @@ fn get_sub_vector_centroids<T: FloatToArrayType>(
         ..(sub_vector_idx + 1) * num_centroids * sub_vector_width]
 }
 
+/// Distance between a sub-vector and one sub-vector centroid.
+type DistanceFn<T, D = f32> = fn(&[T], &[T]) -> D;
+
+/// Compute the PQ codes of `data`, a flatten `num_rows * dimension` array.
+///
+/// If `use_simd` is true, the nearest centroid of each sub-vector is found with the
+/// vectorized distance kernels from `lance_linalg`. Otherwise, scalar kernels are used,
+/// which is the fallback for CPUs without SIMD support (see [`is_simd_supported`]).
+///
+/// Returns the row-major `num_rows * num_sub_vectors` PQ codes.
+pub fn compute_pq_codes<T: ArrowFloatType + Dot + L2>(
+    data: &[T::Native],
+    codebook: &[T::Native],
+    dimension: usize,
+    num_sub_vectors: usize,
+    num_bits: u32,
+    metric_type: MetricType,
+    use_simd: bool,
+) -> Result<Vec<u8>> {
+    let all_centroids = (0..num_sub_vectors)
+        .map(|idx| get_sub_vector_centroids(codebook, dimension, num_bits, num_sub_vectors, idx))
+        .collect::<Vec<_>>();
+
+    // Pick the distance kernel once, so the loop below calls it directly for each
+    // (sub-vector, centroid) pair without allocating an iterator per sub-vector.
+    let distance: DistanceFn<T::Native> = match (metric_type, use_simd) {
+        (MetricType::L2 | MetricType::Cosine, true) => T::l2,
+        (MetricType::Dot, true) => |x, y| -T::dot(x, y),
+        (MetricType::L2 | MetricType::Cosine, false) => l2_scalar::<T::Native, 16>,
+        (MetricType::Dot, false) => |x, y| -dot_scalar::<T::Native, 16>(x, y),
+    };
+
+    assign_pq_codes(data, &all_centroids, dimension, num_sub_vectors, distance)
+}
+
+/// Compute the PQ codes of `data` like [`compute_pq_codes`], with the distances to the
+/// sub-vector centroids accumulated in f64.
+///
+/// The f32 sum of the squared differences of a sub-vector drops the small terms next
+/// to a large one, so two centroids that only differ in those terms tie, and the
+/// first one wins. Accumulating in f64 tells them apart.
+pub fn compute_pq_codes_f64<T: ArrowFloatType>(
+    data: &[T::Native],
+    codebook: &[T::Native],
+    dimension: usize,
+    num_sub_vectors: usize,
+    num_bits: u32,
+    metric_type: MetricType,
+) -> Result<Vec<u8>> {
+    let all_centroids = (0..num_sub_vectors)
+        .map(|idx| get_sub_vector_centroids(codebook, dimension, num_bits, num_sub_vectors, idx))
+        .collect::<Vec<_>>();
+    let distance: DistanceFn<T::Native, f64> = match metric_type {
+        MetricType::L2 | MetricType::Cosine => l2_f64,
+        MetricType::Dot => |x, y| -dot_f64(x, y),
+    };
+    assign_pq_codes(data, &all_centroids, dimension, num_sub_vectors, distance)
+}
+
+fn l2_f64<N: Float>(x: &[N], y: &[N]) -> f64 {
+    x.iter()
+        .zip(y)
+        .map(|(a, b)| {
+            let diff = a.to_f64().unwrap_or(f64::NAN) - b.to_f64().unwrap_or(f64::NAN);
+            diff * diff
+        })
+        .sum()
+}
+
+fn dot_f64<N: Float>(x: &[N], y: &[N]) -> f64 {
+    x.iter()
+        .zip(y)
+        .map(|(a, b)| a.to_f64().unwrap_or(f64::NAN) * b.to_f64().unwrap_or(f64::NAN))
+        .sum()
+}
+
+/// Assign each sub-vector of `data` to its nearest centroid in `all_centroids` by
+/// `distance`.
+fn assign_pq_codes<N: std::fmt::Debug, D: Float>(
+    data: &[N],
+    all_centroids: &[&[N]],
+    dimension: usize,
+    num_sub_vectors: usize,
+    distance: DistanceFn<N, D>,
+) -> Result<Vec<u8>> {
+    let num_rows = data.len() / dimension;
+    let mut codes: Vec<u8> = vec![0; num_sub_vectors * num_rows];
+    // Dimension of each sub-vector.
+    let sub_dim = dimension / num_sub_vectors;
+    for (row, row_codes) in data
+        .chunks_exact(dimension)
+        .zip(codes.chunks_exact_mut(num_sub_vectors))
+    {
+        for ((sub_vector, centroids), code) in row
+            .chunks_exact(sub_dim)
+            .zip(all_centroids.iter())
+            .zip(row_codes.iter_mut())
+        {
+            // Same semantics as `argmin`: the first minimal distance wins, NaN is skipped.
+            let mut min_idx: Option<usize> = None;
+            let mut min_dist = D::max_value();
+            for (idx, centroid) in centroids.chunks_exact(sub_dim).enumerate() {
+                let dist = distance(sub_vector, centroid);
+                if dist < min_dist {
+                    min_dist = dist;
+                    min_idx = Some(idx);
+                }
+            }
+            *code = min_idx.ok_or(Error::Index {
+                message: format!(
+                    "Failed to assign PQ code: {}, sub-vector={:#?}",
+                    "it is likely that distance is NaN or Inf", sub_vector
+                ),
+                location: location!(),
+            })? as u8;
+        }
+    }
+    Ok(codes)
+}
+
+/// Reconstruct the approximate vector of a stored PQ `code`, from the codebook of `pq`
+/// and the `centroid` of the IVF partition the vector was encoded in.
+///
+/// The centroid is only added back if `pq` encodes residuals, see
+/// [`ProductQuantizer::use_residual`].
+pub fn pq_decode(code: &[u8], pq: &dyn ProductQuantizer, centroid: &[f32]) -> Result<Vec<f32>> {
+    if code.len() != pq.num_sub_vectors() {
+        return Err(decode_mismatch(code.len(), centroid.len(), pq));
+    }
+    pq_decode_all(code, pq, centroid)
+}
+
+/// Reconstruct the approximate vectors of the PQ `codes` of many vectors of the same
+/// partition, one code after the other, like [`pq_decode`]. The vectors are returned
+/// one after the other too.
+pub fn pq_decode_all(
+    codes: &[u8],
+    pq: &dyn ProductQuantizer,
+    centroid: &[f32],
+) -> Result<Vec<f32>> {
+    let dimension = pq.dimension();
+    if codes.len() % pq.num_sub_vectors() != 0 || centroid.len() != dimension {
+        return Err(decode_mismatch(codes.len(), centroid.len(), pq));
+    }
+    let num_centroids = num_centroids(pq.num_bits());
+    let sub_vector_width = dimension / pq.num_sub_vectors();
+    let codebook = pq.codebook_as_fsl();
+    let codebook = cast(codebook.values(), &DataType::Float32)?;
+    let codebook = codebook.as_primitive::<Float32Type>().values();
+
+    let mut vectors = Vec::with_capacity(codes.len() / pq.num_sub_vectors() * dimension);
+    for code in codes.chunks_exact(pq.num_sub_vectors()) {
+        for (i, sub_code) in code.iter().enumerate() {
+            let start = (i * num_centroids + *sub_code as usize) * sub_vector_width;
+            vectors.extend_from_slice(&codebook[start..start + sub_vector_width]);
+        }
+    }
+    if pq.use_residual() {
+        vectors
+            .chunks_exact_mut(dimension)
+            .for_each(|vector| vector.iter_mut().zip(centroid).for_each(|(v, c)| *v += c));
+    }
+    Ok(vectors)
+}
+
+fn decode_mismatch(code_len: usize, centroid_len: usize, pq: &dyn ProductQuantizer) -> Error {
+    Error::Index {
+        message: format!(
+            "PQ decode: code of length {} and centroid of dimension {} do not match PQ{} of dimension {}",
+            code_len,
+            centroid_len,
+            pq.num_sub_vectors(),
+            pq.dimension()
+        ),
+        location: location!(),
+    }
+}
+
 impl<T: ArrowFloatType + Cosine + Dot + L2> ProductQuantizerImpl<T> {
     /// Create a [`ProductQuantizer`] with pre-trained codebook.
     pub fn new(
@@ -173,8 +368,7 @@ impl<T: ArrowFloatType + Cosine + Dot + L2> ProductQuantizerImpl<T> {
     /// Reconstruct a vector from its PQ code.
     ///
     /// It only supports U8 PQ code for now.
-    #[allow(dead_code)]
-    pub(crate) fn reconstruct(&self, code: &[u8]) -> Arc<T::ArrayType> {
+    pub fn reconstruct(&self, code: &[u8]) -> Arc<T::ArrayType> {
         assert_eq!(code.len(), self.num_sub_vectors);
         let mut builder = Vec::with_capacity(self.dimension);
         let sub_vector_dim = self.dimension / self.num_sub_vectors;
@@ -192,9 +386,6 @@ impl<T: ArrowFloatType + Cosine + Dot + L2> ProductQuantizerImpl<T> {
     ///
     /// Quantization distortion is the difference between the centroids
     /// from the PQ code to the actual vector.
-    ///
-    /// This method is just for debugging purpose.
-    #[allow(dead_code)]
     pub(crate) async fn distortion(
         &self,
         data: &MatrixView<T>,
@@ -363,6 +554,15 @@ impl<T: ArrowFloatType + Cosine + Dot + L2 + 'static> ProductQuantizer for Produ
     }
 
     async fn transform(&self, data: &dyn Array) -> Result<ArrayRef> {
+        self.transform_with_precision(data, ResidualPrecision::Native)
+            .await
+    }
+
+    async fn transform_with_precision(
+        &self,
+        data: &dyn Array,
+        precision: ResidualPrecision,
+    ) -> Result<ArrayRef> {
         let fsl = data
             .as_fixed_size_list_opt()
             .ok_or(Error::Index {
@@ -399,23 +599,11 @@ impl<T: ArrowFloatType + Cosine + Dot + L2 + 'static> ProductQuantizer for Produ
 
         let num_sub_vectors = self.num_sub_vectors;
         let dim = self.dimension;
-        let num_rows = fsl.len();
         let num_bits = self.num_bits;
         let codebook = self.codebook.clone();
 
         let metric_type = self.metric_type;
         let values = tokio::task::spawn_blocking(move || {
-            let all_centroids = (0..num_sub_vectors)
-                .map(|idx| {
-                    get_sub_vector_centroids(
-                        codebook.as_slice(),
-                        dim,
-                        num_bits,
-                        num_sub_vectors,
-                        idx,
-                    )
-                })
-                .collect::<Vec<_>>();
             let flatten_data =
                 fsl.values()
                     .as_any()
@@ -428,36 +616,26 @@ impl<T: ArrowFloatType + Cosine + Dot + L2 + 'static> ProductQuantizer for Produ
                         location: location!(),
                     })?;
 
-            let flatten_values = flatten_data.as_slice();
-            let capacity = num_sub_vectors * num_rows;
-            let mut builder: Vec<u8> = vec![0; capacity];
-            // Dimension of each sub-vector.
-            let sub_dim = dim / num_sub_vectors;
-            for i in 0..num_rows {
-                let row_offset = i * dim;
-
-                for sub_idx in 0..num_sub_vectors {
-                    let offset = row_offset + sub_idx * sub_dim;
-                    let sub_vector = &flatten_values[offset..offset + sub_dim];
-                    let centroids = all_centroids[sub_idx];
-
-                    let dist_iter = match metric_type {
-                        MetricType::L2 | MetricType::Cosine => {
-                            l2_distance_batch(sub_vector, centroids, sub_dim)
-                        }
-                        MetricType::Dot => dot_distance_batch(sub_vector, centroids, sub_dim),
-                    };
-                    let code = argmin(dist_iter).ok_or(Error::Index {
-                        message: format!(
-                            "Failed to assign PQ code: {}, sub-vector={:#?}",
-                            "it is likely that distance is NaN or Inf", sub_vector
-                        ),
-                        location: location!(),
-                    })? as u8;
-                    builder[i * num_sub_vectors + sub_idx] = code as u8;
-                }
-            }
-            Ok::<UInt8Array, Error>(UInt8Array::from(builder))
+            let codes = match precision {
+                ResidualPrecision::Native => compute_pq_codes::<T>(
+                    flatten_data.as_slice(),
+                    codebook.as_slice(),
+                    dim,
+                    num_sub_vectors,
+                    num_bits,
+                    metric_type,
+                    is_simd_supported(),
+                )?,
+                ResidualPrecision::F64 => compute_pq_codes_f64::<T>(
+                    flatten_data.as_slice(),
+                    codebook.as_slice(),
+                    dim,
+                    num_sub_vectors,
+                    num_bits,
+                    metric_type,
+                )?,
+            };
+            Ok::<UInt8Array, Error>(UInt8Array::from(codes))
         })
         .await??;
 
@@ -540,10 +718,11 @@ mod tests {
     use std::iter::repeat;
 
     use arrow_array::{
-        types::{Float16Type, Float32Type},
+        types::{Float16Type, UInt8Type},
         Float16Array, Float32Array,
     };
     use half::f16;
+    use lance_testing::datagen::generate_random_array_with_seed;
     use num_traits::Zero;
 
     #[test]
@@ -590,6 +769,159 @@ mod tests {
         assert!(pq.use_residual());
     }
 
+    #[test]
+    fn test_simd_and_scalar_pq_codes_match() {
+        const DIM: usize = 64;
+        const NUM_SUB_VECTORS: usize = 8;
+        let codebook = generate_random_array_with_seed::<Float32Type>(256 * DIM, [7; 32]);
+        let data = generate_random_array_with_seed::<Float32Type>(100 * DIM, [8; 32]);
+
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let codes = [true, false].map(|use_simd| {
+                compute_pq_codes::<Float32Type>(
+                    data.values(),
+                    codebook.values(),
+                    DIM,
+                    NUM_SUB_VECTORS,
+                    8,
+                    metric_type,
+                    use_simd,
+                )
+                .unwrap()
+            });
+            assert_eq!(codes[0].len(), 100 * NUM_SUB_VECTORS);
+            assert_eq!(codes[0], codes[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_f64_pq_codes_of_near_identical_centroids() {
+        const DIM: usize = 2;
+        // The first two centroids only differ in the second dimension, the others are
+        // far away from the vector.
+        let mut codebook = vec![0.0, 0.0, 0.0, 1.0];
+        codebook.extend(repeat(1e9).take(254 * DIM));
+        let codebook = Float32Array::from(codebook);
+        // The squares of the first dimension, 1e8, are a few ulps larger than the
+        // squares of the second one, which the f32 sums drop.
+        let vectors = Float32Array::from(vec![1e4, 1.0, -1e4, 1.0]);
+
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            1,
+            8,
+            DIM,
+            Arc::new(codebook.clone()),
+            MetricType::L2,
+        );
+        let fsl = FixedSizeListArray::try_new_from_values(vectors.clone(), DIM as i32).unwrap();
+        let codes = |precision| {
+            let pq = &pq;
+            let fsl = &fsl;
+            async move {
+                let codes = pq.transform_with_precision(fsl, precision).await.unwrap();
+                codes
+                    .as_fixed_size_list()
+                    .values()
+                    .as_primitive::<UInt8Type>()
+                    .values()
+                    .to_vec()
+            }
+        };
+        // The f32 distances to both centroids tie, so the first centroid wins.
+        assert_eq!(codes(ResidualPrecision::Native).await, vec![0, 0]);
+        assert_eq!(codes(ResidualPrecision::F64).await, vec![1, 1]);
+        for use_simd in [true, false] {
+            let native = compute_pq_codes::<Float32Type>(
+                vectors.values(),
+                codebook.values(),
+                DIM,
+                1,
+                8,
+                MetricType::L2,
+                use_simd,
+            )
+            .unwrap();
+            assert_eq!(native, vec![0, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pq_decode_within_quantization_error() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        const WIDTH: usize = DIM / NUM_SUB_VECTORS;
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            Arc::new(generate_random_array_with_seed::<Float32Type>(
+                256 * DIM,
+                [1; 32],
+            )),
+            MetricType::L2,
+        );
+        let centroid = generate_random_array_with_seed::<Float32Type>(DIM, [2; 32]);
+        let vectors = generate_random_array_with_seed::<Float32Type>(10 * DIM, [3; 32]);
+        let residuals = vectors
+            .values()
+            .chunks_exact(DIM)
+            .flat_map(|v| v.iter().zip(centroid.values()).map(|(x, c)| x - c))
+            .collect::<Vec<_>>();
+        let codes = pq
+            .transform(
+                &FixedSizeListArray::try_new_from_values(
+                    Float32Array::from(residuals.clone()),
+                    DIM as i32,
+                )
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+        let codes = codes
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>();
+
+        for (i, vector) in vectors.values().chunks_exact(DIM).enumerate() {
+            let code = &codes.values()[i * NUM_SUB_VECTORS..(i + 1) * NUM_SUB_VECTORS];
+            let decoded = pq_decode(code, &pq, centroid.values()).unwrap();
+            // The quantization error is the distance of each residual sub-vector to
+            // its nearest sub-vector centroid.
+            let error = residuals[i * DIM..(i + 1) * DIM]
+                .chunks_exact(WIDTH)
+                .enumerate()
+                .map(|(j, sub_vec)| {
+                    argmin_value_float(l2_distance_batch(sub_vec, pq.centroids(j), WIDTH)).1
+                })
+                .sum::<f32>();
+            let distance = vector
+                .iter()
+                .zip(decoded.iter())
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>();
+            assert!(
+                distance <= error * (1.0 + 1e-4) + 1e-6,
+                "{} > {}",
+                distance,
+                error
+            );
+        }
+
+        let decoded = codes
+            .values()
+            .chunks_exact(NUM_SUB_VECTORS)
+            .flat_map(|code| pq_decode(code, &pq, centroid.values()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pq_decode_all(codes.values(), &pq, centroid.values()).unwrap(),
+            decoded
+        );
+
+        assert!(pq_decode(&[0; 3], &pq, centroid.values()).is_err());
+        assert!(pq_decode(&[0; 4], &pq, &centroid.values()[..8]).is_err());
+        assert!(pq_decode_all(&[0; 6], &pq, centroid.values()).is_err());
+    }
+
     #[tokio::test]
     async fn test_empty_dist_iter() {
         let pq = ProductQuantizerImpl::<Float32Type> {
@@ -184,6 +184,84 @@ impl PQBuildParams {
     }
 }
 
+/// Choose the number of sub-vectors for PQ with a quick sweep over a small `sample`
+/// of vectors of dimension `dim`.
+///
+/// A PQ is trained at each of the `candidates` and scored by its reconstruction
+/// recall proxy, `1 - E / mean(|x|^2)`, where `E` is the quantization distortion of the
+/// sample. Returns the smallest candidate whose proxy reaches `target_recall`, or the
+/// largest candidate if none does.
+pub async fn sweep_num_sub_vectors(
+    sample: Arc<Float32Array>,
+    dim: usize,
+    candidates: &[usize],
+    target_recall: f64,
+) -> Result<usize> {
+    if dim == 0 || sample.is_empty() || sample.len() % dim != 0 {
+        return Err(Error::Index {
+            message: format!(
+                "PQ sweep: sample of {} values does not hold vectors of dimension {}",
+                sample.len(),
+                dim
+            ),
+            location: location!(),
+        });
+    }
+    let mut candidates = candidates.to_vec();
+    candidates.sort_unstable();
+    candidates.dedup();
+    if candidates.is_empty() || candidates.iter().any(|c| *c == 0 || dim % c != 0) {
+        return Err(Error::Index {
+            message: format!(
+                "PQ sweep: candidates {:?} must be non-empty and divide the dimension {}",
+                candidates, dim
+            ),
+            location: location!(),
+        });
+    }
+
+    let data = MatrixView::<Float32Type>::new(sample, dim);
+    for num_sub_vectors in candidates.iter() {
+        let recall = 1.0 - relative_distortion(&data, *num_sub_vectors).await?;
+        if recall >= target_recall {
+            return Ok(*num_sub_vectors);
+        }
+    }
+    Ok(*candidates.last().unwrap())
+}
+
+/// Quantization distortion of `data` by a PQ of `num_sub_vectors` trained on it,
+/// relative to the mean squared norm of the vectors.
+async fn relative_distortion(
+    data: &MatrixView<Float32Type>,
+    num_sub_vectors: usize,
+) -> Result<f64> {
+    // A few k-means iterations are enough to rank the candidates.
+    let params = PQBuildParams {
+        max_iters: 10,
+        ..PQBuildParams::new(num_sub_vectors, 8)
+    };
+    let pq = params.build_from_matrix(data, MetricType::L2).await?;
+    let pq = pq
+        .as_any()
+        .downcast_ref::<ProductQuantizerImpl<Float32Type>>()
+        .ok_or(Error::Index {
+            message: "PQ sweep: expected a float32 product quantizer".to_string(),
+            location: location!(),
+        })?;
+    let distortion = pq.distortion(data, MetricType::L2).await?;
+    let mean_norm = data
+        .iter()
+        .map(|v| v.iter().map(|x| (x * x) as f64).sum::<f64>())
+        .sum::<f64>()
+        / data.num_rows() as f64;
+    Ok(if mean_norm > 0.0 {
+        distortion / mean_norm
+    } else {
+        0.0
+    })
+}
+
 fn create_typed_pq<
     T: ArrowFloatType<ArrayType = PrimitiveArray<T>> + ArrowNumericType + L2 + Cosine + Dot,
 >(
@@ -241,3 +319,61 @@ pub fn from_proto(proto: &Pq, metric_type: MetricType) -> Result<Arc<dyn Product
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lance_testing::datagen::generate_random_array_with_seed;
+
+    const DIM: usize = 8;
+
+    #[tokio::test]
+    async fn test_sweep_num_sub_vectors() {
+        let sample = Arc::new(generate_random_array_with_seed::<Float32Type>(
+            384 * DIM,
+            [7; 32],
+        ));
+        let data = MatrixView::<Float32Type>::new(sample.clone(), DIM);
+
+        let mut errors = vec![];
+        for num_sub_vectors in [1, 2, 4, 8] {
+            errors.push(relative_distortion(&data, num_sub_vectors).await.unwrap());
+        }
+        assert!(
+            errors.windows(2).all(|w| w[0] > w[1]),
+            "errors {:?} do not decrease",
+            errors
+        );
+
+        let candidates = [8, 2, 1, 4];
+        assert_eq!(
+            sweep_num_sub_vectors(sample.clone(), DIM, &candidates, 0.0)
+                .await
+                .unwrap(),
+            1
+        );
+        // Unreachable target falls back to the finest quantization.
+        assert_eq!(
+            sweep_num_sub_vectors(sample.clone(), DIM, &candidates, 1.1)
+                .await
+                .unwrap(),
+            8
+        );
+        // Half way between the recall proxies of 2 and 4 sub-vectors.
+        let target = 1.0 - (errors[1] + errors[2]) / 2.0;
+        assert_eq!(
+            sweep_num_sub_vectors(sample.clone(), DIM, &candidates, target)
+                .await
+                .unwrap(),
+            4
+        );
+
+        assert!(sweep_num_sub_vectors(sample.clone(), DIM, &[3], 0.5)
+            .await
+            .is_err());
+        assert!(sweep_num_sub_vectors(sample, DIM + 1, &[1], 0.5)
+            .await
+            .is_err());
+    }
+}
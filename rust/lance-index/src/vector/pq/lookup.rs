@@ -0,0 +1,244 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transposed PQ codebook to build distance tables.
+//!
+
+use arrow::compute::cast;
+use arrow_array::{cast::AsArray, types::Float32Type, Array, FixedSizeListArray, Float32Array};
+use arrow_schema::DataType;
+use lance_arrow::FixedSizeListArrayExt;
+use lance_core::{Error, Result};
+use lance_linalg::{distance::MetricType, kernels::normalize};
+use snafu::{location, Location};
+
+use super::{num_centroids, ProductQuantizer};
+use crate::pb;
+
+/// PQ codebook laid out dimension-major, so that the distance table of a query is
+/// computed for all the centroids of a sub-vector at once, one dimension at a time.
+///
+/// Layout:
+///
+/// ```text
+/// // Value of the `dim`-th dimension of every centroid of the sub-vector
+/// // that `dim` belongs to.
+/// Codebook[dim][pq_code]
+/// ```
+#[derive(Debug, Clone)]
+pub struct TransposedCodebook {
+    /// `dimension * num_centroids` of float32s.
+    values: Vec<f32>,
+
+    num_centroids: usize,
+
+    num_sub_vectors: usize,
+}
+
+impl TransposedCodebook {
+    /// Transpose the codebook of `pq`.
+    pub fn try_new(pq: &dyn ProductQuantizer) -> Result<Self> {
+        let num_centroids = num_centroids(pq.num_bits());
+        let num_sub_vectors = pq.num_sub_vectors();
+        let dimension = pq.dimension();
+        let sub_vector_width = dimension / num_sub_vectors;
+
+        let codebook = pq.codebook_as_fsl();
+        let codebook = cast(codebook.values(), &DataType::Float32)?;
+        let codebook = codebook.as_primitive::<Float32Type>().values();
+
+        let mut values = vec![0.0; dimension * num_centroids];
+        codebook
+            .chunks_exact(num_centroids * sub_vector_width)
+            .enumerate()
+            .for_each(|(sub_vector_idx, centroids)| {
+                centroids
+                    .chunks_exact(sub_vector_width)
+                    .enumerate()
+                    .for_each(|(code, centroid)| {
+                        centroid.iter().enumerate().for_each(|(j, v)| {
+                            let dim = sub_vector_idx * sub_vector_width + j;
+                            values[dim * num_centroids + code] = *v;
+                        });
+                    });
+            });
+        Ok(Self {
+            values,
+            num_centroids,
+            num_sub_vectors,
+        })
+    }
+
+    /// Load from the `dimension` rows of [`Self::as_fsl`].
+    pub fn try_from_fsl(fsl: &FixedSizeListArray, num_sub_vectors: usize) -> Result<Self> {
+        if num_sub_vectors == 0 || fsl.len() % num_sub_vectors != 0 {
+            return Err(Error::Index {
+                message: format!(
+                    "Transposed PQ codebook of dimension {} does not match {} sub-vectors",
+                    fsl.len(),
+                    num_sub_vectors
+                ),
+                location: location!(),
+            });
+        }
+        let values = cast(fsl.values(), &DataType::Float32)?;
+        Ok(Self {
+            values: values.as_primitive::<Float32Type>().values().to_vec(),
+            num_centroids: fsl.value_length() as usize,
+            num_sub_vectors,
+        })
+    }
+
+    /// One row of the centroid values per dimension.
+    pub fn as_fsl(&self) -> Result<FixedSizeListArray> {
+        Ok(FixedSizeListArray::try_new_from_values(
+            Float32Array::from(self.values.clone()),
+            self.num_centroids as i32,
+        )?)
+    }
+
+    fn dimension(&self) -> usize {
+        self.values.len() / self.num_centroids
+    }
+
+    /// Distance table of `query`, `num_sub_vectors * num_centroids` of distances of the
+    /// query sub-vectors to the centroids, as built by
+    /// [`ProductQuantizer::build_distance_table`].
+    pub fn distance_table(&self, query: &[f32], metric_type: MetricType) -> Result<Vec<f32>> {
+        if query.len() != self.dimension() {
+            return Err(Error::Index {
+                message: format!(
+                    "Transposed PQ codebook: query dimension {} does not match {}",
+                    query.len(),
+                    self.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        Ok(match metric_type {
+            MetricType::L2 => self.accumulate(query, |q, c| (q - c) * (q - c)),
+            // See [`ProductQuantizer::build_distance_table`].
+            MetricType::Cosine => {
+                let query = normalize(query).collect::<Vec<_>>();
+                self.accumulate(&query, |q, c| (q - c) * (q - c))
+                    .into_iter()
+                    .map(|d| d / 2.0)
+                    .collect()
+            }
+            MetricType::Dot => self.accumulate(query, |q, c| -q * c),
+        })
+    }
+
+    /// Sum `f(query[dim], centroid[dim])` over the dimensions of each sub-vector.
+    fn accumulate(&self, query: &[f32], f: impl Fn(f32, f32) -> f32) -> Vec<f32> {
+        let sub_vector_width = self.dimension() / self.num_sub_vectors;
+        let mut table = vec![0.0; self.num_sub_vectors * self.num_centroids];
+        table
+            .chunks_exact_mut(self.num_centroids)
+            .zip(query.chunks_exact(sub_vector_width))
+            .zip(
+                self.values
+                    .chunks_exact(sub_vector_width * self.num_centroids),
+            )
+            .for_each(|((distances, sub_vec), codebook)| {
+                sub_vec
+                    .iter()
+                    .zip(codebook.chunks_exact(self.num_centroids))
+                    .for_each(|(q, centroids)| {
+                        distances
+                            .iter_mut()
+                            .zip(centroids.iter())
+                            .for_each(|(d, c)| *d += f(*q, *c));
+                    });
+            });
+        table
+    }
+}
+
+impl TryFrom<&TransposedCodebook> for pb::TransposedCodebook {
+    type Error = Error;
+
+    fn try_from(codebook: &TransposedCodebook) -> Result<Self> {
+        Ok(Self {
+            codebook: Some((&codebook.as_fsl()?).try_into()?),
+            num_sub_vectors: codebook.num_sub_vectors as u32,
+        })
+    }
+}
+
+impl TryFrom<&pb::TransposedCodebook> for TransposedCodebook {
+    type Error = Error;
+
+    fn try_from(proto: &pb::TransposedCodebook) -> Result<Self> {
+        let codebook = proto.codebook.as_ref().ok_or_else(|| Error::Index {
+            message: "Transposed PQ codebook is missing its tensor".to_string(),
+            location: location!(),
+        })?;
+        Self::try_from_fsl(
+            &FixedSizeListArray::try_from(codebook)?,
+            proto.num_sub_vectors as usize,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use approx::assert_relative_eq;
+    use lance_linalg::distance::{dot_distance_batch, l2_distance_batch};
+    use lance_testing::datagen::generate_random_array_with_seed;
+
+    use crate::vector::pq::ProductQuantizerImpl;
+
+    #[test]
+    fn test_distance_table_matches_codebook() {
+        const DIM: usize = 16;
+        const NUM_SUB_VECTORS: usize = 4;
+        const WIDTH: usize = DIM / NUM_SUB_VECTORS;
+
+        let codebook = generate_random_array_with_seed::<Float32Type>(256 * DIM, [3; 32]);
+        let pq = ProductQuantizerImpl::<Float32Type>::new(
+            NUM_SUB_VECTORS,
+            8,
+            DIM,
+            Arc::new(codebook),
+            MetricType::L2,
+        );
+        let transposed = TransposedCodebook::try_new(&pq).unwrap();
+        let proto = pb::TransposedCodebook::try_from(&transposed).unwrap();
+        let loaded = TransposedCodebook::try_from(&proto).unwrap();
+
+        let query = generate_random_array_with_seed::<Float32Type>(DIM, [5; 32]);
+        let query = query.values();
+        for metric_type in [MetricType::L2, MetricType::Dot] {
+            let expected = query
+                .chunks_exact(WIDTH)
+                .enumerate()
+                .flat_map(|(i, sub_vec)| match metric_type {
+                    MetricType::Dot => dot_distance_batch(sub_vec, pq.centroids(i), WIDTH),
+                    _ => l2_distance_batch(sub_vec, pq.centroids(i), WIDTH),
+                })
+                .collect::<Vec<_>>();
+            let table = loaded.distance_table(query, metric_type).unwrap();
+            assert_relative_eq!(table.as_slice(), expected.as_slice(), epsilon = 1e-5);
+        }
+
+        assert!(transposed
+            .distance_table(&query[..8], MetricType::L2)
+            .is_err());
+    }
+}
@@ -23,6 +23,7 @@ use lance_core::{Error, Result};
 use snafu::{location, Location};
 
 use super::ProductQuantizer;
+use crate::vector::residual::ResidualPrecision;
 use crate::vector::transform::Transformer;
 
 /// Product Quantizer Transformer
@@ -32,6 +33,7 @@ pub struct PQTransformer {
     quantizer: Arc<dyn ProductQuantizer>,
     input_column: String,
     output_column: String,
+    precision: ResidualPrecision,
 }
 
 impl PQTransformer {
@@ -44,8 +46,15 @@ impl PQTransformer {
             quantizer,
             input_column: input_column.to_owned(),
             output_column: output_column.to_owned(),
+            precision: ResidualPrecision::default(),
         }
     }
+
+    /// Accumulate the distances to the sub-vector centroids in `precision`.
+    pub fn with_precision(mut self, precision: ResidualPrecision) -> Self {
+        self.precision = precision;
+        self
+    }
 }
 
 impl Debug for PQTransformer {
@@ -78,7 +87,10 @@ impl Transformer for PQTransformer {
             ),
             location: location!(),
         })?;
-        let pq_code = self.quantizer.transform(&data).await?;
+        let pq_code = self
+            .quantizer
+            .transform_with_precision(&data, self.precision)
+            .await?;
         let pq_field = Field::new(&self.output_column, pq_code.data_type().clone(), false);
         let batch = batch.try_with_column(pq_field, Arc::new(pq_code))?;
         let batch = batch.drop_column(&self.input_column)?;
@@ -14,8 +14,16 @@
 
 use std::sync::Arc;
 
-use lance_arrow::ArrowFloatType;
+use arrow_array::{
+    cast::AsArray, types::UInt8Type, Array, FixedSizeListArray, RecordBatch, UInt8Array,
+};
+use lance_arrow::{ArrowFloatType, FixedSizeListArrayExt, RecordBatchExt};
+use lance_core::{Error, Result};
 use lance_linalg::MatrixView;
+use snafu::{location, Location};
+
+use crate::pb;
+use crate::vector::PQ_CODE_COLUMN;
 
 /// Divide a 2D vector in [`T::Array`] to `m` sub-vectors.
 ///
@@ -53,6 +61,174 @@ pub fn num_centroids(num_bits: impl Into<u32>) -> usize {
     2_usize.pow(num_bits.into())
 }
 
+/// Storage order of the PQ codes of an IVF partition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeStorageOrder {
+    /// Row-major, the codes of each row are contiguous.
+    #[default]
+    Interleaved,
+
+    /// Sub-vector-major, the codes of each sub-vector are contiguous, see
+    /// [`transpose_pq_codes`].
+    Separate,
+}
+
+impl From<pb::CodeStorageOrder> for CodeStorageOrder {
+    fn from(proto: pb::CodeStorageOrder) -> Self {
+        match proto {
+            pb::CodeStorageOrder::Interleaved => Self::Interleaved,
+            pb::CodeStorageOrder::Separate => Self::Separate,
+        }
+    }
+}
+
+impl From<CodeStorageOrder> for pb::CodeStorageOrder {
+    fn from(order: CodeStorageOrder) -> Self {
+        match order {
+            CodeStorageOrder::Interleaved => Self::Interleaved,
+            CodeStorageOrder::Separate => Self::Separate,
+        }
+    }
+}
+
+/// Layout of the PQ codes to read from an IVF partition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeLayout {
+    /// One byte per code.
+    #[default]
+    Raw,
+
+    /// The bit-packed copy of the codes, see [`pack_codes`]. Only stored if the index
+    /// was built with `dual_code_layout`.
+    BitPacked,
+}
+
+/// Number of bytes of `num_codes` codes packed into `num_bits` each.
+pub fn packed_codes_len(num_codes: usize, num_bits: u32) -> usize {
+    (num_codes * num_bits as usize + 7) / 8
+}
+
+/// Pack `codes`, each below `2 ^ num_bits`, into `num_bits` each.
+///
+/// The bits of code `i` are bits `[i * num_bits, (i + 1) * num_bits)` of the output,
+/// little-endian, and the last byte is padded with zeros.
+pub fn pack_codes(codes: &[u8], num_bits: u32) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(packed_codes_len(codes.len(), num_bits));
+    let (mut buffer, mut num_buffered) = (0_u32, 0);
+    for code in codes {
+        buffer |= (*code as u32) << num_buffered;
+        num_buffered += num_bits;
+        while num_buffered >= 8 {
+            packed.push(buffer as u8);
+            buffer >>= 8;
+            num_buffered -= 8;
+        }
+    }
+    if num_buffered > 0 {
+        packed.push(buffer as u8);
+    }
+    packed
+}
+
+/// Unpack `num_codes` codes of `num_bits` each from `packed`, see [`pack_codes`].
+pub fn unpack_codes(packed: &[u8], num_bits: u32, num_codes: usize) -> Result<Vec<u8>> {
+    if !(1..=8).contains(&num_bits) || packed.len() < packed_codes_len(num_codes, num_bits) {
+        return Err(Error::Index {
+            message: format!(
+                "unpack PQ codes: {} bytes can not hold {} codes of {} bits",
+                packed.len(),
+                num_codes,
+                num_bits
+            ),
+            location: location!(),
+        });
+    }
+    let mask = (1_u32 << num_bits) - 1;
+    let mut codes = Vec::with_capacity(num_codes);
+    let mut bytes = packed.iter();
+    let (mut buffer, mut num_buffered) = (0_u32, 0);
+    for _ in 0..num_codes {
+        while num_buffered < num_bits {
+            buffer |= (*bytes.next().unwrap() as u32) << num_buffered;
+            num_buffered += 8;
+        }
+        codes.push((buffer & mask) as u8);
+        buffer >>= num_bits;
+        num_buffered -= num_bits;
+    }
+    Ok(codes)
+}
+
+/// Convert the [`PQ_CODE_COLUMN`] of `batch` between the row-major and the
+/// sub-vector-major layouts.
+///
+/// In row-major layout, the codes of each row are contiguous. In sub-vector-major
+/// layout, the codes of each sub-vector are contiguous, i.e., the flat codes are
+/// `[sub_vector_0 of all rows, sub_vector_1 of all rows, ...]`. The column keeps its
+/// `FixedSizeList<u8, num_sub_vectors>` type in both layouts.
+///
+/// If `transpose` is true, converts row-major codes to sub-vector-major; otherwise
+/// converts sub-vector-major codes back to row-major. The other columns are untouched.
+pub fn transpose_pq_codes(
+    batch: &RecordBatch,
+    num_sub_vectors: usize,
+    transpose: bool,
+) -> Result<RecordBatch> {
+    let codes = batch
+        .column_by_name(PQ_CODE_COLUMN)
+        .ok_or(Error::Index {
+            message: format!("transpose PQ codes: column {} not found", PQ_CODE_COLUMN),
+            location: location!(),
+        })?
+        .as_fixed_size_list_opt()
+        .ok_or(Error::Index {
+            message: format!(
+                "transpose PQ codes: column {} is not a fixed size list",
+                PQ_CODE_COLUMN
+            ),
+            location: location!(),
+        })?;
+    if codes.value_length() as usize != num_sub_vectors {
+        return Err(Error::Index {
+            message: format!(
+                "transpose PQ codes: expect {} sub-vectors, got {}",
+                num_sub_vectors,
+                codes.value_length()
+            ),
+            location: location!(),
+        });
+    }
+    let values = codes
+        .values()
+        .as_primitive_opt::<UInt8Type>()
+        .ok_or(Error::Index {
+            message: format!(
+                "transpose PQ codes: expect u8 codes, got {}",
+                codes.value_type()
+            ),
+            location: location!(),
+        })?;
+    let values = &values.values()[..codes.len() * num_sub_vectors];
+
+    let num_rows = codes.len();
+    let (from_stride, to_stride) = if transpose {
+        (num_sub_vectors, num_rows)
+    } else {
+        (num_rows, num_sub_vectors)
+    };
+    let mut transposed = vec![0_u8; values.len()];
+    values.iter().enumerate().for_each(|(i, code)| {
+        let (row, col) = (i / from_stride, i % from_stride);
+        transposed[col * to_stride + row] = *code;
+    });
+    let transposed = FixedSizeListArray::try_new_from_values(
+        UInt8Array::from(transposed),
+        num_sub_vectors as i32,
+    )?;
+
+    Ok(batch.replace_column_by_name(PQ_CODE_COLUMN, Arc::new(transposed))?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +250,47 @@ mod tests {
             )
         );
     }
+    #[test]
+    fn test_transpose_pq_codes() {
+        let codes = FixedSizeListArray::try_new_from_values(UInt8Array::from_iter_values(0..12), 4)
+            .unwrap();
+        let schema = arrow_schema::Schema::new(vec![arrow_schema::Field::new(
+            PQ_CODE_COLUMN,
+            codes.data_type().clone(),
+            false,
+        )]);
+        let batch = RecordBatch::try_new(schema.into(), vec![Arc::new(codes)]).unwrap();
+
+        let transposed = transpose_pq_codes(&batch, 4, true).unwrap();
+        let values = transposed[PQ_CODE_COLUMN]
+            .as_fixed_size_list()
+            .values()
+            .as_primitive::<UInt8Type>()
+            .clone();
+        assert_eq!(
+            values,
+            UInt8Array::from(vec![0, 4, 8, 1, 5, 9, 2, 6, 10, 3, 7, 11])
+        );
+
+        let restored = transpose_pq_codes(&transposed, 4, false).unwrap();
+        assert_eq!(restored, batch);
+
+        assert!(transpose_pq_codes(&batch, 3, true).is_err());
+    }
+
+    #[test]
+    fn test_pack_codes() {
+        for num_bits in 1..=8 {
+            let codes = (0..37_u32)
+                .map(|v| (v * 7 % (1 << num_bits)) as u8)
+                .collect::<Vec<_>>();
+            let packed = pack_codes(&codes, num_bits);
+            assert_eq!(packed.len(), packed_codes_len(codes.len(), num_bits));
+            assert_eq!(unpack_codes(&packed, num_bits, codes.len()).unwrap(), codes);
+        }
+        assert_eq!(pack_codes(&[1, 2, 3], 8), vec![1, 2, 3]);
+        assert_eq!(pack_codes(&[1, 2, 3], 4), vec![0x21, 0x03]);
+        assert!(unpack_codes(&[0x21], 4, 3).is_err());
+        assert!(unpack_codes(&[0x21, 0x03], 9, 1).is_err());
+    }
 }
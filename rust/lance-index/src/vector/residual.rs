@@ -26,6 +26,23 @@ use super::transform::Transformer;
 
 pub const RESIDUAL_COLUMN: &str = "__residual_vector";
 
+/// Precision of the product quantization of the residuals.
+///
+/// The residuals themselves are computed in the type of the vectors: the difference
+/// of two floats is rounded once, so computing it in f64 and rounding it back gives
+/// the same residual. The precision is lost when the squared differences of a
+/// sub-vector to its centroids are summed, see [`crate::vector::pq::compute_pq_codes_f64`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResidualPrecision {
+    /// Accumulate the distances in the type of the vectors, with the SIMD kernels.
+    #[default]
+    Native,
+
+    /// Accumulate the distances in f64, trading speed for stabler codes of the
+    /// near-identical sub-vector centroids of near-degenerate clusters.
+    F64,
+}
+
 /// Compute the residual vector of a Vector Matrix to their centroids.
 ///
 /// The residual vector is the difference between the original vector and the centroid.
@@ -0,0 +1,397 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Global per-dimension statistics of a vector column.
+//!
+
+use arrow::compute::cast;
+use arrow_array::{cast::AsArray, types::Float32Type, Array, FixedSizeListArray};
+use arrow_schema::DataType;
+use lance_core::{Error, Result};
+use snafu::{location, Location};
+
+use crate::pb;
+
+/// Per-dimension mean and variance of vectors, gathered in one pass over the batches
+/// of a column, e.g. to standardize queries the same way as the indexed vectors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorStats {
+    num_rows: u64,
+
+    /// Mean of each dimension.
+    mean: Vec<f64>,
+
+    /// Sum of the squared differences from the mean of each dimension.
+    m2: Vec<f64>,
+}
+
+impl VectorStats {
+    /// Statistics of no vectors of `dimension`.
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            num_rows: 0,
+            mean: vec![0.0; dimension],
+            m2: vec![0.0; dimension],
+        }
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.num_rows
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.mean.len()
+    }
+
+    /// Add the vectors of a batch.
+    pub fn update(&mut self, vectors: &FixedSizeListArray) -> Result<()> {
+        let dim = vectors.value_length() as usize;
+        if dim != self.dimension() {
+            return Err(Error::Index {
+                message: format!(
+                    "Vector stats: vector dimension {} does not match {}",
+                    dim,
+                    self.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        if vectors.is_empty() {
+            return Ok(());
+        }
+        let values = cast(vectors.values(), &DataType::Float32)?;
+        let values = values.as_primitive::<Float32Type>().values();
+
+        let mut batch = Self::new(dim);
+        batch.num_rows = vectors.len() as u64;
+        values.chunks_exact(dim).for_each(|vector| {
+            batch
+                .mean
+                .iter_mut()
+                .zip(vector)
+                .for_each(|(m, v)| *m += *v as f64)
+        });
+        batch
+            .mean
+            .iter_mut()
+            .for_each(|m| *m /= batch.num_rows as f64);
+        values.chunks_exact(dim).for_each(|vector| {
+            batch
+                .m2
+                .iter_mut()
+                .zip(vector.iter().zip(batch.mean.iter()))
+                .for_each(|(m2, (v, m))| *m2 += (*v as f64 - m).powi(2))
+        });
+        self.merge(&batch)
+    }
+
+    /// Combine with the statistics of other vectors.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        if other.dimension() != self.dimension() {
+            return Err(Error::Index {
+                message: format!(
+                    "Vector stats: can not merge dimension {} into {}",
+                    other.dimension(),
+                    self.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        if other.num_rows == 0 {
+            return Ok(());
+        }
+        let n = self.num_rows + other.num_rows;
+        let (n_a, n_b) = (self.num_rows as f64, other.num_rows as f64);
+        for i in 0..self.dimension() {
+            let delta = other.mean[i] - self.mean[i];
+            self.mean[i] += delta * n_b / n as f64;
+            self.m2[i] += other.m2[i] + delta * delta * n_a * n_b / n as f64;
+        }
+        self.num_rows = n;
+        Ok(())
+    }
+
+    /// Mean of each dimension.
+    pub fn mean(&self) -> Vec<f32> {
+        self.mean.iter().map(|m| *m as f32).collect()
+    }
+
+    /// Population variance of each dimension, zero if there are no vectors.
+    pub fn variance(&self) -> Vec<f32> {
+        let n = self.num_rows.max(1) as f64;
+        self.m2.iter().map(|m2| (m2 / n) as f32).collect()
+    }
+
+    /// Standard deviation of each dimension.
+    pub fn std(&self) -> Vec<f32> {
+        self.variance().iter().map(|v| v.sqrt()).collect()
+    }
+
+    /// Standardize `vector` to zero mean and unit variance per dimension.
+    ///
+    /// Dimensions without any variance are only centered.
+    pub fn standardize(&self, vector: &[f32]) -> Vec<f32> {
+        vector
+            .iter()
+            .zip(self.mean().iter().zip(self.std().iter()))
+            .map(|(v, (m, s))| if *s > 0.0 { (v - m) / s } else { v - m })
+            .collect()
+    }
+}
+
+/// Bins of [NormHistogram] per doubling of the norm.
+const NORM_BINS_PER_OCTAVE: usize = 4;
+
+/// Smallest norm of [NormHistogram], as a power of two. Smaller norms go to the first
+/// bin and larger norms than `2 ^ -NORM_MIN_EXPONENT` to the last one.
+const NORM_MIN_EXPONENT: i32 = -32;
+
+const NORM_HISTOGRAM_BINS: usize = 64 * NORM_BINS_PER_OCTAVE;
+
+/// Smallest share of the vectors in each of the two modes of [`NormHistogram::bimodality`].
+const MIN_MODE_FRACTION: f64 = 0.05;
+
+/// Smallest separation of the two modes of [`NormHistogram::bimodality`], in standard
+/// deviations of the log norms. A single normal or uniform distribution split in two is
+/// below 3.5.
+const MIN_MODE_SEPARATION: f64 = 4.0;
+
+/// Histogram of the log2 of the L2 norms of vectors, e.g. to detect a column mixing
+/// vectors of very different scales.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormHistogram {
+    counts: Vec<u64>,
+}
+
+/// Two modes of the norms found by [`NormHistogram::bimodality`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bimodality {
+    /// Norm between the two modes.
+    pub threshold: f32,
+
+    /// Share of the vectors with a norm below the threshold.
+    pub low_fraction: f64,
+
+    /// Distance between the means of the log norms of the two modes, in standard
+    /// deviations (Ashman's D).
+    pub separation: f64,
+}
+
+impl Default for NormHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; NORM_HISTOGRAM_BINS],
+        }
+    }
+}
+
+impl NormHistogram {
+    pub fn num_rows(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Add the norms of the vectors of a batch. Vectors with a NaN norm are skipped.
+    pub fn update(&mut self, vectors: &FixedSizeListArray) -> Result<()> {
+        let dim = vectors.value_length() as usize;
+        if vectors.is_empty() || dim == 0 {
+            return Ok(());
+        }
+        let values = cast(vectors.values(), &DataType::Float32)?;
+        for vector in values
+            .as_primitive::<Float32Type>()
+            .values()
+            .chunks_exact(dim)
+        {
+            let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm.is_nan() {
+                continue;
+            }
+            let bin = (norm.log2() - NORM_MIN_EXPONENT as f32) * NORM_BINS_PER_OCTAVE as f32;
+            self.counts[bin.clamp(0.0, (NORM_HISTOGRAM_BINS - 1) as f32) as usize] += 1;
+        }
+        Ok(())
+    }
+
+    /// The two modes of the norms, if the norms are split into two well separated
+    /// groups of at least [MIN_MODE_FRACTION] of the vectors each, at least a doubling
+    /// apart.
+    ///
+    /// The split maximizes the variance between the two groups of log norms (Otsu's
+    /// method).
+    pub fn bimodality(&self) -> Option<Bimodality> {
+        let total = self.num_rows() as f64;
+        if total == 0.0 {
+            return None;
+        }
+        // Log2 norm at the center of each bin.
+        let center = |bin: usize| {
+            (bin as f64 + 0.5) / NORM_BINS_PER_OCTAVE as f64 + NORM_MIN_EXPONENT as f64
+        };
+        let moments = |bins: &[u64], first: usize| {
+            let n = bins.iter().sum::<u64>() as f64;
+            let mean = bins
+                .iter()
+                .enumerate()
+                .map(|(i, c)| *c as f64 * center(first + i))
+                .sum::<f64>()
+                / n;
+            let variance = bins
+                .iter()
+                .enumerate()
+                .map(|(i, c)| *c as f64 * (center(first + i) - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            (n, mean, variance)
+        };
+
+        let mut best: Option<(f64, usize)> = None;
+        for split in 1..NORM_HISTOGRAM_BINS {
+            let low = self.counts[..split].iter().sum::<u64>();
+            if low == 0 || low == total as u64 {
+                continue;
+            }
+            let (n_low, mean_low, _) = moments(&self.counts[..split], 0);
+            let (n_high, mean_high, _) = moments(&self.counts[split..], split);
+            let between = n_low * n_high * (mean_high - mean_low).powi(2);
+            if best.map_or(true, |(b, _)| between > b) {
+                best = Some((between, split));
+            }
+        }
+        let (_, split) = best?;
+        let (n_low, mean_low, var_low) = moments(&self.counts[..split], 0);
+        let (n_high, mean_high, var_high) = moments(&self.counts[split..], split);
+        // Each bin spreads its norms over its width.
+        let bin_variance = 1.0 / (12.0 * (NORM_BINS_PER_OCTAVE * NORM_BINS_PER_OCTAVE) as f64);
+        let separation = std::f64::consts::SQRT_2 * (mean_high - mean_low)
+            / (var_low + var_high + 2.0 * bin_variance).sqrt();
+        let low_fraction = n_low / total;
+        let bimodal = low_fraction.min(n_high / total) >= MIN_MODE_FRACTION
+            && separation >= MIN_MODE_SEPARATION
+            && mean_high - mean_low >= 1.0;
+        // Any split in the gap between the modes is as good, so take its middle.
+        let low_end = (0..split).rev().find(|&i| self.counts[i] > 0)? + 1;
+        let high_start = (split..NORM_HISTOGRAM_BINS).find(|&i| self.counts[i] > 0)?;
+        let middle =
+            (center(low_end) + center(high_start)) / 2.0 - 0.5 / NORM_BINS_PER_OCTAVE as f64;
+        bimodal.then(|| Bimodality {
+            threshold: 2f64.powf(middle) as f32,
+            low_fraction,
+            separation,
+        })
+    }
+}
+
+impl From<&VectorStats> for pb::VectorStats {
+    fn from(stats: &VectorStats) -> Self {
+        Self {
+            num_rows: stats.num_rows,
+            mean: stats.mean.clone(),
+            variance: stats
+                .m2
+                .iter()
+                .map(|m2| m2 / stats.num_rows.max(1) as f64)
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<&pb::VectorStats> for VectorStats {
+    type Error = Error;
+
+    fn try_from(proto: &pb::VectorStats) -> Result<Self> {
+        if proto.mean.len() != proto.variance.len() {
+            return Err(Error::Index {
+                message: format!(
+                    "Vector stats: {} means but {} variances",
+                    proto.mean.len(),
+                    proto.variance.len()
+                ),
+                location: location!(),
+            });
+        }
+        Ok(Self {
+            num_rows: proto.num_rows,
+            mean: proto.mean.clone(),
+            m2: proto
+                .variance
+                .iter()
+                .map(|v| v * proto.num_rows as f64)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_relative_eq;
+    use arrow_array::Float32Array;
+    use lance_arrow::FixedSizeListArrayExt;
+
+    #[test]
+    fn test_merged_batches_match_one_pass() {
+        let values = (0..30).map(|v| (v * v % 7) as f32).collect::<Vec<_>>();
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values.clone()), 3).unwrap();
+
+        let mut stats = VectorStats::new(3);
+        stats.update(&vectors.slice(0, 4)).unwrap();
+        stats.update(&vectors.slice(4, 6)).unwrap();
+        assert_eq!(stats.num_rows(), 10);
+
+        for dim in 0..3 {
+            let column = values.iter().skip(dim).step_by(3).collect::<Vec<_>>();
+            let mean = column.iter().copied().sum::<f32>() / 10.0;
+            let variance = column.iter().map(|v| (*v - mean).powi(2)).sum::<f32>() / 10.0;
+            assert_relative_eq!(stats.mean()[dim], mean, epsilon = 1e-5);
+            assert_relative_eq!(stats.variance()[dim], variance, epsilon = 1e-5);
+        }
+
+        let loaded = VectorStats::try_from(&pb::VectorStats::from(&stats)).unwrap();
+        assert_relative_eq!(
+            loaded.variance().as_slice(),
+            stats.variance().as_slice(),
+            epsilon = 1e-6
+        );
+        assert!(VectorStats::new(2).update(&vectors).is_err());
+        assert!(VectorStats::new(2).merge(&stats).is_err());
+    }
+
+    #[test]
+    fn test_norm_bimodality() {
+        // Norms spread uniformly over 3 doublings, from 1 to 8.
+        let unimodal = (0..1000)
+            .flat_map(|i| [2f32.powf(3.0 * i as f32 / 1000.0), 0.0])
+            .collect::<Vec<_>>();
+        let unimodal =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(unimodal), 2).unwrap();
+        let mut histogram = NormHistogram::default();
+        histogram.update(&unimodal).unwrap();
+        assert_eq!(histogram.num_rows(), 1000);
+        assert_eq!(histogram.bimodality(), None);
+
+        // And 300 vectors of norms around 1000.
+        let scaled = (0..300)
+            .flat_map(|i| [0.0, 1000.0 + i as f32])
+            .collect::<Vec<_>>();
+        let scaled =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(scaled), 2).unwrap();
+        histogram.update(&scaled).unwrap();
+        let bimodality = histogram.bimodality().unwrap();
+        assert!(bimodality.threshold > 8.0 && bimodality.threshold <= 1000.0);
+        assert_relative_eq!(bimodality.low_fraction, 1000.0 / 1300.0, epsilon = 1e-9);
+        assert!(bimodality.separation >= MIN_MODE_SEPARATION);
+    }
+}
@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use arrow_array::{Array, FixedSizeListArray};
+use arrow_array::{cast::AsArray, types::Float32Type, Array, FixedSizeListArray, Float32Array};
 use arrow_schema::{DataType, Field};
+use half::bf16;
 use lance_arrow::{ArrowFloatType, FloatType};
 use lance_core::{encodings::plain::bytes_to_array, Error, Result};
 use lance_linalg::MatrixView;
@@ -119,6 +120,33 @@ impl TryFrom<&pb::Tensor> for FixedSizeListArray {
         let dim = tensor.shape[1] as usize;
         let num_rows = tensor.shape[0] as usize;
 
+        if tensor.data_type == pb::tensor::DataType::Bfloat16 as i32 {
+            // Arrow has no native bfloat16, so upcast to float32.
+            let values = Float32Array::from_iter_values(
+                tensor
+                    .data
+                    .chunks_exact(2)
+                    .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32()),
+            );
+            if values.len() != dim * num_rows {
+                return Err(Error::Index {
+                    message: format!(
+                        "Tensor shape {:?} does not match to data len: {}",
+                        tensor.shape,
+                        values.len()
+                    ),
+                    location: location!(),
+                });
+            }
+            let field = Field::new("item", DataType::Float32, false);
+            return Ok(Self::try_new(
+                Arc::new(field),
+                dim as i32,
+                Arc::new(values),
+                None,
+            )?);
+        }
+
         let data = bytes::Bytes::from(tensor.data.clone());
         let flat_array = bytes_to_array(
             &DataType::from(pb::tensor::DataType::try_from(tensor.data_type).unwrap()),
@@ -148,6 +176,49 @@ impl TryFrom<&pb::Tensor> for FixedSizeListArray {
     }
 }
 
+/// Store the float32 `array` as a tensor of bfloat16s, half the size. The tensor is
+/// read back as float32s, the values of [round_to_bf16].
+pub fn to_bf16_tensor(array: &FixedSizeListArray) -> Result<pb::Tensor> {
+    let values = float32_values(array)?;
+    Ok(pb::Tensor {
+        data_type: pb::tensor::DataType::Bfloat16 as i32,
+        shape: vec![array.len() as u32, array.value_length() as u32],
+        data: values
+            .values()
+            .iter()
+            .flat_map(|v| bf16::from_f32(*v).to_le_bytes())
+            .collect(),
+    })
+}
+
+/// Round the values of the float32 `array` to the nearest bfloat16s, as stored by
+/// [to_bf16_tensor].
+pub fn round_to_bf16(array: &FixedSizeListArray) -> Result<FixedSizeListArray> {
+    let values = float32_values(array)?;
+    let rounded =
+        Float32Array::from_iter_values(values.values().iter().map(|v| bf16::from_f32(*v).to_f32()));
+    let field = Field::new("item", DataType::Float32, false);
+    Ok(FixedSizeListArray::try_new(
+        Arc::new(field),
+        array.value_length(),
+        Arc::new(rounded),
+        None,
+    )?)
+}
+
+fn float32_values(array: &FixedSizeListArray) -> Result<&Float32Array> {
+    array
+        .values()
+        .as_primitive_opt::<Float32Type>()
+        .ok_or_else(|| Error::Index {
+            message: format!(
+                "bfloat16 storage requires float32 values, got: {}",
+                array.value_type()
+            ),
+            location: location!(),
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,4 +288,26 @@ mod tests {
         assert_eq!(tensor.shape, vec![4, 5]);
         assert_eq!(tensor.data.len(), 20 * 8);
     }
+
+    #[test]
+    fn test_bf16_tensor_round_trip() {
+        let values = Float32Array::from_iter_values((0..20).map(|v| v as f32 * 0.1 - 1.0));
+        let fsl = FixedSizeListArray::try_new_from_values(values, 5).unwrap();
+        let tensor = to_bf16_tensor(&fsl).unwrap();
+        assert_eq!(tensor.data_type, pb::tensor::DataType::Bfloat16 as i32);
+        assert_eq!(tensor.shape, vec![4, 5]);
+        assert_eq!(tensor.data.len(), 20 * 2);
+
+        let decoded = FixedSizeListArray::try_from(&tensor).unwrap();
+        assert_eq!(decoded, round_to_bf16(&fsl).unwrap());
+        let original = fsl.values().as_primitive::<Float32Type>();
+        let decoded = decoded.values().as_primitive::<Float32Type>();
+        for (a, b) in original.values().iter().zip(decoded.values().iter()) {
+            assert!((a - b).abs() <= a.abs() / 128.0, "{} vs {}", a, b);
+        }
+
+        let fsl =
+            FixedSizeListArray::try_new_from_values(Float64Array::from(vec![0.0; 20]), 5).unwrap();
+        assert!(to_bf16_tensor(&fsl).is_err());
+    }
 }
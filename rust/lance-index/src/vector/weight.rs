@@ -0,0 +1,137 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-dimension weights of the vector distance.
+//!
+
+use std::sync::Arc;
+
+use arrow::compute::cast;
+use arrow_array::{
+    cast::AsArray, types::Float32Type, Array, FixedSizeListArray, Float32Array, RecordBatch,
+};
+use arrow_schema::DataType;
+use async_trait::async_trait;
+use lance_arrow::{FixedSizeListArrayExt, RecordBatchExt};
+use lance_core::{Error, Result};
+use snafu::{location, Location};
+
+use super::transform::Transformer;
+
+/// Scale each dimension of the vectors in a column by the square root of its weight,
+/// so that the L2 distance between the scaled vectors is the weighted
+/// (diagonal-metric) distance `sum(w_i * (x_i - y_i)^2)` between the original vectors.
+#[derive(Debug, Clone)]
+pub struct WeightTransform {
+    /// Square root of the weight of each dimension.
+    scales: Arc<Vec<f32>>,
+
+    /// Vector Column
+    column: String,
+}
+
+impl WeightTransform {
+    /// Create a transform from non-negative, finite `weights`, one per dimension.
+    pub fn try_new(weights: &[f32], column: &str) -> Result<Self> {
+        if weights.is_empty() || weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return Err(Error::Index {
+                message: format!(
+                    "Vector weights must be non-empty, finite and non-negative, got {:?}",
+                    weights
+                ),
+                location: location!(),
+            });
+        }
+        Ok(Self {
+            scales: Arc::new(weights.iter().map(|w| w.sqrt()).collect()),
+            column: column.to_owned(),
+        })
+    }
+
+    /// Dimension of the vectors.
+    pub fn dimension(&self) -> usize {
+        self.scales.len()
+    }
+
+    /// Scale `vectors`, keeping their value type.
+    pub fn scale(&self, vectors: &FixedSizeListArray) -> Result<FixedSizeListArray> {
+        let dim = vectors.value_length() as usize;
+        if dim != self.dimension() {
+            return Err(Error::Index {
+                message: format!(
+                    "Weight transform: vector dimension {} does not match {} weights",
+                    dim,
+                    self.dimension()
+                ),
+                location: location!(),
+            });
+        }
+        let values = cast(vectors.values(), &DataType::Float32)?;
+        let scaled = values
+            .as_primitive::<Float32Type>()
+            .values()
+            .chunks_exact(dim)
+            .flat_map(|v| v.iter().zip(self.scales.iter()).map(|(x, s)| x * s))
+            .collect::<Vec<_>>();
+        let scaled = cast(&Float32Array::from(scaled), &vectors.value_type())?;
+        Ok(FixedSizeListArray::try_new_from_values(scaled, dim as i32)?)
+    }
+}
+
+#[async_trait]
+impl Transformer for WeightTransform {
+    async fn transform(&self, batch: &RecordBatch) -> Result<RecordBatch> {
+        let arr = batch.column_by_name(&self.column).ok_or(Error::Index {
+            message: format!("Weight transform: column {} not found", self.column),
+            location: location!(),
+        })?;
+        let fsl = arr.as_fixed_size_list_opt().ok_or(Error::Index {
+            message: format!(
+                "Weight transform: column {} is not fixed size list: {}",
+                self.column,
+                arr.data_type()
+            ),
+            location: location!(),
+        })?;
+        let scaled = self.scale(fsl)?;
+        Ok(batch.replace_column_by_name(&self.column, Arc::new(scaled))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lance_linalg::distance::l2;
+
+    #[test]
+    fn test_weighted_l2() {
+        let transform = WeightTransform::try_new(&[4.0, 1.0, 0.0], "vector").unwrap();
+        let vectors = FixedSizeListArray::try_new_from_values(
+            Float32Array::from(vec![1.0, 2.0, 3.0, 0.0, 0.0, 0.0]),
+            3,
+        )
+        .unwrap();
+        let scaled = transform.scale(&vectors).unwrap();
+        let values = scaled.values().as_primitive::<Float32Type>().values();
+        // 4 * 1^2 + 1 * 2^2 + 0 * 3^2
+        assert_eq!(l2(&values[..3], &values[3..]), 8.0);
+
+        assert!(WeightTransform::try_new(&[1.0, -1.0], "vector").is_err());
+        assert!(WeightTransform::try_new(&[1.0, 1.0], "vector")
+            .unwrap()
+            .scale(&vectors)
+            .is_err());
+    }
+}
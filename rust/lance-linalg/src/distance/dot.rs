@@ -36,11 +36,12 @@ use crate::Result;
 
 /// Default implementation of dot product.
 ///
+/// This is pub for test/benchmark only. use [dot] instead.
 // The following code has been tuned for auto-vectorization.
 // Please make sure run `cargo bench --bench dot` with and without AVX-512 before any change.
 // Tested `target-features`: avx512f,avx512vl,f16c
 #[inline]
-fn dot_scalar<T: Real + Sum + AddAssign + AsPrimitive<f32>, const LANES: usize>(
+pub fn dot_scalar<T: Real + Sum + AddAssign + AsPrimitive<f32>, const LANES: usize>(
     from: &[T],
     to: &[T],
 ) -> f32 {
@@ -30,6 +30,25 @@ pub mod i32;
 
 use num_traits::{Float, Num};
 
+/// Whether the running CPU supports the instructions used by the SIMD types in
+/// this module, i.e., [f32::f32x8] and [f32::f32x16].
+///
+/// Kernels built on top of them should fall back to scalar code if not.
+pub fn is_simd_supported() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("neon")
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
 /// Lance SIMD lib
 ///
 pub trait SIMD<T: Num + Copy, const N: usize>:
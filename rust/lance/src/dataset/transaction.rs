@@ -207,6 +207,29 @@ impl Operation {
         other_ids.any(|id| self_ids.contains(&id))
     }
 
+    /// Check whether another operation removes the same index as this one.
+    fn removes_same_indices(&self, other: &Self) -> bool {
+        let (
+            Self::CreateIndex {
+                removed_indices, ..
+            },
+            Self::CreateIndex {
+                removed_indices: other_removed_indices,
+                ..
+            },
+        ) = (self, other)
+        else {
+            return false;
+        };
+        let uuids = removed_indices
+            .iter()
+            .map(|index| index.uuid)
+            .collect::<HashSet<_>>();
+        other_removed_indices
+            .iter()
+            .any(|index| uuids.contains(&index.uuid))
+    }
+
     pub fn name(&self) -> &str {
         match self {
             Self::Append { .. } => "Append",
@@ -277,8 +300,13 @@ impl Transaction {
             ),
             Operation::CreateIndex { .. } => match &other.operation {
                 Operation::Append { .. } => false,
-                // Indices are identified by UUIDs, so they shouldn't conflict.
-                Operation::CreateIndex { .. } => false,
+                // Indices are identified by UUIDs, so they shouldn't conflict, unless
+                // both replace the same existing index. New indices of the same name
+                // created concurrently are committed in turn, the last one replacing
+                // the others.
+                Operation::CreateIndex { .. } => {
+                    self.operation.removes_same_indices(&other.operation)
+                }
                 // Although some of the rows we indexed may have been deleted / moved,
                 // row ids are still valid, so we allow this optimistically.
                 Operation::Delete { .. } | Operation::Update { .. } => false,
@@ -917,6 +945,13 @@ mod tests {
             dataset_version: 1,
             fragment_bitmap: None,
         };
+        let index1 = Index {
+            uuid: uuid::Uuid::new_v4(),
+            name: "test".to_string(),
+            fields: vec![0],
+            dataset_version: 1,
+            fragment_bitmap: None,
+        };
         let fragment0 = Fragment::new(0);
         let fragment1 = Fragment::new(1);
         let fragment2 = Fragment::new(2);
@@ -1002,7 +1037,17 @@ mod tests {
                     new_indices: vec![index0.clone()],
                     removed_indices: vec![index0.clone()],
                 },
-                // Will only conflict with operations that modify row ids.
+                // Will only conflict with operations that modify row ids, and with the
+                // replacement of the same index.
+                [false, true, false, false, true, true, false, false],
+            ),
+            (
+                Operation::CreateIndex {
+                    new_indices: vec![index1.clone()],
+                    removed_indices: vec![],
+                },
+                // Will only conflict with operations that modify row ids, even if the
+                // index has the name of an index created concurrently.
                 [false, false, false, false, true, true, false, false],
             ),
             (
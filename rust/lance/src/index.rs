@@ -144,6 +144,10 @@ pub trait DatasetIndexExt {
     ///            if not provided, it will auto-generate one.
     ///  - `params`: index parameters.
     ///  - `replace`: replace the existing index if it exists.
+    ///
+    /// Indices created concurrently are all committed, the last one replacing the
+    /// others of the same name. Concurrent replacements of the same existing index are
+    /// not: one of them is committed, the others fail with [Error::CommitConflict].
     async fn create_index(
         &mut self,
         columns: &[&str],
@@ -179,6 +183,35 @@ async fn open_index_proto(dataset: &Dataset, reader: &dyn Reader) -> Result<pb::
     Ok(proto)
 }
 
+/// Table of contents of an index file, read from its metadata without opening the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexToc {
+    /// Index name.
+    pub name: String,
+
+    /// The columns the index is built on.
+    pub columns: Vec<String>,
+
+    /// The version of the dataset the index was built from.
+    pub dataset_version: u64,
+
+    /// Key-value metadata stamped onto the index at build time.
+    pub custom_metadata: HashMap<String, String>,
+}
+
+/// Read the [`IndexToc`] of the index `uuid` of `dataset`.
+pub async fn read_index_toc(dataset: &Dataset, uuid: &str) -> Result<IndexToc> {
+    let index_file = dataset.indices_dir().child(uuid).child(INDEX_FILE_NAME);
+    let reader = dataset.object_store().open(&index_file).await?;
+    let proto = open_index_proto(dataset, reader.as_ref()).await?;
+    Ok(IndexToc {
+        name: proto.name,
+        columns: proto.columns,
+        dataset_version: proto.dataset_version,
+        custom_metadata: proto.custom_metadata,
+    })
+}
+
 #[async_trait]
 impl DatasetIndexExt for Dataset {
     #[instrument(skip_all)]
@@ -207,7 +240,8 @@ impl DatasetIndexExt for Dataset {
         // Load indices from the disk.
         let indices = self.load_indices().await?;
         let index_name = name.unwrap_or(format!("{column}_idx"));
-        if let Some(idx) = indices.iter().find(|i| i.name == index_name) {
+        let replaced_index = indices.iter().find(|i| i.name == index_name);
+        if let Some(idx) = replaced_index {
             if idx.fields == [field.id] && !replace {
                 return Err(Error::Index {
                     message: format!(
@@ -259,7 +293,9 @@ impl DatasetIndexExt for Dataset {
             self.manifest.version,
             Operation::CreateIndex {
                 new_indices: vec![new_idx],
-                removed_indices: vec![],
+                // The replaced index is removed, so that the concurrent replacements of
+                // an index conflict instead of replacing each other.
+                removed_indices: replaced_index.into_iter().cloned().collect(),
             },
             None,
         );
@@ -116,11 +116,15 @@ pub async fn append_index(
 mod tests {
     use super::*;
 
+    use std::collections::HashSet;
+
     use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt64Type;
     use arrow_array::{FixedSizeListArray, RecordBatch, RecordBatchIterator};
     use arrow_schema::{DataType, Field, Schema};
     use futures::{stream, StreamExt, TryStreamExt};
     use lance_arrow::FixedSizeListArrayExt;
+    use lance_core::ROW_ID;
     use lance_index::{
         vector::{ivf::IvfBuildParams, pq::PQBuildParams},
         IndexType,
@@ -248,4 +252,70 @@ mod tests {
         assert_eq!(row_in_index, 2000);
         assert_eq!(dataset.index_cache_entry_count(), 6)
     }
+
+    #[tokio::test]
+    async fn test_append_multi_assigned_index() {
+        const DIM: usize = 16;
+
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "vector",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                DIM as i32,
+            ),
+            true,
+        )]));
+        let batch = || {
+            let vectors = generate_random_array(1000 * DIM);
+            let array =
+                Arc::new(FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap());
+            RecordBatch::try_new(schema.clone(), vec![array]).unwrap()
+        };
+        let batches = RecordBatchIterator::new(vec![Ok(batch())], schema.clone());
+        let mut dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.multi_assign = 2;
+        let pq_params = PQBuildParams {
+            num_sub_vectors: 2,
+            ..Default::default()
+        };
+        let params = VectorIndexParams::with_ivf_pq_params(MetricType::L2, ivf_params, pq_params);
+        dataset
+            .create_index(&["vector"], IndexType::Vector, None, &params, true)
+            .await
+            .unwrap();
+
+        let new_batch = batch();
+        let batches = RecordBatchIterator::new(vec![Ok(new_batch.clone())], schema.clone());
+        dataset.append(batches, None).await.unwrap();
+        dataset.optimize_indices().await.unwrap();
+
+        // Each appended row is in 2 of the probed partitions, and returned once.
+        let q = new_batch["vector"].as_fixed_size_list().value(5);
+        let mut scanner = dataset.scan();
+        scanner
+            .nearest("vector", q.as_primitive(), 10)
+            .unwrap()
+            .nprobs(4)
+            .with_row_id();
+        let results = scanner
+            .try_into_stream()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let row_ids = results[0][ROW_ID]
+            .as_primitive::<UInt64Type>()
+            .values()
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        assert_eq!(results[0].num_rows(), 10);
+        assert_eq!(row_ids.len(), 10);
+    }
 }
@@ -37,7 +37,7 @@ use tracing::instrument;
 use uuid::Uuid;
 
 use self::{
-    ivf::{build_ivf_pq_index, remap_index_file, IVFIndex},
+    ivf::{build_ivf_pq_index, remap_index_file, IVFIndex, COLD_INDEX_FILE_NAME},
     pq::PQIndex,
 };
 
@@ -60,7 +60,7 @@ pub use traits::*;
 /// Parameters of each index stage.
 #[derive(Debug, Clone)]
 pub enum StageParams {
-    Ivf(IvfBuildParams),
+    Ivf(Box<IvfBuildParams>),
 
     PQ(PQBuildParams),
 
@@ -94,7 +94,9 @@ impl VectorIndexParams {
         max_iterations: usize,
     ) -> Self {
         let mut stages: Vec<StageParams> = vec![];
-        stages.push(StageParams::Ivf(IvfBuildParams::new(num_partitions)));
+        stages.push(StageParams::Ivf(Box::new(IvfBuildParams::new(
+            num_partitions,
+        ))));
 
         let pq_params = PQBuildParams {
             num_bits: num_bits as usize,
@@ -118,7 +120,7 @@ impl VectorIndexParams {
         ivf: IvfBuildParams,
         pq: PQBuildParams,
     ) -> Self {
-        let stages = vec![StageParams::Ivf(ivf), StageParams::PQ(pq)];
+        let stages = vec![StageParams::Ivf(Box::new(ivf)), StageParams::PQ(pq)];
         Self {
             stages,
             metric_type,
@@ -191,6 +193,12 @@ pub(crate) async fn build_vector_index(
                 location: location!(),
             });
         };
+        if !ivf_params.write {
+            return Err(Error::Index {
+                message: "Build Vector Index: an index built without writing its partitions can not be committed".to_string(),
+                location: location!(),
+            });
+        }
         build_ivf_pq_index(
             dataset,
             column,
@@ -200,7 +208,7 @@ pub(crate) async fn build_vector_index(
             ivf_params,
             pq_params,
         )
-        .await?
+        .await?;
     } else if is_diskann(stages) {
         // This is DiskANN index.
         use self::diskann::build_diskann_index;
@@ -261,6 +269,37 @@ pub(crate) async fn remap_vector_index(
     Ok(())
 }
 
+/// Check that a query vector of `query_dim` with `metric_type` can be searched with the
+/// vector index described by `index_meta`, before scanning the index.
+pub fn check_query_compatibility(
+    index_meta: &lance_index::pb::VectorIndex,
+    query_dim: usize,
+    metric_type: MetricType,
+) -> Result<()> {
+    if index_meta.dimension as usize != query_dim {
+        return Err(Error::InvalidInput {
+            source: format!(
+                "query vector dimension {} does not match index dimension {}",
+                query_dim, index_meta.dimension
+            )
+            .into(),
+            location: location!(),
+        });
+    }
+    let index_metric: MetricType = pb::VectorMetricType::try_from(index_meta.metric_type)?.into();
+    if index_metric != metric_type {
+        return Err(Error::InvalidInput {
+            source: format!(
+                "query metric type {} does not match index metric type {}",
+                metric_type, index_metric
+            )
+            .into(),
+            location: location!(),
+        });
+    }
+    Ok(())
+}
+
 /// Open the Vector index on dataset, specified by the `uuid`.
 #[instrument(level = "debug", skip(dataset, vec_idx, index_dir, reader))]
 pub(crate) async fn open_vector_index(
@@ -313,14 +352,21 @@ pub(crate) async fn open_vector_index(
                     });
                 }
                 let ivf = Ivf::try_from(ivf_pb)?;
-                last_stage = Some(Arc::new(IVFIndex::try_new(
+                let has_cold_partitions = ivf.has_cold_partitions();
+                let mut ivf_index = IVFIndex::try_new(
                     dataset.session.clone(),
                     uuid,
                     ivf,
                     reader.clone(),
                     last_stage.unwrap(),
                     metric_type,
-                )?));
+                )?;
+                if has_cold_partitions {
+                    let cold_path = index_dir.child(COLD_INDEX_FILE_NAME);
+                    let cold_reader = dataset.object_store.open(&cold_path).await?;
+                    ivf_index = ivf_index.with_cold_reader(cold_reader.into());
+                }
+                last_stage = Some(Arc::new(ivf_index));
             }
             Some(Stage::Pq(pq_proto)) => {
                 if last_stage.is_some() {
@@ -361,3 +407,36 @@ pub(crate) async fn open_vector_index(
     dataset.session.index_cache.insert_vector(uuid, idx.clone());
     Ok(idx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_query_compatibility() {
+        let index_meta = lance_index::pb::VectorIndex {
+            spec_version: 1,
+            dimension: 32,
+            stages: vec![],
+            metric_type: pb::VectorMetricType::Cosine.into(),
+        };
+
+        check_query_compatibility(&index_meta, 32, MetricType::Cosine).unwrap();
+
+        let err = check_query_compatibility(&index_meta, 16, MetricType::Cosine).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("query vector dimension 16 does not match index dimension 32"),
+            "{}",
+            err
+        );
+
+        let err = check_query_compatibility(&index_meta, 32, MetricType::L2).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("query metric type l2 does not match index metric type cosine"),
+            "{}",
+            err
+        );
+    }
+}
@@ -378,6 +378,7 @@ async fn write_index_file(
                 MetricType::Dot => pb::VectorMetricType::Dot.into(),
             },
         })),
+        custom_metadata: Default::default(),
     };
 
     let pos = writer.write_protobuf(&metadata).await?;
@@ -16,62 +16,85 @@
 
 use std::{
     any::Any,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::RangeFull,
     sync::{Arc, Weak},
 };
 
+use arrow::compute::cast;
 use arrow_arith::numeric::sub;
 use arrow_array::{
     cast::{as_primitive_array, as_struct_array, AsArray},
-    types::{Float16Type, Float32Type, Float64Type},
-    Array, FixedSizeListArray, Float32Array, RecordBatch, StructArray, UInt32Array, UInt64Array,
+    types::{Float16Type, Float32Type, Float64Type, UInt64Type, UInt8Type},
+    Array, ArrayRef, BooleanArray, FixedSizeListArray, Float32Array, RecordBatch, StructArray,
+    UInt32Array, UInt64Array,
 };
 use arrow_ord::sort::sort_to_indices;
 use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema};
 use arrow_select::{concat::concat_batches, take::take};
 use async_trait::async_trait;
+use datafusion::execution::context::SessionContext;
+use datafusion::logical_expr::Expr;
 use futures::{
     stream::{self, StreamExt},
-    TryStreamExt,
+    Future, Stream, TryStreamExt,
 };
 use lance_arrow::*;
 use lance_core::io::{
-    local::to_local_path, FileReader, ObjectWriter, Reader, RecordBatchStream, WriteExt, Writer,
+    local::to_local_path, read_fixed_stride_array, FileReader, ObjectWriter, Reader,
+    RecordBatchStream, WriteExt, Writer,
 };
 use lance_core::{
-    datatypes::{Field, Schema},
+    datatypes::{Field, LogicalType, Schema},
     encodings::plain::PlainEncoder,
-    format::Index as IndexMetadata,
-    Error, Result,
+    format::{Index as IndexMetadata, RowAddress},
+    Error, Result, ROW_ID,
 };
 use lance_index::{
     vector::{
-        ivf::IvfBuildParams,
-        pq::{PQBuildParams, ProductQuantizer, ProductQuantizerImpl},
-        Query, DIST_COL,
+        ivf::{
+            tree::IvfTree, BuildControl, BytesPerSec, IvfBuildParams, PackageFormat, Tier,
+            TimeWindow,
+        },
+        pca::PcaMatrix,
+        pq::{
+            lookup::TransposedCodebook, pq_decode, pq_decode_all, CodeLayout, CodeStorageOrder,
+            PQBuildParams, ProductQuantizer, ProductQuantizerImpl,
+        },
+        stats::VectorStats,
+        utils::{round_to_bf16, to_bf16_tensor},
+        weight::WeightTransform,
+        Query, DIST_COL, PQ_CODE_COLUMN,
     },
     Index, IndexType,
 };
-use lance_linalg::distance::{Cosine, Dot, MetricType, L2};
-use log::{debug, info};
-use rand::{rngs::SmallRng, SeedableRng};
-use roaring::RoaringBitmap;
+use lance_linalg::distance::{l2_distance, Cosine, Dot, MetricType, L2};
+use log::{debug, info, warn};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use roaring::{RoaringBitmap, RoaringTreemap};
 use serde::Serialize;
 use snafu::{location, Location};
+use tokio::io::AsyncWriteExt;
 use tracing::{instrument, span, Level};
 use uuid::Uuid;
 
 #[cfg(feature = "opq")]
 use super::opq::train_opq;
-use super::{pq::PQIndex, utils::maybe_sample_training_data, VectorIndex};
+use super::{
+    pq::PQIndex,
+    utils::{density_weighted_sample, maybe_sample_training_data, sample_rows, training_pool_size},
+    VectorIndex,
+};
 use crate::{
     dataset::{Dataset, DATA_DIR},
     index::{
-        pb,
+        open_index_proto, pb,
         prefilter::PreFilter,
         vector::{
-            ivf::{builder::shuffle_dataset_v2, io::write_index_partitions},
+            ivf::{
+                builder::{chain_partitions, shuffle_dataset_v2},
+                io::{write_index_partitions, WritePartitionsOptions},
+            },
             Transformer,
         },
         INDEX_FILE_NAME,
@@ -80,9 +103,25 @@ use crate::{
 };
 
 mod builder;
+mod env;
 mod io;
+mod package;
+mod storage;
+
+pub use builder::{scan_index_columns, BuildReport, RowMask, SelfRecall};
+pub use env::BuildEnvConfig;
+pub use io::IndexTxn;
+pub use storage::{estimate_index_size, fit_num_sub_vectors, storage_breakdown, StorageBreakdown};
+
+/// Name of the file next to the index file holding the partitions of [`Tier::Cold`].
+pub const COLD_INDEX_FILE_NAME: &str = "cold.idx";
+
+/// Schema metadata key of the partition centroid attached to the batches of
+/// [`IVFIndex::read_partitions_with_centroids`], as a JSON array of f32.
+pub const CENTROID_METADATA_KEY: &str = "lance:ivf:centroid";
 
 /// IVF Index.
+#[derive(Clone)]
 pub struct IVFIndex {
     uuid: String,
 
@@ -91,6 +130,9 @@ pub struct IVFIndex {
 
     reader: Arc<dyn Reader>,
 
+    /// Reader of the cold tier file, holding the partitions of [`Tier::Cold`].
+    cold_reader: Option<Arc<dyn Reader>>,
+
     /// Index in each partition.
     sub_index: Arc<dyn VectorIndex>,
 
@@ -123,11 +165,43 @@ impl IVFIndex {
             session: Arc::downgrade(&session),
             ivf,
             reader,
+            cold_reader: None,
             sub_index,
             metric_type,
         })
     }
 
+    /// Read the cold partitions of the index from `cold_reader`.
+    pub(crate) fn with_cold_reader(mut self, cold_reader: Arc<dyn Reader>) -> Self {
+        self.cold_reader = Some(cold_reader);
+        self
+    }
+
+    /// The reader of the file holding one partition.
+    fn partition_reader(&self, partition_id: usize) -> Result<&dyn Reader> {
+        match self.ivf.tier(partition_id) {
+            Tier::Hot => Ok(self.reader.as_ref()),
+            Tier::Cold => self.cold_reader.as_deref().ok_or_else(|| Error::Index {
+                message: format!(
+                    "Partition {} is in the cold tier, but no cold tier file is open",
+                    partition_id
+                ),
+                location: location!(),
+            }),
+        }
+    }
+
+    /// The raw partitions can only be read from a single file.
+    fn check_single_tier(&self) -> Result<()> {
+        if self.ivf.has_cold_partitions() {
+            return Err(Error::NotSupported {
+                source: "Reading raw partitions of an index with cold partitions".into(),
+                location: location!(),
+            });
+        }
+        Ok(())
+    }
+
     /// Load one partition of the IVF sub-index.
     ///
     /// Parameters
@@ -149,10 +223,19 @@ impl IVFIndex {
         } else {
             let offset = self.ivf.offsets[partition_id];
             let length = self.ivf.lengths[partition_id] as usize;
-            let idx = self
-                .sub_index
-                .load(self.reader.as_ref(), offset, length)
-                .await?;
+            let reader = self.partition_reader(partition_id)?;
+            let idx = if let Some(codebook) = self.ivf.pq_codebooks.get(partition_id) {
+                self.partition_sub_index(codebook)?
+                    .load(reader, offset, length)
+                    .await?
+            } else {
+                self.sub_index.load(reader, offset, length).await?
+            };
+            let idx = if self.ivf.code_storage_order == CodeStorageOrder::Separate {
+                untranspose_page(idx)?
+            } else {
+                idx
+            };
             let idx: Arc<dyn VectorIndex> = idx.into();
             if write_cache {
                 session.index_cache.insert_vector(&cache_key, idx.clone());
@@ -162,6 +245,346 @@ impl IVFIndex {
         Ok(part_index)
     }
 
+    /// The PQ sub-index of a partition with its own codebook.
+    fn partition_sub_index(&self, codebook: &FixedSizeListArray) -> Result<PQIndex> {
+        let pq_index = self
+            .sub_index
+            .as_any()
+            .downcast_ref::<PQIndex>()
+            .ok_or_else(|| Error::NotSupported {
+                source: "Per-partition codebooks are only supported by a PQ sub-index".into(),
+                location: location!(),
+            })?;
+        let mut proto = pb::Pq::try_from(pq_index.pq.as_ref())?;
+        proto.codebook_tensor = Some(codebook.try_into()?);
+        let pq = lance_index::vector::pq::builder::from_proto(&proto, self.metric_type)?;
+        Ok(PQIndex::new(pq, self.metric_type))
+    }
+
+    /// PCA projection of the reduced vectors stored in each partition, if any.
+    pub fn pca(&self) -> Option<&PcaMatrix> {
+        self.ivf.pca.as_ref()
+    }
+
+    /// Weight of each dimension in the distance the vectors were assigned with, if
+    /// weighted. Query vectors are compared in the same space once scaled by the
+    /// square roots of the weights.
+    pub fn weights(&self) -> Option<&[f32]> {
+        self.ivf.weights.as_deref()
+    }
+
+    /// Dimension-major copy of the PQ codebook, to build the distance tables of queries.
+    ///
+    /// Returns `None` if it was not stored at build time.
+    pub fn transposed_codebook(&self) -> Option<&TransposedCodebook> {
+        self.ivf.transposed_codebook.as_ref()
+    }
+
+    /// Per-dimension statistics of the indexed vectors, if computed at build time,
+    /// see [`IvfBuildParams::compute_global_stats`].
+    pub fn global_stats(&self) -> Option<&VectorStats> {
+        self.ivf.global_stats.as_ref()
+    }
+
+    /// Reconstruct the approximate vector of a PQ `code` stored in a partition, from
+    /// the PQ codebook of the partition and its centroid, see [`pq_decode`].
+    ///
+    /// The vectors of an index built with weights are reconstructed scaled, see
+    /// [`Self::weights`].
+    pub fn decode_pq_code(&self, partition_id: usize, code: &[u8]) -> Result<Vec<f32>> {
+        let (pq, centroid) = self.partition_decoder(partition_id)?;
+        pq_decode(
+            code,
+            pq.as_ref(),
+            centroid.as_primitive::<Float32Type>().values(),
+        )
+    }
+
+    /// Reconstruct the approximate vectors of the PQ `codes` of many rows of a partition,
+    /// like [`Self::decode_pq_code`].
+    pub fn decode_pq_codes(
+        &self,
+        partition_id: usize,
+        codes: &FixedSizeListArray,
+    ) -> Result<FixedSizeListArray> {
+        let (pq, centroid) = self.partition_decoder(partition_id)?;
+        let codes = codes.values().as_primitive::<UInt8Type>();
+        let vectors = pq_decode_all(
+            codes.values(),
+            pq.as_ref(),
+            centroid.as_primitive::<Float32Type>().values(),
+        )?;
+        Ok(FixedSizeListArray::try_new_from_values(
+            Float32Array::from(vectors),
+            self.ivf.dimension() as i32,
+        )?)
+    }
+
+    /// The product quantizer and the float32 centroid to decode the PQ codes of a
+    /// partition.
+    fn partition_decoder(
+        &self,
+        partition_id: usize,
+    ) -> Result<(Arc<dyn ProductQuantizer>, ArrayRef)> {
+        if partition_id >= self.ivf.num_partitions() {
+            return Err(Error::Index {
+                message: format!(
+                    "Partition {} does not exist in an index of {} partitions",
+                    partition_id,
+                    self.ivf.num_partitions()
+                ),
+                location: location!(),
+            });
+        }
+        let pq = match self.ivf.pq_codebooks.get(partition_id) {
+            Some(codebook) => self.partition_sub_index(codebook)?.pq,
+            None => self
+                .sub_index
+                .as_any()
+                .downcast_ref::<PQIndex>()
+                .ok_or_else(|| Error::NotSupported {
+                    source: "Decoding PQ codes requires a PQ sub-index".into(),
+                    location: location!(),
+                })?
+                .pq
+                .clone(),
+        };
+        let centroid = self.ivf.weighted_centroids()?.value(partition_id);
+        Ok((pq, cast(&centroid, &DataType::Float32)?))
+    }
+
+    /// Whether the build stopped at its time budget before reading all the rows, see
+    /// [`IvfBuildParams::max_duration`].
+    pub fn is_partial(&self) -> bool {
+        self.ivf.partial
+    }
+
+    /// Time window of the indexed rows, if the index was built over a window.
+    pub fn time_window(&self) -> Option<&TimeWindow> {
+        self.ivf.time_window.as_ref()
+    }
+
+    /// ROW_ID of the vector closest to the centroid of a partition.
+    ///
+    /// Returns `None` if the medoids were not computed or the partition is empty.
+    pub fn medoid(&self, partition_id: usize) -> Option<u64> {
+        self.ivf
+            .medoids
+            .as_ref()
+            .and_then(|medoids| medoids.get(partition_id).copied())
+            .filter(|row_id| *row_id != RowAddress::TOMBSTONE_ROW)
+    }
+
+    /// Offset of `section` of a partition in the index file.
+    fn section_offset(&self, partition_id: usize, section: io::Section) -> Result<usize> {
+        let pq_index = self
+            .sub_index
+            .as_any()
+            .downcast_ref::<PQIndex>()
+            .ok_or_else(|| Error::NotSupported {
+                source: "Extra partition data is only stored with a PQ sub-index".into(),
+                location: location!(),
+            })?;
+        self.ivf
+            .section_offset(partition_id, section, pq_index.pq.num_sub_vectors())
+    }
+
+    /// Load the valid column of one partition, in the same order as its row ids.
+    ///
+    /// Returns `None` if the index was built without a valid column.
+    pub async fn load_valid(&self, partition_id: usize) -> Result<Option<BooleanArray>> {
+        if !self.ivf.has_valid {
+            return Ok(None);
+        }
+        let length = self.ivf.lengths[partition_id] as usize;
+        let offset = self.section_offset(partition_id, io::Section::Valid)?;
+        let reader = self.partition_reader(partition_id)?;
+        let valid = read_fixed_stride_array(reader, &DataType::Boolean, offset, length, ..).await?;
+        Ok(Some(valid.as_boolean().clone()))
+    }
+
+    /// Load the assignment margin of each row of one partition, in the same order as
+    /// its row ids. See [`lance_index::vector::ASSIGNMENT_MARGIN_COLUMN`].
+    ///
+    /// Returns `None` if the index was built without assignment margins.
+    pub async fn load_assignment_margins(
+        &self,
+        partition_id: usize,
+    ) -> Result<Option<Float32Array>> {
+        if !self.ivf.has_assignment_margin {
+            return Ok(None);
+        }
+        let length = self.ivf.lengths[partition_id] as usize;
+        let offset = self.section_offset(partition_id, io::Section::AssignmentMargins)?;
+        let reader = self.partition_reader(partition_id)?;
+        let margins =
+            read_fixed_stride_array(reader, &DataType::Float32, offset, length, ..).await?;
+        Ok(Some(margins.as_primitive::<Float32Type>().clone()))
+    }
+
+    /// Load the extra column `name` of one partition, in the same order as its row ids,
+    /// see [`IvfBuildParams::post_shuffle_transform`].
+    ///
+    /// Returns `None` if the index does not store the column.
+    pub async fn load_extra_column(
+        &self,
+        partition_id: usize,
+        name: &str,
+    ) -> Result<Option<ArrayRef>> {
+        let Some(index) = self
+            .ivf
+            .extra_columns
+            .iter()
+            .position(|field| field.name() == name)
+        else {
+            return Ok(None);
+        };
+        let length = self.ivf.lengths[partition_id] as usize;
+        let offset = self.section_offset(partition_id, io::Section::ExtraColumn(index))?;
+        let reader = self.partition_reader(partition_id)?;
+        let data_type = self.ivf.extra_columns[index].data_type();
+        Ok(Some(
+            read_fixed_stride_array(reader, data_type, offset, length, ..).await?,
+        ))
+    }
+
+    /// Load the PCA-reduced vectors of one partition, in the same order as its row ids.
+    ///
+    /// Returns `None` if the index was built without a PCA projection.
+    pub async fn load_pca_vectors(
+        &self,
+        partition_id: usize,
+    ) -> Result<Option<FixedSizeListArray>> {
+        let Some(pca) = self.ivf.pca.as_ref() else {
+            return Ok(None);
+        };
+        let length = self.ivf.lengths[partition_id] as usize;
+        let offset = self.section_offset(partition_id, io::Section::PcaVectors)?;
+        let values = read_fixed_stride_array(
+            self.partition_reader(partition_id)?,
+            &DataType::Float32,
+            offset,
+            length * pca.num_components(),
+            ..,
+        )
+        .await?;
+        Ok(Some(FixedSizeListArray::try_new_from_values(
+            values.as_primitive::<Float32Type>().clone(),
+            pca.num_components() as i32,
+        )?))
+    }
+
+    /// Stream the PQ codes and row ids of only the given partitions, e.g. the partitions
+    /// probed by a query, without reading the rest of the index file.
+    ///
+    /// Each partition is yielded as one batch of the partition id, the row-major PQ codes
+    /// and the row ids, in the order of `part_ids`.
+    pub fn read_partitions<'a>(
+        &'a self,
+        part_ids: &'a [u32],
+    ) -> Result<impl Stream<Item = Result<RecordBatch>> + 'a> {
+        self.check_single_tier()?;
+        Ok(io::read_partitions(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+            part_ids,
+        ))
+    }
+
+    /// Stream the partitions like [`Self::read_partitions`], with the stored centroid of
+    /// each partition in the schema metadata of its batch under
+    /// [`CENTROID_METADATA_KEY`], to reconstruct the vectors without looking up the
+    /// centroids separately.
+    pub fn read_partitions_with_centroids<'a>(
+        &'a self,
+        part_ids: &'a [u32],
+    ) -> Result<impl Stream<Item = Result<RecordBatch>> + 'a> {
+        self.check_single_tier()?;
+        Ok(io::read_partitions_with_centroids(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+            part_ids,
+        ))
+    }
+
+    /// Stream the partitions like [`Self::read_partitions`], with only the rows whose
+    /// [`IvfBuildParams::subgroup_column`] has `value`, or is null for `None`. Only the
+    /// byte ranges of those rows are read. Reading an index without subgroups fails.
+    pub fn read_partitions_in_subgroup<'a>(
+        &'a self,
+        part_ids: &'a [u32],
+        value: Option<&'a str>,
+    ) -> Result<impl Stream<Item = Result<RecordBatch>> + 'a> {
+        self.check_single_tier()?;
+        Ok(io::read_partitions_in_subgroup(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+            part_ids,
+            value,
+        ))
+    }
+
+    /// Stream the partitions like [`Self::read_partitions`], decoding the PQ codes from
+    /// `layout`, e.g. to compare the layouts of an index built with
+    /// [`IvfBuildParams::dual_code_layout`]. Reading [`CodeLayout::BitPacked`] from an
+    /// index without the packed codes fails.
+    pub fn read_partitions_with_layout<'a>(
+        &'a self,
+        part_ids: &'a [u32],
+        layout: CodeLayout,
+    ) -> Result<impl Stream<Item = Result<RecordBatch>> + 'a> {
+        self.check_single_tier()?;
+        Ok(io::read_partitions_with_layout(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+            part_ids,
+            layout,
+        ))
+    }
+
+    /// Stream the partitions of `ordered_part_ids` like [`Self::read_partitions`], in
+    /// exactly the given order, e.g. the probe order of a query, reading a few
+    /// partitions ahead so the first ones can be used while the later ones load.
+    pub fn read_partitions_ordered<'a>(
+        &'a self,
+        ordered_part_ids: &'a [u32],
+    ) -> Result<impl Stream<Item = Result<RecordBatch>> + 'a> {
+        self.check_single_tier()?;
+        Ok(io::read_partitions_ordered(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+            ordered_part_ids,
+        ))
+    }
+
+    /// Stream the `(PART_ID, PQ code, ROW_ID)` rows of all the partitions, one batch per
+    /// non-empty partition in the order they are stored in the index file, e.g. to
+    /// export the whole index for offline scoring.
+    pub fn scan_all_codes(&self) -> Result<impl Stream<Item = Result<RecordBatch>> + '_> {
+        self.check_single_tier()?;
+        Ok(io::scan_all_codes(
+            self.reader.as_ref(),
+            &self.ivf,
+            self.pq_sub_index()?.pq.num_sub_vectors(),
+        ))
+    }
+
+    /// The PQ sub-index, to read the raw partitions.
+    fn pq_sub_index(&self) -> Result<&PQIndex> {
+        self.sub_index
+            .as_any()
+            .downcast_ref::<PQIndex>()
+            .ok_or_else(|| Error::NotSupported {
+                source: "Reading raw partitions is only supported by a PQ sub-index".into(),
+                location: location!(),
+            })
+    }
+
     async fn search_in_partition(
         &self,
         partition_id: usize,
@@ -171,7 +594,13 @@ impl IVFIndex {
         let part_index = self.load_partition(partition_id, true).await?;
 
         let query = if self.sub_index.use_residual() {
-            let partition_centroids = self.ivf.centroids.value(partition_id);
+            // Same as the centroid of `weighted_centroids`, without scaling all of them.
+            let partition_centroids = match self.ivf.weights.as_ref() {
+                Some(weights) => WeightTransform::try_new(weights, "")?
+                    .scale(&self.ivf.centroids.slice(partition_id, 1))?
+                    .value(0),
+                None => self.ivf.centroids.value(partition_id),
+            };
             let residual_key = sub(&query.key, &partition_centroids)?;
             let mut part_query = query.clone();
             part_query.key = residual_key;
@@ -206,28 +635,80 @@ impl IVFIndex {
                 message: "Only support append to IVF_PQ".to_string(),
                 location: location!(),
             })?;
+        if !self.ivf.pq_codebooks.is_empty() {
+            return Err(Error::NotSupported {
+                source: "Append to IVF_PQ with per-partition PQ codebooks".into(),
+                location: location!(),
+            });
+        }
+        // Only the PQ codes and the ROW_IDs of the existing partitions are copied.
+        let unsupported_sections = [
+            (self.ivf.pca.is_some(), "PCA reduced vectors"),
+            (self.ivf.has_valid, "a valid column"),
+            (self.ivf.has_assignment_margin, "assignment margins"),
+            (self.ivf.medoids.is_some(), "medoids"),
+            (self.ivf.packed_codes.is_some(), "packed PQ codes"),
+            (!self.ivf.extra_columns.is_empty(), "extra columns"),
+            (self.ivf.subgroups.is_some(), "subgroups"),
+            (!self.ivf.tiers.is_empty(), "cold partitions"),
+        ];
+        if let Some((_, section)) = unsupported_sections
+            .iter()
+            .find(|(unsupported, _)| *unsupported)
+        {
+            return Err(Error::NotSupported {
+                source: format!("Append to IVF_PQ with {}", section).into(),
+                location: location!(),
+            });
+        }
 
         // TODO: merge two IVF implementations.
-        let ivf = lance_index::vector::ivf::new_ivf_with_pq(
-            self.ivf.centroids.values(),
+        let ivf = lance_index::vector::ivf::new_ivf_with_pq_and_options(
+            self.ivf.weighted_centroids()?.values(),
             self.ivf.dimension(),
             self.metric_type,
             column,
             pq_index.pq.clone(),
-            None,
-            None,
+            lance_index::vector::ivf::IvfAssignOptions {
+                tree: self
+                    .ivf
+                    .tree
+                    .as_ref()
+                    .filter(|_| self.ivf.weights.is_none()),
+                multi_assign: self.ivf.multi_assign,
+                ..Default::default()
+            },
         )?;
 
-        let shuffled = shuffle_dataset_v2(
+        let (shuffled, _) = shuffle_dataset_v2(
             data,
             column,
             ivf,
             self.ivf.num_partitions() as u32,
             pq_index.pq.num_sub_vectors(),
+            &IvfBuildParams {
+                weights: self.ivf.weights.clone(),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            &BuildEnvConfig::from_env(),
         )
         .await?;
         let mut ivf_mut = Ivf::new(self.ivf.centroids.clone());
-        write_index_partitions(&mut writer, &mut ivf_mut, shuffled, Some(self)).await?;
+        ivf_mut.tree = self.ivf.tree.clone();
+        ivf_mut.weights = self.ivf.weights.clone();
+        ivf_mut.bf16_centroids = self.ivf.bf16_centroids;
+        ivf_mut.multi_assign = self.ivf.multi_assign;
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf_mut,
+            vec![chain_partitions(shuffled)],
+            Some(self),
+            WritePartitionsOptions::default(),
+        )
+        .await?;
         let metadata = IvfPQIndexMetadata {
             name: metadata.name.clone(),
             column: column.to_string(),
@@ -237,6 +718,9 @@ impl IVFIndex {
             ivf: ivf_mut,
             pq: pq_index.pq.clone(),
             transforms: vec![],
+            custom_metadata: open_index_proto(dataset, self.reader.as_ref())
+                .await?
+                .custom_metadata,
         };
 
         let metadata = pb::Index::try_from(&metadata)?;
@@ -254,92 +738,500 @@ impl std::fmt::Debug for IVFIndex {
     }
 }
 
-#[derive(Serialize)]
-pub struct IvfIndexPartitionStatistics {
-    index: usize,
-    length: u32,
-    offset: usize,
-    centroid: Vec<f32>,
+/// Estimate the recall of searching `index` with each of `nprobes`, to help pick one.
+///
+/// The recall of a query is the fraction of its `ground_truth` row ids found in the
+/// partitions it probes, i.e., the best recall a search with that `nprobes` can reach.
+/// Only the probed partitions are read, in probe order, see
+/// [`IVFIndex::read_partitions_ordered`].
+///
+/// Returns the mean recall over `queries` for each of `nprobes`, in the same order.
+pub async fn estimate_recall_curve(
+    index: &IVFIndex,
+    queries: &FixedSizeListArray,
+    ground_truth: &[Vec<u64>],
+    nprobes: &[usize],
+) -> Result<Vec<(usize, f32)>> {
+    if queries.len() != ground_truth.len() {
+        return Err(Error::InvalidInput {
+            source: format!(
+                "estimate_recall_curve: {} queries but {} ground truth lists",
+                queries.len(),
+                ground_truth.len()
+            )
+            .into(),
+            location: location!(),
+        });
+    }
+    let max_nprobes = nprobes.iter().copied().max().unwrap_or(0);
+    let mut recalls = vec![0.0; nprobes.len()];
+    for (query, expected) in (0..queries.len())
+        .map(|i| queries.value(i))
+        .zip(ground_truth)
+    {
+        let part_ids = index.ivf.find_partitions(
+            &index.ivf.weighted_key(&query)?,
+            max_nprobes,
+            index.metric_type,
+        )?;
+        let expected = expected.iter().collect::<HashSet<_>>();
+
+        // Number of ground truth rows found in the first `n` probed partitions.
+        let mut found = vec![0];
+        let mut partitions = Box::pin(index.read_partitions_ordered(part_ids.values())?);
+        while let Some(batch) = partitions.try_next().await? {
+            let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>();
+            let hits = row_ids
+                .values()
+                .iter()
+                .filter(|row_id| expected.contains(row_id))
+                .count();
+            found.push(found.last().unwrap() + hits);
+        }
+
+        for (recall, &n) in recalls.iter_mut().zip(nprobes) {
+            *recall += if expected.is_empty() {
+                1.0
+            } else {
+                found[n.min(found.len() - 1)] as f32 / expected.len() as f32
+            };
+        }
+    }
+    Ok(nprobes
+        .iter()
+        .zip(recalls)
+        .map(|(&n, recall)| (n, recall / queries.len().max(1) as f32))
+        .collect())
 }
 
-#[derive(Serialize)]
-pub struct IvfIndexStatistics {
-    index_type: String,
-    uuid: String,
-    uri: String,
-    metric_type: String,
-    num_partitions: usize,
-    sub_index: serde_json::Value,
-    partitions: Vec<IvfIndexPartitionStatistics>,
+/// A sampled row whose reconstructed vector is closer to the centroid of another
+/// partition than to the centroid of its own, see [`validate_assignment_consistency`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssignmentViolation {
+    pub row_id: u64,
+
+    /// The partition the row is stored in.
+    pub partition_id: u32,
+
+    /// The randomly picked partition whose centroid is closer.
+    pub other_partition_id: u32,
 }
 
-#[async_trait]
-impl Index for IVFIndex {
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
+/// Random number generator seeded by `seed`, or from entropy if `None`.
+pub(crate) fn seeded_rng(seed: Option<u64>) -> SmallRng {
+    seed.map_or_else(SmallRng::from_entropy, SmallRng::seed_from_u64)
+}
 
-    fn as_index(self: Arc<Self>) -> Arc<dyn Index> {
-        self
-    }
+/// Result of [`validate_assignment_consistency`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// Number of sampled rows that were checked.
+    pub num_checked: usize,
 
-    fn index_type(&self) -> IndexType {
-        IndexType::Vector
+    pub violations: Vec<AssignmentViolation>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
     }
+}
 
-    fn statistics(&self) -> Result<String> {
-        let partitions_statistics = self
-            .ivf
-            .lengths
-            .iter()
-            .enumerate()
-            .map(|(i, &len)| {
-                let centroid = self.ivf.centroids.value(i);
-                let centroid_arr: &Float32Array = as_primitive_array(centroid.as_ref());
-                IvfIndexPartitionStatistics {
-                    index: i,
-                    length: len,
-                    offset: self.ivf.offsets[i],
-                    centroid: centroid_arr.values().to_vec(),
-                }
-            })
-            .collect::<Vec<_>>();
+/// Check that the PQ codes of `index` decode to vectors in the cluster of their
+/// partition, to catch partition assignment and encoding bugs.
+///
+/// Up to `sample_size` rows are sampled. The vector of each row is reconstructed with
+/// `pq`, see [`pq_decode`], and has to be at least as close to the centroid of its own
+/// partition as to the centroid of another, randomly picked, partition in L2. The
+/// sampling is seeded by `seed`, e.g. the [`IvfBuildParams::seed`] of the build, or
+/// from entropy if `None`.
+pub async fn validate_assignment_consistency(
+    index: &IVFIndex,
+    pq: &dyn ProductQuantizer,
+    sample_size: usize,
+    seed: Option<u64>,
+) -> Result<ConsistencyReport> {
+    if index.metric_type != MetricType::L2 {
+        return Err(Error::NotSupported {
+            source: format!(
+                "Assignment consistency is only checked for L2, got {}",
+                index.metric_type
+            )
+            .into(),
+            location: location!(),
+        });
+    }
+    if !index.ivf.pq_codebooks.is_empty() {
+        return Err(Error::NotSupported {
+            source: "Assignment consistency of per-partition PQ is not supported".into(),
+            location: location!(),
+        });
+    }
+    let num_partitions = index.ivf.num_partitions();
+    let total = index.ivf.lengths.iter().map(|l| *l as usize).sum::<usize>();
+    let mut report = ConsistencyReport::default();
+    if num_partitions < 2 || total == 0 {
+        return Ok(report);
+    }
 
-        Ok(serde_json::to_string(&IvfIndexStatistics {
-            index_type: "IVF".to_string(),
-            uuid: self.uuid.clone(),
-            uri: to_local_path(self.reader.path()),
-            metric_type: self.metric_type.to_string(),
-            num_partitions: self.ivf.num_partitions(),
-            // TODO: Not ideal that we have to re-parse the JSON here
-            sub_index: serde_json::from_str(&self.sub_index.statistics()?)?,
-            partitions: partitions_statistics,
-        })?)
+    let centroids = index.ivf.weighted_centroids()?;
+    let centroid_values = cast(centroids.values(), &DataType::Float32)?;
+    let centroid_values = centroid_values.as_primitive::<Float32Type>().values();
+    let dim = index.ivf.dimension();
+    let centroid = |part_id: usize| &centroid_values[part_id * dim..(part_id + 1) * dim];
+
+    // Sampled row offsets within each partition.
+    let mut rng = seeded_rng(seed);
+    let mut positions =
+        rand::seq::index::sample(&mut rng, total, sample_size.min(total)).into_vec();
+    positions.sort_unstable();
+    let mut sampled: Vec<(u32, Vec<usize>)> = vec![];
+    let mut part_start = 0;
+    let mut part_id = 0;
+    for pos in positions {
+        while pos >= part_start + index.ivf.lengths[part_id] as usize {
+            part_start += index.ivf.lengths[part_id] as usize;
+            part_id += 1;
+        }
+        match sampled.last_mut() {
+            Some((id, offsets)) if *id == part_id as u32 => offsets.push(pos - part_start),
+            _ => sampled.push((part_id as u32, vec![pos - part_start])),
+        }
     }
 
-    async fn calculate_included_frags(&self) -> Result<RoaringBitmap> {
-        let mut frag_ids = RoaringBitmap::default();
-        let part_ids = 0..self.ivf.num_partitions();
-        for part_id in part_ids {
-            let part = self.load_partition(part_id, false).await?;
-            frag_ids |= part.calculate_included_frags().await?;
+    let part_ids = sampled.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let mut partitions = Box::pin(index.read_partitions_ordered(&part_ids)?);
+    for (part_id, offsets) in sampled.iter() {
+        let batch = partitions.try_next().await?.ok_or_else(|| Error::Index {
+            message: format!("Partition {} could not be read", part_id),
+            location: location!(),
+        })?;
+        let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+        let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>();
+        let own = centroid(*part_id as usize);
+        for offset in offsets {
+            let code = codes.value(*offset);
+            let vector = pq_decode(code.as_primitive::<UInt8Type>().values(), pq, own)?;
+            let other = match rng.gen_range(0..num_partitions as u32 - 1) {
+                id if id >= *part_id => id + 1,
+                id => id,
+            };
+            if l2_distance(&vector, own) > l2_distance(&vector, centroid(other as usize)) {
+                report.violations.push(AssignmentViolation {
+                    row_id: row_ids.value(*offset),
+                    partition_id: *part_id,
+                    other_partition_id: other,
+                });
+            }
+            report.num_checked += 1;
         }
-        Ok(frag_ids)
     }
+    Ok(report)
 }
 
-#[async_trait]
-impl VectorIndex for IVFIndex {
-    #[instrument(level = "debug", skip_all, name = "IVFIndex::search")]
-    async fn search(&self, query: &Query, pre_filter: Arc<PreFilter>) -> Result<RecordBatch> {
-        let partition_ids =
-            self.ivf
-                .find_partitions(&query.key, query.nprobes, self.metric_type)?;
-        assert!(partition_ids.len() <= query.nprobes);
-        let part_ids = partition_ids.values().to_vec();
-        let batches = stream::iter(part_ids)
-            .map(|part_id| self.search_in_partition(part_id as usize, query, pre_filter.clone()))
-            .buffer_unordered(num_cpus::get())
+/// Result of [`reconcile_with_source`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Number of source rows with a vector.
+    pub num_source_rows: usize,
+
+    /// Number of rows stored in the index, counting each copy of a multi-assigned row.
+    pub num_index_rows: usize,
+
+    /// ROW_IDs of the source rows with a vector that are not in the index, ascending.
+    pub missing: Vec<u64>,
+
+    /// ROW_IDs in the index that are not in the source, ascending.
+    pub extra: Vec<u64>,
+
+    /// ROW_IDs stored more times than each row is assigned to partitions, ascending.
+    pub duplicated: Vec<u64>,
+}
+
+impl ReconcileReport {
+    pub fn is_reconciled(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.duplicated.is_empty()
+    }
+}
+
+/// Check that every row of the source dataset is stored in `index` exactly once, or
+/// once per partition it is assigned to with multi-assignment, to audit a build.
+///
+/// `source` yields batches with the ROW_ID and, optionally, the `column` of the source
+/// rows, e.g. a scan of the dataset with row ids. Rows with a null `column` may or may
+/// not be indexed and are not reported either way.
+pub async fn reconcile_with_source(
+    index: &IVFIndex,
+    column: &str,
+    source: impl Stream<Item = Result<RecordBatch>>,
+) -> Result<ReconcileReport> {
+    let mut report = ReconcileReport::default();
+    let mut indexed = vec![];
+    let mut partitions = Box::pin(index.scan_all_codes()?);
+    while let Some(batch) = partitions.try_next().await? {
+        indexed.extend_from_slice(batch[ROW_ID].as_primitive::<UInt64Type>().values());
+    }
+    report.num_index_rows = indexed.len();
+    indexed.sort_unstable();
+
+    let mut unmatched = RoaringTreemap::new();
+    let mut start = 0;
+    while start < indexed.len() {
+        let row_id = indexed[start];
+        let end = start + indexed[start..].partition_point(|id| *id == row_id);
+        if end - start > index.ivf.multi_assign {
+            report.duplicated.push(row_id);
+        }
+        unmatched.insert(row_id);
+        start = end;
+    }
+
+    let mut source = Box::pin(source);
+    while let Some(batch) = source.try_next().await? {
+        let row_ids = batch
+            .column_by_name(ROW_ID)
+            .ok_or_else(|| Error::Index {
+                message: "ROW ID is required to reconcile the index with its source".to_string(),
+                location: location!(),
+            })?
+            .as_primitive::<UInt64Type>();
+        let vectors = batch.column_by_name(column);
+        for (i, row_id) in row_ids.values().iter().enumerate() {
+            if vectors.is_some_and(|vectors| vectors.is_null(i)) {
+                unmatched.remove(*row_id);
+                continue;
+            }
+            report.num_source_rows += 1;
+            if !unmatched.remove(*row_id) {
+                report.missing.push(*row_id);
+            }
+        }
+    }
+    report.missing.sort_unstable();
+    report.extra = unmatched.iter().collect();
+    Ok(report)
+}
+
+/// Build a new IVF_PQ index of `column` from the PQ codes of `old_index` instead of
+/// from the dataset, under the centroids of [`IvfBuildParams::centroids`] and `pq`.
+///
+/// The vectors are reconstructed from the old codes and re-shuffled into the new
+/// partitions, so the dataset is not scanned. This is lossy: the rebuilt index encodes
+/// the old approximations rather than the source vectors, so the quantization errors
+/// of both PQs add up and its recall is lower than that of an index rebuilt from the
+/// source. Rows are assigned to the new partitions by their approximate vectors too.
+///
+/// The old index must have a PQ sub-index, and neither weights, cold partitions nor
+/// multiple assignments.
+pub async fn rebuild_from_index(
+    dataset: &Dataset,
+    old_index: &IVFIndex,
+    column: &str,
+    index_name: &str,
+    uuid: &str,
+    ivf_params: &IvfBuildParams,
+    pq: Arc<dyn ProductQuantizer>,
+) -> Result<()> {
+    sanity_check_ivf_param(ivf_params)?;
+    let dim = old_index.ivf.dimension();
+    let Some(centroids) = ivf_params.centroids.clone() else {
+        return Err(Error::Index {
+            message: "Rebuilding an index from another requires the new IVF centroids".to_string(),
+            location: location!(),
+        });
+    };
+    if centroids.value_length() as usize != dim || centroids.len() != ivf_params.num_partitions {
+        return Err(Error::Index {
+            message: format!(
+                "Rebuilding an index of dimension {} requires {} centroids of dimension {}, got {} of dimension {}",
+                dim,
+                ivf_params.num_partitions,
+                dim,
+                centroids.len(),
+                centroids.value_length()
+            ),
+            location: location!(),
+        });
+    }
+    if old_index.ivf.weights.is_some() || old_index.ivf.multi_assign > 1 {
+        return Err(Error::NotSupported {
+            source: "Rebuilding from an index built with weights or multiple assignments".into(),
+            location: location!(),
+        });
+    }
+    if !ivf_params.write {
+        return Err(Error::NotSupported {
+            source: "Rebuilding from an index without writing the partitions".into(),
+            location: location!(),
+        });
+    }
+    old_index.check_single_tier()?;
+    old_index.pq_sub_index()?;
+
+    let schema = Arc::new(ArrowSchema::new(vec![
+        ArrowField::new(
+            column,
+            DataType::FixedSizeList(
+                Arc::new(ArrowField::new("item", DataType::Float32, true)),
+                dim as i32,
+            ),
+            true,
+        ),
+        ArrowField::new(ROW_ID, DataType::UInt64, false),
+    ]));
+    let part_ids = (0..old_index.ivf.num_partitions() as u32)
+        .filter(|&part_id| old_index.ivf.lengths[part_id as usize] > 0)
+        .collect::<Vec<_>>();
+    let index = Arc::new(old_index.clone());
+    let batch_schema = schema.clone();
+    let data = stream::iter(part_ids).then(move |part_id| {
+        let index = index.clone();
+        let schema = batch_schema.clone();
+        async move {
+            let part_ids = [part_id];
+            let mut partition = Box::pin(index.read_partitions(&part_ids)?);
+            let Some(batch) = partition.try_next().await? else {
+                return Err(Error::Index {
+                    message: format!("Partition {} of the old index is missing", part_id),
+                    location: location!(),
+                });
+            };
+            let vectors = index
+                .decode_pq_codes(part_id as usize, batch[PQ_CODE_COLUMN].as_fixed_size_list())?;
+            Ok(RecordBatch::try_new(
+                schema,
+                vec![Arc::new(vectors), batch[ROW_ID].clone()],
+            )?)
+        }
+    });
+    let data = lance_core::io::RecordBatchStreamAdapter::new(schema, data.boxed());
+
+    let mut writer = create_index_file(dataset, uuid, ivf_params).await?;
+    let mut cold_writer = create_cold_index_file(dataset, uuid, ivf_params).await?;
+    let mut ivf = Ivf::new(centroids);
+    let metric_type = old_index.metric_type;
+    let report = builder::build_partitions(
+        &mut writer,
+        cold_writer.as_mut().map(|w| w as &mut dyn Writer),
+        data,
+        column,
+        &mut ivf,
+        pq.clone(),
+        metric_type,
+        0..ivf_params.num_partitions as u32,
+        None,
+        ivf_params,
+        &BuildEnvConfig::from_env(),
+    )
+    .await?;
+    if let Some(mut cold_writer) = cold_writer {
+        cold_writer.shutdown().await?;
+    }
+    finish_index_file(
+        writer,
+        dataset,
+        column,
+        index_name,
+        &[],
+        ivf,
+        pq,
+        metric_type,
+        &report,
+        &ivf_params.custom_metadata,
+    )
+    .await
+}
+
+#[derive(Serialize)]
+pub struct IvfIndexPartitionStatistics {
+    index: usize,
+    length: u32,
+    offset: usize,
+    centroid: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct IvfIndexStatistics {
+    index_type: String,
+    uuid: String,
+    uri: String,
+    metric_type: String,
+    num_partitions: usize,
+    sub_index: serde_json::Value,
+    partitions: Vec<IvfIndexPartitionStatistics>,
+}
+
+#[async_trait]
+impl Index for IVFIndex {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_index(self: Arc<Self>) -> Arc<dyn Index> {
+        self
+    }
+
+    fn index_type(&self) -> IndexType {
+        IndexType::Vector
+    }
+
+    fn statistics(&self) -> Result<String> {
+        let partitions_statistics = self
+            .ivf
+            .lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let centroid = self.ivf.centroids.value(i);
+                let centroid_arr: &Float32Array = as_primitive_array(centroid.as_ref());
+                IvfIndexPartitionStatistics {
+                    index: i,
+                    length: len,
+                    offset: self.ivf.offsets[i],
+                    centroid: centroid_arr.values().to_vec(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::to_string(&IvfIndexStatistics {
+            index_type: "IVF".to_string(),
+            uuid: self.uuid.clone(),
+            uri: to_local_path(self.reader.path()),
+            metric_type: self.metric_type.to_string(),
+            num_partitions: self.ivf.num_partitions(),
+            // TODO: Not ideal that we have to re-parse the JSON here
+            sub_index: serde_json::from_str(&self.sub_index.statistics()?)?,
+            partitions: partitions_statistics,
+        })?)
+    }
+
+    async fn calculate_included_frags(&self) -> Result<RoaringBitmap> {
+        let mut frag_ids = RoaringBitmap::default();
+        let part_ids = 0..self.ivf.num_partitions();
+        for part_id in part_ids {
+            let part = self.load_partition(part_id, false).await?;
+            frag_ids |= part.calculate_included_frags().await?;
+        }
+        Ok(frag_ids)
+    }
+}
+
+#[async_trait]
+impl VectorIndex for IVFIndex {
+    #[instrument(level = "debug", skip_all, name = "IVFIndex::search")]
+    async fn search(&self, query: &Query, pre_filter: Arc<PreFilter>) -> Result<RecordBatch> {
+        let query = &Query {
+            key: self.ivf.weighted_key(&query.key)?,
+            ..query.clone()
+        };
+        let partition_ids =
+            self.ivf
+                .find_partitions(&query.key, query.nprobes, self.metric_type)?;
+        assert!(partition_ids.len() <= query.nprobes);
+        let part_ids = partition_ids.values().to_vec();
+        let batches = stream::iter(part_ids)
+            .map(|part_id| self.search_in_partition(part_id as usize, query, pre_filter.clone()))
+            .buffer_unordered(num_cpus::get())
             .try_collect::<Vec<_>>()
             .await?;
         let batch = concat_batches(&batches[0].schema(), &batches)?;
@@ -354,7 +1246,23 @@ impl VectorIndex for IVFIndex {
 
         // TODO: Use a heap sort to get the top-k.
         let limit = query.k * query.refine_factor.unwrap_or(1) as usize;
-        let selection = sort_to_indices(dist_col, None, Some(limit))?;
+        let selection = if self.ivf.multi_assign > 1 {
+            // A row is in up to `multi_assign` of the probed partitions, so the nearest
+            // `limit` rows are among the nearest `limit * multi_assign` results.
+            let candidates = sort_to_indices(dist_col, None, Some(limit * self.ivf.multi_assign))?;
+            let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>();
+            let mut seen = HashSet::new();
+            UInt32Array::from_iter_values(
+                candidates
+                    .values()
+                    .iter()
+                    .copied()
+                    .filter(|i| seen.insert(row_ids.value(*i as usize)))
+                    .take(limit),
+            )
+        } else {
+            sort_to_indices(dist_col, None, Some(limit))?
+        };
         let struct_arr = StructArray::from(batch);
         let taken_distances = take(&struct_arr, &selection, None)?;
         Ok(as_struct_array(&taken_distances).into())
@@ -426,6 +1334,9 @@ pub struct IvfPQIndexMetadata {
 
     /// Transforms to be applied before search.
     transforms: Vec<pb::Transform>,
+
+    /// Key-value metadata stored as is.
+    custom_metadata: HashMap<String, String>,
 }
 
 /// Convert a IvfPQIndex to protobuf payload
@@ -471,6 +1382,7 @@ impl TryFrom<&IvfPQIndexMetadata> for pb::Index {
                     MetricType::Dot => pb::VectorMetricType::Dot.into(),
                 },
             })),
+            custom_metadata: idx.custom_metadata.clone(),
         })
     }
 }
@@ -489,6 +1401,123 @@ pub(crate) struct Ivf {
 
     /// Number of vectors in each partition.
     lengths: Vec<u32>,
+
+    /// PCA projection of the reduced vectors stored in each partition, if any.
+    pca: Option<PcaMatrix>,
+
+    /// PQ codebook of each partition. Empty if all partitions share one codebook.
+    pq_codebooks: Vec<Arc<FixedSizeListArray>>,
+
+    /// Storage order of the PQ codes of each partition.
+    code_storage_order: CodeStorageOrder,
+
+    /// Whether each partition stores a valid column.
+    has_valid: bool,
+
+    /// ROW_ID of the medoid of each partition, if computed.
+    medoids: Option<Vec<u64>>,
+
+    /// Coarse quantizer tree to assign vectors to partitions, if any.
+    tree: Option<IvfTree>,
+
+    /// Weight of each dimension in the distance used for assignment and residuals.
+    weights: Option<Vec<f32>>,
+
+    /// Time window of the indexed rows, if only a window was indexed.
+    time_window: Option<TimeWindow>,
+
+    /// Whether each partition stores the assignment margin of each row.
+    has_assignment_margin: bool,
+
+    /// Dimension-major copy of the shared PQ codebook, if stored.
+    transposed_codebook: Option<TransposedCodebook>,
+
+    /// Per-dimension statistics of the indexed vectors, if computed.
+    global_stats: Option<VectorStats>,
+
+    /// Number of nearest partitions each vector is stored in.
+    multi_assign: usize,
+
+    /// Bit-packed copy of the PQ codes of each partition, if written.
+    packed_codes: Option<PackedCodes>,
+
+    /// Storage tier of each partition, empty if all the partitions are hot. The offsets
+    /// of the cold partitions are in the cold tier file.
+    tiers: Vec<Tier>,
+
+    /// Fixed-width columns stored after the assignment margins of each partition.
+    extra_columns: Vec<ArrowField>,
+
+    /// Whether the build stopped at its time budget before reading all the rows.
+    partial: bool,
+
+    /// Subgroups of the rows of each partition, if they are grouped by a column.
+    subgroups: Option<Subgroups>,
+
+    /// Whether the centroids are stored as bfloat16. They are then exactly
+    /// representable in bfloat16.
+    bf16_centroids: bool,
+}
+
+/// Segments of the rows of each partition with the same value of a column, see
+/// [`IvfBuildParams::subgroup_column`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Subgroups {
+    /// Column the rows are grouped by.
+    column: String,
+
+    /// Subgroups of each partition, in storage order.
+    partitions: Vec<Vec<Subgroup>>,
+}
+
+impl Subgroups {
+    fn new(column: &str) -> Self {
+        Self {
+            column: column.to_string(),
+            partitions: vec![],
+        }
+    }
+
+    /// The subgroup of partition `part_id` with `value`, if it has any rows.
+    fn find(&self, part_id: usize, value: Option<&str>) -> Option<&Subgroup> {
+        self.partitions
+            .get(part_id)?
+            .iter()
+            .find(|subgroup| subgroup.value.as_deref() == value)
+    }
+}
+
+/// Contiguous rows of a partition with the same value of the subgroup column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Subgroup {
+    /// Value of the column cast to a string, `None` for nulls.
+    value: Option<String>,
+
+    /// Index of the first row in the partition.
+    offset: u32,
+
+    /// Number of rows.
+    length: u32,
+}
+
+/// Bit-packed copy of the PQ codes of an IVF_PQ index, written after the other
+/// columns of each partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PackedCodes {
+    /// Number of bits each PQ code is packed into.
+    num_bits: u32,
+
+    /// Offset of the packed codes of each partition in the file.
+    offsets: Vec<usize>,
+}
+
+impl PackedCodes {
+    fn new(num_bits: u32) -> Self {
+        Self {
+            num_bits,
+            offsets: vec![],
+        }
+    }
 }
 
 impl Ivf {
@@ -497,6 +1526,24 @@ impl Ivf {
             centroids,
             offsets: vec![],
             lengths: vec![],
+            pca: None,
+            pq_codebooks: vec![],
+            code_storage_order: CodeStorageOrder::Interleaved,
+            has_valid: false,
+            medoids: None,
+            tree: None,
+            weights: None,
+            time_window: None,
+            has_assignment_margin: false,
+            transposed_codebook: None,
+            global_stats: None,
+            multi_assign: 1,
+            packed_codes: None,
+            tiers: vec![],
+            extra_columns: vec![],
+            partial: false,
+            subgroups: None,
+            bf16_centroids: false,
         }
     }
 
@@ -510,7 +1557,31 @@ impl Ivf {
         self.centroids.len()
     }
 
-    /// Use the query vector to find `nprobes` closest partitions.
+    /// Centroids in the space the vectors are assigned in, i.e., scaled by the
+    /// square roots of the weights if the distance is weighted.
+    fn weighted_centroids(&self) -> Result<Arc<FixedSizeListArray>> {
+        match self.weights.as_ref() {
+            Some(weights) => {
+                let transform = WeightTransform::try_new(weights, "")?;
+                Ok(Arc::new(transform.scale(&self.centroids)?))
+            }
+            None => Ok(self.centroids.clone()),
+        }
+    }
+
+    /// Scale a query vector by the square roots of the weights if the distance is
+    /// weighted, to compare it with the indexed vectors, see [`Self::weighted_centroids`].
+    fn weighted_key(&self, key: &ArrayRef) -> Result<ArrayRef> {
+        let Some(weights) = self.weights.as_ref() else {
+            return Ok(key.clone());
+        };
+        let key = FixedSizeListArray::try_new_from_values(key.clone(), key.len() as i32)?;
+        let key = WeightTransform::try_new(weights, "")?.scale(&key)?;
+        Ok(key.values().clone())
+    }
+
+    /// Use the query vector to find `nprobes` closest partitions, in the space the
+    /// vectors are assigned in, see [`Self::weighted_key`].
     fn find_partitions(
         &self,
         query: &dyn Array,
@@ -518,7 +1589,7 @@ impl Ivf {
         metric_type: MetricType,
     ) -> Result<UInt32Array> {
         let internal = lance_index::vector::ivf::new_ivf(
-            self.centroids.values(),
+            self.weighted_centroids()?.values(),
             self.dimension(),
             metric_type,
             vec![],
@@ -528,6 +1599,16 @@ impl Ivf {
         internal.find_partitions(query, nprobes)
     }
 
+    /// Storage tier of a partition, [`Tier::Hot`] unless the index records tiers.
+    fn tier(&self, partition_id: usize) -> Tier {
+        self.tiers.get(partition_id).copied().unwrap_or_default()
+    }
+
+    /// Whether any partition is stored in the cold tier file.
+    pub(crate) fn has_cold_partitions(&self) -> bool {
+        self.tiers.contains(&Tier::Cold)
+    }
+
     /// Add the offset and length of one partition.
     fn add_partition(&mut self, offset: usize, len: u32) {
         self.offsets.push(offset);
@@ -550,7 +1631,72 @@ impl TryFrom<&Ivf> for pb::Ivf {
             centroids: vec![],
             offsets: ivf.offsets.iter().map(|o| *o as u64).collect(),
             lengths: ivf.lengths.clone(),
-            centroids_tensor: Some(ivf.centroids.as_ref().try_into()?),
+            centroids_tensor: Some(if ivf.bf16_centroids {
+                to_bf16_tensor(&ivf.centroids)?
+            } else {
+                ivf.centroids.as_ref().try_into()?
+            }),
+            pca: ivf.pca.as_ref().map(pb::Pca::try_from).transpose()?,
+            pq_codebooks: ivf
+                .pq_codebooks
+                .iter()
+                .map(|c| c.as_ref().try_into())
+                .collect::<Result<_>>()?,
+            code_storage_order: pb::CodeStorageOrder::from(ivf.code_storage_order).into(),
+            has_valid: ivf.has_valid,
+            medoids: ivf.medoids.clone().unwrap_or_default(),
+            tree: ivf.tree.as_ref().map(pb::IvfTree::try_from).transpose()?,
+            weights: ivf.weights.clone().unwrap_or_default(),
+            time_window: ivf.time_window.as_ref().map(pb::TimeWindow::from),
+            has_assignment_margin: ivf.has_assignment_margin,
+            transposed_codebook: ivf
+                .transposed_codebook
+                .as_ref()
+                .map(pb::TransposedCodebook::try_from)
+                .transpose()?,
+            global_stats: ivf.global_stats.as_ref().map(pb::VectorStats::from),
+            multi_assign: ivf.multi_assign as u32,
+            packed_codes: ivf.packed_codes.as_ref().map(|packed| pb::PackedCodes {
+                num_bits: packed.num_bits,
+                offsets: packed.offsets.iter().map(|o| *o as u64).collect(),
+            }),
+            partition_tiers: ivf
+                .tiers
+                .iter()
+                .map(|tier| pb::PartitionTier::from(*tier).into())
+                .collect(),
+            extra_columns: ivf
+                .extra_columns
+                .iter()
+                .map(|field| {
+                    Ok(pb::ExtraColumn {
+                        name: field.name().clone(),
+                        logical_type: LogicalType::try_from(field.data_type())?.to_string(),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            partial: ivf.partial,
+            subgroup_column: ivf
+                .subgroups
+                .as_ref()
+                .map(|subgroups| subgroups.column.clone())
+                .unwrap_or_default(),
+            partition_subgroups: ivf
+                .subgroups
+                .iter()
+                .flat_map(|subgroups| subgroups.partitions.iter())
+                .map(|subgroups| pb::PartitionSubgroups {
+                    subgroups: subgroups
+                        .iter()
+                        .map(|subgroup| pb::Subgroup {
+                            value: subgroup.value.clone().unwrap_or_default(),
+                            is_null: subgroup.value.is_none(),
+                            offset: subgroup.offset,
+                            length: subgroup.length,
+                        })
+                        .collect(),
+                })
+                .collect(),
         })
     }
 }
@@ -560,6 +1706,10 @@ impl TryFrom<&pb::Ivf> for Ivf {
     type Error = Error;
 
     fn try_from(proto: &pb::Ivf) -> Result<Self> {
+        let bf16_centroids = proto
+            .centroids_tensor
+            .as_ref()
+            .is_some_and(|tensor| tensor.data_type() == pb::tensor::DataType::Bfloat16);
         let centroids = if let Some(tensor) = proto.centroids_tensor.as_ref() {
             debug!("Ivf: loading IVF centroids from index format v2");
             Arc::new(FixedSizeListArray::try_from(tensor)?)
@@ -578,6 +1728,67 @@ impl TryFrom<&pb::Ivf> for Ivf {
             centroids,
             offsets: proto.offsets.iter().map(|o| *o as usize).collect(),
             lengths: proto.lengths.clone(),
+            pca: proto.pca.as_ref().map(PcaMatrix::try_from).transpose()?,
+            pq_codebooks: proto
+                .pq_codebooks
+                .iter()
+                .map(|t| Ok(Arc::new(FixedSizeListArray::try_from(t)?)))
+                .collect::<Result<_>>()?,
+            code_storage_order: proto.code_storage_order().into(),
+            has_valid: proto.has_valid,
+            medoids: (!proto.medoids.is_empty()).then(|| proto.medoids.clone()),
+            tree: proto.tree.as_ref().map(IvfTree::try_from).transpose()?,
+            weights: (!proto.weights.is_empty()).then(|| proto.weights.clone()),
+            time_window: proto.time_window.as_ref().map(TimeWindow::from),
+            has_assignment_margin: proto.has_assignment_margin,
+            transposed_codebook: proto
+                .transposed_codebook
+                .as_ref()
+                .map(TransposedCodebook::try_from)
+                .transpose()?,
+            global_stats: proto
+                .global_stats
+                .as_ref()
+                .map(VectorStats::try_from)
+                .transpose()?,
+            multi_assign: proto.multi_assign.max(1) as usize,
+            packed_codes: proto.packed_codes.as_ref().map(|packed| PackedCodes {
+                num_bits: packed.num_bits,
+                offsets: packed.offsets.iter().map(|o| *o as usize).collect(),
+            }),
+            tiers: proto.partition_tiers().map(Tier::from).collect(),
+            extra_columns: proto
+                .extra_columns
+                .iter()
+                .map(|column| {
+                    let logical_type = LogicalType::from(column.logical_type.as_str());
+                    Ok(ArrowField::new(
+                        &column.name,
+                        DataType::try_from(&logical_type)?,
+                        true,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            partial: proto.partial,
+            subgroups: (!proto.subgroup_column.is_empty()).then(|| Subgroups {
+                column: proto.subgroup_column.clone(),
+                partitions: proto
+                    .partition_subgroups
+                    .iter()
+                    .map(|subgroups| {
+                        subgroups
+                            .subgroups
+                            .iter()
+                            .map(|subgroup| Subgroup {
+                                value: (!subgroup.is_null).then(|| subgroup.value.clone()),
+                                offset: subgroup.offset,
+                                length: subgroup.length,
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            }),
+            bf16_centroids,
         })
     }
 }
@@ -614,28 +1825,410 @@ fn sanity_check<'a>(dataset: &'a Dataset, column: &str) -> Result<&'a Field> {
     Ok(field)
 }
 
-fn sanity_check_ivf_param(params: &IvfBuildParams) -> Result<()> {
-    if params.precomputed_partitons_file.is_some() && params.centroids.is_none() {
+/// The data type of the vector `column` computed by `expr`, see
+/// [`IvfBuildParams::vector_expr`], evaluated over the first row of `dataset`.
+async fn projected_vector_type(
+    dataset: &Dataset,
+    column: &str,
+    expr: &Expr,
+    params: &IvfBuildParams,
+    ctx: &SessionContext,
+) -> Result<DataType> {
+    let input_columns = builder::vector_input_columns(column, params)?;
+    let input_columns = input_columns.iter().map(String::as_str).collect::<Vec<_>>();
+    let projection = dataset.schema().project(&input_columns)?;
+    let batch = dataset.take(&[0], &projection).await?;
+    let vectors = project_training_data(batch, column, expr, ctx).await?;
+    let DataType::FixedSizeList(elem_type, _) = vectors.data_type() else {
+        unreachable!("project_training_data returns fixed size lists");
+    };
+    if !elem_type.data_type().is_floating() {
         return Err(Error::Index {
-            message: "precomputed_partitons_file requires centroids to be set".to_string(),
+            message: format!(
+                "VectorIndex requires the column {} computed by {} to be fixed size list of f16/f32/f64, got {}",
+                column,
+                expr,
+                vectors.data_type()
+            ),
             location: location!(),
         });
     }
-
-    Ok(())
+    Ok(vectors.data_type().clone())
 }
 
-/// Build IVF(PQ) index
-pub async fn build_ivf_pq_index(
-    dataset: &Dataset,
+/// Evaluate `expr` over `batch` into the vectors of `column`, see
+/// [`IvfBuildParams::vector_expr`].
+async fn project_training_data(
+    batch: RecordBatch,
     column: &str,
-    index_name: &str,
+    expr: &Expr,
+    ctx: &SessionContext,
+) -> Result<FixedSizeListArray> {
+    let schema = batch.schema();
+    let data = lance_core::io::RecordBatchStreamAdapter::new(
+        schema,
+        stream::iter(vec![Ok(batch)]).boxed(),
+    );
+    let projected = builder::project_vector_column(data, column, expr.clone(), ctx).await?;
+    let schema = projected.schema();
+    let batches = projected.try_collect::<Vec<_>>().await?;
+    let batch = concat_batches(&schema, &batches)?;
+    let vectors = batch[column].as_fixed_size_list_opt().ok_or_else(|| Error::Index {
+        message: format!(
+            "VectorIndex requires the column {} computed by {} to be fixed size list of floats, got {}",
+            column,
+            expr,
+            batch[column].data_type()
+        ),
+        location: location!(),
+    })?;
+    Ok(vectors.clone())
+}
+
+/// Sample the training data of `column` like [maybe_sample_training_data], evaluating
+/// [`IvfBuildParams::vector_expr`] over the sampled rows if set.
+#[allow(clippy::too_many_arguments)]
+async fn sample_training_data(
+    dataset: &Dataset,
+    column: &str,
+    params: &IvfBuildParams,
+    sample_size_hint: usize,
+    density_sample_size: Option<usize>,
+    rng: &mut (impl Rng + Send),
+    ctx: &SessionContext,
+) -> Result<FixedSizeListArray> {
+    let Some(expr) = params.vector_expr.as_ref() else {
+        return maybe_sample_training_data(
+            dataset,
+            column,
+            sample_size_hint,
+            density_sample_size,
+            rng,
+        )
+        .await;
+    };
+    let input_columns = builder::vector_input_columns(column, params)?;
+    let input_columns = input_columns.iter().map(String::as_str).collect::<Vec<_>>();
+    let num_samples = training_pool_size(sample_size_hint, density_sample_size);
+    let batch = sample_rows(dataset, &input_columns, num_samples, rng).await?;
+    let vectors = project_training_data(batch, column, expr, ctx).await?;
+    match density_sample_size {
+        Some(sample_size) => density_weighted_sample(&vectors, sample_size, rng),
+        None => Ok(vectors),
+    }
+}
+
+fn sanity_check_ivf_param(params: &IvfBuildParams) -> Result<()> {
+    if params.precomputed_partitons_file.is_some() && params.centroids.is_none() {
+        return Err(Error::Index {
+            message: "precomputed_partitons_file requires centroids to be set".to_string(),
+            location: location!(),
+        });
+    }
+
+    if let Some((n, r)) = params.sample_mod {
+        if n == 0 || r >= n {
+            return Err(Error::Index {
+                message: format!("sample_mod requires 0 <= r < n, got (n={}, r={})", n, r),
+                location: location!(),
+            });
+        }
+    }
+    if let Some((num_shards, shard_id)) = params.hash_shard {
+        if num_shards == 0 || shard_id >= num_shards {
+            return Err(Error::Index {
+                message: format!(
+                    "hash_shard requires 0 <= shard_id < num_shards, got (num_shards={}, shard_id={})",
+                    num_shards, shard_id
+                ),
+                location: location!(),
+            });
+        }
+        if params.sample_mod.is_some() {
+            return Err(Error::Index {
+                message: "hash_shard can not be combined with sample_mod".to_string(),
+                location: location!(),
+            });
+        }
+    }
+
+    if let Some(num_coarse_partitions) = params.num_coarse_partitions {
+        if num_coarse_partitions == 0 || num_coarse_partitions > params.num_partitions {
+            return Err(Error::Index {
+                message: format!(
+                    "num_coarse_partitions requires 0 < num_coarse_partitions <= num_partitions, got {} and {}",
+                    num_coarse_partitions, params.num_partitions
+                ),
+                location: location!(),
+            });
+        }
+    }
+
+    if params.tier_fn.is_some() && params.package.is_some() {
+        return Err(Error::Index {
+            message: "tier_fn can not be used with package, the cold partitions are written to a separate file"
+                .to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.transposed_codebook && params.per_partition_pq {
+        return Err(Error::Index {
+            message: "transposed_codebook requires a shared PQ codebook, not per_partition_pq"
+                .to_string(),
+            location: location!(),
+        });
+    }
+
+    match params.pipelined_training_rows {
+        Some(0) => {
+            return Err(Error::Index {
+                message: "pipelined_training_rows must be greater than 0".to_string(),
+                location: location!(),
+            });
+        }
+        Some(_) if params.centroids.is_some() => {
+            return Err(Error::Index {
+                message: "pipelined_training_rows requires the IVF centroids to be trained"
+                    .to_string(),
+                location: location!(),
+            });
+        }
+        Some(_) if !params.write => {
+            return Err(Error::Index {
+                message: "pipelined_training_rows requires the partitions to be written"
+                    .to_string(),
+                location: location!(),
+            });
+        }
+        _ => {}
+    }
+
+    if params.min_partition_rows.is_some() && (params.per_partition_pq || params.assignment_margin)
+    {
+        return Err(Error::Index {
+            message:
+                "min_partition_rows is not supported with per_partition_pq or assignment_margin"
+                    .to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.self_recall_check.is_some()
+        && (params.per_partition_pq || params.weights.is_some() || params.row_id_map.is_some())
+    {
+        return Err(Error::Index {
+            message:
+                "self_recall_check is not supported with per_partition_pq, weights or row_id_map"
+                    .to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.multi_assign == 0 || params.multi_assign > params.num_partitions {
+        return Err(Error::Index {
+            message: format!(
+                "multi_assign requires 0 < multi_assign <= num_partitions, got {} and {}",
+                params.multi_assign, params.num_partitions
+            ),
+            location: location!(),
+        });
+    }
+    if params.multi_assign > 1
+        && (params.num_coarse_partitions.is_some()
+            || params.precomputed_partitons_file.is_some()
+            || params.min_partition_rows.is_some())
+    {
+        return Err(Error::Index {
+            message: "multi_assign is not supported with num_coarse_partitions, precomputed_partitons_file or min_partition_rows".to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.min_training_rows_per_partition == 0
+        || params.min_training_rows_per_partition > params.sample_rate
+    {
+        return Err(Error::Index {
+            message: format!(
+                "min_training_rows_per_partition must be between 1 and the sample_rate {}, got {}",
+                params.sample_rate, params.min_training_rows_per_partition
+            ),
+            location: location!(),
+        });
+    }
+
+    if params.max_open_files == Some(0) {
+        return Err(Error::Index {
+            message: "max_open_files must be greater than 0".to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.subgroup_column.is_some()
+        && params.code_storage_order != CodeStorageOrder::Interleaved
+    {
+        return Err(Error::Index {
+            message: "subgroup_column requires interleaved PQ codes".to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.max_partition_rows == Some(0) {
+        return Err(Error::Index {
+            message: "max_partition_rows must be greater than 0".to_string(),
+            location: location!(),
+        });
+    }
+
+    if params.read_rate_limit == Some(BytesPerSec(0)) {
+        return Err(Error::Index {
+            message: "read_rate_limit must be greater than 0".to_string(),
+            location: location!(),
+        });
+    }
+
+    if let Some(order) = params.output_order.as_deref() {
+        io::check_output_order(order, params.num_partitions)?;
+    }
+
+    if params.vector_expr.is_some() && params.pipelined_training_rows.is_some() {
+        return Err(Error::Index {
+            message: "vector_expr is not supported with pipelined_training_rows".to_string(),
+            location: location!(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Build IVF(PQ) index
+///
+/// Returns the report of building the partitions, the only output of a build with
+/// [`IvfBuildParams::write`] unset.
+pub async fn build_ivf_pq_index(
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
     uuid: &str,
     metric_type: MetricType,
     ivf_params: &IvfBuildParams,
     pq_params: &PQBuildParams,
-) -> Result<()> {
+) -> Result<BuildReport> {
+    do_build_ivf_pq_index(
+        dataset,
+        column,
+        index_name,
+        uuid,
+        metric_type,
+        ivf_params,
+        pq_params,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Build IVF(PQ) index like [build_ivf_pq_index] over the rows kept by `mask`, a
+/// precomputed filter of the rows that is cheaper than a filter expression.
+///
+/// The IVF and PQ models are still trained on a sample of all the rows.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_ivf_pq_index_with_mask(
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    uuid: &str,
+    metric_type: MetricType,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    mask: RowMask,
+) -> Result<BuildReport> {
+    do_build_ivf_pq_index(
+        dataset,
+        column,
+        index_name,
+        uuid,
+        metric_type,
+        ivf_params,
+        pq_params,
+        Some(mask),
+        None,
+    )
+    .await
+}
+
+/// Build IVF(PQ) index like [build_ivf_pq_index], staging the index file in `txn`
+/// instead of writing it to the dataset, so that the index is a part of a larger
+/// atomic commit.
+///
+/// `txn` is committed once the whole index file is staged, and rolled back if the
+/// build fails. The cold tier and the package, which write other files, are not
+/// supported.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_ivf_pq_index_in_txn(
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    uuid: &str,
+    metric_type: MetricType,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    txn: &mut dyn IndexTxn,
+) -> Result<BuildReport> {
+    do_build_ivf_pq_index(
+        dataset,
+        column,
+        index_name,
+        uuid,
+        metric_type,
+        ivf_params,
+        pq_params,
+        None,
+        Some(txn),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn do_build_ivf_pq_index(
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    uuid: &str,
+    metric_type: MetricType,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    mask: Option<RowMask>,
+    txn: Option<&mut dyn IndexTxn>,
+) -> Result<BuildReport> {
     sanity_check_ivf_param(ivf_params)?;
+    if txn.is_some() {
+        let unsupported = [
+            (!ivf_params.write, "without write"),
+            (ivf_params.tier_fn.is_some(), "with tier_fn"),
+            (ivf_params.package.is_some(), "with package"),
+            (
+                ivf_params.pipelined_training_rows.is_some(),
+                "with pipelined_training_rows",
+            ),
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(unsupported, _)| *unsupported) {
+            return Err(Error::Index {
+                message: format!("Building in a transaction is not supported {}", option),
+                location: location!(),
+            });
+        }
+    }
+    let env = BuildEnvConfig::from_env();
+    if ivf_params.store_assignment_accelerator && metric_type != MetricType::L2 {
+        return Err(Error::Index {
+            message: format!(
+                "store_assignment_accelerator requires the L2 metric, got {}",
+                metric_type
+            ),
+            location: location!(),
+        });
+    }
 
     info!(
         "Building vector index: IVF{},{}PQ{}, metric={}",
@@ -645,32 +2238,124 @@ pub async fn build_ivf_pq_index(
         metric_type,
     );
 
-    let field = sanity_check(dataset, column)?;
-    let dim = if let DataType::FixedSizeList(_, d) = field.data_type() {
+    // The DataFusion context of the vector_expr of the training data.
+    let ctx = builder::session_context(None, &env)?;
+    let data_type = match ivf_params.vector_expr.as_ref() {
+        Some(expr) => projected_vector_type(dataset, column, expr, ivf_params, &ctx).await?,
+        None => sanity_check(dataset, column)?.data_type(),
+    };
+    let dim = if let DataType::FixedSizeList(_, d) = data_type {
         d as usize
     } else {
         return Err(Error::Index {
             message: format!(
                 "VectorIndex requires the column data type to be fixed size list of floats, got {}",
-                field.data_type()
+                data_type
             ),
             location: location!(),
         });
     };
 
+    let fitted_pq_params;
+    let pq_params = match ivf_params.target_index_bytes {
+        Some(target_bytes) => {
+            if pq_params.codebook.is_some() {
+                return Err(Error::Index {
+                    message:
+                        "target_index_bytes can not pick the sub-vectors of a given PQ codebook"
+                            .to_string(),
+                    location: location!(),
+                });
+            }
+            let num_rows = dataset.count_rows().await?;
+            let num_sub_vectors =
+                fit_num_sub_vectors(num_rows, dim, ivf_params, pq_params, target_bytes)?;
+            info!(
+                "Using PQ{} to fit the index of {} rows into {} bytes",
+                num_sub_vectors, num_rows, target_bytes
+            );
+            fitted_pq_params = PQBuildParams {
+                num_sub_vectors,
+                ..pq_params.clone()
+            };
+            &fitted_pq_params
+        }
+        None => pq_params,
+    };
+
+    // The codebook of each partition is trained like the shared one by default.
+    let partition_pq_ivf_params;
+    let ivf_params = if ivf_params.per_partition_pq && ivf_params.per_partition_pq_params.is_none()
+    {
+        partition_pq_ivf_params = IvfBuildParams {
+            per_partition_pq_params: Some(pq_params.clone()),
+            ..ivf_params.clone()
+        };
+        &partition_pq_ivf_params
+    } else {
+        ivf_params
+    };
+
+    if let Some(num_training_rows) = ivf_params.pipelined_training_rows {
+        if pq_params.use_opq {
+            return Err(Error::Index {
+                message: "Pipelined IVF training does not support OPQ".to_string(),
+                location: location!(),
+            });
+        }
+        if mask.is_some() {
+            return Err(Error::Index {
+                message: "Pipelined IVF training does not support a row mask".to_string(),
+                location: location!(),
+            });
+        }
+        return write_index_file_pipelined(
+            dataset,
+            column,
+            index_name,
+            uuid,
+            metric_type,
+            num_training_rows,
+            ivf_params,
+            pq_params,
+            &env,
+        )
+        .await;
+    }
+
     // Maximum to train [IvfBuildParams::sample_size](default 256) vectors per centroid, see Faiss.
     let sample_size_hint = std::cmp::max(
         ivf_params.num_partitions,
         lance_index::vector::pq::num_centroids(pq_params.num_bits as u32),
     ) * ivf_params.sample_rate;
 
+    let mut rng = seeded_rng(ivf_params.seed);
+    // The density weighted sample is drawn for the k-means sample of the centroids.
+    let density_sample_size = ivf_params
+        .density_sampling
+        .then(|| ivf_params.num_partitions * ivf_params.sample_rate);
     let mut training_data = if ivf_params.centroids.is_none() {
         let start = std::time::Instant::now();
         log::info!(
             "Loading training data for IVF. Sample size: {}",
             sample_size_hint
         );
-        let data = Some(maybe_sample_training_data(dataset, column, sample_size_hint).await?);
+        let data = sample_training_data(
+            dataset,
+            column,
+            ivf_params,
+            sample_size_hint,
+            density_sample_size,
+            &mut rng,
+            &ctx,
+        )
+        .await?;
+        let data = Some(builder::filter_zero_norm_training_data(
+            data,
+            column,
+            metric_type,
+            ivf_params.on_zero_norm,
+        )?);
         log::info!(
             "Finished loading training data in {:02} seconds",
             start.elapsed().as_secs_f32()
@@ -687,7 +2372,7 @@ pub async fn build_ivf_pq_index(
 
     let start = std::time::Instant::now();
     // Train IVF partitions.
-    let ivf_model = if let Some(centroids) = &ivf_params.centroids {
+    let mut ivf_model = if let Some(centroids) = &ivf_params.centroids {
         if centroids.values().len() != ivf_params.num_partitions * dim {
             return Err(Error::Index {
                 message: format!(
@@ -728,16 +2413,16 @@ pub async fn build_ivf_pq_index(
         "Traied IVF model in {:02} seconds",
         start.elapsed().as_secs_f32()
     );
+    if ivf_params.bf16_centroids {
+        ivf_model.centroids = Arc::new(round_to_bf16(&ivf_model.centroids)?);
+        ivf_model.bf16_centroids = true;
+    }
+    builder::train_coarse_quantizer(&mut ivf_model, metric_type, ivf_params).await?;
+    ivf_model.weights = ivf_params.weights.clone();
 
     let start = std::time::Instant::now();
-    let pq: Arc<dyn ProductQuantizer> = if let Some(codebook) = &pq_params.codebook {
-        Arc::new(ProductQuantizerImpl::<Float32Type>::new(
-            pq_params.num_sub_vectors,
-            pq_params.num_bits as u32,
-            dim,
-            Arc::new(codebook.as_primitive().clone()),
-            metric_type,
-        ))
+    let pq = if let Some(codebook) = &pq_params.codebook {
+        new_pq_with_codebook(codebook, dim, metric_type, pq_params)
     } else {
         info!(
             "Start to train PQ code: PQ{}, bits={}",
@@ -746,6 +2431,8 @@ pub async fn build_ivf_pq_index(
         let expected_sample_size =
             lance_index::vector::pq::num_centroids(pq_params.num_bits as u32)
                 * pq_params.sample_rate;
+        // The PQ is trained on a uniform sample, unlike a density weighted IVF sample.
+        let training_data = training_data.filter(|_| density_sample_size.is_none());
         let training_data = if let Some(training_data) = training_data {
             if training_data.value_length() as usize > expected_sample_size {
                 training_data.sample(expected_sample_size)?
@@ -758,56 +2445,32 @@ pub async fn build_ivf_pq_index(
                 "Loading training data for PQ. Sample size: {}",
                 expected_sample_size
             );
-            let data = maybe_sample_training_data(dataset, column, expected_sample_size).await?;
+            let data = sample_training_data(
+                dataset,
+                column,
+                ivf_params,
+                expected_sample_size,
+                None,
+                &mut rng,
+                &ctx,
+            )
+            .await?;
+            let data = builder::filter_zero_norm_training_data(
+                data,
+                column,
+                metric_type,
+                ivf_params.on_zero_norm,
+            )?;
             log::info!(
                 "Finished loading training data in {:02} seconds",
                 start.elapsed().as_secs_f32()
             );
             data
         };
-
-        // TODO: consolidate IVF models to `lance_index`.
-        let ivf2 = lance_index::vector::ivf::new_ivf(
-            ivf_model.centroids.values(),
-            ivf_model.dimension(),
-            metric_type,
-            vec![],
-            None,
-            None,
-        )?;
-
-        info!(
-            "starting to compute partitions for PQ training, sample size: {}",
-            training_data.value_length()
-        );
-        // Compute the residual vector to train Product Quantizer.
-        // TODO: maybe use precomputed partitions here. since these are aggressively down sampled
-        // the time to compute them is not that bad.
-        let part_ids = ivf2.compute_partitions(&training_data).await?;
-
-        let training_data = if metric_type == MetricType::Cosine {
-            // Do not run residual distance for cosine distance.
-            training_data
-        } else {
-            span!(Level::INFO, "compute residual for PQ training")
-                .in_scope(|| ivf2.compute_residual(&training_data, Some(&part_ids)))
-                .await?
-        };
-        info!("Start train PQ: params={:#?}", pq_params);
-        pq_params.build(&training_data, metric_type).await?
+        train_pq_model(&ivf_model, &training_data, metric_type, pq_params).await?
     };
     info!("Trained PQ in: {} seconds", start.elapsed().as_secs_f32());
 
-    // Transform data, compute residuals and sort by partition ids.
-    let mut scanner = dataset.scan();
-    scanner.batch_readahead(num_cpus::get() * 2);
-    scanner.project(&[column])?;
-    scanner.with_row_id();
-
-    // Scan the dataset and compute residual, pq with with partition ID.
-    // For now, it loads all data into memory.
-    let stream = scanner.try_into_stream().await?;
-
     let precomputed_partitions = match &ivf_params.precomputed_partitons_file {
         Some(file) => {
             info!("Loading precomputed partitions from file: {}", file);
@@ -863,6 +2526,7 @@ pub async fn build_ivf_pq_index(
         None => None,
     };
 
+    // Scan the dataset, transform data, compute residuals and sort by partition ids.
     write_index_file(
         dataset,
         column,
@@ -872,12 +2536,120 @@ pub async fn build_ivf_pq_index(
         ivf_model,
         pq,
         metric_type,
-        stream,
         precomputed_partitions,
+        mask,
+        txn,
+        ivf_params,
+        &env,
     )
     .await
 }
 
+/// Build IVF(PQ) index like [build_ivf_pq_index], returning a [BuildControl] to pause
+/// and resume the build along with the future of the build.
+///
+/// The handle of [`IvfBuildParams::control`] is returned if set, otherwise a new one.
+pub fn build_ivf_pq_index_with_control<'a>(
+    dataset: &'a Dataset,
+    column: &'a str,
+    index_name: &'a str,
+    uuid: &'a str,
+    metric_type: MetricType,
+    ivf_params: &IvfBuildParams,
+    pq_params: &'a PQBuildParams,
+) -> (BuildControl, impl Future<Output = Result<BuildReport>> + 'a) {
+    let control = ivf_params.control.clone().unwrap_or_default();
+    let mut ivf_params = ivf_params.clone();
+    ivf_params.control = Some(control.clone());
+    let build = async move {
+        build_ivf_pq_index(
+            dataset,
+            column,
+            index_name,
+            uuid,
+            metric_type,
+            &ivf_params,
+            pq_params,
+        )
+        .await
+    };
+    (control, build)
+}
+
+/// Product quantizer with the pre-trained `codebook` of `pq_params`.
+fn new_pq_with_codebook(
+    codebook: &ArrayRef,
+    dim: usize,
+    metric_type: MetricType,
+    pq_params: &PQBuildParams,
+) -> Arc<dyn ProductQuantizer> {
+    Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+        pq_params.num_sub_vectors,
+        pq_params.num_bits as u32,
+        dim,
+        Arc::new(codebook.as_primitive().clone()),
+        metric_type,
+    ))
+}
+
+/// Train a product quantizer on the residuals of `training_data` to the centroids of
+/// `ivf_model`.
+///
+/// If `ivf_model` has weights, the PQ is trained on the residuals of the weighted
+/// vectors to the weighted centroids, which are the residuals it encodes.
+async fn train_pq_model(
+    ivf_model: &Ivf,
+    training_data: &FixedSizeListArray,
+    metric_type: MetricType,
+    pq_params: &PQBuildParams,
+) -> Result<Arc<dyn ProductQuantizer>> {
+    let weighted;
+    let training_data = match ivf_model.weights.as_ref() {
+        Some(weights) => {
+            weighted = WeightTransform::try_new(weights, "")?.scale(training_data)?;
+            &weighted
+        }
+        None => training_data,
+    };
+    // TODO: consolidate IVF models to `lance_index`.
+    let ivf2 = lance_index::vector::ivf::new_ivf_with_options(
+        ivf_model.weighted_centroids()?.values(),
+        ivf_model.dimension(),
+        metric_type,
+        vec![],
+        lance_index::vector::ivf::IvfAssignOptions {
+            // The tree clusters the unweighted centroids.
+            tree: ivf_model
+                .tree
+                .as_ref()
+                .filter(|_| ivf_model.weights.is_none()),
+            ..Default::default()
+        },
+    )?;
+
+    info!(
+        "starting to compute partitions for PQ training, sample size: {}",
+        training_data.value_length()
+    );
+    // Compute the residual vector to train Product Quantizer.
+    // TODO: maybe use precomputed partitions here. since these are aggressively down sampled
+    // the time to compute them is not that bad.
+    let part_ids = ivf2.compute_partitions(training_data).await?;
+
+    let residuals;
+    let training_data = if metric_type == MetricType::Cosine {
+        // Do not run residual distance for cosine distance.
+        training_data
+    } else {
+        residuals = span!(Level::INFO, "compute residual for PQ training")
+            .in_scope(|| ivf2.compute_residual(training_data, Some(&part_ids)))
+            .await?;
+        &residuals
+    };
+    info!("Start train PQ: params={:#?}", pq_params);
+    pq_params.build(training_data, metric_type).await
+}
+
 struct RemapPageTask {
     offset: usize,
     length: u32,
@@ -905,6 +2677,9 @@ impl RemapPageTask {
             .sub_index
             .load(reader, self.offset, self.length as usize)
             .await?;
+        if index.ivf.code_storage_order == CodeStorageOrder::Separate {
+            page = untranspose_page(page)?;
+        }
         page.remap(mapping)?;
         self.page = Some(page);
         Ok(self)
@@ -925,6 +2700,18 @@ impl RemapPageTask {
     }
 }
 
+/// Convert the PQ codes of a page stored in sub-vector-major layout to row-major.
+fn untranspose_page(page: Box<dyn VectorIndex>) -> Result<Box<dyn VectorIndex>> {
+    let pq_index = page
+        .as_any()
+        .downcast_ref::<PQIndex>()
+        .ok_or_else(|| Error::NotSupported {
+            source: "Transposed codes are only supported by a PQ sub-index".into(),
+            location: location!(),
+        })?;
+    Ok(Box::new(pq_index.untranspose_codes()?))
+}
+
 fn generate_remap_tasks(offsets: &Vec<usize>, lengths: &[u32]) -> Result<Vec<RemapPageTask>> {
     let mut tasks: Vec<RemapPageTask> = Vec::with_capacity(offsets.len() * 2 + 1);
 
@@ -947,6 +2734,12 @@ pub(crate) async fn remap_index_file(
     column: String,
     transforms: Vec<pb::Transform>,
 ) -> Result<()> {
+    if index.ivf.has_cold_partitions() {
+        return Err(Error::NotSupported {
+            source: "Remap an IVF index with cold partitions".into(),
+            location: location!(),
+        });
+    }
     let object_store = dataset.object_store();
     let old_path = dataset.indices_dir().child(old_uuid).child(INDEX_FILE_NAME);
     let new_path = dataset.indices_dir().child(new_uuid).child(INDEX_FILE_NAME);
@@ -964,6 +2757,28 @@ pub(crate) async fn remap_index_file(
         centroids: index.ivf.centroids.clone(),
         offsets: Vec::with_capacity(index.ivf.offsets.len()),
         lengths: Vec::with_capacity(index.ivf.lengths.len()),
+        // Remapping only rewrites PQ codes and row ids, so the reduced vectors are dropped.
+        pca: None,
+        pq_codebooks: index.ivf.pq_codebooks.clone(),
+        // Remapped pages are loaded, and thus written, in row-major layout.
+        code_storage_order: CodeStorageOrder::Interleaved,
+        has_valid: false,
+        medoids: None,
+        tree: index.ivf.tree.clone(),
+        weights: index.ivf.weights.clone(),
+        time_window: index.ivf.time_window.clone(),
+        has_assignment_margin: false,
+        transposed_codebook: index.ivf.transposed_codebook.clone(),
+        global_stats: index.ivf.global_stats.clone(),
+        multi_assign: index.ivf.multi_assign,
+        // Only the raw PQ codes are remapped.
+        packed_codes: None,
+        tiers: vec![],
+        extra_columns: vec![],
+        partial: index.ivf.partial,
+        // Dropped rows would shift the subgroups.
+        subgroups: None,
+        bf16_centroids: index.ivf.bf16_centroids,
     };
     while let Some(write_task) = task_stream.try_next().await? {
         write_task.write(&mut writer, &mut ivf).await?;
@@ -987,6 +2802,9 @@ pub(crate) async fn remap_index_file(
         metric_type: index.metric_type,
         pq: pq_sub_index.pq.clone(),
         transforms,
+        custom_metadata: open_index_proto(dataset, reader.as_ref())
+            .await?
+            .custom_metadata,
     };
 
     let metadata = pb::Index::try_from(&metadata)?;
@@ -997,7 +2815,76 @@ pub(crate) async fn remap_index_file(
     Ok(())
 }
 
-/// Write the index to the index file.
+/// Merge the partitions of `delta`, e.g. an index of the rows appended since `base`
+/// was built, into those of `base`, as the new index file of `new_uuid`.
+///
+/// Only the partitions with delta rows are decoded and rewritten, the others are
+/// copied as is. See [`io::merge_delta`] for the requirements on the two indices.
+pub async fn merge_delta_index_file(
+    dataset: &Dataset,
+    new_uuid: &str,
+    base: &IVFIndex,
+    delta: &IVFIndex,
+    name: &str,
+    column: &str,
+) -> Result<()> {
+    let new_path = dataset.indices_dir().child(new_uuid).child(INDEX_FILE_NAME);
+    let mut writer = dataset.object_store().create(&new_path).await?;
+    let ivf = io::merge_delta(&mut writer, base, delta).await?;
+    let custom_metadata = open_index_proto(dataset, base.reader.as_ref())
+        .await?
+        .custom_metadata;
+    finish_index_file(
+        writer,
+        dataset,
+        column,
+        name,
+        &[],
+        ivf,
+        base.pq_sub_index()?.pq.clone(),
+        base.metric_type,
+        &BuildReport::default(),
+        &custom_metadata,
+    )
+    .await
+}
+
+/// Open the IVF index `uuid` on `column` of `dataset` from its `format` archive, see
+/// [`IvfBuildParams::package`].
+pub async fn open_packaged_index(
+    dataset: &Dataset,
+    column: &str,
+    uuid: &str,
+    format: PackageFormat,
+) -> Result<Arc<dyn VectorIndex>> {
+    let index_dir = dataset.indices_dir().child(uuid);
+    let reader = package::open_package(
+        dataset.object_store(),
+        &index_dir.child(format.file_name()),
+        format,
+    )
+    .await?;
+    let proto = open_index_proto(dataset, reader.as_ref()).await?;
+    match &proto.implementation {
+        Some(pb::index::Implementation::VectorIndex(vector_index)) => {
+            crate::index::vector::open_vector_index(
+                Arc::new(dataset.clone()),
+                column,
+                uuid,
+                vector_index,
+                index_dir,
+                reader,
+            )
+            .await
+        }
+        None => Err(Error::Index {
+            message: format!("Index package of {} is missing its implementation", uuid),
+            location: location!(),
+        }),
+    }
+}
+
+/// Write the index to the index file, or stage it in `txn` if set.
 ///
 #[allow(clippy::too_many_arguments)]
 async fn write_index_file(
@@ -1009,27 +2896,243 @@ async fn write_index_file(
     mut ivf: Ivf,
     pq: Arc<dyn ProductQuantizer>,
     metric_type: MetricType,
-    stream: impl RecordBatchStream + Unpin + 'static,
     precomputed_partitons: Option<HashMap<u64, u32>>,
-) -> Result<()> {
-    let object_store = dataset.object_store();
-    let path = dataset.indices_dir().child(uuid).child(INDEX_FILE_NAME);
-    let mut writer = object_store.create(&path).await?;
+    mask: Option<RowMask>,
+    txn: Option<&mut dyn IndexTxn>,
+    ivf_params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    if !ivf_params.write {
+        // Nothing is written to the writers without writing the partitions.
+        let mut cold_writer = ivf_params
+            .tier_fn
+            .as_ref()
+            .map(|_| io::BufferWriter::default());
+        let report = builder::build_index_from_dataset(
+            &mut io::BufferWriter::default(),
+            cold_writer.as_mut().map(|w| w as &mut dyn Writer),
+            dataset,
+            column,
+            &mut ivf,
+            pq,
+            metric_type,
+            precomputed_partitons,
+            mask,
+            ivf_params,
+            env,
+        )
+        .await?;
+        info!(
+            "Built IVF partitions without writing them: {}",
+            report.to_json()
+        );
+        return Ok(report);
+    }
+
+    if let Some(txn) = txn {
+        // The whole index file is staged, to be committed at once.
+        let result = write_partitions_and_metadata(
+            io::TxnWriter::new(&mut *txn),
+            None,
+            dataset,
+            column,
+            index_name,
+            transformers,
+            ivf,
+            pq,
+            metric_type,
+            precomputed_partitons,
+            mask,
+            ivf_params,
+            env,
+        )
+        .await;
+        return io::finish_txn(txn, result);
+    }
+
+    let writer = create_index_file(dataset, uuid, ivf_params).await?;
+    let cold_writer = create_cold_index_file(dataset, uuid, ivf_params).await?;
+    write_partitions_and_metadata(
+        writer,
+        cold_writer,
+        dataset,
+        column,
+        index_name,
+        transformers,
+        ivf,
+        pq,
+        metric_type,
+        precomputed_partitons,
+        mask,
+        ivf_params,
+        env,
+    )
+    .await
+}
 
+/// Build the partitions of the index into `writer` and `cold_writer`, then write the
+/// metadata of the index, see [write_index_file].
+#[allow(clippy::too_many_arguments)]
+async fn write_partitions_and_metadata(
+    mut writer: impl Writer,
+    mut cold_writer: Option<ObjectWriter>,
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    transformers: &[Box<dyn Transformer>],
+    mut ivf: Ivf,
+    pq: Arc<dyn ProductQuantizer>,
+    metric_type: MetricType,
+    precomputed_partitons: Option<HashMap<u64, u32>>,
+    mask: Option<RowMask>,
+    ivf_params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
     let start = std::time::Instant::now();
-    let num_partitions = ivf.num_partitions() as u32;
-    builder::build_partitions(
+    let report = builder::build_index_from_dataset(
         &mut writer,
-        stream,
+        cold_writer.as_mut().map(|w| w as &mut dyn Writer),
+        dataset,
         column,
         &mut ivf,
         pq.clone(),
         metric_type,
-        0..num_partitions,
         precomputed_partitons,
+        mask,
+        ivf_params,
+        env,
     )
     .await?;
     info!("Built IVF partitions: {}s", start.elapsed().as_secs_f32());
+    if let Some(mut cold_writer) = cold_writer {
+        cold_writer.shutdown().await?;
+    }
+
+    finish_index_file(
+        writer,
+        dataset,
+        column,
+        index_name,
+        transformers,
+        ivf,
+        pq,
+        metric_type,
+        &report,
+        &ivf_params.custom_metadata,
+    )
+    .await?;
+    Ok(report)
+}
+
+/// Train the models and write the index file in one scan of `dataset`, see
+/// [`IvfBuildParams::pipelined_training_rows`].
+#[allow(clippy::too_many_arguments)]
+async fn write_index_file_pipelined(
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    uuid: &str,
+    metric_type: MetricType,
+    num_training_rows: usize,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    let mut writer = create_index_file(dataset, uuid, ivf_params).await?;
+    let mut cold_writer = create_cold_index_file(dataset, uuid, ivf_params).await?;
+
+    let start = std::time::Instant::now();
+    let stream = builder::scan_index_columns(dataset, column, ivf_params).await?;
+    let (ivf, pq, report) = builder::build_partitions_pipelined(
+        &mut writer,
+        cold_writer.as_mut().map(|w| w as &mut dyn Writer),
+        stream,
+        column,
+        metric_type,
+        num_training_rows,
+        ivf_params,
+        pq_params,
+        env,
+    )
+    .await?;
+    info!(
+        "Trained models and built IVF partitions: {}s",
+        start.elapsed().as_secs_f32()
+    );
+    if let Some(mut cold_writer) = cold_writer {
+        cold_writer.shutdown().await?;
+    }
+
+    finish_index_file(
+        writer,
+        dataset,
+        column,
+        index_name,
+        &[],
+        ivf,
+        pq,
+        metric_type,
+        &report,
+        &ivf_params.custom_metadata,
+    )
+    .await?;
+    Ok(report)
+}
+
+/// Create the index file of `uuid`, and its archive if `ivf_params.package` is set.
+async fn create_index_file(
+    dataset: &Dataset,
+    uuid: &str,
+    ivf_params: &IvfBuildParams,
+) -> Result<package::IndexFileWriter> {
+    let index_dir = dataset.indices_dir().child(uuid);
+    package::IndexFileWriter::try_new(
+        dataset.object_store(),
+        &index_dir.child(INDEX_FILE_NAME),
+        ivf_params
+            .package
+            .map(|format| (index_dir.child(format.file_name()), format)),
+    )
+    .await
+}
+
+/// Create the file of the cold partitions of `uuid` if `ivf_params.tier_fn` is set.
+async fn create_cold_index_file(
+    dataset: &Dataset,
+    uuid: &str,
+    ivf_params: &IvfBuildParams,
+) -> Result<Option<ObjectWriter>> {
+    if ivf_params.tier_fn.is_none() {
+        return Ok(None);
+    }
+    let path = dataset
+        .indices_dir()
+        .child(uuid)
+        .child(COLD_INDEX_FILE_NAME);
+    Ok(Some(dataset.object_store().create(&path).await?))
+}
+
+/// Write the transforms and the metadata of the index after its partitions.
+#[allow(clippy::too_many_arguments)]
+async fn finish_index_file(
+    mut writer: impl Writer,
+    dataset: &Dataset,
+    column: &str,
+    index_name: &str,
+    transformers: &[Box<dyn Transformer>],
+    ivf: Ivf,
+    pq: Arc<dyn ProductQuantizer>,
+    metric_type: MetricType,
+    report: &BuildReport,
+    custom_metadata: &HashMap<String, String>,
+) -> Result<()> {
+    debug!("IVF build report: {}", report.to_json());
+    if report.skipped_batches > 0 {
+        warn!(
+            "Skipped {} bad batches ({} rows) while building IVF partitions",
+            report.skipped_batches, report.skipped_rows
+        );
+    }
 
     // Convert [`Transformer`] to metadata.
     let mut transforms = vec![];
@@ -1047,6 +3150,7 @@ async fn write_index_file(
         ivf,
         pq,
         transforms,
+        custom_metadata: custom_metadata.clone(),
     };
 
     let metadata = pb::Index::try_from(&metadata)?;
@@ -1063,7 +3167,7 @@ async fn do_train_ivf_model<T: ArrowFloatType + Dot + Cosine + L2 + 'static>(
     metric_type: MetricType,
     params: &IvfBuildParams,
 ) -> Result<Ivf> {
-    let rng = SmallRng::from_entropy();
+    let rng = seeded_rng(params.seed);
     const REDOS: usize = 1;
     let centroids = lance_index::vector::kmeans::train_kmeans::<T>(
         data,
@@ -1084,11 +3188,27 @@ async fn do_train_ivf_model<T: ArrowFloatType + Dot + Cosine + L2 + 'static>(
 }
 
 /// Train IVF partitions using kmeans.
+///
+/// Fails if `data` has fewer vectors than required by
+/// `params.min_training_rows_per_partition`.
 async fn train_ivf_model(
     data: &FixedSizeListArray,
     metric_type: MetricType,
     params: &IvfBuildParams,
 ) -> Result<Ivf> {
+    let min_rows = params.num_partitions * params.min_training_rows_per_partition;
+    if data.len() < min_rows {
+        return Err(Error::Index {
+            message: format!(
+                "IVF training needs at least {} vectors ({} per partition for {} partitions), but only {} are available; use fewer partitions or lower min_training_rows_per_partition",
+                min_rows,
+                params.min_training_rows_per_partition,
+                params.num_partitions,
+                data.len()
+            ),
+            location: location!(),
+        });
+    }
     let values = data.values();
     let dim = data.value_length() as usize;
     match values.data_type() {
@@ -1112,26 +3232,44 @@ async fn train_ivf_model(
 mod tests {
     use super::*;
 
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::iter::repeat;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    use arrow_array::{cast::AsArray, RecordBatchIterator, RecordBatchReader, UInt64Array};
-    use arrow_schema::{DataType, Field, Schema};
-    use lance_linalg::distance::l2_distance_batch;
+    use approx::assert_relative_eq;
+    use arrow_array::{
+        cast::AsArray, types::UInt32Type, ArrayRef, Int32Array, RecordBatchIterator,
+        RecordBatchReader, StringArray, TimestampMicrosecondArray, UInt64Array,
+    };
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use datafusion::logical_expr::{col, create_udf, ScalarUDF, Volatility};
+    use datafusion::physical_expr::functions::make_scalar_function;
+    use lance_core::ROW_ID;
+    use lance_index::vector::{
+        ivf::{RowIdMap, TierFn},
+        PART_ID_COLUMN,
+    };
+    use lance_linalg::distance::{l2, l2_distance_batch};
     use lance_testing::datagen::{
         generate_random_array, generate_random_array_with_seed, generate_scaled_random_array,
         sample_without_replacement,
     };
-    use rand::{seq::SliceRandom, thread_rng};
+    use rand::{
+        seq::{IteratorRandom, SliceRandom},
+        thread_rng,
+    };
     use tempfile::tempdir;
     use uuid::Uuid;
 
     use crate::{
         format::RowAddress,
-        index::{vector::VectorIndexParams, DatasetIndexExt, DatasetIndexInternalExt, IndexType},
+        index::{
+            read_index_toc, vector::VectorIndexParams, DatasetIndexExt, DatasetIndexInternalExt,
+            IndexType,
+        },
     };
 
-    const DIM: usize = 32;
+    pub(super) const DIM: usize = 32;
 
     /// This goal of this function is to generate data that behaves in a very deterministic way so that
     /// we can evaluate the correctness of an IVF_PQ implementation.  Currently it is restricted to the
@@ -1340,66 +3478,2642 @@ mod tests {
         }
     }
 
-    async fn generate_test_dataset(test_uri: &str) -> (Dataset, Arc<FixedSizeListArray>) {
-        let vectors = generate_random_array(1000 * DIM);
-        let metadata: HashMap<String, String> = vec![("test".to_string(), "ivf_pq".to_string())]
-            .into_iter()
-            .collect();
+    pub(super) async fn generate_test_dataset(
+        test_uri: &str,
+    ) -> (Dataset, Arc<FixedSizeListArray>) {
+        write_test_dataset(test_uri, generate_random_array(1000 * DIM)).await
+    }
+
+    /// Build an IVF_PQ index with `ivf_params` and a random PQ codebook over the
+    /// dataset of [generate_test_dataset] written to `test_uri`.
+    pub(super) async fn build_test_index(
+        test_uri: &str,
+        ivf_params: &IvfBuildParams,
+    ) -> (Dataset, IVFIndex) {
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let ivf_index = build_test_index_on(&dataset, ivf_params, &pq_params).await;
+        (dataset, ivf_index)
+    }
+
+    /// Build and open an IVF_PQ index of the vector column of `dataset`.
+    pub(super) async fn build_test_index_on(
+        dataset: &Dataset,
+        ivf_params: &IvfBuildParams,
+        pq_params: &PQBuildParams,
+    ) -> IVFIndex {
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            dataset,
+            "vector",
+            "ivf_pq",
+            &uuid,
+            MetricType::L2,
+            ivf_params,
+            pq_params,
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        index.as_any().downcast_ref::<IVFIndex>().unwrap().clone()
+    }
+
+    pub(super) async fn write_test_dataset(
+        test_uri: &str,
+        vectors: Float32Array,
+    ) -> (Dataset, Arc<FixedSizeListArray>) {
+        let metadata: HashMap<String, String> = vec![("test".to_string(), "ivf_pq".to_string())]
+            .into_iter()
+            .collect();
+
+        let schema = Arc::new(
+            Schema::new(vec![Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            )])
+            .with_metadata(metadata),
+        );
+        let array = Arc::new(FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap());
+        let batch = RecordBatch::try_new(schema.clone(), vec![array.clone()]).unwrap();
+
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+        (dataset, array)
+    }
+
+    #[tokio::test]
+    async fn test_create_ivf_pq_with_centroids() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (mut dataset, vector_array) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(2 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let ivf_params = IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
+
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let params = VectorIndexParams::with_ivf_pq_params(MetricType::L2, ivf_params, pq_params);
+
+        dataset
+            .create_index(&["vector"], IndexType::Vector, None, &params, false)
+            .await
+            .unwrap();
+
+        let sample_query = vector_array.value(10);
+        let query = sample_query.as_primitive::<Float32Type>();
+        let results = dataset
+            .scan()
+            .nearest("vector", query, 5)
+            .unwrap()
+            .try_into_stream()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(1, results.len());
+        assert_eq!(5, results[0].num_rows());
+    }
+
+    #[tokio::test]
+    async fn test_build_and_search_tiered_index() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let dataset = Arc::new(dataset);
+
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let search = |uuid: String, tier_fn: Option<TierFn>| {
+            let dataset = dataset.clone();
+            let vectors = vectors.clone();
+            let centroids = centroids.clone();
+            let pq_params = pq_params.clone();
+            async move {
+                let mut ivf_params = IvfBuildParams::try_with_centroids(4, centroids).unwrap();
+                ivf_params.tier_fn = tier_fn;
+                build_ivf_pq_index(
+                    &dataset,
+                    "vector",
+                    "tiered",
+                    &uuid,
+                    MetricType::L2,
+                    &ivf_params,
+                    &pq_params,
+                )
+                .await
+                .unwrap();
+                let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+                let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+                let has_cold_partitions = ivf_index.ivf.has_cold_partitions();
+
+                let index_meta = crate::format::Index {
+                    uuid: Uuid::parse_str(&uuid).unwrap(),
+                    dataset_version: 0,
+                    fields: Vec::new(),
+                    name: "tiered".to_string(),
+                    fragment_bitmap: None,
+                };
+                let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+                let mut results = vec![];
+                for i in 0..10 {
+                    let query = Query {
+                        column: "vector".to_string(),
+                        key: vectors.value(i * 100),
+                        k: 10,
+                        nprobes: 4,
+                        refine_factor: None,
+                        metric_type: MetricType::L2,
+                        use_index: true,
+                    };
+                    let batch = index.search(&query, prefilter.clone()).await.unwrap();
+                    results.push(batch[ROW_ID].as_primitive::<UInt64Type>().clone());
+                }
+                (has_cold_partitions, results)
+            }
+        };
+
+        let tiered_uuid = Uuid::new_v4().to_string();
+        let tier_fn: TierFn = Arc::new(|part_id| {
+            if part_id % 2 == 1 {
+                Tier::Cold
+            } else {
+                Tier::Hot
+            }
+        });
+        let (tiered_has_cold, tiered) = search(tiered_uuid.clone(), Some(tier_fn)).await;
+        let (untiered_has_cold, untiered) = search(Uuid::new_v4().to_string(), None).await;
+
+        assert!(tiered_has_cold);
+        assert!(!untiered_has_cold);
+        let cold_path = dataset
+            .indices_dir()
+            .child(tiered_uuid.as_str())
+            .child(COLD_INDEX_FILE_NAME);
+        assert!(dataset.object_store().exists(&cold_path).await.unwrap());
+        assert_eq!(tiered, untiered);
+    }
+
+    /// Collect the sorted ROW IDs stored across all partitions of an IVF_PQ index.
+    pub(super) async fn indexed_row_ids(index: &IVFIndex) -> Vec<u64> {
+        let mut row_ids = vec![];
+        for part_id in 0..index.ivf.num_partitions() {
+            if index.ivf.lengths[part_id] == 0 {
+                continue;
+            }
+            let part = index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            row_ids.extend(pq_idx.row_ids.as_ref().unwrap().values().iter().copied());
+        }
+        row_ids.sort();
+        row_ids
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_sample_mod() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(2 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let mut ivf_params =
+            IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
+        ivf_params.sample_mod = Some((10, 0));
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let mut builds = vec![];
+        for _ in 0..2 {
+            let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+            builds.push(indexed_row_ids(&ivf_index).await);
+        }
+
+        // Roughly 10% of the 1000 rows are indexed.
+        assert!(
+            (50..=150).contains(&builds[0].len()),
+            "indexed {} rows",
+            builds[0].len()
+        );
+        // The same rows are selected on every build.
+        assert_eq!(builds[0], builds[1]);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_hash_shards() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(2 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let mut ivf_params =
+            IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let mut shards = vec![];
+        for shard_id in 0..2 {
+            ivf_params.hash_shard = Some((2, shard_id));
+            let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+            shards.push(indexed_row_ids(&ivf_index).await);
+        }
+
+        // Both shards hold a part of the rows, and together exactly all of them.
+        assert!(shards.iter().all(|row_ids| !row_ids.is_empty()));
+        let mut union = shards.concat();
+        union.sort();
+        assert_eq!(union, (0..1000).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_row_id_map() {
+        const OFFSET: u64 = 1 << 40;
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(2 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let mut ivf_params =
+            IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let mut builds = vec![];
+        for row_id_map in [None, Some(Arc::new(|id| id + OFFSET) as RowIdMap)] {
+            ivf_params.row_id_map = row_id_map;
+            let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+            builds.push((
+                ivf_index.ivf.lengths.clone(),
+                indexed_row_ids(&ivf_index).await,
+            ));
+        }
+
+        // The rows are assigned the same way, and stored with the mapped ids.
+        assert_eq!(builds[0].0, builds[1].0);
+        assert_eq!(builds[0].1, (0..1000).collect::<Vec<u64>>());
+        assert_eq!(builds[1].1, (OFFSET..OFFSET + 1000).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_pca() {
+        const NUM_COMPONENTS: usize = 24;
+        const K: usize = 10;
+
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        // Vectors lie close to the subspace of the first NUM_COMPONENTS axes.
+        let vectors = generate_random_array_with_seed::<Float32Type>(1000 * DIM, [7; 32])
+            .values()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                if i % DIM < NUM_COMPONENTS {
+                    *v
+                } else {
+                    *v * 0.01
+                }
+            })
+            .collect::<Float32Array>();
+        let (dataset, vector_array) = write_test_dataset(test_uri, vectors).await;
+
+        let centroids = generate_random_array_with_seed::<Float32Type>(2 * DIM, [8; 32]);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let mut ivf_params =
+            IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
+        // Keep the first NUM_COMPONENTS axes.
+        let components = (0..NUM_COMPONENTS)
+            .flat_map(|i| (0..DIM).map(move |j| if i == j { 1.0 } else { 0.0 }))
+            .collect::<Vec<f32>>();
+        ivf_params.pca = Some(
+            PcaMatrix::try_new(
+                FixedSizeListArray::try_new_from_values(Float32Array::from(components), DIM as i32)
+                    .unwrap(),
+                Float32Array::from(vec![0.0; DIM]),
+            )
+            .unwrap(),
+        );
+        let codebook = Arc::new(generate_random_array_with_seed::<Float32Type>(
+            256 * DIM,
+            [9; 32],
+        ));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "pca",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        let pca = ivf_index.pca().unwrap();
+        assert_eq!(pca.num_components(), NUM_COMPONENTS);
+
+        let query = vector_array.value(0);
+        let query = query.as_primitive::<Float32Type>();
+        let l2 = |v: &[f32]| {
+            v.iter()
+                .zip(query.values().iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+        };
+
+        // (row id, PQ distance, distance to the reconstructed vector, true distance)
+        let mut candidates = vec![];
+        for part_id in 0..ivf_index.ivf.num_partitions() {
+            if ivf_index.ivf.lengths[part_id] == 0 {
+                continue;
+            }
+            let part = ivf_index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let residual = sub(query, &ivf_index.ivf.centroids.value(part_id)).unwrap();
+            let pq_distances = pq_idx
+                .pq
+                .build_distance_table(&residual, pq_idx.code.as_ref().unwrap())
+                .unwrap();
+            let reduced = ivf_index.load_pca_vectors(part_id).await.unwrap().unwrap();
+            let reduced = reduced.values().as_primitive::<Float32Type>();
+
+            for (i, row_id) in pq_idx.row_ids.as_ref().unwrap().values().iter().enumerate() {
+                let original = vector_array.value(*row_id as usize);
+                let original = original.as_primitive::<Float32Type>().values();
+                let reconstructed = pca
+                    .reconstruct(&reduced.values()[i * NUM_COMPONENTS..(i + 1) * NUM_COMPONENTS]);
+                assert_eq!(
+                    &reconstructed[..NUM_COMPONENTS],
+                    &original[..NUM_COMPONENTS]
+                );
+                candidates.push((
+                    *row_id,
+                    pq_distances.value(i),
+                    l2(&reconstructed),
+                    l2(original),
+                ));
+            }
+        }
+        assert_eq!(candidates.len(), 1000);
+
+        let top_k = |key: fn(&(u64, f32, f32, f32)) -> f32| {
+            let mut sorted = candidates.clone();
+            sorted.sort_by(|a, b| key(a).total_cmp(&key(b)));
+            sorted.iter().take(K).map(|c| c.0).collect::<HashSet<_>>()
+        };
+        let expected = top_k(|c| c.3);
+        let pq_hits = top_k(|c| c.1).intersection(&expected).count();
+        let rerank_hits = top_k(|c| c.2).intersection(&expected).count();
+        assert!(
+            rerank_hits > pq_hits,
+            "rerank recall {} <= pq recall {}",
+            rerank_hits,
+            pq_hits
+        );
+    }
+
+    /// Mean squared error between each indexed residual and its PQ reconstruction.
+    async fn pq_reconstruction_error(index: &IVFIndex, vectors: &FixedSizeListArray) -> f32 {
+        let mut total = 0.0;
+        let mut count = 0;
+        for part_id in 0..index.ivf.num_partitions() {
+            if index.ivf.lengths[part_id] == 0 {
+                continue;
+            }
+            let part = index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let pq = pq_idx
+                .pq
+                .as_any()
+                .downcast_ref::<ProductQuantizerImpl<Float32Type>>()
+                .unwrap();
+            let centroid = index.ivf.centroids.value(part_id);
+            let centroid = centroid.as_primitive::<Float32Type>().values();
+            let codes = pq_idx.code.as_ref().unwrap().values();
+            let row_ids = pq_idx.row_ids.as_ref().unwrap().values();
+            for (row_id, code) in row_ids.iter().zip(codes.chunks_exact(pq.num_sub_vectors)) {
+                let vector = vectors.value(*row_id as usize);
+                let reconstructed = pq.reconstruct(code);
+                let decoded = index.decode_pq_code(part_id, code).unwrap();
+                centroid
+                    .iter()
+                    .zip(reconstructed.values().iter())
+                    .zip(decoded.iter())
+                    .for_each(|((c, r), d)| assert_relative_eq!(c + r, *d));
+                total += vector
+                    .as_primitive::<Float32Type>()
+                    .values()
+                    .iter()
+                    .zip(centroid.iter())
+                    .zip(reconstructed.values().iter())
+                    .map(|((v, c), r)| (v - c - r) * (v - c - r))
+                    .sum::<f32>();
+                count += 1;
+            }
+        }
+        total / count as f32
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_per_partition_pq() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        // Two clusters centered at `(3, 3, ...)` and `(-3, -3, ...)`, whose residuals lie on
+        // different lines within every sub-vector: `t * (1, 1, 1, ...)` and `t * (1, -1, 1, ...)`.
+        let ts = generate_random_array_with_seed::<Float32Type>(1000, [3; 32]);
+        let vectors = ts
+            .values()
+            .iter()
+            .enumerate()
+            .flat_map(|(i, t)| {
+                (0..DIM).map(move |j| {
+                    let t = t * 2.0 - 1.0;
+                    if i % 2 == 0 {
+                        3.0 + t
+                    } else if j % 2 == 0 {
+                        -3.0 + t
+                    } else {
+                        -3.0 - t
+                    }
+                })
+            })
+            .collect::<Float32Array>();
+        let (dataset, vector_array) = write_test_dataset(test_uri, vectors).await;
+
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(
+            Float32Array::from_iter_values((0..DIM).map(|_| 3.0).chain((0..DIM).map(|_| -3.0))),
+            DIM as i32,
+        )
+        .unwrap();
+        let pq_params = PQBuildParams::new(4, 8);
+
+        let mut indices = vec![];
+        for per_partition_pq in [false, true] {
+            let mut ivf_params =
+                IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids.clone())).unwrap();
+            ivf_params.per_partition_pq = per_partition_pq;
+            let uuid = Uuid::new_v4().to_string();
+            build_ivf_pq_index(
+                &dataset,
+                "vector",
+                "per_partition_pq",
+                &uuid,
+                MetricType::L2,
+                &ivf_params,
+                &pq_params,
+            )
+            .await
+            .unwrap();
+            indices.push(dataset.open_vector_index("vector", &uuid).await.unwrap());
+        }
+        let shared = indices[0].as_any().downcast_ref::<IVFIndex>().unwrap();
+        let per_partition = indices[1].as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert!(shared.ivf.pq_codebooks.is_empty());
+        assert_eq!(per_partition.ivf.pq_codebooks.len(), 2);
+        assert_eq!(per_partition.ivf.lengths, vec![500, 500]);
+        assert_ne!(
+            per_partition.ivf.pq_codebooks[0].values(),
+            per_partition.ivf.pq_codebooks[1].values()
+        );
+
+        let shared_error = pq_reconstruction_error(shared, &vector_array).await;
+        let per_partition_error = pq_reconstruction_error(per_partition, &vector_array).await;
+        assert!(
+            per_partition_error < shared_error * 0.8,
+            "per-partition error {} vs shared error {}",
+            per_partition_error,
+            shared_error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_build() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(4 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+        let mut ivf_params =
+            IvfBuildParams::try_with_centroids(4, Arc::new(ivf_centroids)).unwrap();
+
+        // Pause as soon as the first partition is written.
+        let control = BuildControl::new();
+        let num_written = Arc::new(AtomicUsize::new(0));
+        let (pausing, counter) = (control.clone(), num_written.clone());
+        ivf_params.on_partition_written = Some(Arc::new(move |_, _| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                pausing.pause();
+            }
+        }));
+        ivf_params.control = Some(control.clone());
+
+        let uuid = Uuid::new_v4().to_string();
+        let (handle, build) = build_ivf_pq_index_with_control(
+            &dataset,
+            "vector",
+            "paused",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        );
+        let check = async {
+            while !handle.is_paused() {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            assert_eq!(num_written.load(Ordering::SeqCst), 1);
+            handle.resume();
+        };
+        let (result, _) = futures::join!(build, check);
+        result.unwrap();
+        assert_eq!(num_written.load(Ordering::SeqCst), 4);
+        assert!(!control.is_paused());
+
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(ivf_index.ivf.lengths.iter().sum::<u32>(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_transposed_codes() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let centroids = generate_random_array(2 * DIM);
+        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+
+        let mut pages = vec![];
+        for code_storage_order in [CodeStorageOrder::Interleaved, CodeStorageOrder::Separate] {
+            let mut ivf_params =
+                IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids.clone())).unwrap();
+            ivf_params.code_storage_order = code_storage_order;
+            let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+            assert_eq!(ivf_index.ivf.code_storage_order, code_storage_order);
+
+            let mut partitions = vec![];
+            for part_id in 0..ivf_index.ivf.num_partitions() {
+                let part = ivf_index.load_partition(part_id, false).await.unwrap();
+                let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+                partitions.push((pq_idx.code.clone(), pq_idx.row_ids.clone()));
+            }
+            pages.push(partitions);
+        }
+
+        // Codes are loaded back in row-major layout.
+        assert_eq!(pages[0], pages[1]);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_presorted() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        // Rows of 16 clusters of identical vectors, in random order.
+        const NUM_ROWS: usize = 2000;
+        const NUM_CLUSTERS: usize = 16;
+        let centers = generate_random_array_with_seed::<Float32Type>(NUM_CLUSTERS * DIM, [7; 32]);
+        let mut rng = SmallRng::seed_from_u64(42);
+        let clusters = (0..NUM_ROWS)
+            .map(|_| rng.gen_range(0..NUM_CLUSTERS as i32))
+            .collect::<Vec<_>>();
+        let values = clusters
+            .iter()
+            .flat_map(|c| centers.values()[*c as usize * DIM..(*c as usize + 1) * DIM].to_vec())
+            .collect::<Float32Array>();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("cluster", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(FixedSizeListArray::try_new_from_values(values, DIM as i32).unwrap()),
+                Arc::new(Int32Array::from(clusters)),
+            ],
+        )
+        .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        let codebook = Arc::new(generate_random_array(256 * DIM));
+        let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
+        let mut sizes = vec![];
+        for presort_by in [None, Some("cluster".to_string())] {
+            let mut ivf_params = IvfBuildParams::new(2);
+            ivf_params.presort_by = presort_by;
+            let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+
+            let mut compressed_size = 0;
+            let mut row_ids = vec![];
+            for part_id in 0..ivf_index.ivf.num_partitions() {
+                let part = ivf_index.load_partition(part_id, false).await.unwrap();
+                let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+                let codes = pq_idx.code.as_ref().unwrap();
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                std::io::Write::write_all(&mut encoder, codes.values()).unwrap();
+                compressed_size += encoder.finish().unwrap().len();
+                row_ids.extend(pq_idx.row_ids.as_ref().unwrap().values().iter().copied());
+            }
+            row_ids.sort();
+            assert_eq!(row_ids, (0..NUM_ROWS as u64).collect::<Vec<_>>());
+            sizes.push(compressed_size);
+        }
+        assert!(
+            sizes[1] * 2 < sizes[0],
+            "presorted {} vs unsorted {} bytes",
+            sizes[1],
+            sizes[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_transposed_codebook() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+
+        let codebook = generate_random_array(256 * DIM);
+        let pq_params = PQBuildParams::with_codebook(4, 8, Arc::new(codebook.clone()));
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.transposed_codebook = true;
+        let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+        let transposed = ivf_index.transposed_codebook().unwrap();
+
+        // Same table as computed from the sub-vector-major codebook.
+        const WIDTH: usize = DIM / 4;
+        let query = generate_random_array(DIM);
+        let expected = query
+            .values()
+            .chunks_exact(WIDTH)
+            .zip(codebook.values().chunks_exact(256 * WIDTH))
+            .flat_map(|(sub_vec, centroids)| l2_distance_batch(sub_vec, centroids, WIDTH))
+            .collect::<Vec<_>>();
+        let table = transposed
+            .distance_table(query.values(), MetricType::L2)
+            .unwrap();
+        assert_relative_eq!(table.as_slice(), expected.as_slice(), epsilon = 1e-4);
+
+        ivf_params.per_partition_pq = true;
+        assert!(sanity_check_ivf_param(&ivf_params).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_global_stats() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.compute_global_stats = true;
+        let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+        let stats = ivf_index.global_stats().unwrap();
+        assert_eq!(stats.num_rows(), vectors.len() as u64);
+
+        let values = vectors.values().as_primitive::<Float32Type>().values();
+        let n = vectors.len() as f64;
+        for dim in 0..DIM {
+            let column = values.iter().skip(dim).step_by(DIM).map(|v| *v as f64);
+            let mean = column.clone().sum::<f64>() / n;
+            let variance = column.map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+            assert_relative_eq!(stats.mean()[dim], mean as f32, epsilon = 1e-5);
+            assert_relative_eq!(stats.variance()[dim], variance as f32, epsilon = 1e-5);
+        }
+
+        // Not stored unless requested.
+        let ivf_index = build_test_index_on(&dataset, &IvfBuildParams::new(2), &pq_params).await;
+        assert!(ivf_index.global_stats().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_custom_metadata() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let custom_metadata: HashMap<String, String> = [
+            ("source_version", dataset.version().version.to_string()),
+            ("build_sha", "0a1b2c3".to_string()),
+            ("empty", String::new()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.custom_metadata = custom_metadata.clone();
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "custom_metadata",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .unwrap();
+        let toc = read_index_toc(&dataset, &uuid).await.unwrap();
+        assert_eq!(toc.name, "custom_metadata");
+        assert_eq!(toc.columns, vec!["vector".to_string()]);
+        assert_eq!(toc.dataset_version, dataset.version().version);
+        assert_eq!(toc.custom_metadata, custom_metadata);
+
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "no_custom_metadata",
+            &uuid,
+            MetricType::L2,
+            &IvfBuildParams::new(2),
+            &pq_params,
+        )
+        .await
+        .unwrap();
+        let toc = read_index_toc(&dataset, &uuid).await.unwrap();
+        assert!(toc.custom_metadata.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_validate_assignment_consistency() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        // Two clusters, around 0.5 and 10.5.
+        const NUM_ROWS: usize = 1000;
+        let values = generate_random_array(NUM_ROWS * DIM)
+            .values()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| if i < NUM_ROWS / 2 * DIM { *v } else { v + 10.0 })
+            .collect::<Vec<_>>();
+        let (dataset, _) = write_test_dataset(test_uri, Float32Array::from(values)).await;
+        let mut centroids = vec![0.5; DIM];
+        centroids.extend(vec![10.5; DIM]);
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(Float32Array::from(centroids), DIM as i32)
+                .unwrap(),
+        );
+        // Codes for residuals around 0, -10 and +10, so that the vectors of rows stored
+        // in the wrong partition are still reconstructed.
+        let codebook = generate_random_array(256 * DIM)
+            .values()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v - 0.5 + [0.0, -10.0, 10.0][(i / (DIM / 4)) % 256 % 3])
+            .collect::<Vec<_>>();
+        let pq_params = PQBuildParams::with_codebook(4, 8, Arc::new(Float32Array::from(codebook)));
+        let ivf_params = IvfBuildParams::try_with_centroids(2, centroids.clone()).unwrap();
+
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "consistent",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        let pq = index.pq_sub_index().unwrap().pq.clone();
+        let report = validate_assignment_consistency(index, pq.as_ref(), 200, Some(42))
+            .await
+            .unwrap();
+        assert_eq!(report.num_checked, 200);
+        assert!(report.is_consistent(), "{:?}", report.violations);
+
+        // Store every row in the partition of the other cluster.
+        let mis_assigned = (0..NUM_ROWS as u64)
+            .map(|row_id| (row_id, (row_id < NUM_ROWS as u64 / 2) as u32))
+            .collect::<HashMap<_, _>>();
+        let uuid = Uuid::new_v4().to_string();
+        write_index_file(
+            &dataset,
+            "vector",
+            "mis_assigned",
+            &uuid,
+            &[],
+            Ivf::new(centroids),
+            pq.clone(),
+            MetricType::L2,
+            Some(mis_assigned),
+            None,
+            None,
+            &ivf_params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        let report = validate_assignment_consistency(index, pq.as_ref(), 2 * NUM_ROWS, Some(42))
+            .await
+            .unwrap();
+        assert_eq!(report.num_checked, NUM_ROWS);
+        assert_eq!(report.violations.len(), NUM_ROWS);
+        assert!(report.violations.iter().all(|v| {
+            v.partition_id == (v.row_id < NUM_ROWS as u64 / 2) as u32
+                && v.other_partition_id == 1 - v.partition_id
+        }));
+
+        // The same seed samples the same rows.
+        let sample = |seed| validate_assignment_consistency(index, pq.as_ref(), 100, Some(seed));
+        let report = sample(7).await.unwrap();
+        assert_eq!(report, sample(7).await.unwrap());
+        assert_ne!(report, sample(8).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_with_source() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "reconcile",
+            &uuid,
+            MetricType::L2,
+            &IvfBuildParams::new(2),
+            &PQBuildParams::new(4, 8),
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+        let mut scanner = dataset.scan();
+        scanner.project(&["vector"]).unwrap().with_row_id();
+        let source = scanner
+            .try_into_stream()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let report = reconcile_with_source(index, "vector", stream::iter(source.clone()).map(Ok))
+            .await
+            .unwrap();
+        assert!(report.is_reconciled(), "{:?}", report);
+        assert_eq!(report.num_source_rows, report.num_index_rows);
+
+        // A source row that was never indexed, and one without a vector.
+        let schema = source[0].schema();
+        let vectors = FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![Some(vec![Some(0.0); DIM]), None],
+            DIM as i32,
+        );
+        let not_indexed = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(vectors),
+                Arc::new(UInt64Array::from(vec![1 << 40, (1 << 40) + 1])),
+            ],
+        )
+        .unwrap();
+        let mut source = source.clone();
+        let first = source.remove(0);
+        source.push(first.slice(1, first.num_rows() - 1));
+        source.push(not_indexed);
+        let report = reconcile_with_source(index, "vector", stream::iter(source).map(Ok))
+            .await
+            .unwrap();
+        assert_eq!(report.missing, vec![1 << 40]);
+        assert_eq!(
+            report.extra,
+            vec![first[ROW_ID].as_primitive::<UInt64Type>().value(0)]
+        );
+        assert!(report.duplicated.is_empty());
+        assert_eq!(report.num_source_rows, report.num_index_rows);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_output_order() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let build = |ivf_params: IvfBuildParams| {
+            let dataset = &dataset;
+            async move {
+                let uuid = Uuid::new_v4().to_string();
+                build_ivf_pq_index(
+                    dataset,
+                    "vector",
+                    "ordered",
+                    &uuid,
+                    MetricType::L2,
+                    &ivf_params,
+                    &PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM))),
+                )
+                .await
+                .unwrap();
+                dataset.open_vector_index("vector", &uuid).await.unwrap()
+            }
+        };
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let ivf_params = IvfBuildParams::try_with_centroids(4, centroids).unwrap();
+        let in_id_order = build(ivf_params.clone()).await;
+        let in_id_order = in_id_order.as_any().downcast_ref::<IVFIndex>().unwrap();
+        let mut ivf_params = ivf_params;
+        ivf_params.output_order = Some(vec![3, 1, 0, 2]);
+        let ordered = build(ivf_params).await;
+        let ordered = ordered.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+        let stored_order = ordered
+            .scan_all_codes()
+            .unwrap()
+            .map_ok(|batch| batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let expected = [3, 1, 0, 2]
+            .into_iter()
+            .filter(|&part_id| ordered.ivf.lengths[part_id as usize] > 0)
+            .collect::<Vec<_>>();
+        assert_eq!(stored_order, expected);
+
+        assert_eq!(ordered.ivf.lengths, in_id_order.ivf.lengths);
+        for part_id in 0..4 {
+            let part_ids = [part_id];
+            let mut row_ids = vec![];
+            for index in [ordered, in_id_order] {
+                let partition = index
+                    .read_partitions(&part_ids)
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                let mut ids = partition
+                    .iter()
+                    .flat_map(|batch| batch[ROW_ID].as_primitive::<UInt64Type>().values().to_vec())
+                    .collect::<Vec<_>>();
+                ids.sort_unstable();
+                row_ids.push(ids);
+            }
+            assert_eq!(row_ids[0], row_ids[1]);
+        }
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.output_order = Some(vec![0, 1, 2]);
+        let uuid = Uuid::new_v4().to_string();
+        assert!(build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "ordered",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &PQBuildParams::new(4, 8),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_from_index() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let build = |ivf_params: IvfBuildParams| {
+            let dataset = &dataset;
+            async move {
+                let uuid = Uuid::new_v4().to_string();
+                build_ivf_pq_index(
+                    dataset,
+                    "vector",
+                    "rebuild",
+                    &uuid,
+                    MetricType::L2,
+                    &ivf_params,
+                    &PQBuildParams::new(8, 8),
+                )
+                .await
+                .unwrap();
+                uuid
+            }
+        };
+        let open = |uuid: String| {
+            let dataset = &dataset;
+            async move { dataset.open_vector_index("vector", &uuid).await.unwrap() }
+        };
+        let old_uuid = build(IvfBuildParams::new(2)).await;
+        let old = open(old_uuid).await;
+        let old = old.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let ivf_params = IvfBuildParams::try_with_centroids(4, centroids).unwrap();
+        let source_uuid = build(ivf_params.clone()).await;
+        let from_source = open(source_uuid.clone()).await;
+        let from_source = from_source.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+        let rebuilt_uuid = Uuid::new_v4().to_string();
+        let pq = from_source.pq_sub_index().unwrap().pq.clone();
+        rebuild_from_index(
+            &dataset,
+            old,
+            "vector",
+            "rebuild",
+            &rebuilt_uuid,
+            &ivf_params,
+            pq.clone(),
+        )
+        .await
+        .unwrap();
+        let rebuilt = open(rebuilt_uuid.clone()).await;
+        let rebuilt = rebuilt.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(rebuilt.ivf.num_partitions(), 4);
+        assert_eq!(
+            rebuilt.ivf.lengths.iter().sum::<u32>(),
+            from_source.ivf.lengths.iter().sum::<u32>()
+        );
+
+        // Probing every partition, the results differ only by the loss of the codes.
+        let shared = Arc::new(dataset.clone());
+        let mut results = vec![];
+        for (index, uuid) in [(from_source, &source_uuid), (rebuilt, &rebuilt_uuid)] {
+            let index_meta = crate::format::Index {
+                uuid: Uuid::parse_str(uuid).unwrap(),
+                dataset_version: 0,
+                fields: Vec::new(),
+                name: "rebuild".to_string(),
+                fragment_bitmap: None,
+            };
+            let prefilter = Arc::new(PreFilter::new(shared.clone(), index_meta, None));
+            let mut found = vec![];
+            for i in (0..vectors.len()).step_by(50) {
+                let query = Query {
+                    column: "vector".to_string(),
+                    key: Arc::new(vectors.value(i).as_primitive::<Float32Type>().clone()),
+                    k: 10,
+                    nprobes: 4,
+                    refine_factor: None,
+                    metric_type: MetricType::L2,
+                    use_index: true,
+                };
+                let batch = index.search(&query, prefilter.clone()).await.unwrap();
+                assert_eq!(batch.num_rows(), 10);
+                found.push(
+                    batch[ROW_ID]
+                        .as_primitive::<UInt64Type>()
+                        .values()
+                        .iter()
+                        .copied()
+                        .collect::<HashSet<_>>(),
+                );
+            }
+            results.push(found);
+        }
+        let overlap = results[0]
+            .iter()
+            .zip(results[1].iter())
+            .map(|(expected, found)| found.intersection(expected).count())
+            .sum::<usize>();
+        let overlap = overlap as f32 / (20 * 10) as f32;
+        assert!(overlap >= 0.5, "{}", overlap);
+
+        let err = rebuild_from_index(
+            &dataset,
+            old,
+            "vector",
+            "rebuild",
+            &Uuid::new_v4().to_string(),
+            &IvfBuildParams::new(4),
+            pq,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("requires the new IVF centroids"));
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_too_few_training_rows() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        const NUM_ROWS: usize = 100;
+        let (dataset, _) =
+            write_test_dataset(test_uri, generate_random_array(NUM_ROWS * DIM)).await;
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let build = |min_training_rows_per_partition| {
+            let mut ivf_params = IvfBuildParams::new(10);
+            ivf_params.min_training_rows_per_partition = min_training_rows_per_partition;
+            let (dataset, pq_params) = (&dataset, &pq_params);
+            async move {
+                build_ivf_pq_index(
+                    dataset,
+                    "vector",
+                    "min_training_rows",
+                    &Uuid::new_v4().to_string(),
+                    MetricType::L2,
+                    &ivf_params,
+                    pq_params,
+                )
+                .await
+            }
+        };
+
+        let err = build(16).await.unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "IVF training needs at least 160 vectors (16 per partition for 10 partitions), \
+                 but only 100 are available"
+            ),
+            "{}",
+            err
+        );
+        build(8).await.unwrap();
+        assert!(build(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_packaged_index() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let dataset = Arc::new(dataset);
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let query = Query {
+            column: "vector".to_string(),
+            key: Arc::new(vectors.value(7).as_primitive::<Float32Type>().clone()),
+            k: 10,
+            nprobes: 2,
+            refine_factor: None,
+            metric_type: MetricType::L2,
+            use_index: true,
+        };
+
+        for format in [PackageFormat::Tar, PackageFormat::TarGz] {
+            let mut ivf_params = IvfBuildParams::new(2);
+            ivf_params.package = Some(format);
+            let uuid = Uuid::new_v4().to_string();
+            build_ivf_pq_index(
+                &dataset,
+                "vector",
+                "packaged",
+                &uuid,
+                MetricType::L2,
+                &ivf_params,
+                &pq_params,
+            )
+            .await
+            .unwrap();
+            let index_meta = crate::format::Index {
+                uuid: Uuid::parse_str(&uuid).unwrap(),
+                dataset_version: 0,
+                fields: Vec::new(),
+                name: "packaged".to_string(),
+                fragment_bitmap: None,
+            };
+            let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+            let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+            let expected = index.search(&query, prefilter.clone()).await.unwrap();
+
+            // Only the package is left to query.
+            let index_dir = dataset.indices_dir().child(uuid.as_str());
+            dataset
+                .object_store()
+                .delete(&index_dir.child(INDEX_FILE_NAME))
+                .await
+                .unwrap();
+            let packaged = open_packaged_index(&dataset, "vector", &uuid, format)
+                .await
+                .unwrap();
+            let results = packaged.search(&query, prefilter).await.unwrap();
+            assert_eq!(results, expected);
+            assert_eq!(results.num_rows(), 10);
+
+            assert!(
+                open_packaged_index(&dataset, "vector", &Uuid::new_v4().to_string(), format)
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_valid_column() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        // Every third row is soft-deleted.
+        const NUM_ROWS: usize = 1000;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("deleted", DataType::Boolean, true),
+        ]));
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        let deleted = BooleanArray::from_iter((0..NUM_ROWS).map(|i| Some(i % 3 == 0)));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(vectors), Arc::new(deleted)])
+                .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.valid_column = Some("deleted".to_string());
+        let pq_params = PQBuildParams::new(4, 8);
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "valid",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .unwrap();
+
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert!(ivf_index.ivf.has_valid);
+
+        let mut num_rows = 0;
+        for part_id in 0..ivf_index.ivf.num_partitions() {
+            let part = ivf_index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let row_ids = pq_idx.row_ids.as_ref().unwrap();
+            let valid = ivf_index.load_valid(part_id).await.unwrap().unwrap();
+            assert_eq!(valid.len(), row_ids.len());
+            row_ids
+                .values()
+                .iter()
+                .zip(valid.iter())
+                .for_each(|(row_id, v)| {
+                    assert_eq!(v, Some(row_id % 3 != 0), "row id {}", row_id);
+                });
+            num_rows += row_ids.len();
+        }
+        assert_eq!(num_rows, NUM_ROWS);
+
+        // The valid column of the indexed rows can not be appended to.
+        let mut scanner = dataset.scan();
+        scanner.project(&["vector"]).unwrap().with_row_id();
+        let metadata = IndexMetadata {
+            uuid: Uuid::parse_str(&uuid).unwrap(),
+            fields: vec![0],
+            name: "valid".to_string(),
+            dataset_version: dataset.version().version,
+            fragment_bitmap: None,
+        };
+        let err = ivf_index
+            .append(
+                &dataset,
+                scanner.try_into_stream().await.unwrap(),
+                &metadata,
+                "vector",
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotSupported { .. }), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_subgroup_column() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        const NUM_ROWS: usize = 1000;
+        const LANGUAGES: [&str; 3] = ["en", "fr", "de"];
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("language", DataType::Utf8, true),
+            Field::new("deleted", DataType::Boolean, true),
+        ]));
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        let languages = StringArray::from_iter_values((0..NUM_ROWS).map(|i| LANGUAGES[i % 3]));
+        let deleted = BooleanArray::from_iter((0..NUM_ROWS).map(|i| Some(i % 2 == 0)));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(vectors), Arc::new(languages), Arc::new(deleted)],
+        )
+        .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.subgroup_column = Some("language".to_string());
+        ivf_params.valid_column = Some("deleted".to_string());
+        let pq_params = PQBuildParams::new(4, 8);
+        let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+        let mut num_rows = 0;
+        for part_id in 0..ivf_index.ivf.num_partitions() as u32 {
+            // The valid column is sorted with the rows.
+            let part = ivf_index
+                .load_partition(part_id as usize, false)
+                .await
+                .unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let row_ids = pq_idx.row_ids.as_ref().unwrap();
+            let valid = ivf_index
+                .load_valid(part_id as usize)
+                .await
+                .unwrap()
+                .unwrap();
+            for (row_id, v) in row_ids.values().iter().zip(valid.iter()) {
+                assert_eq!(v, Some(row_id % 2 != 0), "row id {}", row_id);
+            }
+
+            let mut partition_rows = 0;
+            for (i, language) in LANGUAGES.iter().enumerate() {
+                let part_ids = [part_id];
+                let batches = ivf_index
+                    .read_partitions_in_subgroup(&part_ids, Some(language))
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                let row_ids = batches[0][ROW_ID].as_primitive::<UInt64Type>();
+                assert!(row_ids.values().iter().all(|id| *id as usize % 3 == i));
+                partition_rows += row_ids.len();
+            }
+            assert_eq!(partition_rows, row_ids.len());
+            num_rows += partition_rows;
+        }
+        assert_eq!(num_rows, NUM_ROWS);
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.subgroup_column = Some("language".to_string());
+        ivf_params.code_storage_order = CodeStorageOrder::Separate;
+        let result = build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "subgroup",
+            &Uuid::new_v4().to_string(),
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_assignment_margin() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        const NUM_ROWS: usize = 1000;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("deleted", DataType::Boolean, true),
+        ]));
+        let values = generate_random_array(NUM_ROWS * DIM);
+        let vectors = FixedSizeListArray::try_new_from_values(values.clone(), DIM as i32).unwrap();
+        let deleted = BooleanArray::from_iter((0..NUM_ROWS).map(|i| Some(i % 3 == 0)));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(vectors), Arc::new(deleted)])
+                .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        // The margins are stored after the valid column.
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.valid_column = Some("deleted".to_string());
+        ivf_params.assignment_margin = true;
+        let pq_params = PQBuildParams::new(4, 8);
+        let ivf_index = build_test_index_on(&dataset, &ivf_params, &pq_params).await;
+        let centroids = ivf_index
+            .ivf
+            .centroids
+            .values()
+            .as_primitive::<Float32Type>();
+
+        let mut num_rows = 0;
+        for part_id in 0..ivf_index.ivf.num_partitions() {
+            let part = ivf_index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let row_ids = pq_idx.row_ids.as_ref().unwrap();
+            let margins = ivf_index
+                .load_assignment_margins(part_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(margins.len(), row_ids.len());
+            for (&row_id, &margin) in row_ids.values().iter().zip(margins.values().iter()) {
+                assert!((0.0..=1.0).contains(&margin), "margin {}", margin);
+
+                let row_id = row_id as usize;
+                let vector = &values.values()[row_id * DIM..(row_id + 1) * DIM];
+                let mut distances = centroids
+                    .values()
+                    .chunks_exact(DIM)
+                    .map(|c| l2(vector, c))
+                    .collect::<Vec<_>>();
+                distances.sort_by(|a, b| a.total_cmp(b));
+                assert_relative_eq!(margin, 1.0 - distances[0] / distances[1], epsilon = 1e-4);
+            }
+            num_rows += row_ids.len();
+        }
+        assert_eq!(num_rows, NUM_ROWS);
+        // Still readable with the margins stored after it.
+        assert!(ivf_index.load_valid(0).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_post_shuffle_transform_extra_columns() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        // The extra columns are stored after the assignment margins.
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.assignment_margin = true;
+        ivf_params.post_shuffle_transform = Some(Arc::new(|part_id, batch: RecordBatch| {
+            let num_rows = batch.num_rows();
+            let batch = batch.try_with_column(
+                Field::new("constant", DataType::UInt64, false),
+                Arc::new(UInt64Array::from_value(42, num_rows)),
+            )?;
+            Ok(batch.try_with_column(
+                Field::new("part", DataType::UInt32, false),
+                Arc::new(UInt32Array::from_value(part_id, num_rows)),
+            )?)
+        }));
+        let (_dataset, ivf_index) = build_test_index(test_uri, &ivf_params).await;
+        let mut num_rows = 0;
+        for part_id in 0..ivf_index.ivf.num_partitions() {
+            let length = ivf_index.ivf.lengths[part_id] as usize;
+            let constant = ivf_index
+                .load_extra_column(part_id, "constant")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                constant.as_any().downcast_ref::<UInt64Array>().unwrap(),
+                &UInt64Array::from_value(42, length)
+            );
+            let part = ivf_index
+                .load_extra_column(part_id, "part")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                part.as_any().downcast_ref::<UInt32Array>().unwrap(),
+                &UInt32Array::from_value(part_id as u32, length)
+            );
+            let margins = ivf_index
+                .load_assignment_margins(part_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(margins.values().iter().all(|m| (0.0..=1.0).contains(m)));
+            num_rows += length;
+        }
+        assert_eq!(num_rows, 1000);
+        assert!(ivf_index
+            .load_extra_column(0, "missing")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_over_time_window() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        const NUM_ROWS: usize = 1000;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("ts", DataType::Timestamp(TimeUnit::Microsecond, None), true),
+        ]));
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        let timestamps =
+            TimestampMicrosecondArray::from_iter_values((0..NUM_ROWS as i64).map(|i| i * 1000));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(vectors), Arc::new(timestamps)],
+        )
+        .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+
+        let window = TimeWindow::last("ts", NUM_ROWS as i64 * 1000, 300 * 1000);
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.time_window = Some(window.clone());
+        let ivf_index = build_test_index_on(&dataset, &ivf_params, &PQBuildParams::new(4, 8)).await;
+        assert_eq!(ivf_index.time_window(), Some(&window));
+        assert_eq!(
+            indexed_row_ids(&ivf_index).await,
+            (700..NUM_ROWS as u64).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_tree() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let mut ivf_params = IvfBuildParams::new(16);
+        ivf_params.num_coarse_partitions = Some(4);
+        let (_dataset, ivf_index) = build_test_index(test_uri, &ivf_params).await;
+        let tree = ivf_index.ivf.tree.as_ref().unwrap();
+        assert!(tree.num_groups() <= 4);
+        assert_eq!(tree.group_ids().len(), 16);
+        assert_eq!(ivf_index.ivf.lengths.iter().sum::<u32>(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_medoids() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.compute_medoids = true;
+        let (dataset, ivf_index) = build_test_index(test_uri, &ivf_params).await;
+        let projection = dataset.schema().project(&["vector"]).unwrap();
+        for part_id in 0..ivf_index.ivf.num_partitions() {
+            let part = ivf_index.load_partition(part_id, false).await.unwrap();
+            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+            let row_ids = pq_idx.row_ids.as_ref().unwrap().values().to_vec();
+
+            let batch = dataset.take_rows(&row_ids, &projection).await.unwrap();
+            let vectors = batch["vector"].as_fixed_size_list();
+            let centroid = ivf_index.ivf.centroids.value(part_id);
+            let distances = l2_distance_batch(
+                centroid.as_primitive::<Float32Type>().values(),
+                vectors.values().as_primitive::<Float32Type>().values(),
+                DIM,
+            );
+            let closest = distances
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(idx, _)| row_ids[idx]);
+            assert_eq!(ivf_index.medoid(part_id), closest);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_estimate_recall_curve() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+
+        let ivf_index =
+            build_test_index_on(&dataset, &IvfBuildParams::new(8), &PQBuildParams::new(4, 8)).await;
+
+        let queries = vectors.slice(0, 10);
+        let values = vectors.values().as_primitive::<Float32Type>().values();
+        let ground_truth = (0..queries.len())
+            .map(|i| {
+                let query = &values[i * DIM..(i + 1) * DIM];
+                let mut distances = l2_distance_batch(query, values, DIM)
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+                distances.iter().take(10).map(|(i, _)| *i as u64).collect()
+            })
+            .collect::<Vec<Vec<u64>>>();
+
+        let curve = estimate_recall_curve(&ivf_index, &queries, &ground_truth, &[1, 2, 4, 8])
+            .await
+            .unwrap();
+        assert_eq!(
+            curve.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![1, 2, 4, 8]
+        );
+        for window in curve.windows(2) {
+            assert!(window[0].1 <= window[1].1, "{:?}", curve);
+        }
+        // Probing every partition finds all the rows.
+        assert_eq!(curve[3].1, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_multi_assign() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let dataset = Arc::new(dataset);
+
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(8 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let pq_params = PQBuildParams::new(4, 8);
+        let queries = vectors.slice(0, 20);
+        let values = vectors.values().as_primitive::<Float32Type>().values();
+        let ground_truth = (0..queries.len())
+            .map(|i| {
+                let query = &values[i * DIM..(i + 1) * DIM];
+                let mut distances = l2_distance_batch(query, values, DIM)
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+                distances.iter().take(10).map(|(i, _)| *i as u64).collect()
+            })
+            .collect::<Vec<Vec<u64>>>();
+
+        let mut recalls = vec![];
+        for multi_assign in [1, 2] {
+            let mut ivf_params = IvfBuildParams::try_with_centroids(8, centroids.clone()).unwrap();
+            ivf_params.multi_assign = multi_assign;
+            let uuid = Uuid::new_v4().to_string();
+            build_ivf_pq_index(
+                &dataset,
+                "vector",
+                "multi_assign",
+                &uuid,
+                MetricType::L2,
+                &ivf_params,
+                &pq_params,
+            )
+            .await
+            .unwrap();
+            let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+            let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+            // Each row is in `multi_assign` distinct partitions.
+            let mut partitions_of_row = HashMap::<u64, HashSet<u32>>::new();
+            let mut num_rows = 0;
+            let part_ids = (0..8).collect::<Vec<u32>>();
+            let mut partitions = Box::pin(ivf_index.read_partitions(&part_ids).unwrap());
+            for part_id in part_ids.iter() {
+                let batch = partitions.try_next().await.unwrap().unwrap();
+                for row_id in batch[ROW_ID].as_primitive::<UInt64Type>().values() {
+                    partitions_of_row
+                        .entry(*row_id)
+                        .or_default()
+                        .insert(*part_id);
+                    num_rows += 1;
+                }
+            }
+            assert_eq!(num_rows, vectors.len() * multi_assign);
+            assert_eq!(partitions_of_row.len(), vectors.len());
+            assert!(partitions_of_row.values().all(|p| p.len() == multi_assign));
+
+            // Searches return each row once, and probe more of the nearest rows.
+            let index_meta = crate::format::Index {
+                uuid: Uuid::parse_str(&uuid).unwrap(),
+                dataset_version: 0,
+                fields: Vec::new(),
+                name: "multi_assign".to_string(),
+                fragment_bitmap: None,
+            };
+            let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+            for i in 0..queries.len() {
+                let query = Query {
+                    column: "vector".to_string(),
+                    key: Arc::new(queries.value(i).as_primitive::<Float32Type>().clone()),
+                    k: 10,
+                    nprobes: 1,
+                    refine_factor: None,
+                    metric_type: MetricType::L2,
+                    use_index: true,
+                };
+                let results = ivf_index.search(&query, prefilter.clone()).await.unwrap();
+                let found = results[ROW_ID]
+                    .as_primitive::<UInt64Type>()
+                    .values()
+                    .iter()
+                    .copied()
+                    .collect::<HashSet<_>>();
+                assert_eq!(found.len(), results.num_rows());
+            }
+            let curve = estimate_recall_curve(ivf_index, &queries, &ground_truth, &[1])
+                .await
+                .unwrap();
+            recalls.push(curve[0].1);
+        }
+        assert!(recalls[1] > recalls[0], "{:?}", recalls);
+
+        let mut ivf_params = IvfBuildParams::new(8);
+        ivf_params.multi_assign = 9;
+        assert!(build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "multi_assign",
+            &Uuid::new_v4().to_string(),
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_density_sampling_covers_sparse_cluster() {
+        const NUM_DENSE: usize = 16376;
+        const NUM_SPARSE: usize = 8;
+        const SAMPLE_RATE: usize = 64;
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        // A dense cluster in the unit cube, and a sparse one far from it.
+        let mut values = generate_random_array_with_seed::<Float32Type>(NUM_DENSE * DIM, [51; 32])
+            .values()
+            .to_vec();
+        let sparse = generate_random_array_with_seed::<Float32Type>(NUM_SPARSE * DIM, [52; 32]);
+        values.extend(sparse.values().iter().map(|v| 3.0 + 0.1 * v));
+        let (dataset, vectors) = write_test_dataset(test_uri, Float32Array::from(values)).await;
+        let is_sparse = |vector: ArrayRef| {
+            vector
+                .as_primitive::<Float32Type>()
+                .values()
+                .iter()
+                .all(|v| *v > 2.0)
+        };
+
+        // The k-means sample of 2 centroids holds about one sparse vector if uniform.
+        let sample_size = 2 * SAMPLE_RATE;
+        let mut rng = SmallRng::seed_from_u64(53);
+        let uniform = (0..vectors.len())
+            .choose_multiple(&mut rng, sample_size)
+            .into_iter()
+            .filter(|i| is_sparse(vectors.value(*i)))
+            .count();
+        assert!(uniform <= 1, "{}", uniform);
+
+        // The training sample of the dataset over-samples the sparse cluster, and
+        // under-samples the dense one, with a reproducible sample for a seed. The
+        // candidates are the uniform sample of the build, all the rows here.
+        let dataset_ref = &dataset;
+        let training_sample = |seed| async move {
+            maybe_sample_training_data(
+                dataset_ref,
+                "vector",
+                256 * SAMPLE_RATE,
+                Some(sample_size),
+                &mut SmallRng::seed_from_u64(seed),
+            )
+            .await
+            .unwrap()
+        };
+        let sample = training_sample(54).await;
+        assert_eq!(sample.len(), sample_size);
+        let weighted = (0..sample.len())
+            .filter(|i| is_sparse(sample.value(*i)))
+            .count();
+        assert!(weighted >= 4, "{}", weighted);
+        assert!(sample_size - weighted < sample_size * NUM_DENSE / (NUM_DENSE + NUM_SPARSE));
+        assert_eq!(sample, training_sample(54).await);
+
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.sample_rate = SAMPLE_RATE;
+        ivf_params.density_sampling = true;
+        ivf_params.seed = Some(55);
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "density",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM))),
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let centroids = &index
+            .as_any()
+            .downcast_ref::<IVFIndex>()
+            .unwrap()
+            .ivf
+            .centroids;
+        assert!((0..centroids.len()).any(|i| is_sparse(centroids.value(i))));
+    }
+
+    #[tokio::test]
+    async fn test_scan_all_codes() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+
+        let ivf_index =
+            build_test_index_on(&dataset, &IvfBuildParams::new(8), &PQBuildParams::new(4, 8)).await;
+
+        let batches = ivf_index
+            .scan_all_codes()
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut row_ids = HashSet::<u64>::new();
+        let mut num_rows = 0;
+        let mut last_offset = None;
+        for batch in batches.iter() {
+            let part_id = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0) as usize;
+            assert_eq!(batch.num_rows(), ivf_index.ivf.lengths[part_id] as usize);
+            assert_eq!(batch[PQ_CODE_COLUMN].as_fixed_size_list().value_length(), 4);
+            // In file order.
+            let offset = ivf_index.ivf.offsets[part_id];
+            assert!(last_offset < Some(offset));
+            last_offset = Some(offset);
+
+            row_ids.extend(batch[ROW_ID].as_primitive::<UInt64Type>().values().iter());
+            num_rows += batch.num_rows();
+        }
+        assert_eq!(num_rows, vectors.len());
+        assert_eq!(row_ids.len(), vectors.len());
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_index_with_mask() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let mask = BooleanArray::from_iter((0..1000).map(|i| Some(i % 2 == 0)));
+        let uuid = Uuid::new_v4().to_string();
+        let report = build_ivf_pq_index_with_mask(
+            &dataset,
+            "vector",
+            "masked",
+            &uuid,
+            MetricType::L2,
+            &IvfBuildParams::new(4),
+            &PQBuildParams::new(4, 8),
+            RowMask::Whole(mask),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.num_rows, 500);
+
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(
+            indexed_row_ids(ivf_index).await,
+            (0..1000).step_by(2).collect::<Vec<u64>>()
+        );
+    }
+
+    /// In-memory [IndexTxn] that fails to stage more than `capacity` bytes.
+    struct MockTxn {
+        staged: Vec<u8>,
+        visible: Vec<u8>,
+        capacity: usize,
+        num_rollbacks: usize,
+    }
+
+    impl MockTxn {
+        fn with_capacity(capacity: usize) -> Self {
+            Self {
+                staged: vec![],
+                visible: vec![],
+                capacity,
+                num_rollbacks: 0,
+            }
+        }
+    }
+
+    impl IndexTxn for MockTxn {
+        fn stage(&mut self, bytes: &[u8]) -> Result<()> {
+            if self.staged.len() + bytes.len() > self.capacity {
+                return Err(Error::IO {
+                    message: "transaction is full".to_string(),
+                    location: location!(),
+                });
+            }
+            self.staged.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            self.visible.append(&mut self.staged);
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<()> {
+            self.staged.clear();
+            self.num_rollbacks += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_index_in_txn() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
 
-        let schema = Arc::new(
-            Schema::new(vec![Field::new(
-                "vector",
-                DataType::FixedSizeList(
-                    Arc::new(Field::new("item", DataType::Float32, true)),
-                    DIM as i32,
-                ),
-                true,
-            )])
-            .with_metadata(metadata),
+        let (dataset, _) = generate_test_dataset(test_uri).await;
+        let ivf_params = IvfBuildParams::new(4);
+        let pq_params = PQBuildParams::new(4, 8);
+        let build = |mut txn: MockTxn| {
+            let (dataset, ivf_params, pq_params) = (&dataset, &ivf_params, &pq_params);
+            async move {
+                let uuid = Uuid::new_v4().to_string();
+                let result = build_ivf_pq_index_in_txn(
+                    dataset,
+                    "vector",
+                    "txn",
+                    &uuid,
+                    MetricType::L2,
+                    ivf_params,
+                    pq_params,
+                    &mut txn,
+                )
+                .await;
+                // Nothing is written to the dataset.
+                let path = dataset.indices_dir().child(uuid.as_str());
+                assert!(!dataset
+                    .object_store()
+                    .exists(&path.child(INDEX_FILE_NAME))
+                    .await
+                    .unwrap());
+                (result.map(|report| (uuid, report)), txn)
+            }
+        };
+
+        // The whole index file is committed.
+        let (result, txn) = build(MockTxn::with_capacity(usize::MAX)).await;
+        let (uuid, report) = result.unwrap();
+        assert_eq!(report.num_rows, 1000);
+        assert!(txn.staged.is_empty());
+        assert_eq!(txn.num_rollbacks, 0);
+        let path = dataset
+            .indices_dir()
+            .child(uuid.as_str())
+            .child(INDEX_FILE_NAME);
+        dataset
+            .object_store()
+            .put(&path, &txn.visible)
+            .await
+            .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(
+            indexed_row_ids(ivf_index).await,
+            (0..1000).collect::<Vec<_>>()
         );
-        let array = Arc::new(FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap());
-        let batch = RecordBatch::try_new(schema.clone(), vec![array.clone()]).unwrap();
 
-        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
-        let dataset = Dataset::write(batches, test_uri, None).await.unwrap();
-        (dataset, array)
+        // A failed build is rolled back.
+        let (result, txn) = build(MockTxn::with_capacity(1024)).await;
+        assert!(result.is_err());
+        assert!(txn.staged.is_empty());
+        assert!(txn.visible.is_empty());
+        assert_eq!(txn.num_rollbacks, 1);
     }
 
     #[tokio::test]
-    async fn test_create_ivf_pq_with_centroids() {
+    async fn test_append_with_assignment_accelerator() {
+        const NUM_PARTITIONS: usize = 64;
         let test_dir = tempdir().unwrap();
         let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, _) = write_test_dataset(
+            test_uri,
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM, [21; 32]),
+        )
+        .await;
 
-        let (mut dataset, vector_array) = generate_test_dataset(test_uri).await;
-
-        let centroids = generate_random_array(2 * DIM);
-        let ivf_centroids = FixedSizeListArray::try_new_from_values(centroids, DIM as i32).unwrap();
-        let ivf_params = IvfBuildParams::try_with_centroids(2, Arc::new(ivf_centroids)).unwrap();
-
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [22; 32]),
+                DIM as i32,
+            )
+            .unwrap(),
+        );
         let codebook = Arc::new(generate_random_array(256 * DIM));
         let pq_params = PQBuildParams::with_codebook(4, 8, codebook);
 
-        let params = VectorIndexParams::with_ivf_pq_params(MetricType::L2, ivf_params, pq_params);
+        let mut partitions = vec![];
+        for store_assignment_accelerator in [true, false] {
+            let mut ivf_params =
+                IvfBuildParams::try_with_centroids(NUM_PARTITIONS, centroids.clone()).unwrap();
+            ivf_params.store_assignment_accelerator = store_assignment_accelerator;
+            let uuid = Uuid::new_v4().to_string();
+            build_ivf_pq_index(
+                &dataset,
+                "vector",
+                "accelerated",
+                &uuid,
+                MetricType::L2,
+                &ivf_params,
+                &pq_params,
+            )
+            .await
+            .unwrap();
+            let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+            let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+            assert_eq!(
+                ivf_index
+                    .ivf
+                    .tree
+                    .as_ref()
+                    .map(|tree| tree.radii().is_some()),
+                store_assignment_accelerator.then_some(true)
+            );
 
-        dataset
-            .create_index(&["vector"], IndexType::Vector, None, &params, false)
+            // Append the rows of the dataset once more.
+            let mut scanner = dataset.scan();
+            scanner.project(&["vector"]).unwrap().with_row_id();
+            let metadata = IndexMetadata {
+                uuid: Uuid::parse_str(&uuid).unwrap(),
+                fields: vec![0],
+                name: "accelerated".to_string(),
+                dataset_version: dataset.version().version,
+                fragment_bitmap: None,
+            };
+            let new_uuid = ivf_index
+                .append(
+                    &dataset,
+                    scanner.try_into_stream().await.unwrap(),
+                    &metadata,
+                    "vector",
+                )
+                .await
+                .unwrap();
+            let index = dataset
+                .open_vector_index("vector", &new_uuid.to_string())
+                .await
+                .unwrap();
+            let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+            assert_eq!(ivf_index.ivf.tree.is_some(), store_assignment_accelerator);
+            let mut row_ids = vec![];
+            for part_id in 0..ivf_index.ivf.num_partitions() {
+                let part = ivf_index.load_partition(part_id, false).await.unwrap();
+                let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+                let mut ids = pq_idx.row_ids.as_ref().unwrap().values().to_vec();
+                ids.sort();
+                row_ids.push(ids);
+            }
+            // The appended rows are merged with the indexed ones.
+            assert_eq!(row_ids.iter().map(|ids| ids.len()).sum::<usize>(), 2000);
+            partitions.push(row_ids);
+        }
+        // The accelerated assignment is the same as comparing with every centroid.
+        assert_eq!(partitions[0], partitions[1]);
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.store_assignment_accelerator = true;
+        assert!(build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "accelerated",
+            &Uuid::new_v4().to_string(),
+            MetricType::Dot,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merge_delta_index() {
+        check_merge_delta_index(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_merge_delta_index_with_packed_codes() {
+        check_merge_delta_index(true).await;
+    }
+
+    async fn check_merge_delta_index(dual_code_layout: bool) {
+        const NUM_ROWS: usize = 1000;
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, _) = write_test_dataset(
+            test_uri,
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [29; 32]),
+        )
+        .await;
+        let dataset = Arc::new(dataset);
+
+        let centroids = generate_random_array_with_seed::<Float32Type>(4 * DIM, [31; 32]);
+        let pq_params =
+            PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)));
+        let mut ivf_params = IvfBuildParams::try_with_centroids(
+            4,
+            Arc::new(
+                FixedSizeListArray::try_new_from_values(centroids.clone(), DIM as i32).unwrap(),
+            ),
+        )
+        .unwrap();
+        ivf_params.dual_code_layout = dual_code_layout;
+        let base_uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "base",
+            &base_uuid,
+            MetricType::L2,
+            &ivf_params,
+            &pq_params,
+        )
+        .await
+        .unwrap();
+        let base = dataset
+            .open_vector_index("vector", &base_uuid)
+            .await
+            .unwrap();
+        let base = base.as_any().downcast_ref::<IVFIndex>().unwrap();
+
+        // The delta rows only go to the first partition, and to a new partition of
+        // outliers, so the other base partitions are untouched.
+        const NUM_NEAR: usize = 20;
+        const NUM_OUTLIERS: usize = 10;
+        let mut delta_values = centroids.values()[..DIM].repeat(NUM_NEAR);
+        delta_values.extend(repeat(5.0).take(NUM_OUTLIERS * DIM));
+        let mut delta_centroids = centroids.values().to_vec();
+        delta_centroids.extend(repeat(5.0).take(DIM));
+        let delta_rows = NUM_NEAR + NUM_OUTLIERS;
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "vector",
+                Arc::new(
+                    FixedSizeListArray::try_new_from_values(
+                        Float32Array::from(delta_values),
+                        DIM as i32,
+                    )
+                    .unwrap(),
+                ) as ArrayRef,
+            ),
+            (
+                ROW_ID,
+                Arc::new(UInt64Array::from_iter_values(
+                    NUM_ROWS as u64..(NUM_ROWS + delta_rows) as u64,
+                )) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+        let stream = lance_core::io::RecordBatchStreamAdapter::new(
+            batch.schema(),
+            futures::stream::iter(vec![Ok(batch)]),
+        );
+        let mut delta_ivf = Ivf::new(Arc::new(
+            FixedSizeListArray::try_new_from_values(
+                Float32Array::from(delta_centroids),
+                DIM as i32,
+            )
+            .unwrap(),
+        ));
+        let pq = base.pq_sub_index().unwrap().pq.clone();
+        let delta_uuid = Uuid::new_v4().to_string();
+        let path = dataset
+            .indices_dir()
+            .child(delta_uuid.as_str())
+            .child(INDEX_FILE_NAME);
+        let mut writer = dataset.object_store().create(&path).await.unwrap();
+        let report = builder::build_partitions(
+            &mut writer,
+            None,
+            stream,
+            "vector",
+            &mut delta_ivf,
+            pq.clone(),
+            MetricType::L2,
+            0..5,
+            None,
+            &IvfBuildParams {
+                dual_code_layout,
+                ..IvfBuildParams::new(5)
+            },
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+        finish_index_file(
+            writer,
+            &dataset,
+            "vector",
+            "delta",
+            &[],
+            delta_ivf,
+            pq,
+            MetricType::L2,
+            &report,
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        let delta = dataset
+            .open_vector_index("vector", &delta_uuid)
             .await
             .unwrap();
+        let delta = delta.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(&delta.ivf.lengths[1..4], &[0; 3]);
 
-        let sample_query = vector_array.value(10);
-        let query = sample_query.as_primitive::<Float32Type>();
-        let results = dataset
-            .scan()
-            .nearest("vector", query, 5)
-            .unwrap()
-            .try_into_stream()
+        let merged_uuid = Uuid::new_v4();
+        merge_delta_index_file(
+            &dataset,
+            &merged_uuid.to_string(),
+            base,
+            delta,
+            "merged",
+            "vector",
+        )
+        .await
+        .unwrap();
+        let merged = dataset
+            .open_vector_index("vector", &merged_uuid.to_string())
             .await
-            .unwrap()
-            .try_collect::<Vec<_>>()
+            .unwrap();
+        let merged = merged.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(merged.ivf.num_partitions(), 5);
+        assert_eq!(merged.ivf.lengths[0], base.ivf.lengths[0] + NUM_NEAR as u32);
+        assert_eq!(&merged.ivf.lengths[1..4], &base.ivf.lengths[1..4]);
+        assert_eq!(merged.ivf.lengths[4], NUM_OUTLIERS as u32);
+        assert_eq!(
+            indexed_row_ids(merged).await,
+            (0..(NUM_ROWS + delta_rows) as u64).collect::<Vec<_>>()
+        );
+
+        // Each merged partition has the rows and PQ codes of the base and the delta.
+        let rows = |batches: Vec<RecordBatch>| {
+            let mut rows = batches
+                .iter()
+                .flat_map(|b| {
+                    let codes = b[PQ_CODE_COLUMN].as_fixed_size_list();
+                    b[ROW_ID]
+                        .as_primitive::<UInt64Type>()
+                        .values()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, row_id)| {
+                            let code = codes.value(i);
+                            (*row_id, code.as_primitive::<UInt8Type>().values().to_vec())
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            rows.sort();
+            rows
+        };
+        for part_id in 0..5_u32 {
+            let mut expected = vec![];
+            if part_id < 4 {
+                expected.extend(
+                    base.read_partitions(&[part_id])
+                        .unwrap()
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .unwrap(),
+                );
+            }
+            expected.extend(
+                delta
+                    .read_partitions(&[part_id])
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap(),
+            );
+            let actual = merged
+                .read_partitions(&[part_id])
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_eq!(rows(actual.clone()), rows(expected));
+            // The packed codes of the copied and of the merged partitions too.
+            if dual_code_layout {
+                let packed = merged
+                    .read_partitions_with_layout(&[part_id], CodeLayout::BitPacked)
+                    .unwrap()
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                assert_eq!(rows(packed), rows(actual));
+            }
+        }
+
+        // The rows of the added partition are found by a search.
+        let index_meta = crate::format::Index {
+            uuid: merged_uuid,
+            dataset_version: 0,
+            fields: Vec::new(),
+            name: "merged".to_string(),
+            fragment_bitmap: None,
+        };
+        let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+        let query = Query {
+            column: "vector".to_string(),
+            key: Arc::new(Float32Array::from(vec![5.0; DIM])),
+            k: NUM_OUTLIERS,
+            nprobes: 1,
+            refine_factor: None,
+            metric_type: MetricType::L2,
+            use_index: true,
+        };
+        let results = merged.search(&query, prefilter).await.unwrap();
+        let mut found = results[ROW_ID]
+            .as_primitive::<UInt64Type>()
+            .values()
+            .to_vec();
+        found.sort();
+        assert_eq!(
+            found,
+            ((NUM_ROWS + NUM_NEAR) as u64..(NUM_ROWS + delta_rows) as u64).collect::<Vec<_>>()
+        );
+
+        // The delta must have the centroids of the base.
+        assert!(merge_delta_index_file(
+            &dataset,
+            &Uuid::new_v4().to_string(),
+            delta,
+            base,
+            "merged",
+            "vector",
+        )
+        .await
+        .is_err());
+
+        // Either both indices have the packed codes, or neither.
+        if dual_code_layout {
+            let unpacked_uuid = Uuid::new_v4().to_string();
+            ivf_params.dual_code_layout = false;
+            build_ivf_pq_index(
+                &dataset,
+                "vector",
+                "unpacked",
+                &unpacked_uuid,
+                MetricType::L2,
+                &ivf_params,
+                &pq_params,
+            )
             .await
             .unwrap();
-        assert_eq!(1, results.len());
-        assert_eq!(5, results[0].num_rows());
+            let unpacked = dataset
+                .open_vector_index("vector", &unpacked_uuid)
+                .await
+                .unwrap();
+            let unpacked = unpacked.as_any().downcast_ref::<IVFIndex>().unwrap();
+            let err = merge_delta_index_file(
+                &dataset,
+                &Uuid::new_v4().to_string(),
+                unpacked,
+                delta,
+                "merged",
+                "vector",
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("bit-packed"), "{}", err);
+        }
+    }
+
+    /// Vector of `x * (i + 1)` for each dimension `i`.
+    pub(super) fn embed(x: &Float32Array) -> FixedSizeListArray {
+        let values = x
+            .values()
+            .iter()
+            .flat_map(|x| (0..DIM).map(move |i| x * (i + 1) as f32))
+            .collect::<Vec<_>>();
+        FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32).unwrap()
+    }
+
+    /// UDF computing the vectors of [embed].
+    pub(super) fn embed_udf() -> ScalarUDF {
+        let vector_type = DataType::FixedSizeList(
+            Arc::new(Field::new("item", DataType::Float32, true)),
+            DIM as i32,
+        );
+        create_udf(
+            "embed",
+            vec![DataType::Float32],
+            Arc::new(vector_type),
+            Volatility::Immutable,
+            make_scalar_function(|args: &[ArrayRef]| {
+                Ok(Arc::new(embed(args[0].as_primitive::<Float32Type>())) as ArrayRef)
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_build_ivf_pq_with_vector_expr() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let scalars = Float32Array::from_iter_values((0..1000).map(|i| (i % 97) as f32 / 10.0));
+        let schema = Arc::new(Schema::new(vec![Field::new("x", DataType::Float32, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(scalars.clone())]).unwrap();
+        let batches = RecordBatchIterator::new(vec![Ok(batch)], schema);
+        let dataset = Arc::new(Dataset::write(batches, test_uri, None).await.unwrap());
+
+        // The models are trained on the computed "vector" column too.
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.vector_expr = Some(embed_udf().call(vec![col("x")]));
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "embedded",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &PQBuildParams::new(4, 8),
+        )
+        .await
+        .unwrap();
+
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+        let ivf_index = index.as_any().downcast_ref::<IVFIndex>().unwrap();
+        assert_eq!(ivf_index.ivf.dimension(), DIM);
+        assert_eq!(ivf_index.ivf.lengths.iter().sum::<u32>(), 1000);
+
+        let index_meta = crate::format::Index {
+            uuid: Uuid::parse_str(&uuid).unwrap(),
+            dataset_version: 0,
+            fields: Vec::new(),
+            name: "embedded".to_string(),
+            fragment_bitmap: None,
+        };
+        let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+        for x in [0.5, 3.3, 9.0] {
+            let query = Query {
+                column: "vector".to_string(),
+                key: embed(&Float32Array::from(vec![x])).value(0),
+                k: 5,
+                nprobes: 4,
+                refine_factor: None,
+                metric_type: MetricType::L2,
+                use_index: true,
+            };
+            let results = index.search(&query, prefilter.clone()).await.unwrap();
+            let row_ids = results[ROW_ID].as_primitive::<UInt64Type>();
+            assert_eq!(row_ids.len(), 5);
+            for row_id in row_ids.values() {
+                assert_eq!(scalars.value(*row_id as usize), x);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_weighted_index() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = write_test_dataset(
+            test_uri,
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM, [7; 32]),
+        )
+        .await;
+        let dataset = Arc::new(dataset);
+
+        // Only the first four dimensions matter.
+        let weights = (0..DIM)
+            .map(|i| if i < 4 { 10.0 } else { 0.0 })
+            .collect::<Vec<f32>>();
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.weights = Some(weights.clone());
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "weighted",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &PQBuildParams::new(4, 8),
+        )
+        .await
+        .unwrap();
+        let index = dataset.open_vector_index("vector", &uuid).await.unwrap();
+
+        let values = vectors.values().as_primitive::<Float32Type>().values();
+        let weighted_l2 = |a: &[f32], b: &[f32]| {
+            a.iter()
+                .zip(b)
+                .zip(weights.iter())
+                .map(|((x, y), w)| w * (x - y) * (x - y))
+                .sum::<f32>()
+        };
+        let index_meta = crate::format::Index {
+            uuid: Uuid::parse_str(&uuid).unwrap(),
+            dataset_version: 0,
+            fields: Vec::new(),
+            name: "weighted".to_string(),
+            fragment_bitmap: None,
+        };
+        let prefilter = Arc::new(PreFilter::new(dataset.clone(), index_meta, None));
+        let mut recall = 0.0;
+        const NUM_QUERIES: usize = 20;
+        for i in 0..NUM_QUERIES {
+            let query = &values[i * DIM..(i + 1) * DIM];
+            let mut distances = values
+                .chunks_exact(DIM)
+                .map(|v| weighted_l2(query, v))
+                .enumerate()
+                .collect::<Vec<_>>();
+            distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let expected = distances
+                .iter()
+                .take(10)
+                .map(|(i, _)| *i as u64)
+                .collect::<HashSet<_>>();
+
+            let query = Query {
+                column: "vector".to_string(),
+                key: Arc::new(Float32Array::from(query.to_vec())),
+                k: 10,
+                nprobes: 2,
+                refine_factor: None,
+                metric_type: MetricType::L2,
+                use_index: true,
+            };
+            let results = index.search(&query, prefilter.clone()).await.unwrap();
+            let found = results[ROW_ID].as_primitive::<UInt64Type>();
+            recall += found
+                .values()
+                .iter()
+                .filter(|row_id| expected.contains(row_id))
+                .count() as f32
+                / 10.0;
+        }
+        recall /= NUM_QUERIES as f32;
+        // Searching the weighted index without weighting the query finds about 5%.
+        assert!(recall > 0.5, "recall: {}", recall);
     }
 
     fn partition_ids(mut ids: Vec<u64>, num_parts: u32) -> Vec<Vec<u64>> {
@@ -1735,4 +6449,107 @@ mod tests {
             ]))
         );
     }
+
+    #[tokio::test]
+    async fn test_read_partitions_with_centroids() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (_dataset, ivf_index) = build_test_index(test_uri, &IvfBuildParams::new(4)).await;
+
+        let part_ids = [2, 0];
+        let batches = ivf_index
+            .read_partitions_with_centroids(&part_ids)
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let plain = ivf_index
+            .read_partitions(&part_ids)
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        for ((part_id, batch), plain) in part_ids.iter().zip(&batches).zip(&plain) {
+            let centroid: Vec<f32> =
+                serde_json::from_str(&batch.schema().metadata()[CENTROID_METADATA_KEY]).unwrap();
+            let expected = ivf_index.ivf.centroids.value(*part_id as usize);
+            assert_eq!(
+                centroid,
+                expected.as_primitive::<Float32Type>().values().to_vec()
+            );
+            assert_eq!(batch.columns(), plain.columns());
+        }
+        assert!(ivf_index
+            .read_partitions_with_centroids(&[4])
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_bf16_centroids_assign_close_to_f32() {
+        const NUM_PARTITIONS: usize = 16;
+        const NUM_VECTORS: usize = 1000;
+
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(NUM_PARTITIONS * DIM, [7; 32]),
+                DIM as i32,
+            )
+            .unwrap(),
+        );
+        let vectors = generate_random_array_with_seed::<Float32Type>(NUM_VECTORS * DIM, [8; 32]);
+
+        let f32_ivf = Ivf::new(centroids.clone());
+        let mut ivf = Ivf::new(centroids.clone());
+        ivf.bf16_centroids = true;
+        for _ in 0..NUM_PARTITIONS {
+            ivf.add_partition(0, 0);
+        }
+        let proto = pb::Ivf::try_from(&ivf).unwrap();
+        let tensor = proto.centroids_tensor.as_ref().unwrap();
+        assert_eq!(tensor.data.len(), NUM_PARTITIONS * DIM * 2);
+        let bf16_ivf = Ivf::try_from(&proto).unwrap();
+        assert!(bf16_ivf.bf16_centroids);
+
+        let f32_values = centroids.values().as_primitive::<Float32Type>().values();
+        let bf16_values = bf16_ivf
+            .centroids
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .to_vec();
+        let mut num_same = 0;
+        for vector in vectors.values().chunks(DIM) {
+            let query = Float32Array::from(vector.to_vec());
+            let expected = f32_ivf
+                .find_partitions(&query, 1, MetricType::L2)
+                .unwrap()
+                .value(0);
+            let actual = bf16_ivf
+                .find_partitions(&query, 1, MetricType::L2)
+                .unwrap()
+                .value(0);
+            if expected == actual {
+                num_same += 1;
+            }
+            // A different partition is only picked if it is about as close.
+            let f32_distances = l2_distance_batch(vector, f32_values, DIM).collect::<Vec<_>>();
+            let bf16_distance = l2_distance_batch(vector, &bf16_values, DIM)
+                .nth(actual as usize)
+                .unwrap();
+            assert_relative_eq!(
+                f32_distances[actual as usize],
+                f32_distances[expected as usize],
+                max_relative = 0.02
+            );
+            assert_relative_eq!(
+                bf16_distance,
+                f32_distances[actual as usize],
+                max_relative = 0.02
+            );
+        }
+        assert!(num_same >= NUM_VECTORS * 95 / 100, "{}", num_same);
+    }
 }
@@ -14,31 +14,80 @@
 
 use std::collections::HashMap;
 use std::ops::Range;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use arrow_array::RecordBatch;
+use arrow::compute::cast;
+use arrow_arith::boolean::{is_null, not};
+use arrow_array::{
+    cast::AsArray,
+    types::{Float32Type, Int64Type, UInt32Type, UInt64Type, UInt8Type},
+    Array, ArrayRef, BooleanArray, FixedSizeListArray, Float32Array, RecordBatch, UInt32Array,
+    UInt64Array, UInt8Array,
+};
 use arrow_schema::{DataType, Field, Schema};
+use arrow_select::{
+    concat::concat,
+    filter::{filter, filter_record_batch},
+    take::take,
+};
+use async_trait::async_trait;
+use datafusion::dataframe::DataFrame;
 use datafusion::error::DataFusionError;
-use datafusion::execution::context::SessionContext;
-use datafusion::execution::memory_pool::{GreedyMemoryPool, MemoryPool, UnboundedMemoryPool};
+use datafusion::execution::context::{SessionConfig, SessionContext};
 use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
-use datafusion::logical_expr::col;
+use datafusion::logical_expr::{col, Expr};
 use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
-use futures::Stream;
-use futures::{stream::repeat_with, StreamExt};
+use datafusion::physical_plan::SendableRecordBatchStream;
+use futures::{future, stream::BoxStream, Stream};
+use futures::{stream::repeat_with, StreamExt, TryStreamExt};
+use lance_arrow::{FixedSizeListArrayExt, RecordBatchExt, SchemaExt};
 use lance_core::datatypes::Schema as LanceSchema;
 use lance_core::{io::Writer, ROW_ID, ROW_ID_FIELD};
 use lance_datafusion::dataframe::{BatchStreamGrouper, DataFrameExt};
 use lance_datafusion::exec::SessionContextExt;
-use lance_index::vector::ivf::shuffler::IvfShuffler;
-use lance_index::vector::pq::ProductQuantizer;
-use lance_index::vector::{PART_ID_COLUMN, PQ_CODE_COLUMN};
+use lance_index::vector::codec::{DecodeTransform, VectorCodec};
+use lance_index::vector::ivf::{
+    shuffler::{IvfShuffler, PartitionFold},
+    tree::IvfTree,
+    AssignmentStats, BuildControl, BytesPerSec, IvfBuildParams, PartitionWrittenCallback,
+    PostShuffleTransform, RowIdMap, TimeWindow, ZeroNormPolicy,
+};
+use lance_index::vector::pca::{PcaTransform, PCA_VECTOR_COLUMN};
+use lance_index::vector::pq::{
+    lookup::TransposedCodebook, num_centroids, pq_decode, PQBuildParams, ProductQuantizer,
+};
+use lance_index::vector::stats::{NormHistogram, VectorStats};
+use lance_index::vector::transform::Transformer;
+use lance_index::vector::weight::WeightTransform;
+use lance_index::vector::{
+    ALTERNATIVE_DISTANCES_COLUMN, ALTERNATIVE_PARTITIONS_COLUMN, ASSIGNMENT_MARGIN_COLUMN,
+    CENTROID_DISTANCE_COLUMN, PART_ID_COLUMN, PINNED_PARTITION_COLUMN, PQ_CODE_COLUMN,
+    SUBGROUP_COLUMN, VALID_COLUMN,
+};
 use lance_linalg::distance::MetricType;
-use log::info;
+use log::{info, warn};
+use object_store::path::Path;
+use rand::{rngs::SmallRng, Rng};
+use serde::Serialize;
 use snafu::{location, Location};
 use tracing::instrument;
+use uuid::Uuid;
 
-use crate::index::vector::ivf::{io::write_index_partitions, Ivf};
+use crate::dataset::{
+    scanner::{DatasetRecordBatchStream, Scanner},
+    Dataset,
+};
+use crate::index::vector::ivf::{
+    io::{
+        mark_extra_columns, write_index_partitions, ColdTier, PartitionPqParams,
+        WritePartitionsOptions,
+    },
+    new_pq_with_codebook, seeded_rng, train_ivf_model, train_pq_model, BuildEnvConfig, Ivf,
+    PackedCodes, Subgroups,
+};
+use crate::index::vector::utils::density_weighted_sample;
 use crate::{io::RecordBatchStream, Error, Result};
 
 /// Disk-based shuffle a stream of [RecordBatch] into each IVF partition.
@@ -48,6 +97,8 @@ use crate::{io::RecordBatchStream, Error, Result};
 /// ----------
 ///   *data*: input data stream.
 ///   *ivf*: IVF model.
+///   *session_config*: DataFusion settings for the sort, e.g. `target_partitions`
+///   or `batch_size`. Defaults are used if not provided.
 ///
 /// Returns
 /// -------
@@ -63,6 +114,8 @@ pub async fn shuffle_dataset(
     // TODO: Once the transformer can generate schema automatically,
     // we can remove `num_sub_vectors`.
     num_sub_vectors: usize,
+    session_config: Option<SessionConfig>,
+    env: &BuildEnvConfig,
 ) -> Result<BatchStreamGrouper> {
     let column: Arc<str> = column.into();
     let stream = data
@@ -100,156 +153,4460 @@ pub async fn shuffle_dataset(
 
     info!("Building IVF shuffler");
 
-    let memory_limit = if let Ok(memory_limit) = std::env::var("LANCE_MEMORY_LIMIT") {
-        match memory_limit.parse::<usize>() {
-            Ok(memory_limit) => Some(memory_limit),
-            Err(err) => {
-                log::error!(
-                    "Failed to parse LANCE_MEMORY_LIMIT: {}, using default of unbounded.",
-                    err
-                );
-                None
-            }
-        }
-    } else {
-        None
-    };
+    Ok(sort_by_partition(stream, session_config, env)?
+        .group_by_stream(&[PART_ID_COLUMN])
+        .await?)
+}
 
-    let memory_pool: Arc<dyn MemoryPool> = if let Some(memory_limit) = memory_limit {
-        Arc::new(GreedyMemoryPool::new(memory_limit))
-    } else {
-        Arc::new(UnboundedMemoryPool::default())
-    };
-    let runtime_config = RuntimeConfig::new().with_memory_pool(memory_pool);
+/// Sort a stream of partitioned data by [PART_ID_COLUMN], see [sort_by_column].
+fn sort_by_partition(
+    stream: SendableRecordBatchStream,
+    session_config: Option<SessionConfig>,
+    env: &BuildEnvConfig,
+) -> Result<DataFrame> {
+    sort_by_column(stream, PART_ID_COLUMN, session_config, env)
+}
+
+/// DataFusion context of the queries of a build.
+///
+/// The memory pool is always bounded by the memory limit of `env`, while
+/// `session_config` overrides the other DataFusion settings.
+pub(super) fn session_context(
+    session_config: Option<SessionConfig>,
+    env: &BuildEnvConfig,
+) -> Result<SessionContext> {
+    let runtime_config = RuntimeConfig::new().with_memory_pool(env.memory_pool());
     let runtime_env = RuntimeEnv::new(runtime_config)?;
-    let context = SessionContext::new_with_config_rt(Default::default(), Arc::new(runtime_env));
+    Ok(SessionContext::new_with_config_rt(
+        session_config.unwrap_or_default(),
+        Arc::new(runtime_env),
+    ))
+}
 
-    Ok(context
+/// Sort a stream by `column`, nulls first, see [session_context].
+fn sort_by_column(
+    stream: SendableRecordBatchStream,
+    column: &str,
+    session_config: Option<SessionConfig>,
+    env: &BuildEnvConfig,
+) -> Result<DataFrame> {
+    Ok(session_context(session_config, env)?
         .read_one_shot(stream)?
-        .sort(vec![col(PART_ID_COLUMN).sort(true, true)])?
-        .group_by_stream(&[PART_ID_COLUMN])
-        .await?)
+        .sort(vec![col(column).sort(true, true)])?)
 }
 
-pub async fn shuffle_dataset_v2(
-    data: impl RecordBatchStream + Unpin + 'static,
+/// Evaluate `expr` over each batch of `data` into the vector `column`, replacing the
+/// column if the input has one, see [`IvfBuildParams::vector_expr`].
+///
+/// The query runs in `ctx`, the context of the other queries of the build.
+pub(super) async fn project_vector_column(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
     column: &str,
-    ivf: Arc<dyn lance_index::vector::ivf::Ivf>,
-    num_partitions: u32,
-    num_sub_vectors: usize,
-) -> Result<Vec<impl Stream<Item = Result<RecordBatch>>>> {
-    let column: Arc<str> = column.into();
+    expr: Expr,
+    ctx: &SessionContext,
+) -> Result<lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>> {
+    let schema = data.schema();
     let stream = data
-        .zip(repeat_with(move || ivf.clone()))
-        .map(move |(b, ivf)| {
-            let col_ref = column.clone();
+        .map_err(|err| DataFusionError::External(Box::new(err)))
+        .boxed();
+    let stream = Box::pin(RecordBatchStreamAdapter::new(schema, stream));
+    info!("Computing the vector column {} as {}", column, expr);
+    let projected = ctx
+        .read_one_shot(stream)?
+        .with_column(column, expr)?
+        .execute_stream()
+        .await?;
+    let schema = projected.schema();
+    Ok(lance_core::io::RecordBatchStreamAdapter::new(
+        schema,
+        projected.map_err(Error::from).boxed(),
+    ))
+}
 
-            tokio::task::spawn(async move {
-                let batch = b?;
-                ivf.partition_transform(&batch, col_ref.as_ref()).await
+/// Sort the input of the shuffle by the `key` column in `ctx`, see
+/// [`IvfBuildParams::presort_by`].
+async fn presort(
+    data: impl RecordBatchStream + Unpin + 'static,
+    key: &str,
+    ctx: &SessionContext,
+) -> Result<lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>> {
+    let schema = data.schema();
+    let stream = data
+        .map_err(|err| DataFusionError::External(Box::new(err)))
+        .boxed();
+    let stream = Box::pin(RecordBatchStreamAdapter::new(schema.clone(), stream));
+    info!("Sorting the input by {} before the shuffle", key);
+    let sorted = ctx
+        .read_one_shot(stream)?
+        .sort(vec![col(key).sort(true, true)])?
+        .execute_stream()
+        .await?
+        .map_err(Error::from)
+        .boxed();
+    Ok(lance_core::io::RecordBatchStreamAdapter::new(
+        schema, sorted,
+    ))
+}
+
+/// Mix the bits of a ROW ID so that it can be bucketed uniformly.
+///
+/// This is the `splitmix64` finalizer, which is stable across platforms and
+/// releases, so the buckets a row falls into are reproducible.
+fn hash_row_id(row_id: u64) -> u64 {
+    let mut h = row_id.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+/// Keep the rows of `batch` where `hash(ROW_ID) % n == r`.
+fn sample_by_row_id(batch: &RecordBatch, n: u64, r: u64) -> Result<RecordBatch> {
+    let row_ids = batch
+        .column_by_name(ROW_ID)
+        .ok_or(Error::Index {
+            message: "ROW ID is required to sample the input by hash".to_string(),
+            location: location!(),
+        })?
+        .as_primitive::<UInt64Type>();
+    let mask = BooleanArray::from_iter(
+        row_ids
+            .values()
+            .iter()
+            .map(|row_id| Some(hash_row_id(*row_id) % n == r)),
+    );
+    Ok(filter_record_batch(batch, &mask)?)
+}
+
+/// Replace the ROW_IDs of `batch` by their mapping by `row_id_map`.
+fn map_row_ids(batch: &RecordBatch, row_id_map: &RowIdMap) -> Result<RecordBatch> {
+    let row_ids = batch
+        .column_by_name(ROW_ID)
+        .ok_or(Error::Index {
+            message: "ROW ID is required to map the row ids".to_string(),
+            location: location!(),
+        })?
+        .as_primitive::<UInt64Type>();
+    let row_ids = UInt64Array::from_iter_values(row_ids.values().iter().map(|id| row_id_map(*id)));
+    Ok(batch.replace_column_by_name(ROW_ID, Arc::new(row_ids))?)
+}
+
+/// Keep the rows of `batch` in the time `window`.
+fn filter_by_time_window(batch: &RecordBatch, window: &TimeWindow) -> Result<RecordBatch> {
+    let timestamps = batch.column_by_name(&window.column).ok_or(Error::Index {
+        message: format!(
+            "time window column {} does not exist in data stream",
+            window.column
+        ),
+        location: location!(),
+    })?;
+    let timestamps = cast(timestamps, &DataType::Int64)?;
+    let mask = BooleanArray::from_iter(
+        timestamps
+            .as_primitive::<Int64Type>()
+            .iter()
+            .map(|ts| Some(ts.is_some_and(|ts| window.contains(ts)))),
+    );
+    Ok(filter_record_batch(batch, &mask)?)
+}
+
+/// Mark the rows of `batch` valid where the `tombstone` column is null, or false
+/// for a boolean column, as [`VALID_COLUMN`].
+fn add_valid_column(batch: &RecordBatch, tombstone: &str) -> Result<RecordBatch> {
+    let arr = batch.column_by_name(tombstone).ok_or(Error::Index {
+        message: format!(
+            "tombstone column {} does not exist in data stream",
+            tombstone
+        ),
+        location: location!(),
+    })?;
+    let valid = match arr.as_boolean_opt() {
+        Some(deleted) => BooleanArray::from_iter(deleted.iter().map(|d| Some(!d.unwrap_or(false)))),
+        None => is_null(arr)?,
+    };
+    Ok(batch.try_with_column(
+        Field::new(VALID_COLUMN, DataType::Boolean, false),
+        Arc::new(valid),
+    )?)
+}
+
+/// Copy the values of the `column` of `batch`, cast to strings, as [`SUBGROUP_COLUMN`].
+fn add_subgroup_column(batch: &RecordBatch, column: &str) -> Result<RecordBatch> {
+    let arr = batch.column_by_name(column).ok_or(Error::Index {
+        message: format!("subgroup column {} does not exist in data stream", column),
+        location: location!(),
+    })?;
+    Ok(batch.try_with_column(
+        Field::new(SUBGROUP_COLUMN, DataType::Utf8, true),
+        cast(arr, &DataType::Utf8)?,
+    )?)
+}
+
+/// Summary of building the IVF partitions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BuildReport {
+    /// Number of rows shuffled into the partitions, after sampling and filtering.
+    pub num_rows: usize,
+
+    /// Number of input batches skipped because they failed to be transformed.
+    pub skipped_batches: usize,
+
+    /// Number of rows in the skipped batches.
+    pub skipped_rows: usize,
+
+    /// Number of rows of each partition in each bin of [residual_histogram_bin], if
+    /// [`IvfBuildParams::collect_residual_histograms`] is set, empty otherwise.
+    pub residual_histograms: Vec<Vec<u64>>,
+
+    /// Self-recall of a sample of the input vectors, if
+    /// [`IvfBuildParams::self_recall_check`] is set and the partitions are shuffled.
+    pub self_recall: Option<SelfRecall>,
+
+    /// Number of partitions of the index, including the empty ones.
+    pub num_partitions: usize,
+
+    /// Wall-clock time of building the partitions.
+    pub duration: Duration,
+
+    /// SHA-256 digest of the ROW_IDs and PQ codes of the written partitions, to prove
+    /// that two builds wrote the same partitions.
+    ///
+    /// The rows of each partition are digested in sorted order, so the digest does not
+    /// depend on the order the rows were shuffled in.
+    pub output_digest: [u8; 32],
+
+    /// Number of rows with a vector of zero norm in a Cosine build, handled by
+    /// [`IvfBuildParams::on_zero_norm`].
+    pub zero_norm_rows: usize,
+
+    /// Distances computed to assign the rows to partitions, e.g. to compare the
+    /// throughput of distance kernels with [`AssignmentStats::throughput`].
+    pub assignment: AssignmentStats,
+}
+
+impl BuildReport {
+    /// Serialize the report as a JSON object, e.g. for structured logging.
+    pub fn to_json(&self) -> String {
+        // Only integer fields, so serialization can not fail.
+        serde_json::to_string(self).expect("BuildReport is serializable to JSON")
+    }
+
+    /// Format the report as gauges in the Prometheus text exposition format, for simple
+    /// scraping without a metrics library.
+    ///
+    /// The metric names are prefixed with `namespace` and an underscore, if not empty,
+    /// with the characters not allowed in metric names replaced by underscores.
+    pub fn to_prometheus(&self, namespace: &str) -> String {
+        let prefix = if namespace.is_empty() {
+            String::new()
+        } else {
+            let namespace = namespace
+                .chars()
+                .enumerate()
+                .map(|(i, c)| {
+                    if c.is_ascii_alphabetic()
+                        || c == '_'
+                        || c == ':'
+                        || (i > 0 && c.is_ascii_digit())
+                    {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>();
+            format!("{}_", namespace)
+        };
+        let mut metrics = vec![
+            (
+                "ivf_build_rows",
+                "Number of rows written to the partitions.",
+                self.num_rows as f64,
+            ),
+            (
+                "ivf_build_skipped_batches",
+                "Number of input batches skipped.",
+                self.skipped_batches as f64,
+            ),
+            (
+                "ivf_build_skipped_rows",
+                "Number of rows in the skipped batches.",
+                self.skipped_rows as f64,
+            ),
+            (
+                "ivf_build_partitions",
+                "Number of partitions of the index.",
+                self.num_partitions as f64,
+            ),
+            (
+                "ivf_build_duration_seconds",
+                "Wall-clock time of building the partitions.",
+                self.duration.as_secs_f64(),
+            ),
+        ];
+        if let Some(throughput) = self.assignment.throughput() {
+            metrics.push((
+                "ivf_build_distance_computations_per_second",
+                "Distances computed per second to assign the rows to partitions.",
+                throughput,
+            ));
+        }
+        if let Some(self_recall) = self.self_recall.as_ref() {
+            metrics.push((
+                "ivf_build_self_recall",
+                "Fraction of the sampled vectors that found their own row.",
+                self_recall.recall(),
+            ));
+        }
+        metrics
+            .into_iter()
+            .map(|(name, help, value)| {
+                let name = format!("{}{}", prefix, name);
+                format!(
+                    "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                    name, help, name, name, value
+                )
             })
+            .collect()
+    }
+}
+
+/// Number of bins of [`BuildReport::residual_histograms`].
+pub const RESIDUAL_HISTOGRAM_BINS: usize = 16;
+
+/// Bin of a residual of L2 `norm` in [`BuildReport::residual_histograms`].
+///
+/// Bin `i` counts the norms in `[2^(i - 8), 2^(i - 7))`, the first bin also counts the
+/// smaller norms and the last bin the larger ones.
+pub fn residual_histogram_bin(norm: f32) -> usize {
+    if norm.is_nan() || norm <= 0.0 {
+        return 0;
+    }
+    (norm.log2().floor() + 8.0).clamp(0.0, (RESIDUAL_HISTOGRAM_BINS - 1) as f32) as usize
+}
+
+/// Number of nearest rows among which a sampled vector has to find its own row, see
+/// [`IvfBuildParams::self_recall_check`].
+pub const SELF_RECALL_K: usize = 10;
+
+/// Number of nearest partitions searched for each sampled vector.
+const SELF_RECALL_NPROBES: usize = 4;
+
+/// Self-recall of the built partitions, see [`IvfBuildParams::self_recall_check`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SelfRecall {
+    /// Number of sampled vectors whose row was written to the partitions.
+    pub num_queries: usize,
+
+    /// Number of them that found their own row among the [SELF_RECALL_K] nearest.
+    pub num_found: usize,
+}
+
+impl SelfRecall {
+    /// Fraction of the queries that found their own row, 1 without any query.
+    pub fn recall(&self) -> f64 {
+        if self.num_queries == 0 {
+            return 1.0;
+        }
+        self.num_found as f64 / self.num_queries as f64
+    }
+}
+
+/// Uniform random sample of the input vectors, by reservoir sampling.
+struct SelfRecallSample {
+    num_samples: usize,
+    num_rows: usize,
+    rng: SmallRng,
+
+    /// Row id and vector of each sampled row.
+    rows: Vec<(u64, Vec<f32>)>,
+}
+
+impl SelfRecallSample {
+    fn new(num_samples: usize, seed: Option<u64>) -> Self {
+        Self {
+            num_samples,
+            num_rows: 0,
+            rng: seeded_rng(seed),
+            rows: Vec::with_capacity(num_samples),
+        }
+    }
+
+    fn update(&mut self, batch: &RecordBatch, column: &str) -> Result<()> {
+        let vectors = batch[column].as_fixed_size_list();
+        let dim = vectors.value_length() as usize;
+        let values = cast(vectors.values(), &DataType::Float32)?;
+        let values = values.as_primitive::<Float32Type>().values();
+        let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>().values();
+        for (vector, &row_id) in values.chunks_exact(dim).zip(row_ids.iter()) {
+            let slot = if self.rows.len() < self.num_samples {
+                Some(self.rows.len())
+            } else {
+                Some(self.rng.gen_range(0..=self.num_rows)).filter(|&j| j < self.num_samples)
+            };
+            self.num_rows += 1;
+            match slot {
+                Some(slot) if slot == self.rows.len() => self.rows.push((row_id, vector.to_vec())),
+                Some(slot) => self.rows[slot] = (row_id, vector.to_vec()),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Searches the sampled vectors of a [SelfRecallSample] in the partitions as they are
+/// written, through their PQ codes like a query of the index.
+struct SelfRecallQueries {
+    pq: Arc<dyn ProductQuantizer>,
+    queries: Vec<SelfRecallQuery>,
+
+    /// Index in `queries` of the row id of each query.
+    query_ids: HashMap<u64, usize>,
+}
+
+struct SelfRecallQuery {
+    row_id: u64,
+
+    /// Probed partitions, with the query of the PQ distance table of each.
+    probes: HashMap<u32, ArrayRef>,
+
+    /// Whether the row of the query was written to any partition.
+    written: bool,
+
+    /// Nearest rows so far, as distance and row id.
+    nearest: Vec<(f32, u64)>,
+}
+
+impl SelfRecallQueries {
+    fn try_new(
+        sample: &SelfRecallSample,
+        centroids: &FixedSizeListArray,
+        metric_type: MetricType,
+        pq: Arc<dyn ProductQuantizer>,
+    ) -> Result<Self> {
+        let dim = centroids.value_length() as usize;
+        let centroids = cast(centroids.values(), &DataType::Float32)?;
+        let centroids = centroids.as_primitive::<Float32Type>().values();
+        let value_type = pq.codebook_as_fsl().value_type();
+        let distance = metric_type.func();
+        let mut queries = Vec::with_capacity(sample.rows.len());
+        for (row_id, vector) in sample.rows.iter() {
+            let mut nearest = centroids
+                .chunks_exact(dim)
+                .enumerate()
+                .map(|(i, centroid)| (distance(vector, centroid), i))
+                .collect::<Vec<_>>();
+            nearest.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut probes = HashMap::new();
+            for &(_, part_id) in nearest.iter().take(SELF_RECALL_NPROBES) {
+                let key = if pq.use_residual() {
+                    let centroid = &centroids[part_id * dim..(part_id + 1) * dim];
+                    vector.iter().zip(centroid).map(|(v, c)| v - c).collect()
+                } else {
+                    vector.clone()
+                };
+                let key = cast(&Float32Array::from(key), &value_type)?;
+                probes.insert(part_id as u32, key);
+            }
+            queries.push(SelfRecallQuery {
+                row_id: *row_id,
+                probes,
+                written: false,
+                nearest: Vec::with_capacity(SELF_RECALL_K),
+            });
+        }
+        let query_ids = queries
+            .iter()
+            .enumerate()
+            .map(|(i, query)| (query.row_id, i))
+            .collect();
+        Ok(Self {
+            pq,
+            queries,
+            query_ids,
         })
-        .buffer_unordered(num_cpus::get())
-        .map(|res| match res {
-            Ok(Ok(batch)) => Ok(batch),
-            Ok(Err(err)) => Err(Error::IO {
-                message: err.to_string(),
-                location: location!(),
-            }),
-            Err(err) => Err(Error::IO {
-                message: err.to_string(),
+    }
+
+    /// Score the rows of one batch of partitions against the queries probing them.
+    fn update(&mut self, batch: &RecordBatch) -> Result<()> {
+        let part_ids = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().values();
+        let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>().values();
+        let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+        let num_sub_vectors = codes.value_length() as usize;
+        let codes = codes.values().as_primitive::<UInt8Type>().values();
+
+        let mut partitions = HashMap::<u32, Vec<usize>>::new();
+        for (i, (&part_id, row_id)) in part_ids.iter().zip(row_ids.iter()).enumerate() {
+            partitions.entry(part_id).or_default().push(i);
+            if let Some(&query) = self.query_ids.get(row_id) {
+                self.queries[query].written = true;
+            }
+        }
+        for (part_id, rows) in partitions {
+            if !self.queries.iter().any(|q| q.probes.contains_key(&part_id)) {
+                continue;
+            }
+            let part_codes = UInt8Array::from_iter_values(rows.iter().flat_map(|&i| {
+                codes[i * num_sub_vectors..(i + 1) * num_sub_vectors]
+                    .iter()
+                    .copied()
+            }));
+            for query in self.queries.iter_mut() {
+                let Some(key) = query.probes.get(&part_id) else {
+                    continue;
+                };
+                let distances = self.pq.build_distance_table(key.as_ref(), &part_codes)?;
+                query.nearest.extend(
+                    distances
+                        .values()
+                        .iter()
+                        .zip(rows.iter())
+                        .map(|(&dist, &i)| (dist, row_ids[i])),
+                );
+                query.nearest.sort_by(|a, b| a.0.total_cmp(&b.0));
+                query.nearest.truncate(SELF_RECALL_K);
+            }
+        }
+        Ok(())
+    }
+
+    fn result(&self) -> SelfRecall {
+        let written = self.queries.iter().filter(|q| q.written);
+        SelfRecall {
+            num_queries: written.clone().count(),
+            num_found: written
+                .filter(|q| q.nearest.iter().any(|(_, row_id)| *row_id == q.row_id))
+                .count(),
+        }
+    }
+}
+
+/// Score each batch of the partitions of `stream` against `queries` as it is written.
+fn score_self_recall(
+    stream: impl Stream<Item = Result<RecordBatch>>,
+    queries: Arc<Mutex<SelfRecallQueries>>,
+) -> impl Stream<Item = Result<RecordBatch>> {
+    stream.and_then(move |batch| {
+        let res = queries.lock().unwrap().update(&batch).map(|_| batch);
+        future::ready(res)
+    })
+}
+
+/// Apply `transform` to each batch of the partitions of `stream`, marking the columns
+/// it adds to be stored in the partitions, see
+/// [`IvfBuildParams::post_shuffle_transform`].
+fn transform_partitions(
+    stream: impl Stream<Item = Result<RecordBatch>>,
+    transform: PostShuffleTransform,
+) -> impl Stream<Item = Result<RecordBatch>> {
+    stream.and_then(move |batch| {
+        // Each batch holds the rows of a single partition.
+        let res = match batch[PART_ID_COLUMN]
+            .as_primitive::<UInt32Type>()
+            .values()
+            .first()
+        {
+            Some(&part_id) => {
+                let input = batch.schema();
+                transform(part_id, batch).and_then(|batch| mark_extra_columns(batch, &input))
+            }
+            None => Ok(batch),
+        };
+        future::ready(res)
+    })
+}
+
+/// Copy of the vector column, kept past the IVF transforms to compute the residual
+/// histograms under [`IvfBuildParams::collect_residual_histograms`].
+const HISTOGRAM_VECTOR_COLUMN: &str = "__histogram_vector";
+
+/// Number of next nearest partitions of each row carried through the shuffle, to fold
+/// the partitions under [`IvfBuildParams::min_partition_rows`].
+const FOLD_ALTERNATIVES: usize = 4;
+
+/// Reassigns the rows of the folded partitions to the nearest kept partition among
+/// the [ALTERNATIVE_PARTITIONS_COLUMN] found when assigning them, or else to the
+/// kept partition with the nearest centroid, and encodes them again against it.
+///
+/// The vectors are not carried through the shuffle, so the residual PQ codes are
+/// encoded again from the vectors reconstructed from their codes.
+struct NearestPartitionFold {
+    centroids: Arc<FixedSizeListArray>,
+    metric_type: MetricType,
+    pq: Arc<dyn ProductQuantizer>,
+}
+
+impl NearestPartitionFold {
+    /// The kept partition in `targets` with the centroid nearest to the centroid of
+    /// partition `part_id`.
+    fn nearest_target(&self, centroids: &[f32], part_id: usize, targets: &[bool]) -> Result<u32> {
+        let dim = self.centroids.value_length() as usize;
+        let distance = self.metric_type.func();
+        let centroid = &centroids[part_id * dim..(part_id + 1) * dim];
+        centroids
+            .chunks_exact(dim)
+            .enumerate()
+            .filter(|(i, _)| targets[*i])
+            .map(|(i, other)| (i as u32, distance(centroid, other)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+            .ok_or_else(|| Error::Index {
+                message: "No partition to fold the small partitions into".to_string(),
                 location: location!(),
-            }),
+            })
+    }
+}
+
+#[async_trait]
+impl PartitionFold for NearestPartitionFold {
+    async fn fold(&self, batch: &RecordBatch, targets: &[bool]) -> Result<RecordBatch> {
+        let dim = self.centroids.value_length() as usize;
+        let centroids = cast(self.centroids.values(), &DataType::Float32)?;
+        let centroids = centroids.as_primitive::<Float32Type>().values();
+        let distance = self.metric_type.func();
+        let alt_ids = batch[ALTERNATIVE_PARTITIONS_COLUMN].as_fixed_size_list();
+        let alt_distances = batch[ALTERNATIVE_DISTANCES_COLUMN].as_fixed_size_list();
+        let num_alternatives = alt_ids.value_length() as usize;
+        let alt_ids = alt_ids.values().as_primitive::<UInt32Type>().values();
+        let alt_distances = alt_distances
+            .values()
+            .as_primitive::<Float32Type>()
+            .values();
+        let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+        let codes = codes.values().as_primitive::<UInt8Type>().values();
+        let num_sub_vectors = self.pq.num_sub_vectors();
+
+        let mut fallbacks = HashMap::new();
+        let mut part_ids = Vec::with_capacity(batch.num_rows());
+        let mut distances = Vec::with_capacity(batch.num_rows());
+        let mut residuals = Vec::with_capacity(batch.num_rows() * dim);
+        for (i, &part_id) in batch[PART_ID_COLUMN]
+            .as_primitive::<UInt32Type>()
+            .values()
+            .iter()
+            .enumerate()
+        {
+            let part_id = part_id as usize;
+            let alternatives = (i * num_alternatives..(i + 1) * num_alternatives)
+                .map(|j| (alt_ids[j], alt_distances[j]))
+                .find(|(id, _)| *id != u32::MAX && targets[*id as usize]);
+            let needs_vector = self.pq.use_residual() || alternatives.is_none();
+            let vector = if needs_vector {
+                let centroid = &centroids[part_id * dim..(part_id + 1) * dim];
+                let code = &codes[i * num_sub_vectors..(i + 1) * num_sub_vectors];
+                pq_decode(code, self.pq.as_ref(), centroid)?
+            } else {
+                vec![]
+            };
+            let (target, dist) = match alternatives {
+                Some(alternative) => alternative,
+                None => {
+                    let target = match fallbacks.get(&part_id) {
+                        Some(target) => *target,
+                        None => {
+                            let target = self.nearest_target(centroids, part_id, targets)?;
+                            fallbacks.insert(part_id, target);
+                            target
+                        }
+                    };
+                    let target_centroid =
+                        &centroids[target as usize * dim..(target as usize + 1) * dim];
+                    (target, distance(&vector, target_centroid))
+                }
+            };
+            if self.pq.use_residual() {
+                let target_centroid =
+                    &centroids[target as usize * dim..(target as usize + 1) * dim];
+                residuals.extend(vector.iter().zip(target_centroid).map(|(v, c)| v - c));
+            }
+            part_ids.push(target);
+            distances.push(dist);
+        }
+
+        let mut batch =
+            batch.replace_column_by_name(PART_ID_COLUMN, Arc::new(UInt32Array::from(part_ids)))?;
+        if self.pq.use_residual() {
+            let residuals = cast(&Float32Array::from(residuals), &self.centroids.value_type())?;
+            let residuals = FixedSizeListArray::try_new_from_values(residuals, dim as i32)?;
+            let codes = self.pq.transform(&residuals).await?;
+            batch = batch.replace_column_by_name(PQ_CODE_COLUMN, codes)?;
+        }
+        if batch.column_by_name(CENTROID_DISTANCE_COLUMN).is_some() {
+            batch = batch.replace_column_by_name(
+                CENTROID_DISTANCE_COLUMN,
+                Arc::new(Float32Array::from(distances)),
+            )?;
+        }
+        Ok(batch)
+    }
+}
+
+/// Partition and [residual_histogram_bin] of each row, from the L2 norm of the
+/// residual of the vector in `column` to the centroid of its partition.
+fn residual_bins(
+    batch: &RecordBatch,
+    column: &str,
+    centroids: &FixedSizeListArray,
+) -> Result<Vec<(usize, usize)>> {
+    let vectors = batch[column].as_fixed_size_list();
+    let dim = vectors.value_length() as usize;
+    let values = cast(vectors.values(), &DataType::Float32)?;
+    let centroids = cast(centroids.values(), &DataType::Float32)?;
+    let centroids = centroids.as_primitive::<Float32Type>().values();
+    Ok(values
+        .as_primitive::<Float32Type>()
+        .values()
+        .chunks_exact(dim)
+        .zip(batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().values())
+        .map(|(vector, &part_id)| {
+            let part_id = part_id as usize;
+            let centroid = &centroids[part_id * dim..(part_id + 1) * dim];
+            let norm = vector
+                .iter()
+                .zip(centroid)
+                .map(|(v, c)| (v - c) * (v - c))
+                .sum::<f32>()
+                .sqrt();
+            (part_id, residual_histogram_bin(norm))
         })
+        .collect())
+}
+
+/// Number of rows kept in each partition under [`IvfBuildParams::max_partition_rows`].
+///
+/// The transformed batches drop the rows of the full partitions, and the input stops
+/// once every partition of the range being built is full.
+pub struct PartitionCaps {
+    max_rows: usize,
+    part_range: Range<u32>,
+    state: Mutex<PartitionCounts>,
+    all_full: AtomicBool,
+}
+
+struct PartitionCounts {
+    counts: Vec<usize>,
+    num_full: usize,
+}
+
+impl PartitionCaps {
+    fn new(max_rows: usize, num_partitions: u32, part_range: Range<u32>) -> Self {
+        Self {
+            max_rows,
+            all_full: AtomicBool::new(part_range.is_empty()),
+            part_range,
+            state: Mutex::new(PartitionCounts {
+                counts: vec![0; num_partitions as usize],
+                num_full: 0,
+            }),
+        }
+    }
+
+    /// Mask of the rows of `batch` whose partition is not full yet, which are counted.
+    fn admit(&self, batch: &RecordBatch) -> BooleanArray {
+        let part_ids = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>();
+        let mut state = self.state.lock().unwrap();
+        let mask = BooleanArray::from_iter(part_ids.values().iter().map(|&part_id| {
+            let count = &mut state.counts[part_id as usize];
+            if *count >= self.max_rows {
+                return Some(false);
+            }
+            *count += 1;
+            if *count == self.max_rows && self.part_range.contains(&part_id) {
+                state.num_full += 1;
+            }
+            Some(true)
+        }));
+        if state.num_full == self.part_range.len() {
+            self.all_full.store(true, Ordering::SeqCst);
+        }
+        mask
+    }
+
+    /// Whether every partition of the range is full.
+    fn all_full(&self) -> bool {
+        self.all_full.load(Ordering::SeqCst)
+    }
+}
+
+/// Pass on the batches of `data` until every partition of `caps` is full.
+fn stop_when_full(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    caps: Arc<PartitionCaps>,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let schema = data.schema();
+    let stream = data
+        .take_while(move |_| future::ready(!caps.all_full()))
         .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Input batches after the IVF transforms, not grouped by partition yet.
+struct TransformedStream {
+    stream: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+
+    /// Report of the skipped batches and of the residual histograms, complete once
+    /// `stream` is consumed.
+    report: Arc<Mutex<BuildReport>>,
+}
 
+/// Apply the transforms of `params` and the IVF partitioning of `ivf` to each batch
+/// of `data`, see [shuffle_dataset_v2] for the parameters.
+///
+/// `fold` carries the alternative partitions of `ivf` for a [PartitionFold].
+#[allow(clippy::too_many_arguments)]
+fn transform_dataset(
+    data: impl RecordBatchStream + Unpin + 'static,
+    column: &str,
+    ivf: Arc<dyn lance_index::vector::ivf::Ivf>,
+    num_partitions: u32,
+    num_sub_vectors: usize,
+    params: &IvfBuildParams,
+    centroids: Option<(Arc<FixedSizeListArray>, MetricType)>,
+    fold: bool,
+    caps: Option<Arc<PartitionCaps>>,
+    env: &BuildEnvConfig,
+) -> Result<TransformedStream> {
     // TODO: dynamically detect schema from the transforms.
-    let schema = Arc::new(Schema::new(vec![
+    let mut fields = vec![
         ROW_ID_FIELD.clone(),
         Field::new(PART_ID_COLUMN, DataType::UInt32, false),
-        Field::new(
+    ];
+    if params.per_partition_pq {
+        // PQ codes are computed per partition after grouping, so carry the vectors instead.
+        fields.push(data.schema().field_with_name(column)?.clone());
+    } else {
+        fields.push(Field::new(
             PQ_CODE_COLUMN,
             DataType::FixedSizeList(
                 Arc::new(Field::new("item", DataType::UInt8, true)),
                 num_sub_vectors as i32,
             ),
             false,
-        ),
-    ]));
-
-    let stream = lance_core::io::RecordBatchStreamAdapter::new(schema.clone(), stream);
-
-    let shuffler = IvfShuffler::try_new(
-        num_partitions,
-        num_sub_vectors,
-        None,
-        LanceSchema::try_from(schema.as_ref())?,
-    )?;
-
-    let start = std::time::Instant::now();
-    shuffler.write_unsorted_stream(stream).await?;
-    info!("wrote raw stream: {:?}", start.elapsed());
+        ));
+    }
+    if let Some(pca) = params.pca.as_ref() {
+        fields.push(Field::new(
+            PCA_VECTOR_COLUMN,
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                pca.num_components() as i32,
+            ),
+            false,
+        ));
+    }
+    if params.valid_column.is_some() {
+        fields.push(Field::new(VALID_COLUMN, DataType::Boolean, false));
+    }
+    if params.subgroup_column.is_some() {
+        fields.push(Field::new(SUBGROUP_COLUMN, DataType::Utf8, true));
+    }
+    if params.assignment_margin {
+        fields.push(Field::new(
+            ASSIGNMENT_MARGIN_COLUMN,
+            DataType::Float32,
+            false,
+        ));
+    }
+    let histogram_centroids = centroids.filter(|_| params.collect_residual_histograms);
+    if params.compute_medoids {
+        fields.push(Field::new(
+            CENTROID_DISTANCE_COLUMN,
+            DataType::Float32,
+            false,
+        ));
+    }
+    if fold {
+        // The folded partitions are only known once the whole input is assigned, so
+        // carry the next nearest partitions of each row through the shuffle.
+        for (name, data_type) in [
+            (ALTERNATIVE_PARTITIONS_COLUMN, DataType::UInt32),
+            (ALTERNATIVE_DISTANCES_COLUMN, DataType::Float32),
+        ] {
+            fields.push(Field::new(
+                name,
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", data_type, true)),
+                    FOLD_ALTERNATIVES as i32,
+                ),
+                false,
+            ));
+        }
+    }
+    let schema = Arc::new(Schema::new(fields));
 
-    let start = std::time::Instant::now();
-    let partition_files = shuffler.write_partitioned_shuffles(10000, 2).await?;
-    info!("counted partition sizes: {:?}", start.elapsed());
+    let pca_transform = params
+        .pca
+        .as_ref()
+        .map(|pca| Arc::new(PcaTransform::new(pca.clone(), column)));
+    let weight_transform = params
+        .weights
+        .as_ref()
+        .map(|weights| WeightTransform::try_new(weights, column).map(Arc::new))
+        .transpose()?;
+    let column: Arc<str> = column.into();
+    let sample_mod = params.sample_mod;
+    let hash_shard = params.hash_shard;
+    let row_id_map = params.row_id_map.clone();
+    let time_window = params.time_window.clone();
+    let valid_column = params.valid_column.clone();
+    let subgroup_column = params.subgroup_column.clone();
+    let shuffle_schema = schema.clone();
+    let max_bad_batches = params.max_bad_batches;
+    let report = Arc::new(Mutex::new(BuildReport::default()));
+    if histogram_centroids.is_some() {
+        report.lock().unwrap().residual_histograms =
+            vec![vec![0; RESIDUAL_HISTOGRAM_BINS]; num_partitions as usize];
+    }
+    let task_report = report.clone();
+    let stream = data
+        .zip(repeat_with(move || {
+            (ivf.clone(), pca_transform.clone(), weight_transform.clone())
+        }))
+        .map(move |(b, (ivf, pca_transform, weight_transform))| {
+            let col_ref = column.clone();
+            let schema = shuffle_schema.clone();
+            let valid_column = valid_column.clone();
+            let subgroup_column = subgroup_column.clone();
+            let time_window = time_window.clone();
+            let histogram_centroids = histogram_centroids.clone();
+            let row_id_map = row_id_map.clone();
 
-    let start = std::time::Instant::now();
-    let stream = shuffler.load_partitioned_shuffles(partition_files).await?;
-    info!("merged partitioned shuffles: {:?}", start.elapsed());
+            tokio::task::spawn(async move {
+                let mut batch = b?;
+                let num_rows = batch.num_rows();
+                let res = async move {
+                    if let Some((num_shards, shard_id)) = hash_shard {
+                        batch = sample_by_row_id(&batch, num_shards, shard_id)?;
+                    }
+                    if let Some((n, r)) = sample_mod {
+                        batch = sample_by_row_id(&batch, n, r)?;
+                    }
+                    if let Some(window) = time_window {
+                        batch = filter_by_time_window(&batch, &window)?;
+                    }
+                    if batch.num_rows() == 0 {
+                        return Ok::<_, Error>(None);
+                    }
+                    if let Some(tombstone) = valid_column {
+                        batch = add_valid_column(&batch, &tombstone)?;
+                    }
+                    if let Some(subgroup_column) = subgroup_column {
+                        batch = add_subgroup_column(&batch, &subgroup_column)?;
+                    }
+                    if let Some(pca_transform) = pca_transform {
+                        batch = pca_transform.transform(&batch).await?;
+                    }
+                    if let Some(weight_transform) = weight_transform {
+                        batch = weight_transform.transform(&batch).await?;
+                    }
+                    if histogram_centroids.is_some() {
+                        let vectors = batch
+                            .column_by_name(col_ref.as_ref())
+                            .ok_or(Error::Index {
+                                message: format!("Column {} does not exist.", col_ref),
+                                location: location!(),
+                            })?
+                            .clone();
+                        batch = batch.try_with_column(
+                            Field::new(HISTOGRAM_VECTOR_COLUMN, vectors.data_type().clone(), false),
+                            vectors,
+                        )?;
+                    }
+                    let batch = ivf.partition_transform(&batch, col_ref.as_ref()).await?;
+                    let bins = histogram_centroids
+                        .map(|(centroids, _)| {
+                            residual_bins(&batch, HISTOGRAM_VECTOR_COLUMN, &centroids)
+                        })
+                        .transpose()?;
+                    // Transforms may append columns in any order.
+                    let mut batch = batch.project_by_schema(&schema)?;
+                    if let Some(row_id_map) = row_id_map {
+                        batch = map_row_ids(&batch, &row_id_map)?;
+                    }
+                    Ok(Some((batch, bins)))
+                }
+                .await;
+                Ok::<_, Error>((num_rows, res))
+            })
+        })
+        .buffer_unordered(env.concurrency)
+        .map(move |res| {
+            let (num_rows, err) = match res {
+                Ok(Ok((_, Ok(None)))) => return Ok(None),
+                Ok(Ok((_, Ok(Some((batch, bins)))))) => {
+                    // Only the admitted rows are counted in the histograms, so that
+                    // they add up to the lengths of the partitions.
+                    let (batch, bins) = match caps.as_ref() {
+                        Some(caps) => {
+                            let mask = caps.admit(&batch);
+                            let bins = bins.map(|bins| {
+                                bins.into_iter()
+                                    .zip(mask.values().iter())
+                                    .filter_map(|(bin, admitted)| admitted.then_some(bin))
+                                    .collect::<Vec<_>>()
+                            });
+                            let batch = filter_record_batch(&batch, &mask)?;
+                            (Some(batch).filter(|batch| batch.num_rows() > 0), bins)
+                        }
+                        None => (Some(batch), bins),
+                    };
+                    let mut report = task_report.lock().unwrap();
+                    if let Some(bins) = bins {
+                        for (part_id, bin) in bins {
+                            report.residual_histograms[part_id][bin] += 1;
+                        }
+                    }
+                    if let Some(batch) = batch.as_ref() {
+                        report.num_rows += batch.num_rows();
+                    }
+                    return Ok(batch);
+                }
+                Ok(Ok((num_rows, Err(err)))) => (num_rows, err),
+                // Only the batches that fail to be transformed count as bad batches, the
+                // errors of the input stream and of the task itself fail the build.
+                Ok(Err(err)) => return Err(err),
+                Err(err) => {
+                    return Err(Error::IO {
+                        message: err.to_string(),
+                        location: location!(),
+                    })
+                }
+            };
+            let mut report = task_report.lock().unwrap();
+            if max_bad_batches.is_some_and(|max| report.skipped_batches < max) {
+                warn!("Skipping a batch of {} rows: {}", num_rows, err);
+                report.skipped_batches += 1;
+                report.skipped_rows += num_rows;
+                Ok(None)
+            } else {
+                Err(Error::IO {
+                    message: err.to_string(),
+                    location: location!(),
+                })
+            }
+        })
+        .try_filter_map(|batch| future::ready(Ok(batch)))
+        .boxed();
+    let stream = match params.transform_channel_capacity {
+        Some(capacity) => bounded_channel(stream, capacity).boxed(),
+        None => stream,
+    };
 
-    Ok(stream)
+    Ok(TransformedStream {
+        stream: lance_core::io::RecordBatchStreamAdapter::new(schema, stream),
+        report,
+    })
 }
 
-/// Build specific partitions of IVF index.
+/// Disk-based shuffle a stream of [RecordBatch] into each IVF partition.
 ///
+/// Parameters
+/// ----------
+///   *data*: input data stream.
+///   *ivf*: IVF model. Under `params.compute_medoids` it adds the
+///   [CENTROID_DISTANCE_COLUMN], and with a fold the [ALTERNATIVE_PARTITIONS_COLUMN]
+///   of [FOLD_ALTERNATIVES] partitions, see [`lance_index::vector::ivf::new_ivf`].
+///   *centroids*: IVF centroids and metric, to compute the residual histograms under
+///   `params.collect_residual_histograms`, not computed if not provided.
+///   *fold*: reassigns the rows of the partitions under
+///   `params.min_partition_rows`, from their alternative partitions.
+///   *caps*: drops the rows of the partitions full under
+///   `params.max_partition_rows`.
 ///
+/// Returns
+/// -------
+///   The stream of each of the `num_partitions` partitions, tagged with its partition
+///   id, in increasing partition order, e.g. to process each partition in its own
+///   task, see [split_by_partition]. Also a [BuildReport] of the input batches skipped
+///   under `params.max_bad_batches` and of the residual histograms.
 #[allow(clippy::too_many_arguments)]
-#[instrument(level = "debug", skip(writer, data, ivf, pq))]
-pub(super) async fn build_partitions(
-    writer: &mut dyn Writer,
+pub async fn shuffle_dataset_v2(
+    data: impl RecordBatchStream + Unpin + 'static,
+    column: &str,
+    ivf: Arc<dyn lance_index::vector::ivf::Ivf>,
+    num_partitions: u32,
+    num_sub_vectors: usize,
+    params: &IvfBuildParams,
+    centroids: Option<(Arc<FixedSizeListArray>, MetricType)>,
+    fold: Option<Arc<dyn PartitionFold>>,
+    caps: Option<Arc<PartitionCaps>>,
+    env: &BuildEnvConfig,
+) -> Result<(
+    Vec<(u32, impl Stream<Item = Result<RecordBatch>>)>,
+    BuildReport,
+)> {
+    let fold = params.min_partition_rows.zip(fold);
+    let TransformedStream { stream, report } = transform_dataset(
+        data,
+        column,
+        ivf,
+        num_partitions,
+        num_sub_vectors,
+        params,
+        centroids,
+        fold.is_some(),
+        caps,
+        env,
+    )?;
+    let schema = stream.schema();
+
+    let spill_dir = env
+        .spill_dir
+        .as_ref()
+        .map(|dir| {
+            Path::from_filesystem_path(dir)
+                .map(|dir| dir.child(Uuid::new_v4().to_string()))
+                .map_err(|err| Error::IO {
+                    message: format!("invalid spill directory {}: {}", dir.display(), err),
+                    location: location!(),
+                })
+        })
+        .transpose()?;
+    let mut shuffler = IvfShuffler::try_new(
+        num_partitions,
+        spill_dir,
+        LanceSchema::try_from(schema.as_ref())?,
+    )?;
+    if let Some(callback) = params.on_spill_finalized.as_ref() {
+        shuffler.with_spill_callback(callback.clone());
+    }
+    if let Some(codec) = params.spill_codec.as_ref() {
+        shuffler.with_spill_codec(codec.clone());
+    }
+    if let Some(file_name_fn) = params.spill_file_name_fn.as_ref() {
+        shuffler.with_file_name_fn(file_name_fn.clone());
+    }
+    if let Some(pool) = params.shuffle_memory_pool.as_ref() {
+        shuffler.with_memory_pool(pool.clone(), params.shuffle_memory_min_available);
+    }
+    if let Some(max_open_files) = params.max_open_files {
+        shuffler.with_max_open_files(max_open_files);
+    }
+    if let Some(concurrency) = params.shuffle_count_concurrency {
+        shuffler.with_count_concurrency(concurrency);
+    }
+    shuffler.with_deterministic_spill_order(params.deterministic_spill_order);
+    if let Some((min_partition_rows, fold)) = fold {
+        shuffler.with_partition_fold(min_partition_rows, fold);
+    }
+
+    let start = std::time::Instant::now();
+    shuffler.write_unsorted_stream(stream).await?;
+    info!("wrote raw stream: {:?}", start.elapsed());
+
+    let start = std::time::Instant::now();
+    let partition_files = shuffler.write_partitioned_shuffles(10000, 2).await?;
+    info!("counted partition sizes: {:?}", start.elapsed());
+
+    shuffler.validate_spill_files(&partition_files).await?;
+    let start = std::time::Instant::now();
+    let streams = shuffler.load_partitioned_shuffles(partition_files).await?;
+    info!("merged partitioned shuffles: {:?}", start.elapsed());
+
+    let report = report.lock().unwrap().clone();
+    Ok((split_by_partition(streams, num_partitions), report))
+}
+
+/// Split `streams`, each sorted by partition id with the rows of one partition per
+/// batch, into a stream of each of the `num_partitions` partitions, tagged with its
+/// partition id.
+///
+/// The partition streams share the reads of `streams`. Reading the partitions in
+/// increasing order reads each batch once, straight to its partition. Reading a
+/// partition before the previous ones holds the batches of those in memory until
+/// their streams are read.
+pub(super) fn split_by_partition(
+    streams: Vec<BoxStream<'static, Result<RecordBatch>>>,
+    num_partitions: u32,
+) -> Vec<(u32, BoxStream<'static, Result<RecordBatch>>)> {
+    let splitter = Arc::new(tokio::sync::Mutex::new(PartitionSplitter {
+        streams: streams
+            .into_iter()
+            .map(|stream| Box::pin(stream.peekable()))
+            .collect(),
+        pending: HashMap::new(),
+    }));
+    (0..num_partitions)
+        .map(|part_id| {
+            let stream = futures::stream::unfold(splitter.clone(), move |splitter| async move {
+                let batch = splitter.lock().await.next_batch(part_id).await?;
+                Some((batch, splitter))
+            });
+            (part_id, stream.boxed())
+        })
+        .collect()
+}
+
+/// Chain the partition streams of [split_by_partition] back into one stream sorted by
+/// partition id.
+pub(super) fn chain_partitions(
+    partitions: Vec<(
+        u32,
+        impl Stream<Item = Result<RecordBatch>> + Send + 'static,
+    )>,
+) -> BoxStream<'static, Result<RecordBatch>> {
+    futures::stream::iter(partitions)
+        .flat_map(|(_, stream)| stream)
+        .boxed()
+}
+
+type PeekableBatches =
+    std::pin::Pin<Box<futures::stream::Peekable<BoxStream<'static, Result<RecordBatch>>>>>;
+
+/// Shared state of the partition streams of [split_by_partition].
+struct PartitionSplitter {
+    streams: Vec<PeekableBatches>,
+
+    /// Batches read ahead of the stream of their partition.
+    pending: HashMap<u32, std::collections::VecDeque<RecordBatch>>,
+}
+
+impl PartitionSplitter {
+    /// The next batch of partition `part_id`, or `None` once it has no more rows.
+    async fn next_batch(&mut self, part_id: u32) -> Option<Result<RecordBatch>> {
+        loop {
+            if let Some(batch) = self.pending.get_mut(&part_id).and_then(|b| b.pop_front()) {
+                return Some(Ok(batch));
+            }
+            // The stream whose next batch has the smallest partition id.
+            let mut next: Option<(u32, usize)> = None;
+            for (idx, stream) in self.streams.iter_mut().enumerate() {
+                let head = match stream.as_mut().peek().await {
+                    Some(Ok(batch)) if batch.num_rows() == 0 => 0,
+                    Some(Ok(batch)) => batch[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0),
+                    Some(Err(_)) => return stream.next().await,
+                    None => continue,
+                };
+                if next.map_or(true, |(min, _)| head < min) {
+                    next = Some((head, idx));
+                }
+            }
+            let (head, idx) = next?;
+            if head > part_id {
+                return None;
+            }
+            let batch = match self.streams[idx].next().await? {
+                Ok(batch) if batch.num_rows() == 0 => continue,
+                Ok(batch) => batch,
+                Err(err) => return Some(Err(err)),
+            };
+            if head == part_id {
+                return Some(Ok(batch));
+            }
+            self.pending.entry(head).or_default().push_back(batch);
+        }
+    }
+}
+
+/// Forward `stream` through a channel of `capacity` items, from a spawned task.
+///
+/// The task only pulls from `stream` while the channel has room, so the work in
+/// flight upstream is capped by the progress of the consumer.
+fn bounded_channel<T: Send + 'static>(
+    stream: impl Stream<Item = Result<T>> + Send + 'static,
+    capacity: usize,
+) -> impl Stream<Item = Result<T>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(capacity.max(1));
+    let producer = tokio::task::spawn(async move {
+        let mut stream = Box::pin(stream);
+        while let Some(item) = stream.next().await {
+            if tx.send(item).await.is_err() {
+                // The consumer is gone.
+                break;
+            }
+        }
+    });
+    futures::stream::unfold((rx, Some(producer)), |(mut rx, producer)| async move {
+        if let Some(item) = rx.recv().await {
+            return Some((item, (rx, producer)));
+        }
+        // The channel is closed, report if the producer panicked.
+        match producer?.await {
+            Ok(()) => None,
+            Err(err) => Some((
+                Err(Error::IO {
+                    message: err.to_string(),
+                    location: location!(),
+                }),
+                (rx, None),
+            )),
+        }
+    })
+}
+
+/// State updated with the vector `column` of each batch read by [build_partitions], see
+/// [inspect_batches].
+trait BatchObserver: Send + 'static {
+    fn observe(&mut self, batch: &RecordBatch, column: &str) -> Result<()>;
+}
+
+impl BatchObserver for VectorStats {
+    fn observe(&mut self, batch: &RecordBatch, column: &str) -> Result<()> {
+        self.update(batch[column].as_fixed_size_list())
+    }
+}
+
+impl BatchObserver for NormHistogram {
+    fn observe(&mut self, batch: &RecordBatch, column: &str) -> Result<()> {
+        self.update(batch[column].as_fixed_size_list())
+    }
+}
+
+impl BatchObserver for SelfRecallSample {
+    fn observe(&mut self, batch: &RecordBatch, column: &str) -> Result<()> {
+        self.update(batch, column)
+    }
+}
+
+/// Pass each batch of `data` to `observer` as it is read.
+fn inspect_batches(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    column: &str,
+    observer: Arc<Mutex<impl BatchObserver>>,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let schema = data.schema();
+    let column = column.to_string();
+    let stream = data
+        .and_then(move |batch| {
+            let res = observer
+                .lock()
+                .unwrap()
+                .observe(&batch, column.as_str())
+                .map(|_| batch);
+            future::ready(res)
+        })
+        .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Apply `policy` to the vectors of zero norm in each batch of `data`, counting them in
+/// `count`, see [`IvfBuildParams::on_zero_norm`]. `centroid` is the centroid of
+/// partition 0.
+///
+/// With [`ZeroNormPolicy::AssignToPartitionZero`], the batches get a
+/// [PINNED_PARTITION_COLUMN] assigning the rows of zero norm to partition 0.
+fn handle_zero_norms(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    column: &str,
+    policy: ZeroNormPolicy,
+    centroid: ArrayRef,
+    count: Arc<AtomicUsize>,
+) -> Result<lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>> {
+    let pinned_field = Field::new(PINNED_PARTITION_COLUMN, DataType::UInt32, true);
+    let schema = match policy {
+        ZeroNormPolicy::AssignToPartitionZero => Arc::new(
+            data.schema()
+                .as_ref()
+                .try_with_column(pinned_field.clone())?,
+        ),
+        _ => data.schema(),
+    };
+    let column = column.to_string();
+    let stream = data
+        .and_then(move |batch| {
+            let res = (|| {
+                let vectors = batch[column.as_str()].as_fixed_size_list();
+                let zero_norms = zero_norm_mask(vectors)?;
+                let num_zero_norms = zero_norms.true_count();
+                if num_zero_norms == 0 && policy != ZeroNormPolicy::AssignToPartitionZero {
+                    return Ok(batch);
+                }
+                count.fetch_add(num_zero_norms, Ordering::Relaxed);
+                match policy {
+                    ZeroNormPolicy::Skip => Ok(filter_record_batch(&batch, &not(&zero_norms)?)?),
+                    ZeroNormPolicy::Error => Err(Error::Index {
+                        message: format!(
+                            "Column {} has vectors of zero norm, which have no direction under the Cosine metric: {} in a batch",
+                            column, num_zero_norms
+                        ),
+                        location: location!(),
+                    }),
+                    ZeroNormPolicy::AssignToPartitionZero => {
+                        // The vectors are encoded as the centroid, in partition 0 whatever
+                        // the nearest centroid to it.
+                        let pinned = zero_norms
+                            .iter()
+                            .map(|zero_norm| zero_norm.unwrap_or_default().then_some(0))
+                            .collect::<UInt32Array>();
+                        let batch = if num_zero_norms > 0 {
+                            let vectors =
+                                replace_vectors(vectors, &zero_norms, centroid.as_ref())?;
+                            batch.replace_column_by_name(&column, Arc::new(vectors))?
+                        } else {
+                            batch
+                        };
+                        Ok(batch.try_with_column(pinned_field.clone(), Arc::new(pinned))?)
+                    }
+                }
+            })();
+            future::ready(res)
+        })
+        .boxed();
+    Ok(lance_core::io::RecordBatchStreamAdapter::new(
+        schema, stream,
+    ))
+}
+
+/// Apply `policy` to the vectors of zero norm of the training sample `data` of
+/// `column`, see [`IvfBuildParams::on_zero_norm`]: they are left out of the sample,
+/// or fail the build with [`ZeroNormPolicy::Error`]. Only the Cosine metric handles
+/// the vectors of zero norm.
+pub(super) fn filter_zero_norm_training_data(
+    data: FixedSizeListArray,
+    column: &str,
+    metric_type: MetricType,
+    policy: ZeroNormPolicy,
+) -> Result<FixedSizeListArray> {
+    if metric_type != MetricType::Cosine {
+        return Ok(data);
+    }
+    let zero_norms = zero_norm_mask(&data)?;
+    let num_zero_norms = zero_norms.true_count();
+    if num_zero_norms == 0 {
+        return Ok(data);
+    }
+    if policy == ZeroNormPolicy::Error {
+        return Err(Error::Index {
+            message: format!(
+                "Column {} has vectors of zero norm, which have no direction under the Cosine metric: {} in the training sample",
+                column, num_zero_norms
+            ),
+            location: location!(),
+        });
+    }
+    Ok(filter(&data, &not(&zero_norms)?)?
+        .as_fixed_size_list()
+        .clone())
+}
+
+/// Whether each vector of `vectors` is non-null with all its values zero.
+fn zero_norm_mask(vectors: &FixedSizeListArray) -> Result<BooleanArray> {
+    let dim = vectors.value_length() as usize;
+    let values = cast(vectors.values(), &DataType::Float32)?;
+    let values = values.as_primitive::<Float32Type>().values();
+    Ok(BooleanArray::from_iter((0..vectors.len()).map(|i| {
+        let start = vectors.value_offset(i) as usize;
+        Some(vectors.is_valid(i) && values[start..start + dim].iter().all(|v| *v == 0.0))
+    })))
+}
+
+/// `vectors` with the vectors selected by `mask` replaced with `replacement`.
+fn replace_vectors(
+    vectors: &FixedSizeListArray,
+    mask: &BooleanArray,
+    replacement: &dyn Array,
+) -> Result<FixedSizeListArray> {
+    let DataType::FixedSizeList(field, dim) = vectors.data_type() else {
+        unreachable!("vectors are a fixed size list");
+    };
+    let values = vectors.values();
+    let replacement = cast(replacement, values.data_type())?;
+    let combined = concat(&[values.as_ref(), replacement.as_ref()])?;
+    let indices = (0..vectors.len())
+        .flat_map(|i| {
+            let start = if mask.value(i) {
+                values.len()
+            } else {
+                vectors.value_offset(i) as usize
+            };
+            (start..start + *dim as usize).map(|idx| idx as u64)
+        })
+        .collect::<UInt64Array>();
+    Ok(FixedSizeListArray::try_new(
+        field.clone(),
+        *dim,
+        take(&combined, &indices, None)?,
+        vectors.nulls().cloned(),
+    )?)
+}
+
+/// Pass on each batch of `data` once `control` is not paused, so that a paused build
+/// transforms no further batches.
+fn pause_with(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    control: BuildControl,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let schema = data.schema();
+    let stream = data
+        .then(move |batch| {
+            let control = control.clone();
+            async move {
+                control.wait_if_paused().await;
+                batch
+            }
+        })
+        .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Token bucket of [`IvfBuildParams::read_rate_limit`], holding up to a tenth of a
+/// second of bytes.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: BytesPerSec) -> Self {
+        let rate = rate.0.max(1) as f64;
+        Self {
+            rate,
+            capacity: rate / 10.0,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` from the bucket and return how long to wait before reading them.
+    ///
+    /// A batch larger than the bucket is still let through, and the debt is paid by
+    /// the following waits.
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity) - bytes as f64;
+        self.last_refill = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Pass on each batch of `data` once it fits in `rate`, by its in-memory size, see
+/// [`IvfBuildParams::read_rate_limit`].
+fn throttle_reads(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    rate: BytesPerSec,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let schema = data.schema();
+    let bucket = Arc::new(Mutex::new(TokenBucket::new(rate)));
+    let stream = data
+        .then(move |batch| {
+            let wait = match batch.as_ref() {
+                Ok(batch) => bucket.lock().unwrap().take(batch.get_array_memory_size()),
+                Err(_) => Duration::ZERO,
+            };
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                batch
+            }
+        })
+        .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Pass on the batches of `data` until `deadline`, then end the stream and set
+/// `stopped`, see [`IvfBuildParams::max_duration`].
+///
+/// The deadline is raced against the next batch, so the stream also ends in time if
+/// `data` stalls.
+fn stop_at(
+    data: lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>,
+    deadline: tokio::time::Instant,
+    stopped: Arc<AtomicBool>,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let schema = data.schema();
+    let stream = data
+        .take_until(async move {
+            tokio::time::sleep_until(deadline).await;
+            stopped.store(true, Ordering::SeqCst);
+        })
+        .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Fail if the norms of `histogram` are bimodal, see
+/// [`IvfBuildParams::reject_bimodal_norms`].
+fn check_norm_bimodality(histogram: &NormHistogram, column: &str) -> Result<()> {
+    match histogram.bimodality() {
+        Some(bimodality) => Err(Error::Index {
+            message: format!(
+                "Column {}: the vector norms are bimodal, {:.1}% of the {} vectors have a norm below {} and the others above, {:.1} standard deviations apart. The column may mix embeddings of different models.",
+                column,
+                bimodality.low_fraction * 100.0,
+                histogram.num_rows(),
+                bimodality.threshold,
+                bimodality.separation
+            ),
+            location: location!(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Decode the binary vector `column` of `data` with `codec`, in parallel.
+fn decode_vector_column(
     data: impl RecordBatchStream + Unpin + 'static,
     column: &str,
+    codec: Arc<dyn VectorCodec>,
+    dimension: usize,
+) -> lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>> {
+    let transform = Arc::new(DecodeTransform::new(codec, column, dimension));
+    let schema = data.schema();
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|f| {
+            if f.name() == column {
+                Arc::new(Field::new(column, transform.data_type(), f.is_nullable()))
+            } else {
+                f.clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    let schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+
+    let stream = data
+        .zip(repeat_with(move || transform.clone()))
+        .map(|(b, transform)| {
+            tokio::task::spawn(async move {
+                let batch = b?;
+                transform.transform(&batch).await
+            })
+        })
+        .buffered(num_cpus::get())
+        .map(|res| match res {
+            Ok(batch) => batch,
+            Err(err) => Err(Error::IO {
+                message: err.to_string(),
+                location: location!(),
+            }),
+        })
+        .boxed();
+    lance_core::io::RecordBatchStreamAdapter::new(schema, stream)
+}
+
+/// Scan the columns of `dataset` needed to build the IVF partitions of `column`.
+///
+/// The scan is projected to the vector column, ROW_ID and the columns `params`
+/// refer to. Deleted rows are skipped by the scan.
+///
+/// It is the scan [`super::build_ivf_pq_index`] builds the partitions from.
+pub async fn scan_index_columns(
+    dataset: &Dataset,
+    column: &str,
+    params: &IvfBuildParams,
+) -> Result<DatasetRecordBatchStream> {
+    let mut scanner = dataset.scan();
+    project_index_columns(&mut scanner, column, params)?;
+    scanner.try_into_stream().await
+}
+
+fn project_index_columns(
+    scanner: &mut Scanner,
+    column: &str,
+    params: &IvfBuildParams,
+) -> Result<()> {
+    scanner.batch_readahead(num_cpus::get() * 2);
+    let input_columns = vector_input_columns(column, params)?;
+    let mut projection = input_columns.iter().map(String::as_str).collect::<Vec<_>>();
+    if let Some(valid_column) = params.valid_column.as_deref() {
+        projection.push(valid_column);
+    }
+    if let Some(subgroup_column) = params.subgroup_column.as_deref() {
+        projection.push(subgroup_column);
+    }
+    if let Some(window) = params.time_window.as_ref() {
+        projection.push(&window.column);
+    }
+    if let Some(key) = params.presort_by.as_deref() {
+        projection.push(key);
+    }
+    scanner.project(&projection)?;
+    scanner.with_row_id();
+    Ok(())
+}
+
+/// The columns of the dataset the vector `column` is read from: the columns read by
+/// [`IvfBuildParams::vector_expr`] if set, sorted by name, otherwise `column` itself.
+pub(super) fn vector_input_columns(column: &str, params: &IvfBuildParams) -> Result<Vec<String>> {
+    match params.vector_expr.as_ref() {
+        Some(expr) => {
+            let mut columns = expr
+                .to_columns()?
+                .into_iter()
+                .map(|c| c.name)
+                .collect::<Vec<_>>();
+            columns.sort();
+            Ok(columns)
+        }
+        None => Ok(vec![column.to_string()]),
+    }
+}
+
+/// Scan the index columns of the `fragment_ids` of `dataset`, see [scan_index_columns],
+/// with up to `max_concurrency` fragments scanned at the same time.
+///
+/// The batches of the fragments being scanned are interleaved in the order they are read.
+pub(super) fn scan_fragments(
+    dataset: &Dataset,
+    fragment_ids: &[usize],
+    column: &str,
+    params: &IvfBuildParams,
+    max_concurrency: usize,
+) -> Result<lance_core::io::RecordBatchStreamAdapter<BoxStream<'static, Result<RecordBatch>>>> {
+    let scanners = fragment_ids
+        .iter()
+        .map(|&fragment_id| {
+            let fragment = dataset
+                .get_fragment(fragment_id)
+                .ok_or_else(|| Error::Index {
+                    message: format!("fragment {} does not exist in dataset", fragment_id),
+                    location: location!(),
+                })?;
+            let mut scanner = fragment.scan();
+            project_index_columns(&mut scanner, column, params)?;
+            Ok(scanner)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let Some(first) = scanners.first() else {
+        return Err(Error::Index {
+            message: "No fragments to scan for building the index".to_string(),
+            location: location!(),
+        });
+    };
+    let schema = first.schema()?;
+
+    let max_concurrency = max_concurrency.max(1);
+    let stream = futures::stream::iter(scanners)
+        .map(|scanner| async move { scanner.try_into_stream().await })
+        .buffered(max_concurrency)
+        .try_flatten_unordered(max_concurrency)
+        .boxed();
+    Ok(lance_core::io::RecordBatchStreamAdapter::new(
+        schema, stream,
+    ))
+}
+
+/// Build all the partitions of IVF index from a scan of `dataset`.
+///
+/// See [scan_index_columns] for the columns that are read. The public entry point
+/// is [`super::build_ivf_pq_index`], which trains the IVF and PQ models first.
+/// With [`IvfBuildParams::parallel_fragment_scans`], each fragment is scanned
+/// separately, see [build_index_from_fragments].
+///
+/// Only the rows kept by `mask`, if any, are indexed, see [build_partitions_with_mask].
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn build_index_from_dataset(
+    writer: &mut dyn Writer,
+    cold_writer: Option<&mut dyn Writer>,
+    dataset: &Dataset,
+    column: &str,
     ivf: &mut Ivf,
     pq: Arc<dyn ProductQuantizer>,
     metric_type: MetricType,
-    part_range: Range<u32>,
     precomputed_partitons: Option<HashMap<u64, u32>>,
-) -> Result<()> {
-    let schema = data.schema();
-    if schema.column_with_name(column).is_none() {
-        return Err(Error::Schema {
-            message: format!("column {} does not exist in data stream", column),
+    mask: Option<RowMask>,
+    params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    if let Some(mask) = mask {
+        if params.parallel_fragment_scans.is_some() {
+            return Err(Error::Index {
+                message: "A row mask is not supported with parallel_fragment_scans".to_string(),
+                location: location!(),
+            });
+        }
+        let stream = scan_index_columns(dataset, column, params).await?;
+        let num_partitions = ivf.num_partitions() as u32;
+        return build_partitions_with_mask(
+            writer,
+            cold_writer,
+            stream,
+            mask,
+            column,
+            ivf,
+            pq,
+            metric_type,
+            0..num_partitions,
+            precomputed_partitons,
+            params,
+            env,
+        )
+        .await;
+    }
+    if params.parallel_fragment_scans.is_some() {
+        let fragment_ids = dataset
+            .get_fragments()
+            .iter()
+            .map(|f| f.id())
+            .collect::<Vec<_>>();
+        return build_index_from_fragments(
+            writer,
+            cold_writer,
+            dataset,
+            &fragment_ids,
+            column,
+            ivf,
+            pq,
+            metric_type,
+            precomputed_partitons,
+            params,
+            env,
+        )
+        .await;
+    }
+    let stream = scan_index_columns(dataset, column, params).await?;
+    let num_partitions = ivf.num_partitions() as u32;
+    build_partitions(
+        writer,
+        cold_writer,
+        stream,
+        column,
+        ivf,
+        pq,
+        metric_type,
+        0..num_partitions,
+        precomputed_partitons,
+        params,
+        env,
+    )
+    .await
+}
+
+/// The columns of the input of [build_partitions] for `column`, with `params`.
+fn required_columns<'a>(column: &'a str, params: &'a IvfBuildParams) -> Vec<&'a str> {
+    // Otherwise the vector column is produced from the columns the expression reads.
+    let mut columns = match params.vector_expr {
+        Some(_) => vec![ROW_ID],
+        None => vec![column, ROW_ID],
+    };
+    if let Some(valid_column) = params.valid_column.as_deref() {
+        columns.push(valid_column);
+    }
+    if let Some(subgroup_column) = params.subgroup_column.as_deref() {
+        columns.push(subgroup_column);
+    }
+    if let Some(window) = params.time_window.as_ref() {
+        columns.push(&window.column);
+    }
+    if let Some(key) = params.presort_by.as_deref() {
+        columns.push(key);
+    }
+    columns
+}
+
+/// Check that `schema` has all the `columns`, reporting all the missing ones at once.
+fn require_columns(schema: &Schema, columns: &[&str]) -> Result<()> {
+    let missing = columns
+        .iter()
+        .filter(|c| schema.column_with_name(c).is_none())
+        .map(|c| format!("column {} does not exist in data stream", c))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Schema {
+            message: missing.join("; "),
             location: location!(),
-        });
+        })
     }
-    if schema.column_with_name(ROW_ID).is_none() {
-        return Err(Error::Schema {
-            message: "ROW ID is not set when building index partitions".to_string(),
+}
+
+/// Check that `pq` encodes vectors of the dimension of `column`, so that a PQ trained
+/// for other vectors fails the build instead of producing wrong codes.
+fn check_pq_dimension(schema: &Schema, column: &str, pq: &dyn ProductQuantizer) -> Result<()> {
+    let data_type = schema.field_with_name(column)?.data_type();
+    let DataType::FixedSizeList(_, dimension) = data_type else {
+        return Err(Error::Index {
+            message: format!(
+                "column {} is not a fixed size list of vectors: {}",
+                column, data_type
+            ),
+            location: location!(),
+        });
+    };
+    if *dimension as usize != pq.dimension() {
+        return Err(Error::Index {
+            message: format!(
+                "PQ dimension {} does not match the dimension {} of column {}",
+                pq.dimension(),
+                dimension,
+                column
+            ),
             location: location!(),
         });
     }
+    Ok(())
+}
 
-    let ivf_model = lance_index::vector::ivf::new_ivf_with_pq(
-        ivf.centroids.values(),
-        ivf.centroids.value_length() as usize,
+/// Train the coarse quantizer tree of `ivf` if [`IvfBuildParams::num_coarse_partitions`]
+/// or [`IvfBuildParams::store_assignment_accelerator`] is set, with the covering radii
+/// of its groups for the latter.
+pub(super) async fn train_coarse_quantizer(
+    ivf: &mut Ivf,
+    metric_type: MetricType,
+    params: &IvfBuildParams,
+) -> Result<()> {
+    let num_groups = match params.num_coarse_partitions {
+        Some(num_coarse_partitions) => num_coarse_partitions,
+        None if params.store_assignment_accelerator => {
+            (ivf.num_partitions() as f64).sqrt().ceil() as usize
+        }
+        None => return Ok(()),
+    };
+    let mut tree = IvfTree::train(
+        &ivf.centroids,
+        num_groups,
         metric_type,
-        column,
-        pq.clone(),
-        Some(part_range),
-        precomputed_partitons,
-    )?;
-
-    let stream = shuffle_dataset_v2(
-        data,
-        column,
-        ivf_model,
-        ivf.num_partitions() as u32,
-        pq.num_sub_vectors(),
+        params.seed.unwrap_or_else(rand::random),
     )
     .await?;
+    if params.store_assignment_accelerator {
+        tree = tree.with_radii(&ivf.centroids)?;
+    }
+    ivf.tree = Some(tree);
+    Ok(())
+}
 
-    write_index_partitions(writer, ivf, stream, None).await?;
+/// Train the IVF and PQ models on the first `num_training_rows` rows of `data`, and
+/// build all the partitions of the index from the whole of `data` in the same pass.
+///
+/// `data` is read ahead while the models are trained, so the IO of the scan overlaps
+/// with the training. The training rows are not sampled, so the prefix of `data`
+/// should be representative of the rest, e.g. a dataset written in random order.
+///
+/// The index is the same as the one built by [build_partitions] from the trained models.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn build_partitions_pipelined(
+    writer: &mut dyn Writer,
+    cold_writer: Option<&mut dyn Writer>,
+    data: impl RecordBatchStream + Unpin + 'static,
+    column: &str,
+    metric_type: MetricType,
+    num_training_rows: usize,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<(Ivf, Arc<dyn ProductQuantizer>, BuildReport)> {
+    if ivf_params.vector_codec.is_some() {
+        return Err(Error::Index {
+            message: "Pipelined IVF training does not support vector_codec".to_string(),
+            location: location!(),
+        });
+    }
+    let schema = data.schema();
+    require_columns(&schema, &required_columns(column, ivf_params))?;
+    let mut data = bounded_channel(data, num_cpus::get() * 2).boxed();
 
-    Ok(())
+    let mut prefix = vec![];
+    let mut num_rows = 0;
+    while num_rows < num_training_rows {
+        let Some(batch) = data.try_next().await? else {
+            break;
+        };
+        num_rows += batch.num_rows();
+        prefix.push(batch);
+    }
+    let vectors = prefix
+        .iter()
+        .map(|batch| {
+            batch.column_by_name(column).ok_or_else(|| Error::Schema {
+                message: format!("column {} does not exist in batch", column),
+                location: location!(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if num_rows == 0 {
+        return Err(Error::Index {
+            message: "Pipelined IVF training: the data stream is empty".to_string(),
+            location: location!(),
+        });
+    }
+    let vectors = concat(&vectors.iter().map(|v| v.as_ref()).collect::<Vec<_>>())?;
+    let training_data = vectors
+        .as_fixed_size_list_opt()
+        .ok_or_else(|| Error::Index {
+            message: format!(
+                "Pipelined IVF training: column {} is not fixed size list: {}",
+                column,
+                vectors.data_type()
+            ),
+            location: location!(),
+        })?
+        .slice(0, num_rows.min(num_training_rows));
+    let training_data = filter_zero_norm_training_data(
+        training_data,
+        column,
+        metric_type,
+        ivf_params.on_zero_norm,
+    )?;
+
+    let start = std::time::Instant::now();
+    info!(
+        "Start to train IVF model on the first {} rows",
+        training_data.len()
+    );
+    let mut ivf = if ivf_params.density_sampling {
+        let sample = density_weighted_sample(
+            &training_data,
+            ivf_params.num_partitions * ivf_params.sample_rate,
+            &mut seeded_rng(ivf_params.seed),
+        )?;
+        train_ivf_model(&sample, metric_type, ivf_params).await?
+    } else {
+        train_ivf_model(&training_data, metric_type, ivf_params).await?
+    };
+    train_coarse_quantizer(&mut ivf, metric_type, ivf_params).await?;
+    ivf.weights = ivf_params.weights.clone();
+    let pq = if let Some(codebook) = &pq_params.codebook {
+        new_pq_with_codebook(codebook, ivf.dimension(), metric_type, pq_params)
+    } else {
+        let sample_size = num_centroids(pq_params.num_bits as u32) * pq_params.sample_rate;
+        let training_data = if training_data.len() > sample_size {
+            training_data.sample(sample_size)?
+        } else {
+            training_data
+        };
+        train_pq_model(&ivf, &training_data, metric_type, pq_params).await?
+    };
+    info!(
+        "Trained IVF and PQ models in {:02} seconds",
+        start.elapsed().as_secs_f32()
+    );
+
+    let data = futures::stream::iter(prefix.into_iter().map(Ok))
+        .chain(data)
+        .boxed();
+    let data = lance_core::io::RecordBatchStreamAdapter::new(schema, data);
+    let num_partitions = ivf.num_partitions() as u32;
+    let report = build_partitions(
+        writer,
+        cold_writer,
+        data,
+        column,
+        &mut ivf,
+        pq.clone(),
+        metric_type,
+        0..num_partitions,
+        None,
+        ivf_params,
+        env,
+    )
+    .await?;
+    Ok((ivf, pq, report))
+}
+
+/// Build all the partitions of IVF index from parallel scans of the `fragment_ids`
+/// of `dataset`, see [scan_fragments], of up to
+/// [`IvfBuildParams::parallel_fragment_scans`] fragments at a time.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn build_index_from_fragments(
+    writer: &mut dyn Writer,
+    cold_writer: Option<&mut dyn Writer>,
+    dataset: &Dataset,
+    fragment_ids: &[usize],
+    column: &str,
+    ivf: &mut Ivf,
+    pq: Arc<dyn ProductQuantizer>,
+    metric_type: MetricType,
+    precomputed_partitons: Option<HashMap<u64, u32>>,
+    params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    let max_concurrency = params.parallel_fragment_scans.unwrap_or_else(num_cpus::get);
+    let stream = scan_fragments(dataset, fragment_ids, column, params, max_concurrency)?;
+    let num_partitions = ivf.num_partitions() as u32;
+    build_partitions(
+        writer,
+        cold_writer,
+        stream,
+        column,
+        ivf,
+        pq,
+        metric_type,
+        0..num_partitions,
+        precomputed_partitons,
+        params,
+        env,
+    )
+    .await
+}
+
+/// Precomputed boolean mask of the rows of the input to index, true for the rows to
+/// keep. A null is the same as false.
+///
+/// The input of [`super::build_ivf_pq_index_with_mask`] is the rows of the dataset
+/// read by [scan_index_columns], in order.
+pub enum RowMask {
+    /// One mask over all the rows of the input, in order.
+    Whole(BooleanArray),
+    /// One mask per batch of the input, each of the length of its batch.
+    Batches(BoxStream<'static, Result<BooleanArray>>),
+}
+
+/// Build specific partitions of IVF index from the rows of `data` kept by `mask`.
+///
+/// Unlike a filter expression, the mask is applied to each batch as it is read,
+/// without evaluating anything. It is the same as [build_partitions] otherwise.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn build_partitions_with_mask(
+    writer: &mut dyn Writer,
+    cold_writer: Option<&mut dyn Writer>,
+    data: impl RecordBatchStream + Unpin + 'static,
+    mask: RowMask,
+    column: &str,
+    ivf: &mut Ivf,
+    pq: Arc<dyn ProductQuantizer>,
+    metric_type: MetricType,
+    part_range: Range<u32>,
+    precomputed_partitons: Option<HashMap<u64, u32>>,
+    params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    let schema = data.schema();
+    let data = data.boxed();
+    let stream = match mask {
+        RowMask::Whole(mask) => {
+            let mut offset = 0;
+            data.and_then(move |batch| {
+                let num_rows = batch.num_rows();
+                let res = if offset + num_rows > mask.len() {
+                    Err(Error::Index {
+                        message: format!(
+                            "Row mask of {} rows is shorter than the input, which has at least {} rows",
+                            mask.len(),
+                            offset + num_rows
+                        ),
+                        location: location!(),
+                    })
+                } else {
+                    let batch_mask = mask.slice(offset, num_rows);
+                    offset += num_rows;
+                    filter_record_batch(&batch, &batch_mask).map_err(Error::from)
+                };
+                future::ready(res)
+            })
+            .boxed()
+        }
+        RowMask::Batches(masks) => data
+            .zip(masks.map(Some).chain(repeat_with(|| None)))
+            .map(|(batch, mask)| {
+                let batch = batch?;
+                let mask = mask.ok_or_else(|| Error::Index {
+                    message: "Row mask has fewer batches than the input".to_string(),
+                    location: location!(),
+                })??;
+                if mask.len() != batch.num_rows() {
+                    return Err(Error::Index {
+                        message: format!(
+                            "Row mask of {} rows is not aligned to a batch of {} rows",
+                            mask.len(),
+                            batch.num_rows()
+                        ),
+                        location: location!(),
+                    });
+                }
+                Ok(filter_record_batch(&batch, &mask)?)
+            })
+            .boxed(),
+    };
+    let data = lance_core::io::RecordBatchStreamAdapter::new(schema, stream);
+    build_partitions(
+        writer,
+        cold_writer,
+        data,
+        column,
+        ivf,
+        pq,
+        metric_type,
+        part_range,
+        precomputed_partitons,
+        params,
+        env,
+    )
+    .await
+}
+
+/// Build specific partitions of IVF index.
+///
+/// The index has all the partitions of `ivf`, those outside of `part_range` are empty.
+/// With an empty `part_range`, e.g. for a worker of a distributed build that got no
+/// partitions, `data` is not scanned and an index of only empty partitions is written.
+/// With a single partition, the transformed rows are written as they come, without
+/// the shuffle.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip(writer, cold_writer, data, ivf, pq))]
+pub(super) async fn build_partitions(
+    writer: &mut dyn Writer,
+    cold_writer: Option<&mut dyn Writer>,
+    data: impl RecordBatchStream + Unpin + 'static,
+    column: &str,
+    ivf: &mut Ivf,
+    pq: Arc<dyn ProductQuantizer>,
+    metric_type: MetricType,
+    part_range: Range<u32>,
+    precomputed_partitons: Option<HashMap<u64, u32>>,
+    params: &IvfBuildParams,
+    env: &BuildEnvConfig,
+) -> Result<BuildReport> {
+    let start = Instant::now();
+    let schema = data.schema();
+    require_columns(&schema, &required_columns(column, params))?;
+    let cold_tier = match (params.tier_fn.clone(), cold_writer) {
+        (Some(tier_fn), Some(writer)) => Some(ColdTier { writer, tier_fn }),
+        (Some(_), None) => {
+            return Err(Error::Index {
+                message: "tier_fn requires a writer of the cold partitions".to_string(),
+                location: location!(),
+            })
+        }
+        (None, _) => None,
+    };
+    let data = lance_core::io::RecordBatchStreamAdapter::new(schema.clone(), data.boxed());
+    let data = match params.read_rate_limit {
+        Some(rate) => throttle_reads(data, rate),
+        None => data,
+    };
+    // The DataFusion queries of the build share one context.
+    let ctx = session_context(None, env)?;
+    let data = match params.presort_by.as_deref() {
+        Some(key) if !part_range.is_empty() => presort(data, key, &ctx).await?,
+        _ => data,
+    };
+    let data = match params.vector_codec.as_ref() {
+        Some(codec) => decode_vector_column(data, column, codec.clone(), ivf.dimension()),
+        None => lance_core::io::RecordBatchStreamAdapter::new(schema, data.boxed()),
+    };
+    let data = match params.vector_expr.as_ref() {
+        Some(expr) => project_vector_column(data, column, expr.clone(), &ctx).await?,
+        None => data,
+    };
+    check_pq_dimension(&data.schema(), column, pq.as_ref())?;
+    let zero_norm_rows = Arc::new(AtomicUsize::new(0));
+    let data = if metric_type == MetricType::Cosine {
+        handle_zero_norms(
+            data,
+            column,
+            params.on_zero_norm,
+            ivf.centroids.value(0),
+            zero_norm_rows.clone(),
+        )?
+    } else {
+        data
+    };
+
+    let global_stats = params
+        .compute_global_stats
+        .then(|| Arc::new(Mutex::new(VectorStats::new(ivf.dimension()))));
+    let data = match global_stats.clone() {
+        Some(stats) => inspect_batches(data, column, stats),
+        None => data,
+    };
+    let norm_histogram = params
+        .reject_bimodal_norms
+        .then(|| Arc::new(Mutex::new(NormHistogram::default())));
+    let data = match norm_histogram.clone() {
+        Some(histogram) => inspect_batches(data, column, histogram),
+        None => data,
+    };
+    let self_recall_sample = params
+        .self_recall_check
+        .map(|num_samples| Arc::new(Mutex::new(SelfRecallSample::new(num_samples, params.seed))));
+    let data = match self_recall_sample.clone() {
+        Some(sample) => inspect_batches(data, column, sample),
+        None => data,
+    };
+    let data = match params.control.clone() {
+        Some(control) => pause_with(data, control),
+        None => data,
+    };
+    let stopped = Arc::new(AtomicBool::new(false));
+    let data = match params.max_duration {
+        Some(max_duration) => stop_at(
+            data,
+            tokio::time::Instant::now() + max_duration,
+            stopped.clone(),
+        ),
+        None => data,
+    };
+    let caps = params.max_partition_rows.map(|max_rows| {
+        Arc::new(PartitionCaps::new(
+            max_rows,
+            ivf.num_partitions() as u32,
+            part_range.clone(),
+        ))
+    });
+    let data = match caps.clone() {
+        Some(caps) => stop_when_full(data, caps),
+        None => data,
+    };
+    let check_norms = || match norm_histogram.as_ref() {
+        Some(histogram) => check_norm_bimodality(&histogram.lock().unwrap(), column),
+        None => Ok(()),
+    };
+
+    let is_empty_range = part_range.is_empty();
+    ivf.weights = params.weights.clone();
+    ivf.multi_assign = params.multi_assign;
+    let centroids = ivf.weighted_centroids()?;
+    // The tree clusters the unweighted centroids, so weighted vectors are assigned
+    // by comparing with every centroid.
+    let tree = ivf.tree.as_ref().filter(|_| ivf.weights.is_none());
+    let num_alternatives = if params.min_partition_rows.is_some() && ivf.num_partitions() > 1 {
+        FOLD_ALTERNATIVES
+    } else {
+        0
+    };
+    let ivf_model = if params.per_partition_pq {
+        lance_index::vector::ivf::new_ivf_with_options(
+            centroids.values(),
+            ivf.dimension(),
+            metric_type,
+            vec![],
+            lance_index::vector::ivf::IvfAssignOptions {
+                range: Some(part_range),
+                precomputed_partitions: precomputed_partitons,
+                tree,
+                assignment_margin: params.assignment_margin,
+                centroid_distance: params.compute_medoids,
+                num_alternatives,
+                multi_assign: params.multi_assign,
+                ..Default::default()
+            },
+        )?
+    } else {
+        lance_index::vector::ivf::new_ivf_with_pq_and_options(
+            centroids.values(),
+            ivf.dimension(),
+            metric_type,
+            column,
+            pq.clone(),
+            lance_index::vector::ivf::IvfAssignOptions {
+                range: Some(part_range),
+                precomputed_partitions: precomputed_partitons,
+                tree,
+                assignment_margin: params.assignment_margin,
+                centroid_distance: params.compute_medoids,
+                num_alternatives,
+                multi_assign: params.multi_assign,
+                residual_precision: params.residual_precision,
+            },
+        )?
+    };
+
+    let assigner = ivf_model.clone();
+    let report_centroids = params
+        .collect_residual_histograms
+        .then(|| (centroids.clone(), metric_type));
+    let mut self_recall = None;
+    let (stream, report) = if is_empty_range {
+        info!("Empty partition range, writing an empty IVF index");
+        (vec![], Arc::new(Mutex::new(BuildReport::default())))
+    } else if ivf.num_partitions() == 1 {
+        // All the rows go to the one partition, so there is nothing to shuffle, and
+        // nothing to fold it into.
+        info!("Single IVF partition, writing it without a shuffle");
+        let TransformedStream { stream, report } = transform_dataset(
+            data,
+            column,
+            ivf_model,
+            1,
+            pq.num_sub_vectors(),
+            params,
+            report_centroids,
+            false,
+            caps,
+            env,
+        )?;
+        (vec![stream.boxed()], report)
+    } else {
+        let (streams, report) = shuffle_dataset_v2(
+            data,
+            column,
+            ivf_model,
+            ivf.num_partitions() as u32,
+            pq.num_sub_vectors(),
+            params,
+            report_centroids,
+            params.min_partition_rows.map(|_| {
+                Arc::new(NearestPartitionFold {
+                    centroids: centroids.clone(),
+                    metric_type,
+                    pq: pq.clone(),
+                }) as Arc<dyn PartitionFold>
+            }),
+            caps,
+            env,
+        )
+        .await?;
+        // The shuffle has read the whole input, so fail before writing the partitions.
+        check_norms()?;
+        // The sample is complete too, so its queries can be scored as the partitions
+        // are written.
+        self_recall = self_recall_sample
+            .map(|sample| {
+                let sample = sample.lock().unwrap();
+                SelfRecallQueries::try_new(&sample, &centroids, metric_type, pq.clone())
+                    .map(|queries| Arc::new(Mutex::new(queries)))
+            })
+            .transpose()?;
+        let stream = chain_partitions(streams);
+        (
+            vec![match self_recall.clone() {
+                Some(queries) => score_self_recall(stream, queries).boxed(),
+                None => stream,
+            }],
+            Arc::new(Mutex::new(report)),
+        )
+    };
+
+    let stream = match params.post_shuffle_transform.clone() {
+        Some(transform) => stream
+            .into_iter()
+            .map(|stream| transform_partitions(stream, transform.clone()).boxed())
+            .collect(),
+        None => stream,
+    };
+
+    let partition_pq = params.per_partition_pq.then(|| PartitionPqParams {
+        column: column.to_string(),
+        pq_params: params.per_partition_pq_params.clone().unwrap_or_default(),
+        pq: pq.clone(),
+        metric_type,
+        centroids,
+    });
+
+    ivf.pca = params.pca.clone();
+    ivf.code_storage_order = params.code_storage_order;
+    ivf.packed_codes = params
+        .dual_code_layout
+        .then(|| PackedCodes::new(pq.num_bits()));
+    ivf.has_valid = params.valid_column.is_some();
+    ivf.subgroups = params.subgroup_column.as_deref().map(Subgroups::new);
+    ivf.medoids = params.compute_medoids.then(Vec::new);
+    ivf.time_window = params.time_window.clone();
+    ivf.has_assignment_margin = params.assignment_margin;
+    ivf.transposed_codebook = params
+        .transposed_codebook
+        .then(|| TransposedCodebook::try_new(pq.as_ref()))
+        .transpose()?;
+    let on_partition_written = with_build_progress(params, report.clone());
+    let output_digest = if params.write {
+        write_index_partitions(
+            vec![writer],
+            ivf,
+            stream,
+            None,
+            WritePartitionsOptions {
+                partition_pq: partition_pq.as_ref(),
+                on_partition_written: on_partition_written.as_ref(),
+                control: params.control.as_ref(),
+                cold_tier,
+                output_order: params.output_order.as_deref(),
+            },
+        )
+        .await?
+    } else {
+        discard_partitions(ivf, stream).await?;
+        [0; 32]
+    };
+    // Without the shuffle, the input is only read while writing the partitions.
+    check_norms()?;
+    ivf.partial = stopped.load(Ordering::SeqCst);
+    if ivf.partial {
+        warn!(
+            "IVF build stopped at its time budget of {:?}, the index is partial with {} rows",
+            params.max_duration.unwrap_or_default(),
+            ivf.lengths.iter().sum::<u32>()
+        );
+    }
+    // The stats are complete once the shuffle has consumed the whole input.
+    ivf.global_stats = global_stats
+        .filter(|_| !is_empty_range)
+        .map(|stats| stats.lock().unwrap().clone());
+    let mut report = report.lock().unwrap().clone();
+    report.self_recall = self_recall.map(|queries| queries.lock().unwrap().result());
+    report.num_partitions = ivf.num_partitions();
+    report.duration = start.elapsed();
+    report.output_digest = output_digest;
+    report.zero_norm_rows = zero_norm_rows.load(Ordering::Relaxed);
+    report.assignment = assigner.assignment_stats();
+    if let Some((namespace, callback)) = params.on_prometheus_metrics.as_ref() {
+        callback(report.to_prometheus(namespace));
+    }
+    if let Some(self_recall) = report.self_recall.as_ref() {
+        info!(
+            "Self-recall@{} of {} sampled vectors: {:.3}",
+            SELF_RECALL_K,
+            self_recall.num_queries,
+            self_recall.recall()
+        );
+    }
+
+    if let Some(k) = params.log_top_partitions {
+        info!(
+            "Largest IVF partitions (id, rows): {:?}",
+            top_partitions(&ivf.lengths, k)
+        );
+    }
+    if let Some(path) = params.write_summary.as_ref() {
+        tokio::fs::write(path, build_summary(ivf, metric_type, &report)).await?;
+    }
+
+    Ok(report)
+}
+
+/// Number of the largest partitions listed in [`IvfBuildParams::write_summary`].
+const SUMMARY_TOP_PARTITIONS: usize = 10;
+
+/// The human-readable summary of a build, see [`IvfBuildParams::write_summary`].
+fn build_summary(ivf: &Ivf, metric_type: MetricType, report: &BuildReport) -> String {
+    let top = top_partitions(&ivf.lengths, SUMMARY_TOP_PARTITIONS)
+        .into_iter()
+        .filter(|(_, length)| *length > 0)
+        .map(|(part_id, length)| format!("{}:{}", part_id, length))
+        .collect::<Vec<_>>();
+    let lines = [
+        format!("metric: {}", metric_type),
+        format!("dimension: {}", ivf.dimension()),
+        format!("num_partitions: {}", ivf.num_partitions()),
+        format!("num_rows: {}", ivf.lengths.iter().sum::<u32>()),
+        format!(
+            "non_empty_partitions: {}",
+            ivf.lengths.iter().filter(|&&length| length > 0).count()
+        ),
+        format!("top_partitions: {}", top.join(" ")),
+        format!("build_duration: {:.3}s", report.duration.as_secs_f64()),
+    ];
+    let mut summary = lines.join("\n");
+    summary.push('\n');
+    summary
+}
+
+/// Drain the partitions of `streams` without writing them, adding the partitions to
+/// `ivf` with their lengths and an offset of 0, see [`IvfBuildParams::write`].
+async fn discard_partitions(
+    ivf: &mut Ivf,
+    streams: Vec<impl Stream<Item = Result<RecordBatch>> + Unpin>,
+) -> Result<()> {
+    let mut lengths = vec![0_u32; ivf.num_partitions()];
+    for mut stream in streams {
+        while let Some(batch) = stream.try_next().await? {
+            let part_ids = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>();
+            for part_id in part_ids.values() {
+                lengths[*part_id as usize] += 1;
+            }
+        }
+    }
+    for length in lengths {
+        ivf.add_partition(0, length);
+    }
+    Ok(())
+}
+
+/// [`IvfBuildParams::on_partition_written`], also reporting the rows written so far to
+/// [`IvfBuildParams::on_build_progress`] as a percentage of [`BuildReport::num_rows`].
+fn with_build_progress(
+    params: &IvfBuildParams,
+    report: Arc<Mutex<BuildReport>>,
+) -> Option<PartitionWrittenCallback> {
+    let Some(on_build_progress) = params.on_build_progress.clone() else {
+        return params.on_partition_written.clone();
+    };
+    let on_partition_written = params.on_partition_written.clone();
+    let rows_written = AtomicUsize::new(0);
+    Some(Arc::new(move |part_id, num_rows| {
+        if let Some(callback) = on_partition_written.as_ref() {
+            callback(part_id, num_rows);
+        }
+        let rows_written = rows_written.fetch_add(num_rows, Ordering::Relaxed) + num_rows;
+        // The shuffle counts all the rows before the first partition is written. Without
+        // it, the only partition is written once the input is drained, so the count is
+        // also complete.
+        let total_rows = report.lock().unwrap().num_rows;
+        on_build_progress(build_progress(rows_written, total_rows));
+    }))
+}
+
+/// Percentage of `total_rows` in `rows_written`, 100 if there are no rows.
+fn build_progress(rows_written: usize, total_rows: usize) -> f64 {
+    if total_rows == 0 {
+        return 100.0;
+    }
+    (rows_written as f64 * 100.0 / total_rows as f64).min(100.0)
+}
+
+/// The `k` largest partitions as `(partition id, number of rows)`, largest first.
+///
+/// Partitions of the same size are ordered by id.
+fn top_partitions(lengths: &[u32], k: usize) -> Vec<(usize, u32)> {
+    let mut partitions = lengths.iter().copied().enumerate().collect::<Vec<_>>();
+    partitions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    partitions.truncate(k);
+    partitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::iter::repeat;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use approx::assert_relative_eq;
+    use arrow_array::{types::UInt32Type, ArrayRef, BinaryArray};
+    use arrow_array::{RecordBatchIterator, StringArray, UInt32Array, UInt64Array};
+    use arrow_schema::Schema;
+    use datafusion::execution::memory_pool::{MemoryPool, UnboundedMemoryPool};
+    use lance_arrow::FixedSizeListArrayExt;
+    use lance_index::vector::pq::ProductQuantizerImpl;
+    use lance_linalg::kernels::normalize;
+    use lance_testing::datagen::generate_random_array;
+    use lance_testing::datagen::generate_random_array_with_seed;
+    use tempfile::tempdir;
+
+    use crate::format::RowAddress;
+    use crate::index::vector::ivf::{
+        build_ivf_pq_index, sanity_check_ivf_param,
+        tests::{
+            build_test_index, embed, embed_udf, generate_test_dataset, indexed_row_ids,
+            write_test_dataset, DIM,
+        },
+    };
+    use crate::index::{pb, INDEX_FILE_NAME};
+
+    #[tokio::test]
+    async fn test_bounded_channel_caps_spawned_tasks() {
+        const CONCURRENCY: usize = 4;
+        const CAPACITY: usize = 2;
+
+        // Transform tasks spawned and not yet consumed.
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let counters = (in_flight.clone(), max_in_flight.clone());
+        let stream = futures::stream::iter(0..100)
+            .map(move |i| {
+                let now = counters.0.fetch_add(1, Ordering::SeqCst) + 1;
+                counters.1.fetch_max(now, Ordering::SeqCst);
+                tokio::task::spawn(async move { Ok::<_, Error>(i) })
+            })
+            .buffer_unordered(CONCURRENCY)
+            .map(|res| res.unwrap());
+
+        let mut stream = Box::pin(bounded_channel(stream, CAPACITY));
+        let mut consumed = 0;
+        while let Some(item) = stream.next().await {
+            item.unwrap();
+            consumed += 1;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            // A slow consumer.
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        assert_eq!(consumed, 100);
+        // The buffered tasks, the channel, and the item being sent by the producer.
+        assert!(max_in_flight.load(Ordering::SeqCst) <= CONCURRENCY + CAPACITY + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stop_at_stalled_source() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "value",
+            DataType::UInt64,
+            false,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt64Array::from_iter_values(0..100))],
+        )
+        .unwrap();
+        let budget = Duration::from_secs(10);
+
+        // The source stalls after 3 batches.
+        let stream = futures::stream::iter(vec![batch.clone(); 3])
+            .map(Ok)
+            .chain(futures::stream::pending())
+            .boxed();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let start = tokio::time::Instant::now();
+        let batches = stop_at(
+            lance_core::io::RecordBatchStreamAdapter::new(schema.clone(), stream),
+            start + budget,
+            stopped.clone(),
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(batches.len(), 3);
+        assert!(stopped.load(Ordering::SeqCst));
+        assert_eq!(start.elapsed(), budget);
+
+        // A source that ends in time is not stopped.
+        let stream = futures::stream::iter(vec![batch; 3]).map(Ok).boxed();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let batches = stop_at(
+            lance_core::io::RecordBatchStreamAdapter::new(schema, stream),
+            tokio::time::Instant::now() + budget,
+            stopped.clone(),
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(batches.len(), 3);
+        assert!(!stopped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_reads() {
+        const NUM_BATCHES: usize = 20;
+
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "value",
+            DataType::UInt64,
+            false,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt64Array::from_iter_values(0..1000))],
+        )
+        .unwrap();
+        let batch_size = batch.get_array_memory_size();
+        // Reading all the batches takes about half a second.
+        let rate = BytesPerSec((batch_size * NUM_BATCHES * 2) as u64);
+
+        let start = Instant::now();
+        let stream = futures::stream::iter(vec![batch; NUM_BATCHES])
+            .map(Ok)
+            .boxed();
+        let mut stream = throttle_reads(
+            lance_core::io::RecordBatchStreamAdapter::new(schema, stream),
+            rate,
+        );
+        let mut bytes_read = 0;
+        while let Some(batch) = stream.next().await {
+            bytes_read += batch.unwrap().get_array_memory_size();
+            let observed = bytes_read as f64 / start.elapsed().as_secs_f64();
+            assert!(
+                observed <= rate.0 as f64,
+                "read {} bytes/s, over the limit of {}",
+                observed,
+                rate.0
+            );
+        }
+        assert_eq!(bytes_read, batch_size * NUM_BATCHES);
+    }
+
+    #[tokio::test]
+    async fn test_shuffle_tags_streams_with_part_id() {
+        const DIM: usize = 16;
+        const NUM_ROWS: usize = 1000;
+        const NUM_PARTITIONS: u32 = 4;
+
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        let pq = PQBuildParams::new(4, 8)
+            .build(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+        let centroids = generate_random_array(NUM_PARTITIONS as usize * DIM);
+        let ivf = lance_index::vector::ivf::new_ivf_with_pq(
+            &centroids,
+            DIM,
+            MetricType::L2,
+            "vector",
+            pq,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("vector", vectors.data_type().clone(), false),
+            ROW_ID_FIELD.clone(),
+        ]));
+        let batches = (0..NUM_ROWS)
+            .step_by(100)
+            .map(|start| {
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(vectors.slice(start, 100)),
+                        Arc::new(UInt64Array::from_iter_values(
+                            start as u64..start as u64 + 100,
+                        )),
+                    ],
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        let data = lance_core::io::RecordBatchStreamAdapter::new(
+            schema,
+            futures::stream::iter(batches.into_iter().map(Ok)).boxed(),
+        );
+        let (partitions, _) = shuffle_dataset_v2(
+            data,
+            "vector",
+            ivf,
+            NUM_PARTITIONS,
+            4,
+            &IvfBuildParams::new(NUM_PARTITIONS as usize),
+            None,
+            None,
+            None,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            partitions
+                .iter()
+                .map(|(part_id, _)| *part_id)
+                .collect::<Vec<_>>(),
+            (0..NUM_PARTITIONS).collect::<Vec<_>>()
+        );
+
+        // Read the partitions out of order, as independent tasks would.
+        let mut row_ids = vec![];
+        for (part_id, stream) in partitions.into_iter().rev() {
+            let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+            for batch in batches {
+                let part_ids = batch[PART_ID_COLUMN].as_primitive::<UInt32Type>();
+                assert!(part_ids.values().iter().all(|id| *id == part_id));
+                row_ids.extend_from_slice(batch[ROW_ID].as_primitive::<UInt64Type>().values());
+            }
+        }
+        row_ids.sort_unstable();
+        assert_eq!(row_ids, (0..NUM_ROWS as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_top_partitions() {
+        let lengths = [5, 40, 0, 12, 40, 7];
+        assert_eq!(top_partitions(&lengths, 3), vec![(1, 40), (4, 40), (3, 12)]);
+        assert_eq!(top_partitions(&lengths, 10).len(), lengths.len());
+        assert!(top_partitions(&lengths, 0).is_empty());
+    }
+
+    #[test]
+    fn test_require_columns_lists_all_missing() {
+        let schema = Schema::new(vec![Field::new("vector", DataType::Float32, false)]);
+        let mut params = IvfBuildParams::new(2);
+        params.valid_column = Some("deleted".to_string());
+        let columns = required_columns("vector", &params);
+        assert_eq!(columns, vec!["vector", ROW_ID, "deleted"]);
+
+        let err = require_columns(&schema, &columns).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("column {} does not exist", ROW_ID)),
+            "{}",
+            err
+        );
+        assert!(err.contains("column deleted does not exist"), "{}", err);
+        assert!(!err.contains("column vector"), "{}", err);
+
+        assert!(require_columns(&schema, &["vector"]).is_ok());
+    }
+
+    #[test]
+    fn test_build_report_to_json() {
+        let report = BuildReport {
+            num_rows: 800,
+            skipped_batches: 2,
+            skipped_rows: 200,
+            residual_histograms: vec![vec![1, 0], vec![0, 3]],
+            self_recall: Some(SelfRecall {
+                num_queries: 10,
+                num_found: 9,
+            }),
+            num_partitions: 2,
+            duration: Duration::from_millis(1500),
+            output_digest: [7; 32],
+            zero_norm_rows: 3,
+            assignment: AssignmentStats {
+                distance_computations: 1600,
+                duration: Duration::from_millis(20),
+            },
+        };
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["num_rows"], 800);
+        assert_eq!(json["skipped_batches"], 2);
+        assert_eq!(json["skipped_rows"], 200);
+        assert_eq!(json["residual_histograms"][1][1], 3);
+        assert_eq!(json["self_recall"]["num_found"], 9);
+        assert_eq!(json["num_partitions"], 2);
+        assert_eq!(json["duration"]["secs"], 1);
+        assert_eq!(json["output_digest"][31], 7);
+        assert_eq!(json["zero_norm_rows"], 3);
+        assert_eq!(json["assignment"]["distance_computations"], 1600);
+    }
+
+    #[test]
+    fn test_build_report_to_prometheus() {
+        let report = BuildReport {
+            num_rows: 800,
+            skipped_batches: 2,
+            skipped_rows: 200,
+            residual_histograms: vec![],
+            self_recall: Some(SelfRecall {
+                num_queries: 10,
+                num_found: 9,
+            }),
+            num_partitions: 4,
+            duration: Duration::from_millis(1500),
+            output_digest: [0; 32],
+            zero_norm_rows: 0,
+            assignment: AssignmentStats {
+                distance_computations: 3000,
+                duration: Duration::from_millis(1500),
+            },
+        };
+        let text = report.to_prometheus("lance-idx");
+
+        // Each sample is preceded by its HELP and TYPE lines, see
+        // https://prometheus.io/docs/instrumenting/exposition_formats/
+        let is_metric_name = |name: &str| {
+            name.chars().enumerate().all(|(i, c)| {
+                c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+            }) && !name.is_empty()
+        };
+        assert!(text.ends_with('\n'));
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len() % 3, 0);
+        let mut samples = HashMap::new();
+        for metric in lines.chunks_exact(3) {
+            let help = metric[0].strip_prefix("# HELP ").unwrap();
+            let (name, _) = help.split_once(' ').unwrap();
+            assert!(is_metric_name(name), "{}", name);
+            assert_eq!(metric[1], format!("# TYPE {} gauge", name));
+            let (sample_name, value) = metric[2].split_once(' ').unwrap();
+            assert_eq!(sample_name, name);
+            samples.insert(name.to_string(), value.parse::<f64>().unwrap());
+        }
+        assert_eq!(
+            samples,
+            HashMap::from([
+                ("lance_idx_ivf_build_rows".to_string(), 800.0),
+                ("lance_idx_ivf_build_skipped_batches".to_string(), 2.0),
+                ("lance_idx_ivf_build_skipped_rows".to_string(), 200.0),
+                ("lance_idx_ivf_build_partitions".to_string(), 4.0),
+                ("lance_idx_ivf_build_duration_seconds".to_string(), 1.5),
+                ("lance_idx_ivf_build_self_recall".to_string(), 0.9),
+                (
+                    "lance_idx_ivf_build_distance_computations_per_second".to_string(),
+                    2000.0,
+                ),
+            ])
+        );
+
+        assert!(BuildReport::default()
+            .to_prometheus("")
+            .starts_with("# HELP ivf_build_rows "));
+    }
+
+    #[test]
+    fn test_residual_histogram_bin() {
+        assert_eq!(residual_histogram_bin(0.0), 0);
+        assert_eq!(residual_histogram_bin(f32::NAN), 0);
+        assert_eq!(residual_histogram_bin(1e-6), 0);
+        assert_eq!(residual_histogram_bin(0.5), 7);
+        assert_eq!(residual_histogram_bin(1.0), 8);
+        assert_eq!(residual_histogram_bin(1.5), 8);
+        assert_eq!(residual_histogram_bin(2.0), 9);
+        assert_eq!(residual_histogram_bin(1e6), RESIDUAL_HISTOGRAM_BINS - 1);
+    }
+
+    #[tokio::test]
+    async fn test_sort_by_partition_with_session_config() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            PART_ID_COLUMN,
+            DataType::UInt32,
+            false,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(UInt32Array::from(vec![2, 0, 1]))],
+        )
+        .unwrap();
+        let stream = Box::pin(RecordBatchStreamAdapter::new(
+            schema,
+            futures::stream::iter(vec![Ok(batch)]),
+        ));
+
+        let config = SessionConfig::new().with_target_partitions(3);
+        let df = sort_by_partition(stream, Some(config), &BuildEnvConfig::default()).unwrap();
+        assert_eq!(df.task_ctx().session_config().target_partitions(), 3);
+
+        let batches = df.collect().await.unwrap();
+        let part_ids = batches
+            .iter()
+            .flat_map(|b| {
+                b[PART_ID_COLUMN]
+                    .as_primitive::<UInt32Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(part_ids, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_index_columns() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        const NUM_ROWS: usize = 100;
+        let vectors =
+            FixedSizeListArray::try_new_from_values(generate_random_array(NUM_ROWS * 4), 4)
+                .unwrap();
+        let batch = RecordBatch::try_from_iter(vec![
+            (
+                "id",
+                Arc::new(UInt64Array::from_iter_values(0..NUM_ROWS as u64)) as _,
+            ),
+            ("vector", Arc::new(vectors) as _),
+            (
+                "deleted",
+                Arc::new(BooleanArray::from(vec![false; NUM_ROWS])) as _,
+            ),
+            (
+                "payload",
+                Arc::new(StringArray::from_iter_values(
+                    (0..NUM_ROWS).map(|i| format!("payload {}", i)),
+                )) as _,
+            ),
+        ])
+        .unwrap();
+        let schema = batch.schema();
+        let batches = RecordBatchIterator::new(vec![Ok(batch)], schema);
+        let mut dataset = Dataset::write(batches, test_uri, None).await.unwrap();
+        dataset.delete("id < 10").await.unwrap();
+
+        let mut params = IvfBuildParams::default();
+        let scanned_columns = |batches: &[RecordBatch]| {
+            batches[0]
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().clone())
+                .collect::<Vec<_>>()
+        };
+        let batches = scan_index_columns(&dataset, "vector", &params)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(scanned_columns(&batches), vec!["vector", ROW_ID]);
+        let row_ids = batches
+            .iter()
+            .flat_map(|b| b[ROW_ID].as_primitive::<UInt64Type>().values().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(row_ids, (10..NUM_ROWS as u64).collect::<Vec<_>>());
+
+        params.valid_column = Some("deleted".to_string());
+        let batches = scan_index_columns(&dataset, "vector", &params)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(scanned_columns(&batches), vec!["vector", "deleted", ROW_ID]);
+    }
+
+    #[test]
+    fn test_filter_zero_norm_training_data() {
+        let data = FixedSizeListArray::try_new_from_values(
+            Float32Array::from(vec![1.0, 2.0, 0.0, 0.0, 3.0, 4.0]),
+            2,
+        )
+        .unwrap();
+        let filtered = |metric_type, policy| {
+            filter_zero_norm_training_data(data.clone(), "vector", metric_type, policy)
+        };
+
+        let skipped = filtered(MetricType::Cosine, ZeroNormPolicy::Skip).unwrap();
+        assert_eq!(
+            skipped.values().as_primitive::<Float32Type>().values(),
+            &[1.0, 2.0, 3.0, 4.0]
+        );
+        let assigned = filtered(MetricType::Cosine, ZeroNormPolicy::AssignToPartitionZero);
+        assert_eq!(assigned.unwrap(), skipped);
+        assert!(filtered(MetricType::Cosine, ZeroNormPolicy::Error).is_err());
+        assert_eq!(
+            filtered(MetricType::L2, ZeroNormPolicy::Error).unwrap(),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_skips_bad_batches() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("vector", vectors.data_type().clone(), true),
+            ROW_ID_FIELD.clone(),
+        ]));
+        let mut batches = (0..5)
+            .map(|i| {
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(vectors.slice(i * 200, 200)),
+                        Arc::new(UInt64Array::from_iter_values(
+                            (i * 200) as u64..(i * 200 + 200) as u64,
+                        )),
+                    ],
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        // Batches without the vector column fail to be partitioned.
+        for i in [1, 4] {
+            let bad_batch = RecordBatch::try_from_iter(vec![(
+                ROW_ID,
+                Arc::new(UInt64Array::from_iter_values(0..100)) as ArrayRef,
+            )])
+            .unwrap();
+            batches.insert(i, bad_batch);
+        }
+
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(2 * DIM), DIM as i32)
+                .unwrap();
+        let pq = PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)))
+            .build(vectors.as_ref(), MetricType::L2)
+            .await
+            .unwrap();
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.max_bad_batches = Some(3);
+
+        let centroids = Arc::new(centroids);
+        let mut ivf = Ivf::new(centroids.clone());
+        let path = dataset.indices_dir().child("bad_batches");
+        let mut writer = dataset.object_store().create(&path).await.unwrap();
+        let stream = lance_core::io::RecordBatchStreamAdapter::new(
+            schema.clone(),
+            futures::stream::iter(batches.clone().into_iter().map(Ok)),
+        );
+        let report = build_partitions(
+            &mut writer,
+            None,
+            stream,
+            "vector",
+            &mut ivf,
+            pq.clone(),
+            MetricType::L2,
+            0..2,
+            None,
+            &ivf_params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+        writer.shutdown().await.unwrap();
+
+        assert_eq!(
+            report,
+            BuildReport {
+                num_rows: 1000,
+                skipped_batches: 2,
+                skipped_rows: 200,
+                residual_histograms: vec![],
+                self_recall: None,
+                num_partitions: 2,
+                duration: report.duration,
+                output_digest: report.output_digest,
+                zero_norm_rows: 0,
+                assignment: report.assignment,
+            }
+        );
+        assert_eq!(ivf.lengths.iter().sum::<u32>(), 1000);
+
+        // Failures to read the data are not bad batches, and fail the build.
+        let read_error = futures::stream::iter([Err(Error::IO {
+            message: "failed to read the data".to_string(),
+            location: location!(),
+        })]);
+        let stream = lance_core::io::RecordBatchStreamAdapter::new(
+            schema,
+            futures::stream::iter(batches.into_iter().map(Ok)).chain(read_error),
+        );
+        let mut ivf = Ivf::new(centroids);
+        let err = build_partitions(
+            &mut std::io::Cursor::new(Vec::new()),
+            None,
+            stream,
+            "vector",
+            &mut ivf,
+            pq,
+            MetricType::L2,
+            0..2,
+            None,
+            &ivf_params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("failed to read the data"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_residual_histograms() {
+        let fixture = PartitionsFixture::new(1000, 4, 31).await;
+        // The histograms only count the rows kept under max_partition_rows too.
+        for max_partition_rows in [None, Some(100)] {
+            let mut params = IvfBuildParams::new(4);
+            params.collect_residual_histograms = true;
+            params.max_partition_rows = max_partition_rows;
+
+            let (report, ivf, _) = fixture.build(fixture.stream(), &params).await.unwrap();
+
+            assert_eq!(report.residual_histograms.len(), 4);
+            for (histogram, &length) in report.residual_histograms.iter().zip(ivf.lengths.iter()) {
+                assert_eq!(histogram.len(), RESIDUAL_HISTOGRAM_BINS);
+                assert_eq!(histogram.iter().sum::<u64>(), length as u64);
+            }
+            match max_partition_rows {
+                Some(max_rows) => assert!(ivf.lengths.iter().all(|l| *l <= max_rows as u32)),
+                None => assert_eq!(ivf.lengths.iter().sum::<u32>(), 1000),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_self_recall() {
+        let mut fixture = PartitionsFixture::new(1000, 4, 33).await;
+        fixture.pq = PQBuildParams::new(16, 8)
+            .build(fixture.vectors.as_ref(), MetricType::L2)
+            .await
+            .unwrap();
+        let mut params = IvfBuildParams::new(4);
+        params.self_recall_check = Some(50);
+        let metrics = Arc::new(Mutex::new(String::new()));
+        let captured = metrics.clone();
+        params.on_prometheus_metrics = Some((
+            "lance".to_string(),
+            Arc::new(move |text| *captured.lock().unwrap() = text),
+        ));
+
+        let (report, _, _) = fixture.build(fixture.stream(), &params).await.unwrap();
+
+        let self_recall = report.self_recall.unwrap();
+        assert_eq!(self_recall.num_queries, 50);
+        assert!(self_recall.recall() >= 0.9, "{:?}", self_recall);
+        let metrics = metrics.lock().unwrap();
+        assert!(
+            metrics.contains("\nlance_ivf_build_rows 1000\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("\nlance_ivf_build_partitions 4\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("\nlance_ivf_build_self_recall "),
+            "{}",
+            metrics
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_build_partitions_with_max_duration() {
+        let fixture = PartitionsFixture::new(1000, 4, 35).await;
+        let mut params = IvfBuildParams::new(4);
+        params.max_duration = Some(Duration::from_secs(10));
+
+        // The first 3 batches come at once, then the source stalls. The paused clock
+        // only reaches the deadline once the 3 batches are taken in.
+        let data = fixture.stream();
+        let schema = data.schema();
+        let data = data.take(3).chain(futures::stream::pending());
+        let data = lance_core::io::RecordBatchStreamAdapter::new(schema, data.boxed());
+
+        let mut ivf = Ivf::new(fixture.centroids.clone());
+        let mut writer = std::io::Cursor::new(Vec::new());
+        let env = BuildEnvConfig {
+            concurrency: 3,
+            ..Default::default()
+        };
+        build_partitions(
+            &mut writer,
+            None,
+            data,
+            "vector",
+            &mut ivf,
+            fixture.pq.clone(),
+            MetricType::L2,
+            0..4,
+            None,
+            &params,
+            &env,
+        )
+        .await
+        .unwrap();
+        let bytes = writer.into_inner();
+
+        assert!(ivf.partial);
+        let mut row_ids = read_partitions_in_memory(&bytes, &ivf, 4)
+            .into_iter()
+            .flatten()
+            .map(|(row_id, _)| row_id)
+            .collect::<Vec<_>>();
+        row_ids.sort();
+        assert_eq!(row_ids, (0..300).collect::<Vec<_>>());
+        let proto = pb::Ivf::try_from(&ivf).unwrap();
+        assert!(proto.partial);
+        assert!(Ivf::try_from(&proto).unwrap().partial);
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_stops_when_partitions_are_full() {
+        const NUM_ROWS: usize = 50_000;
+        const MAX_ROWS: usize = 50;
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [37; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let centroids = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(4 * DIM, [38; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let pq = PQBuildParams::new(4, 8)
+            .build(&vectors.slice(0, 1000), MetricType::L2)
+            .await
+            .unwrap();
+        let mut params = IvfBuildParams::new(4);
+        params.max_partition_rows = Some(MAX_ROWS);
+
+        let num_read = Arc::new(AtomicUsize::new(0));
+        let data = in_memory_stream(Arc::new(vectors));
+        let schema = data.schema();
+        let counter = num_read.clone();
+        let data = data.inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let data = lance_core::io::RecordBatchStreamAdapter::new(schema, data.boxed());
+
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let mut writer = std::io::Cursor::new(Vec::new());
+        build_partitions(
+            &mut writer,
+            None,
+            data,
+            "vector",
+            &mut ivf,
+            pq.clone(),
+            MetricType::L2,
+            1..3,
+            None,
+            &params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(ivf.lengths, vec![0, MAX_ROWS as u32, MAX_ROWS as u32, 0]);
+        // The batches in flight when the partitions filled up are read too.
+        let num_batches = NUM_ROWS / 100;
+        let num_read = num_read.load(Ordering::SeqCst);
+        assert!(
+            num_read < num_batches / 2,
+            "read {} of {} batches",
+            num_read,
+            num_batches
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_rejects_pq_of_other_dimension() {
+        let mut fixture = PartitionsFixture::new(1000, 2, 37).await;
+        let other_vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM / 2, [39; 32]),
+            DIM as i32 / 2,
+        )
+        .unwrap();
+        fixture.pq = PQBuildParams::new(4, 8)
+            .build(&other_vectors, MetricType::L2)
+            .await
+            .unwrap();
+
+        let num_read = Arc::new(AtomicUsize::new(0));
+        let data = fixture.stream();
+        let schema = data.schema();
+        let counter = num_read.clone();
+        let data = data.inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let data = lance_core::io::RecordBatchStreamAdapter::new(schema, data.boxed());
+
+        let mut ivf = Ivf::new(fixture.centroids.clone());
+        let mut writer = std::io::Cursor::new(Vec::new());
+        let result = build_partitions(
+            &mut writer,
+            None,
+            data,
+            "vector",
+            &mut ivf,
+            fixture.pq.clone(),
+            MetricType::L2,
+            0..2,
+            None,
+            &IvfBuildParams::new(2),
+            &BuildEnvConfig::default(),
+        )
+        .await;
+        let expected = format!(
+            "PQ dimension {} does not match the dimension {}",
+            DIM / 2,
+            DIM
+        );
+        assert!(
+            matches!(result, Err(Error::Index { ref message, .. }) if message.contains(&expected)),
+            "{:?}",
+            result
+        );
+        assert_eq!(num_read.load(Ordering::SeqCst), 0);
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_writes_summary() {
+        let fixture = PartitionsFixture::new(1000, 4, 37).await;
+
+        let test_dir = tempdir().unwrap();
+        let path = test_dir.path().join("index.summary.txt");
+        let mut params = IvfBuildParams::new(4);
+        params.write_summary = Some(path.clone());
+        let (report, ivf, _) = fixture.build(fixture.stream(), &params).await.unwrap();
+
+        let summary = std::fs::read_to_string(&path).unwrap();
+        let fields = summary
+            .lines()
+            .map(|line| line.split_once(": ").unwrap())
+            .collect::<HashMap<_, _>>();
+        assert_eq!(fields["metric"], "l2");
+        assert_eq!(fields["dimension"], DIM.to_string());
+        assert_eq!(fields["num_partitions"], "4");
+        assert_eq!(fields["num_rows"], "1000");
+        let non_empty = ivf.lengths.iter().filter(|&&length| length > 0).count();
+        assert_eq!(fields["non_empty_partitions"], non_empty.to_string());
+        let (largest, length) = ivf
+            .lengths
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(&a.0)))
+            .unwrap();
+        assert!(fields["top_partitions"].starts_with(&format!("{}:{}", largest, length)));
+        assert_eq!(fields["top_partitions"].split(' ').count(), non_empty);
+        assert_eq!(
+            fields["build_duration"],
+            format!("{:.3}s", report.duration.as_secs_f64())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_with_zero_norm_vectors() {
+        let mut values = generate_random_array_with_seed::<Float32Type>(1000 * DIM, [37; 32])
+            .values()
+            .to_vec();
+        for row in [3, 500] {
+            values[row * DIM..(row + 1) * DIM].fill(0.0);
+        }
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+                .unwrap();
+        // Unlike the centroids trained for Cosine, centroid 0 is not normalized, so
+        // it is not the nearest centroid to itself.
+        let centroids = generate_random_array_with_seed::<Float32Type>(4 * DIM, [38; 32])
+            .values()
+            .chunks_exact(DIM)
+            .enumerate()
+            .flat_map(|(i, centroid)| {
+                let scale = if i == 0 { 10.0 } else { 1.0 };
+                normalize(centroid).map(move |v| v * scale)
+            })
+            .collect::<Vec<_>>();
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(Float32Array::from(centroids), DIM as i32)
+                .unwrap(),
+        );
+        let training_data = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM, [39; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let pq = PQBuildParams::new(4, 8)
+            .build(&training_data, MetricType::Cosine)
+            .await
+            .unwrap();
+        let vectors = Arc::new(vectors);
+        let build = |policy| {
+            let mut params = IvfBuildParams::new(4);
+            params.on_zero_norm = policy;
+            let (vectors, centroids, pq) = (vectors.clone(), centroids.clone(), pq.clone());
+            async move {
+                let mut ivf = Ivf::new(centroids);
+                build_partitions(
+                    &mut std::io::Cursor::new(Vec::new()),
+                    None,
+                    in_memory_stream(vectors),
+                    "vector",
+                    &mut ivf,
+                    pq,
+                    MetricType::Cosine,
+                    0..4,
+                    None,
+                    &params,
+                    &BuildEnvConfig::default(),
+                )
+                .await
+                .map(|report| (report, ivf.lengths))
+            }
+        };
+
+        let (report, skipped) = build(ZeroNormPolicy::Skip).await.unwrap();
+        assert_eq!(report.zero_norm_rows, 2);
+        assert_eq!(report.num_rows, 998);
+        assert_eq!(skipped.iter().sum::<u32>(), 998);
+
+        let err = build(ZeroNormPolicy::Error).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Column vector has vectors of zero norm"),
+            "{}",
+            err
+        );
+
+        let (report, assigned) = build(ZeroNormPolicy::AssignToPartitionZero).await.unwrap();
+        assert_eq!(report.zero_norm_rows, 2);
+        assert_eq!(report.num_rows, 1000);
+        assert_eq!(assigned[0], skipped[0] + 2);
+        assert_eq!(assigned[1..], skipped[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_without_writing() {
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let (dataset, vectors) = generate_test_dataset(test_uri).await;
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let pq = PQBuildParams::new(4, 8)
+            .build(vectors.as_ref(), MetricType::L2)
+            .await
+            .unwrap();
+        let build = |write| {
+            let mut params = IvfBuildParams::new(4);
+            params.write = write;
+            params.self_recall_check = Some(20);
+            let (vectors, centroids, pq) = (vectors.clone(), centroids.clone(), pq.clone());
+            async move {
+                let mut writer = std::io::Cursor::new(Vec::new());
+                let mut ivf = Ivf::new(centroids);
+                let report = build_partitions(
+                    &mut writer,
+                    None,
+                    in_memory_stream(vectors),
+                    "vector",
+                    &mut ivf,
+                    pq,
+                    MetricType::L2,
+                    0..4,
+                    None,
+                    &params,
+                    &BuildEnvConfig::default(),
+                )
+                .await
+                .unwrap();
+                (report, ivf, writer.into_inner())
+            }
+        };
+
+        let (_, written, bytes) = build(true).await;
+        assert!(!bytes.is_empty());
+        let (report, ivf, bytes) = build(false).await;
+        assert!(bytes.is_empty());
+        assert!(report.duration > Duration::ZERO);
+        assert_eq!(report.num_rows, 1000);
+        assert_eq!(report.num_partitions, 4);
+        assert_eq!(report.self_recall.unwrap().num_queries, 20);
+        assert_eq!(ivf.lengths, written.lengths);
+        assert_eq!(ivf.offsets, vec![0; 4]);
+
+        // No index file is created, only the report is returned.
+        let uuid = Uuid::new_v4().to_string();
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.write = false;
+        let report = build_ivf_pq_index(
+            &dataset,
+            "vector",
+            "benchmark",
+            &uuid,
+            MetricType::L2,
+            &ivf_params,
+            &PQBuildParams::new(4, 8),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.num_rows, 1000);
+        assert_eq!(report.num_partitions, 4);
+        let path = dataset.indices_dir().child(uuid).child(INDEX_FILE_NAME);
+        assert!(!dataset.object_store().exists(&path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_with_mask() {
+        let fixture = PartitionsFixture::new(1000, 4, 41).await;
+        let params = IvfBuildParams::new(4);
+        let build = |mask| {
+            let (fixture, params) = (&fixture, &params);
+            async move {
+                let mut writer = std::io::Cursor::new(Vec::new());
+                let mut ivf = Ivf::new(fixture.centroids.clone());
+                let pq = fixture.pq.clone();
+                let report = build_partitions_with_mask(
+                    &mut writer,
+                    None,
+                    fixture.stream(),
+                    mask,
+                    "vector",
+                    &mut ivf,
+                    pq.clone(),
+                    MetricType::L2,
+                    0..4,
+                    None,
+                    params,
+                    &BuildEnvConfig::default(),
+                )
+                .await?;
+                let partitions =
+                    read_partitions_in_memory(&writer.into_inner(), &ivf, pq.num_sub_vectors());
+                Ok::<_, Error>((report, partitions))
+            }
+        };
+        fn alternating(len: usize) -> BooleanArray {
+            BooleanArray::from_iter((0..len).map(|i| Some(i % 2 == 0)))
+        }
+
+        let expected = (0..1000).step_by(2).collect::<Vec<u64>>();
+        let masks = [
+            RowMask::Whole(alternating(1000)),
+            RowMask::Batches(futures::stream::iter((0..10).map(|_| Ok(alternating(100)))).boxed()),
+        ];
+        for mask in masks {
+            let (report, partitions) = build(mask).await.unwrap();
+            assert_eq!(report.num_rows, 500);
+            let mut row_ids = partitions
+                .iter()
+                .flat_map(|rows| rows.iter().map(|(row_id, _)| *row_id))
+                .collect::<Vec<_>>();
+            row_ids.sort();
+            assert_eq!(row_ids, expected);
+        }
+
+        // The masks must cover every row of the input.
+        let masks = [
+            RowMask::Whole(alternating(999)),
+            RowMask::Batches(futures::stream::iter((0..9).map(|_| Ok(alternating(100)))).boxed()),
+            RowMask::Batches(futures::stream::iter((0..10).map(|_| Ok(alternating(50)))).boxed()),
+        ];
+        for mask in masks {
+            let err = build(mask).await.unwrap_err();
+            assert!(err.to_string().contains("Row mask"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_reports_distance_throughput() {
+        const NUM_ROWS: usize = 5000;
+
+        // Each vector is compared with every centroid.
+        for num_partitions in [4, 256] {
+            let fixture = PartitionsFixture::new(NUM_ROWS, num_partitions, 43).await;
+            let params = IvfBuildParams::new(num_partitions);
+            let (report, _, _) = fixture.build(fixture.stream(), &params).await.unwrap();
+            let assignment = report.assignment;
+            assert_eq!(
+                assignment.distance_computations,
+                (NUM_ROWS * num_partitions) as u64
+            );
+            assert!(assignment.throughput().unwrap() > 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_output_digest() {
+        let fixture = PartitionsFixture::new(1000, 4, 37).await;
+        let params = IvfBuildParams::new(4);
+
+        let build = |vectors: FixedSizeListArray| {
+            let (fixture, params) = (&fixture, &params);
+            async move {
+                let stream = in_memory_stream(Arc::new(vectors));
+                fixture.build(stream, params).await.unwrap().0.output_digest
+            }
+        };
+        let digest = build(fixture.vectors.as_ref().clone()).await;
+        assert_ne!(digest, [0; 32]);
+        assert_eq!(build(fixture.vectors.as_ref().clone()).await, digest);
+
+        // Moving one vector far away changes its PQ code.
+        let values = fixture.vectors.values().as_primitive::<Float32Type>();
+        let mut changed = values.values().to_vec();
+        changed[..DIM].iter_mut().for_each(|v| *v += 100.0);
+        let changed =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(changed), DIM as i32)
+                .unwrap();
+        assert_ne!(build(changed).await, digest);
+    }
+
+    /// Vectors stored as little-endian f32 bytes.
+    #[derive(Debug)]
+    struct LeBytesCodec;
+
+    impl lance_index::vector::codec::VectorCodec for LeBytesCodec {
+        fn decode(&self, data: &[u8]) -> Vec<f32> {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()
+        }
+    }
+
+    /// Seeded vectors, IVF centroids and PQ model to build the partitions of.
+    struct PartitionsFixture {
+        vectors: Arc<FixedSizeListArray>,
+        centroids: Arc<FixedSizeListArray>,
+        pq: Arc<dyn ProductQuantizer>,
+    }
+
+    impl PartitionsFixture {
+        /// `num_rows` vectors generated from `seed`, `num_partitions` centroids
+        /// generated from `seed + 1`, and a PQ model with 4 sub-vectors trained on
+        /// the vectors.
+        async fn new(num_rows: usize, num_partitions: usize, seed: u8) -> Self {
+            let vectors = FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(num_rows * DIM, [seed; 32]),
+                DIM as i32,
+            )
+            .unwrap();
+            let centroids = FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(
+                    num_partitions * DIM,
+                    [seed + 1; 32],
+                ),
+                DIM as i32,
+            )
+            .unwrap();
+            let pq = PQBuildParams::new(4, 8)
+                .build(&vectors, MetricType::L2)
+                .await
+                .unwrap();
+            Self {
+                vectors: Arc::new(vectors),
+                centroids: Arc::new(centroids),
+                pq,
+            }
+        }
+
+        /// Stream of the vectors, see [in_memory_stream].
+        fn stream(&self) -> impl RecordBatchStream + Unpin + 'static {
+            in_memory_stream(self.vectors.clone())
+        }
+
+        /// Build all the partitions of `stream` into memory, and return the build
+        /// report, the IVF model and the written bytes.
+        async fn build(
+            &self,
+            stream: impl RecordBatchStream + Unpin + 'static,
+            params: &IvfBuildParams,
+        ) -> Result<(BuildReport, Ivf, Vec<u8>)> {
+            let mut ivf = Ivf::new(self.centroids.clone());
+            let num_partitions = ivf.num_partitions() as u32;
+            let mut writer = std::io::Cursor::new(Vec::new());
+            let report = build_partitions(
+                &mut writer,
+                None,
+                stream,
+                "vector",
+                &mut ivf,
+                self.pq.clone(),
+                MetricType::L2,
+                0..num_partitions,
+                None,
+                params,
+                &BuildEnvConfig::default(),
+            )
+            .await?;
+            Ok((report, ivf, writer.into_inner()))
+        }
+    }
+
+    /// Stream of batches of 100 rows of `vector_column` and ROW_IDs.
+    fn in_memory_stream(vector_column: ArrayRef) -> impl RecordBatchStream + Unpin + 'static {
+        let num_rows = vector_column.len();
+        let batch = RecordBatch::try_from_iter(vec![
+            ("vector", vector_column),
+            (
+                ROW_ID,
+                Arc::new(UInt64Array::from_iter_values(0..num_rows as u64)) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+        lance_core::io::RecordBatchStreamAdapter::new(
+            batch.schema(),
+            futures::stream::iter(
+                (0..num_rows)
+                    .step_by(100)
+                    .map(move |i| Ok(batch.slice(i, 100.min(num_rows - i)))),
+            ),
+        )
+    }
+
+    /// Build the partitions of `vectors` into memory, and return the IVF model with
+    /// the sorted `(row_id, pq_code)` pairs of each partition.
+    async fn build_partitions_in_memory(
+        vector_column: ArrayRef,
+        centroids: &FixedSizeListArray,
+        pq: Arc<dyn ProductQuantizer>,
+        params: &IvfBuildParams,
+    ) -> (Ivf, Vec<Vec<(u64, Vec<u8>)>>) {
+        let stream = in_memory_stream(vector_column);
+        let mut ivf = Ivf::new(Arc::new(centroids.clone()));
+        let num_partitions = ivf.num_partitions() as u32;
+        let mut writer = std::io::Cursor::new(Vec::new());
+        build_partitions(
+            &mut writer,
+            None,
+            stream,
+            "vector",
+            &mut ivf,
+            pq.clone(),
+            MetricType::L2,
+            0..num_partitions,
+            None,
+            params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let partitions =
+            read_partitions_in_memory(&writer.into_inner(), &ivf, pq.num_sub_vectors());
+        (ivf, partitions)
+    }
+
+    /// The `(ROW_ID, PQ code)` rows of each partition, sorted by ROW_ID.
+    fn read_partitions_in_memory(
+        bytes: &[u8],
+        ivf: &Ivf,
+        num_sub_vectors: usize,
+    ) -> Vec<Vec<(u64, Vec<u8>)>> {
+        ivf.offsets
+            .iter()
+            .zip(ivf.lengths.iter())
+            .map(|(&offset, &length)| {
+                let length = length as usize;
+                let codes = &bytes[offset..offset + length * num_sub_vectors];
+                let row_ids = &bytes[offset + codes.len()..offset + codes.len() + length * 8];
+                let mut rows = row_ids
+                    .chunks_exact(8)
+                    .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                    .zip(codes.chunks_exact(num_sub_vectors).map(|c| c.to_vec()))
+                    .collect::<Vec<_>>();
+                rows.sort();
+                rows
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_build_index_from_fragments() {
+        const NUM_ROWS: usize = 1000;
+        let test_dir = tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+        let (dataset, vectors) = write_test_dataset(
+            test_uri,
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [17; 32]),
+        )
+        .await;
+        // Append the same vectors as a second fragment.
+        let schema = Arc::new(Schema::from(dataset.schema()));
+        let batch = RecordBatch::try_new(schema.clone(), vec![vectors]).unwrap();
+        let mut dataset = dataset;
+        dataset
+            .append(RecordBatchIterator::new(vec![Ok(batch)], schema), None)
+            .await
+            .unwrap();
+        assert_eq!(dataset.get_fragments().len(), 2);
+
+        // Each fragment is scanned on its own. With one scan at a time, the rows of
+        // the fragments come one fragment after the other.
+        let params = IvfBuildParams::new(4);
+        let scanned_fragments = |batches: Vec<RecordBatch>| {
+            batches
+                .iter()
+                .flat_map(|b| {
+                    b[ROW_ID]
+                        .as_primitive::<UInt64Type>()
+                        .values()
+                        .iter()
+                        .map(|id| RowAddress::new_from_id(*id).fragment_id())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let batches = scan_fragments(&dataset, &[1, 0], "vector", &params, 1)
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut expected = vec![1; NUM_ROWS];
+        expected.extend(vec![0; NUM_ROWS]);
+        assert_eq!(scanned_fragments(batches), expected);
+        let batches = scan_fragments(&dataset, &[0, 1], "vector", &params, 2)
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let mut fragments = scanned_fragments(batches);
+        fragments.sort();
+        assert_eq!(fragments, expected.into_iter().rev().collect::<Vec<_>>());
+        assert!(scan_fragments(&dataset, &[7], "vector", &params, 2).is_err());
+
+        // Both fragments are indexed, as by a single scan of the dataset.
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let pq: Arc<dyn ProductQuantizer> = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        ));
+        let mut builds = vec![];
+        for parallel_fragment_scans in [None, Some(2)] {
+            let mut params = IvfBuildParams::new(4);
+            params.parallel_fragment_scans = parallel_fragment_scans;
+            let mut ivf = Ivf::new(centroids.clone());
+            let mut writer = std::io::Cursor::new(Vec::new());
+            build_index_from_dataset(
+                &mut writer,
+                None,
+                &dataset,
+                "vector",
+                &mut ivf,
+                pq.clone(),
+                MetricType::L2,
+                None,
+                None,
+                &params,
+                &BuildEnvConfig::default(),
+            )
+            .await
+            .unwrap();
+            builds.push(read_partitions_in_memory(
+                &writer.into_inner(),
+                &ivf,
+                pq.num_sub_vectors(),
+            ));
+        }
+        assert_eq!(
+            builds[1].iter().map(|p| p.len()).sum::<usize>(),
+            2 * NUM_ROWS
+        );
+        assert_eq!(builds[0], builds[1]);
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_pipelined() {
+        const NUM_ROWS: usize = 1000;
+        let vectors: ArrayRef = Arc::new(
+            FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [13; 32]),
+                DIM as i32,
+            )
+            .unwrap(),
+        );
+        let mut params = IvfBuildParams::new(4);
+        params.pipelined_training_rows = Some(500);
+        let pq_params = PQBuildParams::new(4, 8);
+
+        let mut writer = std::io::Cursor::new(Vec::new());
+        let (ivf, pq, _) = build_partitions_pipelined(
+            &mut writer,
+            None,
+            in_memory_stream(vectors.clone()),
+            "vector",
+            MetricType::L2,
+            500,
+            &params,
+            &pq_params,
+            &BuildEnvConfig::default(),
+        )
+        .await
+        .unwrap();
+        let pipelined = read_partitions_in_memory(&writer.into_inner(), &ivf, pq.num_sub_vectors());
+        assert_eq!(pipelined.iter().map(|p| p.len()).sum::<usize>(), NUM_ROWS);
+
+        // The same as building the partitions from the trained models afterwards.
+        let (sequential_ivf, sequential) =
+            build_partitions_in_memory(vectors, &ivf.centroids, pq, &params).await;
+        assert_eq!(ivf.lengths, sequential_ivf.lengths);
+        assert_eq!(pipelined, sequential);
+
+        // Through the dataset build, the training rows are also indexed.
+        let test_dir = tempdir().unwrap();
+        let mut ivf_params = IvfBuildParams::new(2);
+        ivf_params.pipelined_training_rows = Some(500);
+        let (_dataset, ivf_index) =
+            build_test_index(test_dir.path().to_str().unwrap(), &ivf_params).await;
+        assert_eq!(
+            indexed_row_ids(&ivf_index).await,
+            (0..NUM_ROWS as u64).collect::<Vec<_>>()
+        );
+
+        ivf_params.pipelined_training_rows = Some(0);
+        assert!(sanity_check_ivf_param(&ivf_params).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fold_small_partitions() {
+        const NUM_ROWS: usize = 1000;
+        // With 3 outliers, each finds a kept partition among its alternatives, with 6
+        // they are all folded into the kept partition with the nearest centroid.
+        for num_outliers in [3, 6] {
+            // Outliers far from the random vectors, each alone in its own partition.
+            let mut values =
+                generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [19; 32])
+                    .values()
+                    .to_vec();
+            let outliers = (0..num_outliers)
+                .flat_map(|i| repeat(10.0 + i as f32).take(DIM))
+                .collect::<Vec<_>>();
+            values.extend_from_slice(&outliers);
+            let vectors: ArrayRef = Arc::new(
+                FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+                    .unwrap(),
+            );
+            let mut centroids = generate_random_array_with_seed::<Float32Type>(4 * DIM, [23; 32])
+                .values()
+                .to_vec();
+            centroids.extend_from_slice(&outliers);
+            let centroids =
+                FixedSizeListArray::try_new_from_values(Float32Array::from(centroids), DIM as i32)
+                    .unwrap();
+            let pq: Arc<dyn ProductQuantizer> = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+                4,
+                8,
+                DIM,
+                Arc::new(generate_random_array(256 * DIM)),
+                MetricType::L2,
+            ));
+
+            let params = IvfBuildParams::new(4 + num_outliers);
+            let (ivf, _) =
+                build_partitions_in_memory(vectors.clone(), &centroids, pq.clone(), &params).await;
+            assert_eq!(ivf.lengths[4..], vec![1; num_outliers]);
+
+            // The folded outliers are assigned as if their centroids were too far away to
+            // be the nearest. They are encoded again from their reconstructed vectors, so
+            // only the codes of the other rows are compared.
+            let mut far_centroids =
+                centroids.values().as_primitive::<Float32Type>().values()[..4 * DIM].to_vec();
+            far_centroids.extend(repeat(1e4).take(num_outliers * DIM));
+            let far_centroids = FixedSizeListArray::try_new_from_values(
+                Float32Array::from(far_centroids),
+                DIM as i32,
+            )
+            .unwrap();
+            let (_, expected) =
+                build_partitions_in_memory(vectors.clone(), &far_centroids, pq.clone(), &params)
+                    .await;
+
+            let pools: [Option<Arc<dyn MemoryPool>>; 2] =
+                [None, Some(Arc::new(UnboundedMemoryPool::default()))];
+            for pool in pools {
+                let mut params = IvfBuildParams::new(4 + num_outliers);
+                params.min_partition_rows = Some(10);
+                params.shuffle_memory_pool = pool;
+                let (ivf, partitions) =
+                    build_partitions_in_memory(vectors.clone(), &centroids, pq.clone(), &params)
+                        .await;
+                assert_eq!(ivf.lengths[4..], vec![0; num_outliers]);
+                assert!(ivf.lengths[..4].iter().all(|l| *l >= 10));
+                assert_eq!(
+                    ivf.lengths.iter().sum::<u32>() as usize,
+                    NUM_ROWS + num_outliers
+                );
+                let without_folded_codes = |partitions: Vec<Vec<(u64, Vec<u8>)>>| {
+                    partitions
+                        .into_iter()
+                        .map(|rows| {
+                            rows.into_iter()
+                                .map(|(row_id, code)| {
+                                    (row_id, Some(code).filter(|_| row_id < NUM_ROWS as u64))
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(
+                    without_folded_codes(partitions),
+                    without_folded_codes(expected.clone())
+                );
+            }
+        }
+
+        let mut params = IvfBuildParams::new(7);
+        params.min_partition_rows = Some(10);
+        params.per_partition_pq = true;
+        assert!(sanity_check_ivf_param(&params).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_single_partition_without_shuffle() {
+        const NUM_ROWS: usize = 1000;
+        let vectors = generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [41; 32]);
+        let vectors = FixedSizeListArray::try_new_from_values(vectors, DIM as i32).unwrap();
+        let centroids = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(2 * DIM, [42; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let pq: Arc<dyn ProductQuantizer> = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        ));
+
+        let num_spills = Arc::new(AtomicUsize::new(0));
+        let counter = num_spills.clone();
+        let mut params = IvfBuildParams::new(1);
+        params.on_spill_finalized = Some(Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let single_centroid = centroids.slice(0, 1);
+        let (ivf, partitions) = build_partitions_in_memory(
+            Arc::new(vectors.clone()),
+            &single_centroid,
+            pq.clone(),
+            &params,
+        )
+        .await;
+        assert_eq!(num_spills.load(Ordering::SeqCst), 0);
+        assert_eq!(ivf.lengths, vec![NUM_ROWS as u32]);
+
+        let centroid = single_centroid.value(0);
+        let centroid = centroid.as_primitive::<Float32Type>().values();
+        let residuals = vectors
+            .values()
+            .as_primitive::<Float32Type>()
+            .values()
+            .chunks_exact(DIM)
+            .flat_map(|v| v.iter().zip(centroid.iter()).map(|(v, c)| v - c))
+            .collect::<Vec<_>>();
+        let residuals =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(residuals), DIM as i32)
+                .unwrap();
+        let codes = pq.transform(&residuals).await.unwrap();
+        let codes = codes.as_fixed_size_list();
+        let expected = (0..NUM_ROWS)
+            .map(|i| {
+                let code = codes.value(i);
+                (i as u64, code.as_primitive::<UInt8Type>().values().to_vec())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(partitions, vec![expected]);
+
+        // More partitions go through the shuffle, into the spill files named by
+        // `spill_file_name_fn`.
+        let spill_files = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let files = spill_files.clone();
+        params.on_spill_finalized = Some(Arc::new(move |info| {
+            files.lock().unwrap().push(info.file_name.clone());
+        }));
+        params.spill_file_name_fn = Some(Arc::new(|id, part_ids| {
+            format!("job-7-{}-{:?}.lance", id, part_ids)
+        }));
+        params.num_partitions = 2;
+        build_partitions_in_memory(Arc::new(vectors), &centroids, pq, &params).await;
+        let spill_files = spill_files.lock().unwrap();
+        assert!(!spill_files.is_empty());
+        assert!(
+            spill_files
+                .iter()
+                .all(|name| name.starts_with("job-7-") && name.ends_with("[0, 1].lance")),
+            "{:?}",
+            spill_files
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_progress_follows_rows_written() {
+        const NUM_ROWS: usize = 2000;
+        // Skewed partitions of 1400, 400, 150 and 50 rows around the 4 centroids.
+        let values = generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [7; 32]);
+        let values = values
+            .values()
+            .chunks_exact(DIM)
+            .enumerate()
+            .flat_map(|(i, v)| {
+                let shift = match i {
+                    0..=1399 => 0.0,
+                    1400..=1799 => 1.0,
+                    1800..=1949 => 2.0,
+                    _ => 3.0,
+                };
+                v.iter().map(move |x| x + shift)
+            })
+            .collect::<Vec<_>>();
+        let vectors =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+                .unwrap();
+        let mut centroids = vec![0.5; DIM];
+        centroids.extend((1..4).flat_map(|i| vec![0.5 + i as f32; DIM]));
+        let centroids =
+            FixedSizeListArray::try_new_from_values(Float32Array::from(centroids), DIM as i32)
+                .unwrap();
+        let pq: Arc<dyn ProductQuantizer> = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        ));
+
+        let progress = Arc::new(Mutex::new(vec![]));
+        let progress_ref = progress.clone();
+        let mut params = IvfBuildParams::new(4);
+        params.on_build_progress = Some(Arc::new(move |percent| {
+            progress_ref.lock().unwrap().push(percent);
+        }));
+        let (ivf, _) = build_partitions_in_memory(Arc::new(vectors), &centroids, pq, &params).await;
+
+        let progress = progress.lock().unwrap().clone();
+        assert_eq!(progress.len(), 4);
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+        assert_relative_eq!(*progress.last().unwrap(), 100.0, epsilon = 1e-9);
+        // Each step is the share of the rows of the partition written.
+        let mut written = 0;
+        for (percent, length) in progress.iter().zip(ivf.lengths.iter()) {
+            written += *length as usize;
+            assert_relative_eq!(
+                *percent,
+                written as f64 * 100.0 / NUM_ROWS as f64,
+                epsilon = 1e-9
+            );
+        }
+        assert_eq!(ivf.lengths, vec![1400, 400, 150, 50]);
+    }
+
+    #[tokio::test]
+    async fn test_reject_bimodal_norms() {
+        const NUM_ROWS: usize = 1000;
+        let values = generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [11; 32]);
+        // Scale every fifth vector, as if it came from another model.
+        let vectors_scaled_by = |scale: f32| -> ArrayRef {
+            let values = values
+                .values()
+                .chunks_exact(DIM)
+                .enumerate()
+                .flat_map(|(i, v)| {
+                    let scale = if i % 5 == 0 { scale } else { 1.0 };
+                    v.iter().map(move |x| x * scale)
+                })
+                .collect::<Vec<_>>();
+            Arc::new(
+                FixedSizeListArray::try_new_from_values(Float32Array::from(values), DIM as i32)
+                    .unwrap(),
+            )
+        };
+        let pq: Arc<dyn ProductQuantizer> = Arc::new(ProductQuantizerImpl::<Float32Type>::new(
+            4,
+            8,
+            DIM,
+            Arc::new(generate_random_array(256 * DIM)),
+            MetricType::L2,
+        ));
+
+        // With and without the shuffle.
+        for num_partitions in [1, 2] {
+            let centroids = FixedSizeListArray::try_new_from_values(
+                generate_random_array_with_seed::<Float32Type>(num_partitions * DIM, [42; 32]),
+                DIM as i32,
+            )
+            .unwrap();
+            let mut params = IvfBuildParams::new(num_partitions);
+            params.reject_bimodal_norms = true;
+            let mut ivf = Ivf::new(Arc::new(centroids.clone()));
+            let err = build_partitions(
+                &mut std::io::Cursor::new(Vec::new()),
+                None,
+                in_memory_stream(vectors_scaled_by(100.0)),
+                "vector",
+                &mut ivf,
+                pq.clone(),
+                MetricType::L2,
+                0..num_partitions as u32,
+                None,
+                &params,
+                &BuildEnvConfig::default(),
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("bimodal"), "{}", err);
+            assert!(err.to_string().contains("80.0%"), "{}", err);
+
+            let (ivf, _) =
+                build_partitions_in_memory(vectors_scaled_by(1.0), &centroids, pq.clone(), &params)
+                    .await;
+            assert_eq!(ivf.lengths.iter().sum::<u32>(), NUM_ROWS as u32);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_with_vector_codec() {
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM, [7; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let blobs = BinaryArray::from_iter_values(
+            vectors
+                .values()
+                .as_primitive::<Float32Type>()
+                .values()
+                .chunks_exact(DIM)
+                .map(|v| v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>()),
+        );
+
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap();
+        let pq = PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)))
+            .build(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+
+        let (_, expected) = build_partitions_in_memory(
+            Arc::new(vectors),
+            &centroids,
+            pq.clone(),
+            &IvfBuildParams::new(4),
+        )
+        .await;
+        let mut params = IvfBuildParams::new(4);
+        params.vector_codec = Some(Arc::new(LeBytesCodec));
+        let (_, decoded) =
+            build_partitions_in_memory(Arc::new(blobs), &centroids, pq, &params).await;
+
+        assert_eq!(expected.iter().map(|p| p.len()).sum::<usize>(), 1000);
+        assert_eq!(decoded, expected);
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_with_vector_expr() {
+        let scalars = Float32Array::from_iter_values((0..1000).map(|i| (i % 97) as f32 / 10.0));
+        let vectors = embed(&scalars);
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap();
+        let pq = PQBuildParams::new(4, 8)
+            .build(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+
+        let (_, expected) = build_partitions_in_memory(
+            Arc::new(vectors.clone()),
+            &centroids,
+            pq.clone(),
+            &IvfBuildParams::new(4),
+        )
+        .await;
+
+        let mut params = IvfBuildParams::new(4);
+        // The input "vector" column holds the scalars the vectors are computed from.
+        params.vector_expr = Some(embed_udf().call(vec![col("vector")]));
+        let (_, computed) =
+            build_partitions_in_memory(Arc::new(scalars), &centroids, pq, &params).await;
+
+        assert_eq!(expected.iter().map(|p| p.len()).sum::<usize>(), 1000);
+        assert_eq!(computed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_build_partitions_with_weights() {
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(1000 * DIM, [3; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap();
+        let pq = PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)))
+            .build(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+        // Only the first two dimensions matter.
+        let weights = (0..DIM)
+            .map(|i| if i < 2 { 100.0 } else { 0.0 })
+            .collect::<Vec<f32>>();
+
+        let (_, uniform) = build_partitions_in_memory(
+            Arc::new(vectors.clone()),
+            &centroids,
+            pq.clone(),
+            &IvfBuildParams::new(4),
+        )
+        .await;
+        let mut params = IvfBuildParams::new(4);
+        params.weights = Some(weights.clone());
+        let (ivf, weighted) =
+            build_partitions_in_memory(Arc::new(vectors.clone()), &centroids, pq, &params).await;
+        assert_ne!(uniform, weighted);
+
+        let values = vectors.values().as_primitive::<Float32Type>().values();
+        let centroid_values = centroids.values().as_primitive::<Float32Type>().values();
+        let weighted_l2 = |a: &[f32], b: &[f32]| {
+            a.iter()
+                .zip(b)
+                .zip(weights.iter())
+                .map(|((x, y), w)| w * (x - y) * (x - y))
+                .sum::<f32>()
+        };
+        for (part_id, rows) in weighted.iter().enumerate() {
+            for (row_id, _) in rows {
+                let vector = &values[*row_id as usize * DIM..(*row_id as usize + 1) * DIM];
+                let closest = centroid_values
+                    .chunks_exact(DIM)
+                    .map(|c| weighted_l2(vector, c))
+                    .enumerate()
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+                    .0;
+                assert_eq!(closest, part_id);
+            }
+        }
+
+        let proto = pb::Ivf::try_from(&ivf).unwrap();
+        let loaded = Ivf::try_from(&proto).unwrap();
+        assert_eq!(loaded.weights, Some(weights));
+    }
 }
@@ -0,0 +1,179 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Build settings read from the environment.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use datafusion::execution::memory_pool::{GreedyMemoryPool, MemoryPool, UnboundedMemoryPool};
+use log::{error, info};
+
+/// Memory limit in bytes of the sorts of a build.
+pub const MEMORY_LIMIT_ENV: &str = "LANCE_MEMORY_LIMIT";
+
+/// Directory to write the shuffle spill files into, instead of a temporary directory.
+pub const SPILL_DIR_ENV: &str = "LANCE_SPILL_DIR";
+
+/// Number of batches transformed in parallel during the shuffle.
+pub const CONCURRENCY_ENV: &str = "LANCE_BUILD_CONCURRENCY";
+
+/// Settings of an IVF build that come from environment variables.
+///
+/// | Variable | Default |
+/// |----------|---------|
+/// | [`MEMORY_LIMIT_ENV`] | unbounded |
+/// | [`SPILL_DIR_ENV`] | a temporary directory |
+/// | [`CONCURRENCY_ENV`] | the number of CPUs |
+///
+/// Invalid values are logged and the default is used instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildEnvConfig {
+    /// Memory limit in bytes of the sorts, unbounded if `None`.
+    pub memory_limit: Option<usize>,
+
+    /// Existing directory to write the spill files into. Each shuffle writes into a
+    /// new directory in it.
+    pub spill_dir: Option<PathBuf>,
+
+    /// Number of batches transformed in parallel, at least 1.
+    pub concurrency: usize,
+}
+
+impl Default for BuildEnvConfig {
+    fn default() -> Self {
+        Self {
+            memory_limit: None,
+            spill_dir: None,
+            concurrency: num_cpus::get(),
+        }
+    }
+}
+
+impl BuildEnvConfig {
+    /// Read the settings from the environment of the process.
+    pub fn from_env() -> Self {
+        let config = Self::from_vars(|name| std::env::var(name).ok());
+        info!("IVF build environment: {:?}", config);
+        config
+    }
+
+    /// Read the settings from the variables returned by `var`.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut config = Self::default();
+        if let Some(value) = var(MEMORY_LIMIT_ENV) {
+            match value.parse::<usize>() {
+                Ok(memory_limit) => config.memory_limit = Some(memory_limit),
+                Err(err) => error!(
+                    "Failed to parse {}: {}, using default of unbounded.",
+                    MEMORY_LIMIT_ENV, err
+                ),
+            }
+        }
+        if let Some(value) = var(SPILL_DIR_ENV) {
+            let dir = PathBuf::from(value);
+            if dir.is_dir() {
+                config.spill_dir = Some(dir);
+            } else {
+                error!(
+                    "{} {} is not a directory, using a temporary directory.",
+                    SPILL_DIR_ENV,
+                    dir.display()
+                );
+            }
+        }
+        if let Some(value) = var(CONCURRENCY_ENV) {
+            match value.parse::<usize>() {
+                Ok(concurrency) if concurrency > 0 => config.concurrency = concurrency,
+                Ok(_) => error!(
+                    "{} must be positive, using default of {}.",
+                    CONCURRENCY_ENV, config.concurrency
+                ),
+                Err(err) => error!(
+                    "Failed to parse {}: {}, using default of {}.",
+                    CONCURRENCY_ENV, err, config.concurrency
+                ),
+            }
+        }
+        config
+    }
+
+    /// Memory pool of the sorts, bounded by [`Self::memory_limit`].
+    pub fn memory_pool(&self) -> Arc<dyn MemoryPool> {
+        match self.memory_limit {
+            Some(memory_limit) => Arc::new(GreedyMemoryPool::new(memory_limit)),
+            None => Arc::new(UnboundedMemoryPool::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use tempfile::tempdir;
+
+    fn from_vars(vars: &[(&str, &str)]) -> BuildEnvConfig {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        BuildEnvConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = from_vars(&[]);
+        assert_eq!(config, BuildEnvConfig::default());
+        assert_eq!(config.memory_limit, None);
+        assert_eq!(config.spill_dir, None);
+        assert_eq!(config.concurrency, num_cpus::get());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let config = from_vars(&[(MEMORY_LIMIT_ENV, "1048576")]);
+        assert_eq!(config.memory_limit, Some(1048576));
+        assert_eq!(config.memory_pool().reserved(), 0);
+
+        assert_eq!(from_vars(&[(MEMORY_LIMIT_ENV, "1GB")]).memory_limit, None);
+        assert_eq!(from_vars(&[(MEMORY_LIMIT_ENV, "-1")]).memory_limit, None);
+    }
+
+    #[test]
+    fn test_spill_dir() {
+        let dir = tempdir().unwrap();
+        let config = from_vars(&[(SPILL_DIR_ENV, dir.path().to_str().unwrap())]);
+        assert_eq!(config.spill_dir.as_deref(), Some(dir.path()));
+
+        let missing = dir.path().join("missing");
+        let config = from_vars(&[(SPILL_DIR_ENV, missing.to_str().unwrap())]);
+        assert_eq!(config.spill_dir, None);
+    }
+
+    #[test]
+    fn test_concurrency() {
+        assert_eq!(from_vars(&[(CONCURRENCY_ENV, "3")]).concurrency, 3);
+        assert_eq!(
+            from_vars(&[(CONCURRENCY_ENV, "0")]).concurrency,
+            num_cpus::get()
+        );
+        assert_eq!(
+            from_vars(&[(CONCURRENCY_ENV, "many")]).concurrency,
+            num_cpus::get()
+        );
+    }
+}
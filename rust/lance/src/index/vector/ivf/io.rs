@@ -12,39 +12,243 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Instant;
 
+use arrow::compute::{cast, sort_to_indices, SortOptions};
+use arrow_arith::numeric::sub;
 use arrow_array::cast::AsArray;
-use arrow_array::{Array, FixedSizeListArray, RecordBatch, UInt32Array, UInt64Array};
-use futures::{Stream, StreamExt};
+use arrow_array::types::{Float32Type, UInt64Type, UInt8Type};
+use arrow_array::{
+    Array, ArrayRef, FixedSizeListArray, RecordBatch, UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+use arrow_select::{concat::concat, take::take};
+use async_trait::async_trait;
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use lance_arrow::*;
-use lance_core::io::Writer;
+use lance_core::io::{read_fixed_stride_array, Reader, Writer};
 use lance_core::Error;
-use lance_index::vector::{PART_ID_COLUMN, PQ_CODE_COLUMN};
+use lance_index::vector::ivf::{BuildControl, PartitionWrittenCallback, Tier, TierFn};
+use lance_index::vector::pq::{
+    pack_codes, packed_codes_len, transpose_pq_codes, unpack_codes, CodeLayout, CodeStorageOrder,
+    PQBuildParams, ProductQuantizer,
+};
+use lance_index::vector::{
+    pca::PCA_VECTOR_COLUMN, ASSIGNMENT_MARGIN_COLUMN, CENTROID_DISTANCE_COLUMN, PART_ID_COLUMN,
+    PQ_CODE_COLUMN, SUBGROUP_COLUMN, VALID_COLUMN,
+};
+use lance_linalg::distance::MetricType;
+use sha2::{Digest, Sha256};
 use snafu::{location, Location};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-use super::{IVFIndex, Ivf};
+use super::{IVFIndex, Ivf, PackedCodes, Subgroup, CENTROID_METADATA_KEY};
 use crate::dataset::ROW_ID;
 use crate::encodings::plain::PlainEncoder;
+use crate::format::RowAddress;
 use crate::index::vector::pq::PQIndex;
 use crate::Result;
 
+/// Train a PQ codebook for each partition while writing it.
+#[derive(Clone)]
+pub(super) struct PartitionPqParams {
+    /// Vector column carried through the shuffle instead of the PQ codes.
+    pub column: String,
+
+    /// Parameters to train the codebook of each partition with, e.g. the number of
+    /// kmeans iterations. The number of sub-vectors and bits are the ones of `pq`.
+    pub pq_params: PQBuildParams,
+
+    /// The shared product quantizer. It provides the PQ parameters, and the codebook of
+    /// the partitions that are too small to train their own.
+    pub pq: Arc<dyn ProductQuantizer>,
+
+    pub metric_type: MetricType,
+
+    /// Centroids to compute the residuals from, in the same space as the vectors.
+    pub centroids: Arc<FixedSizeListArray>,
+}
+
+impl PartitionPqParams {
+    /// Train a product quantizer on the vectors of one partition, and encode them.
+    async fn encode(&self, centroid: ArrayRef, vectors: &[ArrayRef]) -> Result<PartitionCodes> {
+        let vector_refs = vectors.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
+        let vectors = concat(&vector_refs)?;
+        let vectors = vectors.as_fixed_size_list();
+        let data = if self.pq.use_residual() {
+            let centroids = concat(&vec![centroid.as_ref(); vectors.len()])?;
+            FixedSizeListArray::try_new_from_values(
+                sub(vectors.values(), &centroids)?,
+                vectors.value_length(),
+            )?
+        } else {
+            vectors.clone()
+        };
+
+        let pq = if data.len() < 2_usize.pow(self.pq.num_bits()) {
+            self.pq.clone()
+        } else {
+            PQBuildParams {
+                num_sub_vectors: self.pq.num_sub_vectors(),
+                num_bits: self.pq.num_bits() as usize,
+                codebook: None,
+                ..self.pq_params.clone()
+            }
+            .build(&data, self.metric_type)
+            .await?
+        };
+        let codes = pq.transform(&data).await?;
+        Ok((pq, codes))
+    }
+}
+
+/// Options of [write_index_partitions], none set by default.
+#[derive(Default)]
+pub(super) struct WritePartitionsOptions<'a> {
+    /// Encode each partition with its own codebook, from the vectors carried by the
+    /// streams instead of the PQ codes.
+    pub partition_pq: Option<&'a PartitionPqParams>,
+
+    /// Called with the id and the number of rows of each partition once written.
+    pub on_partition_written: Option<&'a PartitionWrittenCallback>,
+
+    /// Pauses the merge before the next partition while paused.
+    pub control: Option<&'a BuildControl>,
+
+    /// Writer of the cold partitions.
+    pub cold_tier: Option<ColdTier<'a>>,
+
+    /// Order the hot partitions are written in, a permutation of the partition ids.
+    pub output_order: Option<&'a [u32]>,
+}
+
 /// Write each partition of IVF_PQ index to the index file.
 ///
 /// `batches`: RecordBatch stream of PQ codes and row ids, sorted by PQ code.
+/// If `partition_pq` is set, the streams carry the vectors instead of the PQ codes,
+/// and each partition is encoded with its own codebook.
+///
+/// Each stream must be sorted by partition id, and may have gaps or no batches at all.
+/// So the shards of an index built over different `part_range`s are merged by passing
+/// the [read_partitions] stream of each shard.
+///
+/// The bytes of each partition are written to all `writers`, which must be at the
+/// same offset. Fails if any of the writers fails.
+///
+/// A partition is only added to `ivf` once its bytes are written, so the partitions
+/// already in `ivf` mark the ones finalized by an interrupted merge. To resume the
+/// merge, pass the same `ivf`, writers positioned at the end of the last finalized
+/// partition, and the full `streams` again: the finalized partitions are skipped and
+/// not written twice.
+///
+/// `on_partition_written` is called with the id and the number of rows of each
+/// partition right after it is added to `ivf`. While `control` is paused, no further
+/// partition is started.
+///
+/// With a `cold_tier`, the partitions it assigns to [`Tier::Cold`] are written to its
+/// writer instead of `writers`, and the tier of each partition is recorded in `ivf`.
+///
+/// If `ivf` has subgroups, the rows of each partition are sorted by their
+/// [SUBGROUP_COLUMN], see [sort_into_subgroups].
+///
+/// With an `output_order`, a permutation of the partition ids, the hot partitions are
+/// written to `writers` in that order instead, while `ivf` still records the offset of
+/// each partition by its id. The partitions built before their turn are held in memory
+/// until they are written, see [OrderedPartitions] for the memory cost. An interrupted
+/// merge cannot be resumed with an order.
+///
+/// Returns the digest of the partitions written by this call, see [update_digest].
 pub(super) async fn write_index_partitions(
-    writer: &mut dyn Writer,
+    mut writers: Vec<&mut dyn Writer>,
     ivf: &mut Ivf,
     streams: Vec<impl Stream<Item = Result<RecordBatch>>>,
     existing_partitions: Option<&IVFIndex>,
-) -> Result<()> {
-    // build the inital heap
+    options: WritePartitionsOptions<'_>,
+) -> Result<[u8; 32]> {
+    let WritePartitionsOptions {
+        partition_pq,
+        on_partition_written,
+        control,
+        mut cold_tier,
+        output_order,
+    } = options;
+    let mut digest = Sha256::new();
+    let mut cold_offset = match cold_tier.as_mut() {
+        Some(cold_tier) => cold_tier.writer.tell().await?,
+        None => 0,
+    };
+    let mut offset = match writers.first_mut() {
+        Some(writer) => writer.tell().await?,
+        None => {
+            return Err(Error::Index {
+                message: "write_index_partitions: no writer provided".to_string(),
+                location: location!(),
+            })
+        }
+    };
+    for writer in writers.iter_mut().skip(1) {
+        let writer_offset = writer.tell().await?;
+        if writer_offset != offset {
+            return Err(Error::Index {
+                message: format!(
+                    "write_index_partitions: writers are at different offsets: {} and {}",
+                    offset, writer_offset
+                ),
+                location: location!(),
+            });
+        }
+    }
+
+    if ivf.subgroups.is_some() && existing_partitions.is_some() {
+        return Err(Error::Index {
+            message: "write_index_partitions: the rows of existing partitions have no subgroups"
+                .to_string(),
+            location: location!(),
+        });
+    }
+
+    // Partitions finalized by a previous, interrupted merge.
+    let num_finalized = ivf.lengths.len() as u32;
+    let mut ordered = match output_order {
+        Some(order) => {
+            check_output_order(order, ivf.num_partitions())?;
+            if num_finalized > 0 {
+                return Err(Error::Index {
+                    message:
+                        "write_index_partitions: a merge with an output order cannot be resumed"
+                            .to_string(),
+                    location: location!(),
+                });
+            }
+            Some(OrderedPartitions::new(order))
+        }
+        None => None,
+    };
+    if num_finalized > 0 {
+        log::info!(
+            "Resuming merge after {} finalized partitions",
+            num_finalized
+        );
+    }
+
+    // build the inital heap, of the next partition id of each stream, smallest first.
     let mut streams_heap = BinaryHeap::new();
     let mut new_streams = vec![];
 
     for stream in streams {
+        let stream = stream.try_filter(move |batch| {
+            let part_ids: &UInt32Array = batch
+                .column_by_name(PART_ID_COLUMN)
+                .expect("part id column not found")
+                .as_primitive();
+            // Empty batches, e.g. of empty partitions, have nothing to merge.
+            future::ready(!part_ids.is_empty() && part_ids.value(0) >= num_finalized)
+        });
         let mut stream = Box::pin(stream.peekable());
 
         match stream.as_mut().peek().await {
@@ -54,7 +258,7 @@ pub(super) async fn write_index_partitions(
                     .expect("part id column not found")
                     .as_primitive();
                 let part_id = part_ids.values()[0];
-                streams_heap.push((part_id, new_streams.len()));
+                streams_heap.push(Reverse((part_id, new_streams.len())));
                 new_streams.push(stream);
             }
             Some(Err(e)) => {
@@ -63,107 +267,2265 @@ pub(super) async fn write_index_partitions(
                     location: location!(),
                 });
             }
-            None => {
-                return Err(Error::IO {
-                    message: "failed to read batch: end of stream".to_string(),
-                    location: location!(),
-                });
-            }
+            // All the partitions of this stream are already finalized, or empty, e.g.
+            // the stream of an empty index shard.
+            None => {}
         }
     }
 
-    for part_id in 0..ivf.num_partitions() as u32 {
-        let start = Instant::now();
-        let mut pq_array = Vec::<Arc<dyn Array>>::new();
-        let mut row_id_array = Vec::<Arc<dyn Array>>::new();
-
-        if let Some(existing_idx) = existing_partitions.as_ref() {
-            let part = existing_idx.load_partition(part_id as usize, true).await?;
-            let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
-            if pq_idx.code.is_some() {
-                let pq_code_arr = pq_idx.code.as_ref().unwrap().clone();
-                let pq_code_fixed_size_arr = FixedSizeListArray::try_new_from_values(
-                    pq_code_arr.as_ref().clone(),
-                    pq_idx.pq.num_sub_vectors() as i32,
-                )?;
-                pq_array.push(Arc::new(pq_code_fixed_size_arr));
-                row_id_array.push(pq_idx.row_ids.as_ref().unwrap().clone());
+    // With per-partition PQ, the codebooks of up to `num_cpus` partitions are trained
+    // concurrently, while the rows of the next partitions are read. The partitions are
+    // still written in order.
+    let max_pending = if partition_pq.is_some() {
+        num_cpus::get()
+    } else {
+        1
+    };
+    let mut pending = VecDeque::new();
+    let mut next_part_id = num_finalized;
+    loop {
+        if next_part_id < ivf.num_partitions() as u32 && pending.len() < max_pending {
+            let part_id = next_part_id;
+            next_part_id += 1;
+            if let Some(control) = control {
+                control.wait_if_paused().await;
             }
-        }
+            let start = Instant::now();
+            let mut pq_array = Vec::<Arc<dyn Array>>::new();
+            let mut row_id_array = Vec::<Arc<dyn Array>>::new();
+            let mut pca_array = Vec::<Arc<dyn Array>>::new();
+            let mut valid_array = Vec::<Arc<dyn Array>>::new();
+            let mut margin_array = Vec::<Arc<dyn Array>>::new();
+            let mut subgroup_array = Vec::<Arc<dyn Array>>::new();
+            let mut extra_arrays = vec![Vec::<Arc<dyn Array>>::new(); ivf.extra_columns.len()];
+            // (distance, ROW_ID) of the vector closest to the centroid.
+            let mut medoid: Option<(f32, u64)> = None;
 
-        // Merge all streams with the same partition id.
-        while let Some((stream_part_id, stream_idx)) = streams_heap.pop() {
-            if stream_part_id != part_id {
-                streams_heap.push((stream_part_id, stream_idx));
-                break;
+            if let Some(existing_idx) = existing_partitions.as_ref() {
+                let part = existing_idx.load_partition(part_id as usize, true).await?;
+                let pq_idx = part.as_any().downcast_ref::<PQIndex>().unwrap();
+                if pq_idx.code.is_some() {
+                    let pq_code_arr = pq_idx.code.as_ref().unwrap().clone();
+                    let pq_code_fixed_size_arr = FixedSizeListArray::try_new_from_values(
+                        pq_code_arr.as_ref().clone(),
+                        pq_idx.pq.num_sub_vectors() as i32,
+                    )?;
+                    pq_array.push(Arc::new(pq_code_fixed_size_arr));
+                    row_id_array.push(pq_idx.row_ids.as_ref().unwrap().clone());
+                }
             }
 
-            let mut stream = new_streams[stream_idx].as_mut();
-            let batch = match stream.next().await {
-                Some(Ok(batch)) => batch,
-                Some(Err(e)) => {
-                    return Err(Error::IO {
-                        message: format!("failed to read batch: {}", e),
-                        location: location!(),
-                    });
+            // Merge all streams with the same partition id.
+            while let Some(Reverse((stream_part_id, stream_idx))) = streams_heap.pop() {
+                if stream_part_id != part_id {
+                    streams_heap.push(Reverse((stream_part_id, stream_idx)));
+                    break;
                 }
-                None => {
-                    return Err(Error::IO {
-                        message: "failed to read batch: unexpected end of stream".to_string(),
+
+                let mut stream = new_streams[stream_idx].as_mut();
+                let batch = match stream.next().await {
+                    Some(Ok(batch)) => batch,
+                    Some(Err(e)) => {
+                        return Err(Error::IO {
+                            message: format!("failed to read batch: {}", e),
+                            location: location!(),
+                        });
+                    }
+                    None => {
+                        return Err(Error::IO {
+                            message: "failed to read batch: unexpected end of stream".to_string(),
+                            location: location!(),
+                        });
+                    }
+                };
+
+                let pq_codes = if let Some(params) = partition_pq {
+                    batch
+                        .column_by_name(&params.column)
+                        .expect("vector column not found")
+                        .clone()
+                } else {
+                    batch
+                        .column_by_name(PQ_CODE_COLUMN)
+                        .expect("pq code column not found")
+                        .clone()
+                };
+
+                let row_ids: UInt64Array = batch
+                    .column_by_name(ROW_ID)
+                    .expect("row id column not found")
+                    .as_primitive()
+                    .clone();
+
+                let batch_extra_columns = extra_columns(&batch)?;
+                if ivf.extra_columns.is_empty() && !batch_extra_columns.is_empty() {
+                    if ivf.lengths.iter().any(|len| *len > 0) || !row_id_array.is_empty() {
+                        return Err(Error::Index {
+                            message: format!(
+                                "Partition {}: rows were written without the extra columns {:?}",
+                                part_id, batch_extra_columns
+                            ),
+                            location: location!(),
+                        });
+                    }
+                    extra_arrays = vec![vec![]; batch_extra_columns.len()];
+                    ivf.extra_columns = batch_extra_columns;
+                } else if batch_extra_columns != ivf.extra_columns {
+                    return Err(Error::Index {
+                        message: format!(
+                            "Partition {}: extra columns {:?} of a batch do not match {:?}",
+                            part_id, batch_extra_columns, ivf.extra_columns
+                        ),
                         location: location!(),
                     });
                 }
-            };
+                for (field, arrays) in ivf.extra_columns.iter().zip(extra_arrays.iter_mut()) {
+                    arrays.push(batch[field.name().as_str()].clone());
+                }
+
+                pq_array.push(pq_codes);
+                row_id_array.push(Arc::new(row_ids.clone()));
+                if ivf.pca.is_some() {
+                    let pca_vectors = batch
+                        .column_by_name(PCA_VECTOR_COLUMN)
+                        .expect("pca vector column not found")
+                        .clone();
+                    pca_array.push(pca_vectors);
+                }
+                if ivf.has_valid {
+                    let valid = batch
+                        .column_by_name(VALID_COLUMN)
+                        .expect("valid column not found")
+                        .clone();
+                    valid_array.push(valid);
+                }
+                if ivf.has_assignment_margin {
+                    let margins = batch
+                        .column_by_name(ASSIGNMENT_MARGIN_COLUMN)
+                        .expect("assignment margin column not found")
+                        .clone();
+                    margin_array.push(margins);
+                }
+                if ivf.subgroups.is_some() {
+                    let values = batch
+                        .column_by_name(SUBGROUP_COLUMN)
+                        .expect("subgroup column not found")
+                        .clone();
+                    subgroup_array.push(values);
+                }
+                if ivf.medoids.is_some() {
+                    let distances = batch
+                        .column_by_name(CENTROID_DISTANCE_COLUMN)
+                        .expect("centroid distance column not found")
+                        .as_primitive::<Float32Type>();
+                    for (&dist, &row_id) in distances.values().iter().zip(row_ids.values().iter()) {
+                        if medoid.map_or(true, |(best, _)| dist < best) {
+                            medoid = Some((dist, row_id));
+                        }
+                    }
+                }
 
-            let pq_codes = batch
-                .column_by_name(PQ_CODE_COLUMN)
-                .expect("pq code column not found")
-                .as_fixed_size_list()
-                .clone();
-
-            let row_ids: UInt64Array = batch
-                .column_by_name(ROW_ID)
-                .expect("row id column not found")
-                .as_primitive()
-                .clone();
-
-            pq_array.push(Arc::new(pq_codes));
-            row_id_array.push(Arc::new(row_ids));
-
-            match stream.peek().await {
-                Some(Ok(batch)) => {
-                    let part_ids: &UInt32Array = batch
-                        .column_by_name(PART_ID_COLUMN)
-                        .expect("part id column not found")
-                        .as_primitive();
-                    if !part_ids.is_empty() {
-                        streams_heap.push((part_ids.value(0), stream_idx));
+                match stream.peek().await {
+                    Some(Ok(batch)) => {
+                        let part_ids: &UInt32Array = batch
+                            .column_by_name(PART_ID_COLUMN)
+                            .expect("part id column not found")
+                            .as_primitive();
+                        streams_heap.push(Reverse((part_ids.value(0), stream_idx)));
+                    }
+                    Some(Err(e)) => {
+                        return Err(Error::IO {
+                            message: format!("IVF Shuffler::failed to read batch: {}", e),
+                            location: location!(),
+                        });
                     }
+                    None => {}
                 }
-                Some(Err(e)) => {
-                    return Err(Error::IO {
-                        message: format!("IVF Shuffler::failed to read batch: {}", e),
+            }
+
+            let encoding = match partition_pq {
+                Some(params) if !pq_array.is_empty() => {
+                    let params = params.clone();
+                    let centroid = params.centroids.value(part_id as usize);
+                    let vectors = std::mem::take(&mut pq_array);
+                    Some(AbortOnDrop(tokio::task::spawn(async move {
+                        params.encode(centroid, &vectors).await
+                    })))
+                }
+                _ => None,
+            };
+            pending.push_back(GatheredPartition {
+                part_id,
+                start,
+                encoding,
+                pq_array,
+                row_id_array,
+                pca_array,
+                valid_array,
+                margin_array,
+                subgroup_array,
+                extra_arrays,
+                medoid,
+            });
+            continue;
+        }
+
+        let Some(GatheredPartition {
+            part_id,
+            start,
+            encoding,
+            mut pq_array,
+            mut row_id_array,
+            mut pca_array,
+            mut valid_array,
+            mut margin_array,
+            subgroup_array,
+            mut extra_arrays,
+            medoid,
+        }) = pending.pop_front()
+        else {
+            break;
+        };
+
+        let mut codebook = None;
+        if let Some(params) = partition_pq {
+            match encoding {
+                Some(mut encoding) => {
+                    let (pq, codes) = (&mut encoding.0).await.map_err(|err| Error::Index {
+                        message: format!(
+                            "Failed to train the PQ codebook of partition {}: {}",
+                            part_id, err
+                        ),
                         location: location!(),
-                    });
+                    })??;
+                    codebook = Some(Arc::new(pq.codebook_as_fsl()));
+                    pq_array = vec![codes];
                 }
-                None => {}
+                None => codebook = Some(Arc::new(params.pq.codebook_as_fsl())),
             }
         }
 
+        let mut subgroups = vec![];
+        if !subgroup_array.is_empty() {
+            let mut columns = vec![
+                &mut pq_array,
+                &mut row_id_array,
+                &mut pca_array,
+                &mut valid_array,
+                &mut margin_array,
+            ];
+            columns.extend(extra_arrays.iter_mut());
+            subgroups = sort_into_subgroups(&subgroup_array, columns)?;
+        }
+
+        update_digest(&mut digest, part_id, &row_id_array, &pq_array)?;
+
+        if ivf.code_storage_order == CodeStorageOrder::Separate && !pq_array.is_empty() {
+            let pq_refs = pq_array.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
+            let codes = concat(&pq_refs)?;
+            let num_sub_vectors = codes.as_fixed_size_list().value_length() as usize;
+            let batch = RecordBatch::try_from_iter([(PQ_CODE_COLUMN, codes)])?;
+            let batch = transpose_pq_codes(&batch, num_sub_vectors, true)?;
+            pq_array = vec![batch[PQ_CODE_COLUMN].clone()];
+        }
+
         let total_records = row_id_array.iter().map(|a| a.len()).sum::<usize>();
-        ivf.add_partition(writer.tell().await?, total_records as u32);
+        let tier = cold_tier
+            .as_ref()
+            .map_or(Tier::Hot, |cold_tier| (cold_tier.tier_fn)(part_id));
+        let part_offset = match tier {
+            Tier::Hot => offset,
+            Tier::Cold => cold_offset,
+        };
+        let mut packed_offset = part_offset;
         if total_records > 0 {
-            let pq_refs = pq_array.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
-            PlainEncoder::write(writer, &pq_refs).await?;
+            let columns = PartitionColumns {
+                pq_codes: &pq_array,
+                row_ids: &row_id_array,
+                pca_vectors: &pca_array,
+                valid: &valid_array,
+                margins: &margin_array,
+                extra_columns: &extra_arrays,
+            };
+            match (tier, cold_tier.as_mut(), ordered.as_mut()) {
+                (Tier::Cold, Some(cold_tier), _) => {
+                    let (len, packed) = write_partition(cold_tier.writer, ivf, &columns).await?;
+                    cold_offset += len;
+                    packed_offset = part_offset + packed.unwrap_or_default();
+                }
+                (_, _, Some(ordered)) => {
+                    let mut buffer = BufferWriter::default();
+                    let (_, packed) = write_partition(&mut buffer, ivf, &columns).await?;
+                    packed_offset = part_offset + packed.unwrap_or_default();
+                    ordered.hold(
+                        part_id,
+                        PendingPartition {
+                            bytes: buffer.0,
+                            packed_offset: packed,
+                        },
+                    );
+                }
+                _ => {
+                    // The writers are at the same offset, so the partition has the same
+                    // layout in all of them.
+                    let mut written = (0, None);
+                    for writer in writers.iter_mut() {
+                        written = write_partition(*writer, ivf, &columns).await?;
+                    }
+                    let (len, packed) = written;
+                    offset += len;
+                    packed_offset = part_offset + packed.unwrap_or_default();
+                }
+            }
+        }
 
-            let row_ids_refs = row_id_array.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
-            PlainEncoder::write(writer, row_ids_refs.as_slice()).await?;
+        // The partition is finalized.
+        ivf.add_partition(part_offset, total_records as u32);
+        if cold_tier.is_some() {
+            ivf.tiers.push(tier);
+        }
+        if let Some(packed) = ivf.packed_codes.as_mut() {
+            packed.offsets.push(packed_offset);
+        }
+        if let Some(codebook) = codebook {
+            ivf.pq_codebooks.push(codebook);
+        }
+        if let Some(medoids) = ivf.medoids.as_mut() {
+            medoids.push(medoid.map_or(RowAddress::TOMBSTONE_ROW, |(_, row_id)| row_id));
+        }
+        if let Some(partitions) = ivf.subgroups.as_mut().map(|s| &mut s.partitions) {
+            partitions.push(subgroups);
+        }
+        if let Some(ordered) = ordered.as_mut() {
+            if total_records == 0 || tier == Tier::Cold {
+                ordered.hold(part_id, PendingPartition::default());
+            }
+            offset = ordered.write_ready(&mut writers, ivf, offset).await?;
         }
         log::info!(
             "Wrote partition {} in {} ms",
             part_id,
             start.elapsed().as_millis()
         );
+        if let Some(callback) = on_partition_written {
+            callback(part_id, total_records);
+        }
+    }
+    if let Some(ordered) = ordered {
+        log::info!(
+            "Held up to {} bytes of partitions to write them in the output order",
+            ordered.peak_pending_bytes
+        );
+    }
+    Ok(digest.finalize().into())
+}
+
+/// The columns of the rows of a partition, see [write_partition].
+struct PartitionColumns<'a> {
+    pq_codes: &'a [ArrayRef],
+    row_ids: &'a [ArrayRef],
+    pca_vectors: &'a [ArrayRef],
+    valid: &'a [ArrayRef],
+    margins: &'a [ArrayRef],
+    extra_columns: &'a [Vec<ArrayRef>],
+}
+
+/// Write the `columns` of a partition to `writer`, one after the other, followed by
+/// the packed PQ codes if `ivf` has them.
+///
+/// Returns the number of bytes written, and the offset of the packed codes relative to
+/// the start of the partition.
+async fn write_partition(
+    writer: &mut dyn Writer,
+    ivf: &Ivf,
+    columns: &PartitionColumns<'_>,
+) -> Result<(usize, Option<usize>)> {
+    fn refs(arrays: &[ArrayRef]) -> Vec<&dyn Array> {
+        arrays.iter().map(|a| a.as_ref()).collect()
+    }
+
+    let start = writer.tell().await?;
+    for section in ivf.partition_sections() {
+        let arrays = match section {
+            Section::PqCodes => columns.pq_codes,
+            Section::RowIds => columns.row_ids,
+            Section::PcaVectors => columns.pca_vectors,
+            Section::Valid => columns.valid,
+            Section::AssignmentMargins => columns.margins,
+            Section::ExtraColumn(index) => &columns.extra_columns[index],
+        };
+        PlainEncoder::write(writer, &refs(arrays)).await?;
+    }
+
+    let mut packed_offset = None;
+    if let Some(packed) = ivf.packed_codes.as_ref() {
+        let codes = columns
+            .pq_codes
+            .iter()
+            .flat_map(|a| {
+                a.as_fixed_size_list()
+                    .values()
+                    .as_primitive::<UInt8Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        packed_offset = Some(writer.tell().await? - start);
+        writer
+            .write_all(&pack_codes(&codes, packed.num_bits))
+            .await?;
+    }
+    Ok((writer.tell().await? - start, packed_offset))
+}
+
+/// In-memory [Writer], e.g. of a partition waiting for its turn in the output order.
+#[derive(Default)]
+pub(super) struct BufferWriter(Vec<u8>);
+
+impl AsyncWrite for BufferWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().0.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl Writer for BufferWriter {
+    async fn tell(&mut self) -> Result<usize> {
+        Ok(self.0.len())
+    }
+}
+
+/// Transaction of a transactional store, e.g. one with a write-ahead log, so that the
+/// index file is written as a part of a larger atomic commit with other changes.
+pub trait IndexTxn: Send {
+    /// Stage `bytes` to be appended to the index file. Staged bytes are not visible
+    /// until committed.
+    fn stage(&mut self, bytes: &[u8]) -> Result<()>;
+
+    /// Make all the staged bytes visible at once.
+    fn commit(&mut self) -> Result<()>;
+
+    /// Drop all the staged bytes.
+    fn rollback(&mut self) -> Result<()>;
+}
+
+/// [Writer] that stages the bytes written to it in an [IndexTxn], from the start of
+/// the index file.
+pub(super) struct TxnWriter<'a> {
+    txn: &'a mut dyn IndexTxn,
+    offset: usize,
+}
+
+impl<'a> TxnWriter<'a> {
+    pub(super) fn new(txn: &'a mut dyn IndexTxn) -> Self {
+        Self { txn, offset: 0 }
+    }
+}
+
+impl AsyncWrite for TxnWriter<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.txn
+            .stage(buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        this.offset += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl Writer for TxnWriter<'_> {
+    async fn tell(&mut self) -> Result<usize> {
+        Ok(self.offset)
+    }
+}
+
+/// Commit `txn` if the writes staged in it succeeded with `result`, otherwise roll it
+/// back. The commit is rolled back too if it fails.
+pub(super) fn finish_txn<T>(txn: &mut dyn IndexTxn, result: Result<T>) -> Result<T> {
+    let result = result.and_then(|value| txn.commit().map(|_| value));
+    if result.is_err() {
+        txn.rollback()?;
+    }
+    result
+}
+
+/// Check that `order` is a permutation of the ids of `num_partitions` partitions.
+pub(super) fn check_output_order(order: &[u32], num_partitions: usize) -> Result<()> {
+    let mut seen = vec![false; num_partitions];
+    for &part_id in order {
+        match seen.get_mut(part_id as usize) {
+            Some(seen) if !*seen => *seen = true,
+            _ => {
+                return Err(Error::Index {
+                    message: format!(
+                        "output order {:?} is not a permutation of the {} partition ids",
+                        order, num_partitions
+                    ),
+                    location: location!(),
+                })
+            }
+        }
+    }
+    if order.len() != num_partitions {
+        return Err(Error::Index {
+            message: format!(
+                "output order has {} partition ids, expected {}",
+                order.len(),
+                num_partitions
+            ),
+            location: location!(),
+        });
+    }
+    Ok(())
+}
+
+/// Codebook of a partition, and the PQ codes of its vectors.
+type PartitionCodes = (Arc<dyn ProductQuantizer>, ArrayRef);
+
+/// The rows of a partition read from the streams, waiting for its PQ codes to be
+/// trained, see [PartitionPqParams].
+struct GatheredPartition {
+    part_id: u32,
+    start: Instant,
+    /// Training and encoding of the partition vectors in `pq_array`, if they are encoded
+    /// with their own codebook.
+    encoding: Option<AbortOnDrop<Result<PartitionCodes>>>,
+    pq_array: Vec<ArrayRef>,
+    row_id_array: Vec<ArrayRef>,
+    pca_array: Vec<ArrayRef>,
+    valid_array: Vec<ArrayRef>,
+    margin_array: Vec<ArrayRef>,
+    subgroup_array: Vec<ArrayRef>,
+    extra_arrays: Vec<Vec<ArrayRef>>,
+    medoid: Option<(f32, u64)>,
+}
+
+/// Task that is aborted when dropped, e.g. when the merge fails before awaiting it.
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Bytes of a built partition waiting for its turn in the output order.
+#[derive(Default)]
+struct PendingPartition {
+    /// Empty for empty and cold partitions, which take no bytes in the index file.
+    bytes: Vec<u8>,
+
+    /// Offset of the packed codes relative to the start of the partition, if written.
+    packed_offset: Option<usize>,
+}
+
+/// Hot partitions written in an output order, see [write_index_partitions].
+///
+/// The partitions are built in id order, and each one is held in memory until all the
+/// partitions before it in `order` are written. The memory held is the size of the
+/// partitions built ahead of their turn: up to the whole index if `order` starts with
+/// the last partition, while an order close to the id order holds few partitions.
+struct OrderedPartitions<'a> {
+    order: &'a [u32],
+
+    /// Position in `order` of the next partition to write.
+    next: usize,
+
+    pending: HashMap<u32, PendingPartition>,
+
+    /// Bytes of the partitions in `pending`.
+    pending_bytes: usize,
+
+    /// Largest `pending_bytes` so far.
+    peak_pending_bytes: usize,
+}
+
+impl<'a> OrderedPartitions<'a> {
+    fn new(order: &'a [u32]) -> Self {
+        Self {
+            order,
+            next: 0,
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            peak_pending_bytes: 0,
+        }
+    }
+
+    /// Hold `partition` until its turn.
+    fn hold(&mut self, part_id: u32, partition: PendingPartition) {
+        self.pending_bytes += partition.bytes.len();
+        self.peak_pending_bytes = self.peak_pending_bytes.max(self.pending_bytes);
+        self.pending.insert(part_id, partition);
+    }
+
+    /// Write the pending partitions whose turn has come at `offset`, record their
+    /// offsets in `ivf`, and return the offset after them.
+    async fn write_ready(
+        &mut self,
+        writers: &mut [&mut dyn Writer],
+        ivf: &mut Ivf,
+        mut offset: usize,
+    ) -> Result<usize> {
+        while let Some(&part_id) = self.order.get(self.next) {
+            let Some(partition) = self.pending.remove(&part_id) else {
+                break;
+            };
+            self.next += 1;
+            self.pending_bytes -= partition.bytes.len();
+            if ivf.tier(part_id as usize) == Tier::Cold {
+                continue;
+            }
+            ivf.offsets[part_id as usize] = offset;
+            if let Some(packed) = ivf.packed_codes.as_mut() {
+                packed.offsets[part_id as usize] =
+                    offset + partition.packed_offset.unwrap_or_default();
+            }
+            for writer in writers.iter_mut() {
+                writer.write_all(&partition.bytes).await?;
+            }
+            offset += partition.bytes.len();
+        }
+        Ok(offset)
+    }
+}
+
+/// Sort the rows of a partition by their subgroup `values`, and return the
+/// subgroups of the rows with the same value, nulls last.
+///
+/// Each of `columns` holds the arrays of one column of the partition, and is replaced
+/// by a single sorted array. Empty columns, i.e. not stored, are left as is.
+fn sort_into_subgroups(
+    values: &[ArrayRef],
+    columns: Vec<&mut Vec<ArrayRef>>,
+) -> Result<Vec<Subgroup>> {
+    let values = concat(&values.iter().map(|a| a.as_ref()).collect::<Vec<_>>())?;
+    let options = SortOptions {
+        descending: false,
+        nulls_first: false,
+    };
+    let indices = sort_to_indices(&values, Some(options), None)?;
+    for column in columns {
+        if column.is_empty() {
+            continue;
+        }
+        let arr = concat(&column.iter().map(|a| a.as_ref()).collect::<Vec<_>>())?;
+        *column = vec![take(&arr, &indices, None)?];
+    }
+
+    let values = take(&values, &indices, None)?;
+    let mut subgroups = Vec::<Subgroup>::new();
+    for (row, value) in values.as_string::<i32>().iter().enumerate() {
+        match subgroups.last_mut() {
+            Some(subgroup) if subgroup.value.as_deref() == value => subgroup.length += 1,
+            _ => subgroups.push(Subgroup {
+                value: value.map(str::to_string),
+                offset: row as u32,
+                length: 1,
+            }),
+        }
+    }
+    Ok(subgroups)
+}
+
+/// Add partition `part_id` to the SHA-256 `digest` of the partitions: its id, its number
+/// of rows, and the ROW_ID and the row-major PQ code of each row, sorted by ROW_ID then
+/// PQ code. The digest does not depend on the order the rows were shuffled in.
+fn update_digest(
+    digest: &mut Sha256,
+    part_id: u32,
+    row_id_array: &[Arc<dyn Array>],
+    pq_array: &[Arc<dyn Array>],
+) -> Result<()> {
+    digest.update(part_id.to_le_bytes());
+    if row_id_array.is_empty() {
+        digest.update(0_u64.to_le_bytes());
+        return Ok(());
+    }
+    // The codes of a partition encoded with its own codebook are in a single array,
+    // so the row ids and the codes are zipped across the arrays. Only the row ids and
+    // the references to the codes are sorted, the arrays are not copied.
+    let row_ids = row_id_array
+        .iter()
+        .flat_map(|a| a.as_primitive::<UInt64Type>().values().iter().copied());
+    let codes = pq_array.iter().flat_map(|a| {
+        let codes = a.as_fixed_size_list();
+        codes
+            .values()
+            .as_primitive::<UInt8Type>()
+            .values()
+            .chunks_exact(codes.value_length() as usize)
+    });
+    let mut rows = row_ids.zip(codes).collect::<Vec<_>>();
+    rows.sort_unstable();
+    digest.update((rows.len() as u64).to_le_bytes());
+    for (row_id, code) in rows {
+        digest.update(row_id.to_le_bytes());
+        digest.update(code);
     }
     Ok(())
 }
+
+/// Field metadata key marking the columns of a batch that [write_index_partitions]
+/// stores after the other columns of its partition.
+const EXTRA_COLUMN_KEY: &str = "lance:ivf:extra_column";
+
+/// Mark the columns of `batch` that are not in `input` as extra columns, e.g. the
+/// columns added by [`lance_index::vector::ivf::PostShuffleTransform`].
+pub(super) fn mark_extra_columns(batch: RecordBatch, input: &Schema) -> Result<RecordBatch> {
+    let fields = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| {
+            if input.field_with_name(field.name()).is_ok() {
+                field.as_ref().clone()
+            } else {
+                field
+                    .as_ref()
+                    .clone()
+                    .with_metadata([(EXTRA_COLUMN_KEY.to_string(), "true".to_string())].into())
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        batch.columns().to_vec(),
+    )?)
+}
+
+/// The extra columns of `batch`, see [mark_extra_columns].
+fn extra_columns(batch: &RecordBatch) -> Result<Vec<Field>> {
+    batch
+        .schema()
+        .fields()
+        .iter()
+        .filter(|field| field.metadata().contains_key(EXTRA_COLUMN_KEY))
+        .map(|field| {
+            let field = Field::new(field.name(), field.data_type().clone(), true);
+            extra_column_width(&field)?;
+            Ok(field)
+        })
+        .collect()
+}
+
+/// Bytes of each value of an extra column, which must be of a fixed-width primitive
+/// type.
+pub(super) fn extra_column_width(field: &Field) -> Result<usize> {
+    field
+        .data_type()
+        .primitive_width()
+        .ok_or_else(|| Error::Index {
+            message: format!(
+                "Extra column {} must be of a fixed-width primitive type, got {}",
+                field.name(),
+                field.data_type()
+            ),
+            location: location!(),
+        })
+}
+
+/// A section of the rows of each partition in the index file, see
+/// [`Ivf::partition_sections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Section {
+    PqCodes,
+    RowIds,
+    PcaVectors,
+    Valid,
+    AssignmentMargins,
+    /// The extra column at this position of [`Ivf::extra_columns`].
+    ExtraColumn(usize),
+}
+
+impl Ivf {
+    /// The sections of each partition, in the order they are written. The packed PQ
+    /// codes, if any, follow the last section.
+    pub(super) fn partition_sections(&self) -> Vec<Section> {
+        let mut sections = vec![Section::PqCodes, Section::RowIds];
+        if self.pca.is_some() {
+            sections.push(Section::PcaVectors);
+        }
+        if self.has_valid {
+            sections.push(Section::Valid);
+        }
+        if self.has_assignment_margin {
+            sections.push(Section::AssignmentMargins);
+        }
+        sections.extend((0..self.extra_columns.len()).map(Section::ExtraColumn));
+        sections
+    }
+
+    /// Bytes of `section` in a partition of `length` rows.
+    pub(super) fn section_len(
+        &self,
+        section: Section,
+        length: usize,
+        num_sub_vectors: usize,
+    ) -> Result<usize> {
+        Ok(match section {
+            Section::PqCodes => length * num_sub_vectors,
+            Section::RowIds => length * std::mem::size_of::<u64>(),
+            Section::PcaVectors => {
+                let num_components = self.pca.as_ref().map_or(0, |pca| pca.num_components());
+                length * num_components * std::mem::size_of::<f32>()
+            }
+            Section::Valid => arrow_buffer::bit_util::ceil(length, 8),
+            Section::AssignmentMargins => length * std::mem::size_of::<f32>(),
+            Section::ExtraColumn(index) => length * extra_column_width(&self.extra_columns[index])?,
+        })
+    }
+
+    /// Offset of `section` of the partition `partition_id` in the index file.
+    pub(super) fn section_offset(
+        &self,
+        partition_id: usize,
+        section: Section,
+        num_sub_vectors: usize,
+    ) -> Result<usize> {
+        let length = self.lengths[partition_id] as usize;
+        let mut offset = self.offsets[partition_id];
+        for partition_section in self.partition_sections() {
+            if partition_section == section {
+                return Ok(offset);
+            }
+            offset += self.section_len(partition_section, length, num_sub_vectors)?;
+        }
+        Err(Error::Index {
+            message: format!("The partitions of the index have no {:?} section", section),
+            location: location!(),
+        })
+    }
+}
+
+/// Writer of the cold partitions of [write_index_partitions].
+pub(super) struct ColdTier<'a> {
+    /// Writer of the cold tier file.
+    pub writer: &'a mut dyn Writer,
+
+    /// Tier of each partition id.
+    pub tier_fn: TierFn,
+}
+
+/// Merge `delta_index`, e.g. built over the rows appended since `base_index` was built,
+/// into the partitions of `base_index`, written to `base_writer`. Returns the merged
+/// IVF model, to be written to the index metadata.
+///
+/// The rows of each delta partition are appended to the rows of the base partition.
+/// The partitions without delta rows are copied byte for byte, without being decoded.
+/// The delta may have more partitions than the base, whose first centroids must be the
+/// centroids of the base: these new partitions are added with the delta rows.
+///
+/// Both indices must share the PQ codebook, and only store the PQ codes and row ids,
+/// and the bit-packed PQ codes if both have them.
+pub(super) async fn merge_delta(
+    base_writer: &mut dyn Writer,
+    base_index: &IVFIndex,
+    delta_index: &IVFIndex,
+) -> Result<Ivf> {
+    let (base, delta) = (&base_index.ivf, &delta_index.ivf);
+    let num_sub_vectors = check_delta_compatible(base_index, delta_index)?;
+
+    let num_base_partitions = base.num_partitions();
+    let mut ivf = Ivf::new(delta.centroids.clone());
+    ivf.bf16_centroids = delta.bf16_centroids;
+    ivf.code_storage_order = base.code_storage_order;
+    ivf.partial = base.partial || delta.partial;
+    ivf.weights = base.weights.clone();
+    ivf.transposed_codebook = base.transposed_codebook.clone();
+    // The tree only clusters the base partitions.
+    if delta.num_partitions() == num_base_partitions {
+        ivf.tree = base.tree.clone();
+    }
+    if base.time_window == delta.time_window {
+        ivf.time_window = base.time_window.clone();
+    }
+    if let (Some(base_stats), Some(delta_stats)) = (&base.global_stats, &delta.global_stats) {
+        let mut stats = base_stats.clone();
+        stats.merge(delta_stats)?;
+        ivf.global_stats = Some(stats);
+    }
+    ivf.multi_assign = std::cmp::max(base.multi_assign, delta.multi_assign);
+    ivf.packed_codes = base
+        .packed_codes
+        .as_ref()
+        .map(|packed| PackedCodes::new(packed.num_bits));
+
+    let mut offset = base_writer.tell().await?;
+    let mut num_copied = 0;
+    for part_id in 0..delta.num_partitions() {
+        let base_length = base.lengths.get(part_id).copied().unwrap_or(0) as usize;
+        let delta_length = delta.lengths[part_id] as usize;
+        let part_offset = offset;
+        let mut packed_offset = part_offset;
+        if delta_length == 0 {
+            if base_length > 0 {
+                let start = base.offsets[part_id];
+                let mut end = start + base_length * (num_sub_vectors + 8);
+                // The packed codes follow the row ids, and are copied along.
+                if let Some(packed) = base.packed_codes.as_ref() {
+                    packed_offset = part_offset + packed.offsets[part_id] - start;
+                    end = packed.offsets[part_id]
+                        + packed_codes_len(base_length * num_sub_vectors, packed.num_bits);
+                }
+                let bytes = base_index.reader.get_range(start..end).await?;
+                base_writer.write_all(&bytes).await?;
+                offset += bytes.len();
+                num_copied += 1;
+            }
+        } else {
+            let mut partitions = vec![];
+            if base_length > 0 {
+                partitions.push(
+                    read_partition(
+                        base_index.reader.as_ref(),
+                        base,
+                        num_sub_vectors,
+                        part_id as u32,
+                        CodeLayout::Raw,
+                    )
+                    .await?,
+                );
+            }
+            partitions.push(
+                read_partition(
+                    delta_index.reader.as_ref(),
+                    delta,
+                    num_sub_vectors,
+                    part_id as u32,
+                    CodeLayout::Raw,
+                )
+                .await?,
+            );
+            let codes = partitions
+                .iter()
+                .map(|b| b[PQ_CODE_COLUMN].as_ref())
+                .collect::<Vec<_>>();
+            let mut codes = concat(&codes)?;
+            if ivf.code_storage_order == CodeStorageOrder::Separate {
+                let batch = RecordBatch::try_from_iter([(PQ_CODE_COLUMN, codes)])?;
+                codes = transpose_pq_codes(&batch, num_sub_vectors, true)?[PQ_CODE_COLUMN].clone();
+            }
+            let row_ids = partitions
+                .iter()
+                .map(|b| b[ROW_ID].as_ref())
+                .collect::<Vec<_>>();
+
+            PlainEncoder::write(base_writer, &[codes.as_ref()]).await?;
+            PlainEncoder::write(base_writer, &row_ids).await?;
+            if let Some(packed) = ivf.packed_codes.as_ref() {
+                packed_offset = base_writer.tell().await?;
+                let codes = codes
+                    .as_fixed_size_list()
+                    .values()
+                    .as_primitive::<UInt8Type>();
+                base_writer
+                    .write_all(&pack_codes(codes.values(), packed.num_bits))
+                    .await?;
+            }
+            offset = base_writer.tell().await?;
+        }
+        if let Some(packed) = ivf.packed_codes.as_mut() {
+            packed.offsets.push(packed_offset);
+        }
+        ivf.add_partition(part_offset, (base_length + delta_length) as u32);
+    }
+    log::info!(
+        "Merged a delta index of {} rows, {} base partitions copied",
+        delta.lengths.iter().sum::<u32>(),
+        num_copied
+    );
+    Ok(ivf)
+}
+
+/// Check that the partitions of `delta_index` can be merged into `base_index`.
+/// Returns the number of PQ sub-vectors.
+fn check_delta_compatible(base_index: &IVFIndex, delta_index: &IVFIndex) -> Result<usize> {
+    let (base, delta) = (&base_index.ivf, &delta_index.ivf);
+    let base_pq = base_index.pq_sub_index()?.pq.clone();
+    let delta_pq = delta_index.pq_sub_index()?.pq.clone();
+    let not_supported = |reason: &str| {
+        Err(Error::NotSupported {
+            source: format!("Merge delta index: {}", reason).into(),
+            location: location!(),
+        })
+    };
+    if base_index.metric_type != delta_index.metric_type {
+        return not_supported("the indices have different metric types");
+    }
+    if base.dimension() != delta.dimension() || delta.num_partitions() < base.num_partitions() {
+        return not_supported("the delta must have at least the partitions of the base");
+    }
+    let base_centroids = cast(base.centroids.values(), &DataType::Float32)?;
+    let delta_centroids = cast(delta.centroids.values(), &DataType::Float32)?;
+    if base_centroids.as_primitive::<Float32Type>().values()[..]
+        != delta_centroids.as_primitive::<Float32Type>().values()[..base_centroids.len()]
+    {
+        return not_supported("the delta does not have the centroids of the base");
+    }
+    if base_pq.num_sub_vectors() != delta_pq.num_sub_vectors()
+        || base_pq.num_bits() != delta_pq.num_bits()
+        || base_pq.codebook_as_fsl() != delta_pq.codebook_as_fsl()
+    {
+        return not_supported("the indices have different PQ codebooks");
+    }
+    if base.weights != delta.weights {
+        return not_supported("the indices have different weights");
+    }
+    if base.code_storage_order != delta.code_storage_order {
+        return not_supported("the indices store the PQ codes in different orders");
+    }
+    if base.has_cold_partitions() || delta.has_cold_partitions() {
+        return not_supported("the indices have cold partitions");
+    }
+    if base.packed_codes.is_some() != delta.packed_codes.is_some() {
+        return not_supported("only one of the indices has bit-packed PQ codes");
+    }
+    for ivf in [base, delta] {
+        if !ivf.pq_codebooks.is_empty()
+            || ivf.pca.is_some()
+            || ivf.has_valid
+            || ivf.has_assignment_margin
+            || !ivf.extra_columns.is_empty()
+        {
+            return not_supported(
+                "only partitions of PQ codes and row ids are supported, not per-partition PQ, PCA vectors, valid columns, assignment margins or extra columns",
+            );
+        }
+    }
+    Ok(base_pq.num_sub_vectors())
+}
+
+/// Stream the PQ codes and row ids of the requested partitions of an IVF_PQ index file.
+///
+/// Only the byte ranges of `part_ids`, looked up in the IVF offset table, are read from
+/// `reader`; the other partitions are skipped entirely. Each partition is yielded as one
+/// batch of [PART_ID_COLUMN], [PQ_CODE_COLUMN] and [ROW_ID], in the order of `part_ids`,
+/// with the PQ codes in row-major layout.
+pub(super) fn read_partitions<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+    part_ids: &'a [u32],
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    read_partitions_with_layout(reader, ivf, num_sub_vectors, part_ids, CodeLayout::Raw)
+}
+
+/// Stream the requested partitions like [read_partitions], decoding the PQ codes from
+/// `layout`. Both layouts yield the same batches.
+pub(super) fn read_partitions_with_layout<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+    part_ids: &'a [u32],
+    layout: CodeLayout,
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    stream::iter(part_ids)
+        .then(move |&part_id| read_partition(reader, ivf, num_sub_vectors, part_id, layout))
+}
+
+/// Stream the requested partitions like [read_partitions], with the centroid of each
+/// partition attached to the schema metadata of its batch, see [attach_centroid].
+pub(super) fn read_partitions_with_centroids<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+    part_ids: &'a [u32],
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    stream::iter(part_ids).then(move |&part_id| async move {
+        let batch = read_partition(reader, ivf, num_sub_vectors, part_id, CodeLayout::Raw).await?;
+        attach_centroid(batch, ivf, part_id)
+    })
+}
+
+/// Attach the stored centroid of partition `part_id` to the schema metadata of `batch`
+/// under [CENTROID_METADATA_KEY], as a JSON array of f32.
+fn attach_centroid(batch: RecordBatch, ivf: &Ivf, part_id: u32) -> Result<RecordBatch> {
+    let centroid = cast(&ivf.centroids.value(part_id as usize), &DataType::Float32)?;
+    let centroid = serde_json::to_string(&centroid.as_primitive::<Float32Type>().values()[..])?;
+    let mut metadata = batch.schema().metadata().clone();
+    metadata.insert(CENTROID_METADATA_KEY.to_string(), centroid);
+    let schema = Schema::new_with_metadata(batch.schema().fields().clone(), metadata);
+    Ok(batch.with_schema(Arc::new(schema))?)
+}
+
+/// Stream the rows of the requested partitions like [read_partitions], keeping only
+/// the subgroup of each partition with `value`, see [read_subgroup].
+pub(super) fn read_partitions_in_subgroup<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+    part_ids: &'a [u32],
+    value: Option<&'a str>,
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    stream::iter(part_ids)
+        .then(move |&part_id| read_subgroup(reader, ivf, num_sub_vectors, part_id, value))
+}
+
+/// Read the rows of partition `part_id` with the subgroup `value` like
+/// [read_partition], from only the byte ranges of the subgroup. The batch is empty if
+/// no row of the partition has the value.
+async fn read_subgroup(
+    reader: &dyn Reader,
+    ivf: &Ivf,
+    num_sub_vectors: usize,
+    part_id: u32,
+    value: Option<&str>,
+) -> Result<RecordBatch> {
+    let idx = part_id as usize;
+    let Some(subgroups) = ivf.subgroups.as_ref() else {
+        return Err(Error::Index {
+            message: "read subgroup: the rows of the partitions are not grouped".to_string(),
+            location: location!(),
+        });
+    };
+    if ivf.code_storage_order != CodeStorageOrder::Interleaved {
+        return Err(Error::Index {
+            message: "read subgroup: the PQ codes are not interleaved".to_string(),
+            location: location!(),
+        });
+    }
+    if idx >= ivf.num_partitions() {
+        return Err(Error::Index {
+            message: format!(
+                "read subgroup: partition {} out of range, index has {} partitions",
+                part_id,
+                ivf.num_partitions()
+            ),
+            location: location!(),
+        });
+    }
+    let offset = ivf.offsets[idx];
+    let length = ivf.lengths[idx] as usize;
+
+    let (codes, row_ids) = match subgroups.find(idx, value) {
+        Some(subgroup) => {
+            let (start, num_rows) = (subgroup.offset as usize, subgroup.length as usize);
+            let codes = read_fixed_stride_array(
+                reader,
+                &DataType::UInt8,
+                offset + start * num_sub_vectors,
+                num_rows * num_sub_vectors,
+                ..,
+            )
+            .await?;
+            let row_ids = read_fixed_stride_array(
+                reader,
+                &DataType::UInt64,
+                offset + length * num_sub_vectors + start * 8,
+                num_rows,
+                ..,
+            )
+            .await?;
+            (codes.as_primitive::<UInt8Type>().clone(), row_ids)
+        }
+        None => (
+            UInt8Array::from(Vec::<u8>::new()),
+            Arc::new(UInt64Array::from(Vec::<u64>::new())) as ArrayRef,
+        ),
+    };
+    let num_rows = row_ids.len();
+    let codes = FixedSizeListArray::try_new_from_values(codes, num_sub_vectors as i32)?;
+    Ok(RecordBatch::try_from_iter([
+        (
+            PART_ID_COLUMN,
+            Arc::new(UInt32Array::from(vec![part_id; num_rows])) as ArrayRef,
+        ),
+        (PQ_CODE_COLUMN, Arc::new(codes) as ArrayRef),
+        (ROW_ID, row_ids),
+    ])?)
+}
+
+/// Number of partitions read ahead by [read_partitions_ordered].
+const PARTITION_READ_AHEAD: usize = 4;
+
+/// Stream the partitions of `ordered_part_ids` like [read_partitions], reading up to
+/// [PARTITION_READ_AHEAD] partitions ahead.
+///
+/// The partitions are yielded in exactly the order of `ordered_part_ids`, e.g. the probe
+/// order of a query, regardless of their order in the file or of which reads finish
+/// first, so the first partitions can be searched while the later ones load.
+pub(super) fn read_partitions_ordered<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+    ordered_part_ids: &'a [u32],
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    stream::iter(ordered_part_ids)
+        .map(move |&part_id| read_partition(reader, ivf, num_sub_vectors, part_id, CodeLayout::Raw))
+        .buffered(PARTITION_READ_AHEAD)
+}
+
+/// Stream every non-empty partition of `ivf` like [read_partitions], in the order the
+/// partitions are stored in the file, reading up to [PARTITION_READ_AHEAD] partitions
+/// ahead.
+///
+/// The reads follow the file from start to end, so this is the cheapest full scan of
+/// the index.
+pub(super) fn scan_all_codes<'a>(
+    reader: &'a dyn Reader,
+    ivf: &'a Ivf,
+    num_sub_vectors: usize,
+) -> impl Stream<Item = Result<RecordBatch>> + 'a {
+    let mut part_ids = (0..ivf.num_partitions() as u32)
+        .filter(|&part_id| ivf.lengths[part_id as usize] > 0)
+        .collect::<Vec<_>>();
+    part_ids.sort_by_key(|&part_id| ivf.offsets[part_id as usize]);
+    stream::iter(part_ids)
+        .map(move |part_id| read_partition(reader, ivf, num_sub_vectors, part_id, CodeLayout::Raw))
+        .buffered(PARTITION_READ_AHEAD)
+}
+
+/// Read one partition as a batch of [PART_ID_COLUMN], [PQ_CODE_COLUMN] and [ROW_ID],
+/// with the PQ codes decoded from `layout`.
+async fn read_partition(
+    reader: &dyn Reader,
+    ivf: &Ivf,
+    num_sub_vectors: usize,
+    part_id: u32,
+    layout: CodeLayout,
+) -> Result<RecordBatch> {
+    let idx = part_id as usize;
+    if idx >= ivf.num_partitions() {
+        return Err(Error::Index {
+            message: format!(
+                "read partitions: partition {} out of range, index has {} partitions",
+                part_id,
+                ivf.num_partitions()
+            ),
+            location: location!(),
+        });
+    }
+    let offset = ivf.offsets[idx];
+    let length = ivf.lengths[idx] as usize;
+
+    let codes = match layout {
+        CodeLayout::Raw => read_fixed_stride_array(
+            reader,
+            &DataType::UInt8,
+            offset,
+            length * num_sub_vectors,
+            ..,
+        )
+        .await?
+        .as_primitive::<UInt8Type>()
+        .clone(),
+        CodeLayout::BitPacked => {
+            let Some(packed) = ivf.packed_codes.as_ref() else {
+                return Err(Error::Index {
+                    message: "read partitions: the index has no bit-packed PQ codes".to_string(),
+                    location: location!(),
+                });
+            };
+            let start = packed.offsets[idx];
+            let num_codes = length * num_sub_vectors;
+            let bytes = reader
+                .get_range(start..start + packed_codes_len(num_codes, packed.num_bits))
+                .await?;
+            UInt8Array::from(unpack_codes(&bytes, packed.num_bits, num_codes)?)
+        }
+    };
+    let row_ids = read_fixed_stride_array(
+        reader,
+        &DataType::UInt64,
+        offset + length * num_sub_vectors,
+        length,
+        ..,
+    )
+    .await?;
+
+    let codes = FixedSizeListArray::try_new_from_values(codes, num_sub_vectors as i32)?;
+    let batch = RecordBatch::try_from_iter([
+        (
+            PART_ID_COLUMN,
+            Arc::new(UInt32Array::from(vec![part_id; length])) as ArrayRef,
+        ),
+        (PQ_CODE_COLUMN, Arc::new(codes) as ArrayRef),
+        (ROW_ID, row_ids),
+    ])?;
+    match ivf.code_storage_order {
+        CodeStorageOrder::Interleaved => Ok(batch),
+        CodeStorageOrder::Separate => Ok(transpose_pq_codes(&batch, num_sub_vectors, false)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::ops::Range;
+    use std::sync::Mutex;
+
+    use arrow_array::types::UInt32Type;
+    use arrow_array::StringArray;
+    use arrow_schema::{Field, Schema};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use lance_index::vector::ivf::IvfBuildParams;
+    use lance_testing::datagen::{generate_random_array, generate_random_array_with_seed};
+    use object_store::path::Path;
+
+    use crate::index::pb;
+    use crate::index::vector::ivf::{builder, tests::DIM, BuildEnvConfig, PackedCodes, Subgroups};
+    use crate::io::ObjectStore;
+
+    fn partition_batch(part_id: u32, row_ids: std::ops::Range<u64>) -> RecordBatch {
+        let num_rows = row_ids.end - row_ids.start;
+        let codes = FixedSizeListArray::try_new_from_values(
+            UInt8Array::from_iter_values((0..num_rows * 4).map(|v| v as u8)),
+            4,
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(PART_ID_COLUMN, DataType::UInt32, false),
+            Field::new(PQ_CODE_COLUMN, codes.data_type().clone(), false),
+            Field::new(ROW_ID, DataType::UInt64, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(UInt32Array::from(vec![part_id; num_rows as usize])),
+                Arc::new(codes),
+                Arc::new(UInt64Array::from_iter_values(row_ids)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_index_partitions_to_multiple_writers() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(2 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let stream = futures::stream::iter(vec![
+            Ok(partition_batch(0, 0..10)),
+            Ok(partition_batch(1, 10..25)),
+        ]);
+
+        let mut first = Cursor::new(Vec::new());
+        let mut second = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut first, &mut second],
+            &mut ivf,
+            vec![stream],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(ivf.lengths, vec![10, 15]);
+        assert_eq!(first.get_ref().len(), 25 * (4 + 8));
+        assert_eq!(first.get_ref(), second.get_ref());
+    }
+
+    #[tokio::test]
+    async fn test_write_cold_partitions_to_cold_writer() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let stream = futures::stream::iter(vec![
+            Ok(partition_batch(0, 0..10)),
+            Ok(partition_batch(1, 10..25)),
+            Ok(partition_batch(2, 25..30)),
+            Ok(partition_batch(3, 30..42)),
+        ]);
+
+        let mut hot = Cursor::new(Vec::new());
+        let mut cold = Cursor::new(Vec::new());
+        let tier_fn: TierFn = Arc::new(|part_id| {
+            if part_id % 2 == 1 {
+                Tier::Cold
+            } else {
+                Tier::Hot
+            }
+        });
+        write_index_partitions(
+            vec![&mut hot],
+            &mut ivf,
+            vec![stream],
+            None,
+            WritePartitionsOptions {
+                cold_tier: Some(ColdTier {
+                    writer: &mut cold,
+                    tier_fn,
+                }),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let row_size = 4 + 8;
+        assert_eq!(ivf.lengths, vec![10, 15, 5, 12]);
+        assert_eq!(
+            ivf.tiers,
+            vec![Tier::Hot, Tier::Cold, Tier::Hot, Tier::Cold]
+        );
+        // Each tier file holds only its own partitions, back to back.
+        assert_eq!(ivf.offsets, vec![0, 0, 10 * row_size, 15 * row_size]);
+        assert_eq!(hot.get_ref().len(), (10 + 5) * row_size);
+        assert_eq!(cold.get_ref().len(), (15 + 12) * row_size);
+        // The row ids of partition 1 follow its codes at the start of the cold file.
+        let row_ids = &cold.get_ref()[15 * 4..15 * row_size];
+        assert_eq!(u64::from_le_bytes(row_ids[..8].try_into().unwrap()), 10);
+        assert!(ivf.has_cold_partitions());
+
+        let proto = pb::Ivf::try_from(&ivf).unwrap();
+        assert_eq!(Ivf::try_from(&proto).unwrap().tiers, ivf.tiers);
+    }
+
+    #[tokio::test]
+    async fn test_write_partitions_in_output_order() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(5 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        // Partition 4 is empty.
+        let stream = futures::stream::iter(vec![
+            Ok(partition_batch(0, 0..10)),
+            Ok(partition_batch(1, 10..25)),
+            Ok(partition_batch(2, 25..30)),
+            Ok(partition_batch(3, 30..42)),
+        ]);
+
+        let mut writer = Cursor::new(Vec::new());
+        let order = [2, 4, 0, 3, 1];
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![stream],
+            None,
+            WritePartitionsOptions {
+                output_order: Some(&order),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let row_size = 4 + 8;
+        assert_eq!(ivf.lengths, vec![10, 15, 5, 12, 0]);
+        assert_eq!(
+            ivf.offsets,
+            vec![
+                5 * row_size,
+                (5 + 10 + 12) * row_size,
+                0,
+                (5 + 10) * row_size,
+                5 * row_size
+            ]
+        );
+        assert_eq!(writer.get_ref().len(), 42 * row_size);
+        // Each partition is still found by its id: its first row id follows its codes.
+        let bytes = writer.get_ref();
+        for (part_id, first_row_id) in [(0, 0), (1, 10), (2, 25), (3, 30)] {
+            let start = ivf.offsets[part_id] + ivf.lengths[part_id] as usize * 4;
+            let row_id = u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap());
+            assert_eq!(row_id, first_row_id);
+        }
+
+        let mut ivf = Ivf::new(ivf.centroids.clone());
+        let result = write_index_partitions(
+            vec![&mut Cursor::new(Vec::new())],
+            &mut ivf,
+            vec![futures::stream::iter(vec![Ok(partition_batch(0, 0..10))])],
+            None,
+            WritePartitionsOptions {
+                output_order: Some(&[0, 1, 2, 2, 4]),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_partition_written_callback() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        // Partition 2 has no rows, and partition 1 is split across both streams.
+        let streams = vec![
+            futures::stream::iter(vec![
+                Ok(partition_batch(0, 0..10)),
+                Ok(partition_batch(1, 10..25)),
+            ]),
+            futures::stream::iter(vec![
+                Ok(partition_batch(1, 25..30)),
+                Ok(partition_batch(3, 30..42)),
+            ]),
+        ];
+
+        let written = Arc::new(Mutex::new(vec![]));
+        let written_ref = written.clone();
+        let callback: PartitionWrittenCallback = Arc::new(move |part_id, num_rows| {
+            written_ref.lock().unwrap().push((part_id, num_rows));
+        });
+        let mut writer = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            streams,
+            None,
+            WritePartitionsOptions {
+                on_partition_written: Some(&callback),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(ivf.lengths, vec![10, 20, 0, 12]);
+        assert_eq!(
+            *written.lock().unwrap(),
+            ivf.lengths
+                .iter()
+                .enumerate()
+                .map(|(part_id, len)| (part_id as u32, *len as usize))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_merge() {
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * 8), 8).unwrap(),
+        );
+        let batches = || {
+            vec![
+                partition_batch(0, 0..10),
+                partition_batch(1, 10..25),
+                partition_batch(2, 25..30),
+                partition_batch(3, 30..42),
+            ]
+        };
+
+        let mut expected_ivf = Ivf::new(centroids.clone());
+        let mut expected = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut expected],
+            &mut expected_ivf,
+            vec![futures::stream::iter(batches().into_iter().map(Ok))],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // The merge is interrupted while reading partition 2.
+        let mut ivf = Ivf::new(centroids);
+        let mut writer = Cursor::new(Vec::new());
+        let interrupted = batches()
+            .into_iter()
+            .take(3)
+            .map(Ok)
+            .chain(std::iter::once(Err(Error::IO {
+                message: "interrupted".to_string(),
+                location: location!(),
+            })));
+        assert!(write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![futures::stream::iter(interrupted)],
+            None,
+            WritePartitionsOptions::default()
+        )
+        .await
+        .is_err());
+        assert_eq!(ivf.lengths, vec![10, 15]);
+        assert_eq!(writer.get_ref().len(), 25 * (4 + 8));
+
+        // Resuming with the full streams only writes the remaining partitions.
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![futures::stream::iter(batches().into_iter().map(Ok))],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ivf.lengths, expected_ivf.lengths);
+        assert_eq!(ivf.offsets, expected_ivf.offsets);
+        assert_eq!(writer.get_ref(), expected.get_ref());
+    }
+
+    #[tokio::test]
+    async fn test_code_storage_order_round_trip() {
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(2 * 8), 8).unwrap(),
+        );
+        let batches = || vec![partition_batch(0, 0..10), partition_batch(1, 10..25)];
+
+        let mut files = vec![];
+        for order in [CodeStorageOrder::Interleaved, CodeStorageOrder::Separate] {
+            let mut ivf = Ivf::new(centroids.clone());
+            ivf.code_storage_order = order;
+            let mut writer = Cursor::new(Vec::new());
+            write_index_partitions(
+                vec![&mut writer],
+                &mut ivf,
+                vec![futures::stream::iter(batches().into_iter().map(Ok))],
+                None,
+                WritePartitionsOptions::default(),
+            )
+            .await
+            .unwrap();
+            let reader = CountingReader {
+                data: Bytes::from(writer.into_inner()),
+                path: Path::from("index.idx"),
+                ranges: Mutex::new(Vec::new()),
+            };
+
+            let decoded = read_partitions(&reader, &ivf, 4, &[0, 1])
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            for (batch, expected) in decoded.iter().zip(batches()) {
+                assert_eq!(
+                    batch[PQ_CODE_COLUMN].as_ref(),
+                    expected[PQ_CODE_COLUMN].as_ref()
+                );
+            }
+            files.push(reader.data);
+        }
+        // Same bytes, in a different order.
+        assert_eq!(files[0].len(), files[1].len());
+        assert_ne!(files[0], files[1]);
+    }
+
+    #[tokio::test]
+    async fn test_dual_code_layout_round_trip() {
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(3 * 8), 8).unwrap(),
+        );
+        // Partition 1 is empty.
+        let batches = || vec![partition_batch(0, 0..10), partition_batch(2, 10..25)];
+
+        for order in [CodeStorageOrder::Interleaved, CodeStorageOrder::Separate] {
+            let mut ivf = Ivf::new(centroids.clone());
+            ivf.code_storage_order = order;
+            // The codes of `partition_batch` are below 100.
+            ivf.packed_codes = Some(PackedCodes::new(7));
+            let mut writer = Cursor::new(Vec::new());
+            write_index_partitions(
+                vec![&mut writer],
+                &mut ivf,
+                vec![futures::stream::iter(batches().into_iter().map(Ok))],
+                None,
+                WritePartitionsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(ivf.packed_codes.as_ref().unwrap().offsets.len(), 3);
+            let reader = CountingReader {
+                data: Bytes::from(writer.into_inner()),
+                path: Path::from("index.idx"),
+                ranges: Mutex::new(Vec::new()),
+            };
+
+            let mut decoded = vec![];
+            for layout in [CodeLayout::Raw, CodeLayout::BitPacked] {
+                decoded.push(
+                    read_partitions_with_layout(&reader, &ivf, 4, &[0, 1, 2], layout)
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .unwrap(),
+                );
+            }
+            assert_eq!(decoded[0], decoded[1]);
+            assert_eq!(decoded[0][1].num_rows(), 0);
+            for (batch, expected) in [&decoded[1][0], &decoded[1][2]].into_iter().zip(batches()) {
+                assert_eq!(
+                    batch[PQ_CODE_COLUMN].as_ref(),
+                    expected[PQ_CODE_COLUMN].as_ref()
+                );
+                assert_eq!(batch[ROW_ID].as_ref(), expected[ROW_ID].as_ref());
+            }
+
+            // The packed codes follow the raw partition.
+            let raw_len = 10 * (4 + 8);
+            assert_eq!(ivf.packed_codes.as_ref().unwrap().offsets[0], raw_len);
+
+            ivf.packed_codes = None;
+            let result = read_partitions_with_layout(&reader, &ivf, 4, &[0], CodeLayout::BitPacked)
+                .try_collect::<Vec<_>>()
+                .await;
+            assert!(result.is_err());
+        }
+    }
+
+    /// Bytes of a [MockTxn], shared to look at them while the transaction is in use.
+    #[derive(Default)]
+    struct MockTxnState {
+        staged: Vec<u8>,
+        visible: Vec<u8>,
+        num_rollbacks: usize,
+    }
+
+    /// In-memory [IndexTxn] that only makes the staged bytes visible on commit.
+    #[derive(Default)]
+    struct MockTxn(Arc<Mutex<MockTxnState>>);
+
+    impl IndexTxn for MockTxn {
+        fn stage(&mut self, bytes: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().staged.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            let mut state = self.0.lock().unwrap();
+            let mut staged = std::mem::take(&mut state.staged);
+            state.visible.append(&mut staged);
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<()> {
+            let mut state = self.0.lock().unwrap();
+            state.staged.clear();
+            state.num_rollbacks += 1;
+            Ok(())
+        }
+    }
+
+    /// Write the partitions like the build in a transaction, staging them in `txn`.
+    async fn write_index_partitions_in_txn(
+        txn: &mut dyn IndexTxn,
+        ivf: &mut Ivf,
+        streams: Vec<impl Stream<Item = Result<RecordBatch>>>,
+        on_partition_written: Option<&PartitionWrittenCallback>,
+    ) -> Result<[u8; 32]> {
+        let result = write_index_partitions(
+            vec![&mut TxnWriter::new(&mut *txn)],
+            ivf,
+            streams,
+            None,
+            WritePartitionsOptions {
+                on_partition_written,
+                ..Default::default()
+            },
+        )
+        .await;
+        finish_txn(txn, result)
+    }
+
+    #[tokio::test]
+    async fn test_write_index_partitions_in_txn() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(2 * 8), 8).unwrap();
+        let batches = || {
+            vec![
+                Ok(partition_batch(0, 0..10)),
+                Ok(partition_batch(1, 10..25)),
+            ]
+        };
+
+        // Nothing is visible until all the partitions are written.
+        let mut txn = MockTxn::default();
+        let state = txn.0.clone();
+        let on_partition_written: PartitionWrittenCallback = Arc::new(move |_, _| {
+            let state = state.lock().unwrap();
+            assert!(!state.staged.is_empty());
+            assert!(state.visible.is_empty());
+        });
+        let mut ivf = Ivf::new(Arc::new(centroids.clone()));
+        write_index_partitions_in_txn(
+            &mut txn,
+            &mut ivf,
+            vec![futures::stream::iter(batches())],
+            Some(&on_partition_written),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ivf.lengths, vec![10, 15]);
+
+        let mut writer = Cursor::new(Vec::new());
+        let mut expected = Ivf::new(Arc::new(centroids.clone()));
+        write_index_partitions(
+            vec![&mut writer],
+            &mut expected,
+            vec![futures::stream::iter(batches())],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ivf.offsets, expected.offsets);
+        {
+            let state = txn.0.lock().unwrap();
+            assert!(state.staged.is_empty());
+            assert_eq!(state.visible, writer.into_inner());
+            assert_eq!(state.num_rollbacks, 0);
+        }
+
+        // A failed write is rolled back, with none of its partitions.
+        let mut txn = MockTxn::default();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let num_written = Arc::new(Mutex::new(0));
+        let counter = num_written.clone();
+        let on_partition_written: PartitionWrittenCallback =
+            Arc::new(move |_, _| *counter.lock().unwrap() += 1);
+        let batches = vec![
+            Ok(partition_batch(0, 0..10)),
+            Ok(partition_batch(1, 10..25)),
+            Err(Error::IO {
+                message: "failed to read the batch".to_string(),
+                location: location!(),
+            }),
+        ];
+        assert!(write_index_partitions_in_txn(
+            &mut txn,
+            &mut ivf,
+            vec![futures::stream::iter(batches)],
+            Some(&on_partition_written),
+        )
+        .await
+        .is_err());
+        assert_eq!(*num_written.lock().unwrap(), 1);
+        let state = txn.0.lock().unwrap();
+        assert!(state.staged.is_empty());
+        assert!(state.visible.is_empty());
+        assert_eq!(state.num_rollbacks, 1);
+    }
+
+    /// In-memory [Reader] that records the byte ranges it is asked for.
+    struct CountingReader {
+        data: Bytes,
+        path: Path,
+        ranges: Mutex<Vec<Range<usize>>>,
+    }
+
+    #[async_trait]
+    impl Reader for CountingReader {
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn block_size(&self) -> usize {
+            4096
+        }
+
+        async fn size(&self) -> lance_core::Result<usize> {
+            Ok(self.data.len())
+        }
+
+        async fn get_range(&self, range: Range<usize>) -> lance_core::Result<Bytes> {
+            self.ranges.lock().unwrap().push(range.clone());
+            Ok(self.data.slice(range))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_partitions_only_reads_requested_ranges() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(10 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let batches = (0..10)
+            .map(|part_id| {
+                Ok(partition_batch(
+                    part_id,
+                    part_id as u64 * 100..part_id as u64 * 100 + 10 + part_id as u64,
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let mut writer = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![futures::stream::iter(batches)],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let reader = CountingReader {
+            data: Bytes::from(writer.into_inner()),
+            path: Path::from("index.idx"),
+            ranges: Mutex::new(Vec::new()),
+        };
+
+        let part_ids = [7, 2];
+        let batches = read_partitions(&reader, &ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(batches.len(), 2);
+        for (batch, part_id) in batches.iter().zip(part_ids) {
+            let expected = partition_batch(
+                part_id,
+                part_id as u64 * 100..part_id as u64 * 100 + 10 + part_id as u64,
+            );
+            assert_eq!(
+                batch[PART_ID_COLUMN].as_ref(),
+                expected[PART_ID_COLUMN].as_ref()
+            );
+            assert_eq!(
+                batch[PQ_CODE_COLUMN].as_ref(),
+                expected[PQ_CODE_COLUMN].as_ref()
+            );
+            assert_eq!(batch[ROW_ID].as_ref(), expected[ROW_ID].as_ref());
+        }
+
+        let partition_range = |part_id: usize| {
+            ivf.offsets[part_id]..ivf.offsets[part_id] + ivf.lengths[part_id] as usize * (4 + 8)
+        };
+        let ranges = reader.ranges.lock().unwrap();
+        assert!(!ranges.is_empty());
+        for range in ranges.iter() {
+            assert!(
+                part_ids.iter().any(|&part_id| {
+                    let part = partition_range(part_id as usize);
+                    part.start <= range.start && range.end <= part.end
+                }),
+                "read {:?} outside of the requested partitions",
+                range
+            );
+        }
+        let bytes_read = ranges.iter().map(|r| r.len()).sum::<usize>();
+        assert_eq!(bytes_read, (17 + 12) * (4 + 8));
+    }
+
+    #[tokio::test]
+    async fn test_read_subgroup_only_reads_its_rows() {
+        const NUM_ROWS: u64 = 30;
+        let language = |row_id: u64| ["en", "fr", ""][(row_id % 3) as usize];
+
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(3 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        ivf.subgroups = Some(Subgroups::new("language"));
+        // Two batches per partition, with the languages interleaved.
+        let batches = (0..3)
+            .flat_map(|part_id| {
+                let start = part_id as u64 * 100;
+                [
+                    start..start + NUM_ROWS / 2,
+                    start + NUM_ROWS / 2..start + NUM_ROWS,
+                ]
+                .map(|row_ids| (part_id, row_ids))
+            })
+            .map(|(part_id, row_ids)| {
+                let languages = StringArray::from_iter(
+                    row_ids
+                        .clone()
+                        .map(|row_id| Some(language(row_id)).filter(|l| !l.is_empty())),
+                );
+                let batch = partition_batch(part_id, row_ids);
+                batch
+                    .try_with_column(
+                        Field::new(SUBGROUP_COLUMN, DataType::Utf8, true),
+                        Arc::new(languages),
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut codes_by_row_id = HashMap::new();
+        for batch in batches.iter() {
+            let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+            for (i, row_id) in batch[ROW_ID]
+                .as_primitive::<UInt64Type>()
+                .values()
+                .iter()
+                .enumerate()
+            {
+                codes_by_row_id.insert(*row_id, codes.value(i));
+            }
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![futures::stream::iter(batches.into_iter().map(Ok))],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let subgroups = ivf.subgroups.as_ref().unwrap();
+        assert_eq!(subgroups.partitions.len(), 3);
+        for partition in subgroups.partitions.iter() {
+            let values = partition
+                .iter()
+                .map(|s| s.value.as_deref())
+                .collect::<Vec<_>>();
+            assert_eq!(values, vec![Some("en"), Some("fr"), None]);
+            assert!(partition.iter().all(|s| s.length == NUM_ROWS as u32 / 3));
+        }
+        let proto = pb::Ivf::try_from(&ivf).unwrap();
+        assert_eq!(&Ivf::try_from(&proto).unwrap().subgroups, &ivf.subgroups);
+
+        let reader = CountingReader {
+            data: Bytes::from(writer.into_inner()),
+            path: Path::from("index.idx"),
+            ranges: Mutex::new(Vec::new()),
+        };
+        let part_ids = [2, 0];
+        let batches = read_partitions_in_subgroup(&reader, &ivf, 4, &part_ids, Some("fr"))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        for batch in batches.iter() {
+            assert_eq!(batch.num_rows(), NUM_ROWS as usize / 3);
+            let codes = batch[PQ_CODE_COLUMN].as_fixed_size_list();
+            let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>().values();
+            for (i, row_id) in row_ids.iter().enumerate() {
+                assert_eq!(language(*row_id), "fr");
+                assert_eq!(codes.value(i).as_ref(), codes_by_row_id[row_id].as_ref());
+            }
+        }
+
+        // The codes and the row ids of the subgroup.
+        let subgroup_ranges = |part_id: usize| {
+            let subgroup = subgroups.find(part_id, Some("fr")).unwrap();
+            let (start, len) = (subgroup.offset as usize, subgroup.length as usize);
+            let offset = ivf.offsets[part_id];
+            let row_ids = offset + NUM_ROWS as usize * 4 + start * 8;
+            [
+                offset + start * 4..offset + (start + len) * 4,
+                row_ids..row_ids + len * 8,
+            ]
+        };
+        {
+            let ranges = reader.ranges.lock().unwrap();
+            for range in ranges.iter() {
+                assert!(
+                    part_ids.iter().any(|&part_id| {
+                        subgroup_ranges(part_id as usize)
+                            .iter()
+                            .any(|r| r.start <= range.start && range.end <= r.end)
+                    }),
+                    "read {:?} outside of the requested subgroups",
+                    range
+                );
+            }
+            let bytes_read = ranges.iter().map(|r| r.len()).sum::<usize>();
+            assert_eq!(bytes_read, 2 * (NUM_ROWS as usize / 3) * (4 + 8));
+        }
+
+        let batches = read_partitions_in_subgroup(&reader, &ivf, 4, &part_ids, None)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        for batch in batches.iter() {
+            let row_ids = batch[ROW_ID].as_primitive::<UInt64Type>().values();
+            assert_eq!(row_ids.len(), NUM_ROWS as usize / 3);
+            assert!(row_ids.iter().all(|row_id| language(*row_id).is_empty()));
+        }
+        let batches = read_partitions_in_subgroup(&reader, &ivf, 4, &part_ids, Some("de"))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(batches.iter().all(|batch| batch.num_rows() == 0));
+    }
+
+    /// In-memory [Reader] where the reads of earlier byte ranges take longer.
+    struct SlowStartReader {
+        data: Bytes,
+        path: Path,
+    }
+
+    #[async_trait]
+    impl Reader for SlowStartReader {
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn block_size(&self) -> usize {
+            4096
+        }
+
+        async fn size(&self) -> lance_core::Result<usize> {
+            Ok(self.data.len())
+        }
+
+        async fn get_range(&self, range: Range<usize>) -> lance_core::Result<Bytes> {
+            let delay = (self.data.len() - range.start) / 100;
+            tokio::time::sleep(std::time::Duration::from_millis(delay as u64)).await;
+            Ok(self.data.slice(range))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_partitions_ordered() {
+        let centroids =
+            FixedSizeListArray::try_new_from_values(generate_random_array(10 * 8), 8).unwrap();
+        let mut ivf = Ivf::new(Arc::new(centroids));
+        let batches = (0..10)
+            .map(|part_id| {
+                Ok(partition_batch(
+                    part_id,
+                    part_id as u64 * 100..part_id as u64 * 100 + 50,
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut writer = Cursor::new(Vec::new());
+        write_index_partitions(
+            vec![&mut writer],
+            &mut ivf,
+            vec![futures::stream::iter(batches)],
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let reader = SlowStartReader {
+            data: Bytes::from(writer.into_inner()),
+            path: Path::from("index.idx"),
+        };
+
+        // The probe order, not the file order, and the reads of the partitions early
+        // in the file finish last.
+        let part_ids = [8, 1, 9, 0, 4, 2];
+        let batches = read_partitions_ordered(&reader, &ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            batches
+                .iter()
+                .map(|b| b[PART_ID_COLUMN].as_primitive::<UInt32Type>().value(0))
+                .collect::<Vec<_>>(),
+            part_ids
+        );
+        let expected = read_partitions(&reader, &ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(batches, expected);
+
+        assert!(read_partitions_ordered(&reader, &ivf, 4, &[3, 10])
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merge_empty_shard() {
+        const NUM_ROWS: usize = 1000;
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array_with_seed::<Float32Type>(NUM_ROWS * DIM, [11; 32]),
+            DIM as i32,
+        )
+        .unwrap();
+        let batch = RecordBatch::try_from_iter(vec![
+            ("vector", Arc::new(vectors.clone()) as ArrayRef),
+            (
+                ROW_ID,
+                Arc::new(UInt64Array::from_iter_values(0..NUM_ROWS as u64)) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+        let centroids = Arc::new(
+            FixedSizeListArray::try_new_from_values(generate_random_array(4 * DIM), DIM as i32)
+                .unwrap(),
+        );
+        let pq = PQBuildParams::with_codebook(4, 8, Arc::new(generate_random_array(256 * DIM)))
+            .build(&vectors, MetricType::L2)
+            .await
+            .unwrap();
+        let params = IvfBuildParams::new(4);
+        let part_ids = (0..4).collect::<Vec<u32>>();
+
+        let store = ObjectStore::memory();
+        let mut shards = vec![];
+        for (i, part_range) in [0..0, 0..2, 2..4, 4..4, 0..4].into_iter().enumerate() {
+            let path = Path::from(format!("shard_{}", i));
+            let mut writer = store.create(&path).await.unwrap();
+            let stream = lance_core::io::RecordBatchStreamAdapter::new(
+                batch.schema(),
+                futures::stream::iter(vec![Ok(batch.clone())]),
+            );
+            let mut ivf = Ivf::new(centroids.clone());
+            builder::build_partitions(
+                &mut writer,
+                None,
+                stream,
+                "vector",
+                &mut ivf,
+                pq.clone(),
+                MetricType::L2,
+                part_range,
+                None,
+                &params,
+                &BuildEnvConfig::default(),
+            )
+            .await
+            .unwrap();
+            writer.shutdown().await.unwrap();
+            shards.push((store.open(&path).await.unwrap(), ivf));
+        }
+        let (full_reader, full_ivf) = shards.pop().unwrap();
+
+        // The empty shard has all the partitions, with no rows.
+        let (reader, ivf) = &shards[0];
+        assert_eq!(ivf.lengths, vec![0; 4]);
+        let batches = read_partitions(reader.as_ref(), ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(batches.len(), 4);
+        assert!(batches.iter().all(|b| b.num_rows() == 0));
+
+        let path = Path::from("merged");
+        let mut writer = store.create(&path).await.unwrap();
+        let mut merged_ivf = Ivf::new(centroids.clone());
+        let streams = shards
+            .iter()
+            .map(|(reader, ivf)| read_partitions(reader.as_ref(), ivf, 4, &part_ids))
+            .collect::<Vec<_>>();
+        write_index_partitions(
+            vec![&mut writer],
+            &mut merged_ivf,
+            streams,
+            None,
+            WritePartitionsOptions::default(),
+        )
+        .await
+        .unwrap();
+        writer.shutdown().await.unwrap();
+        let merged_reader = store.open(&path).await.unwrap();
+
+        // Same partitions as built in one shard.
+        assert_eq!(merged_ivf.lengths, full_ivf.lengths);
+        assert_eq!(merged_ivf.lengths.iter().sum::<u32>(), NUM_ROWS as u32);
+        let merged = read_partitions(merged_reader.as_ref(), &merged_ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let full = read_partitions(full_reader.as_ref(), &full_ivf, 4, &part_ids)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(merged, full);
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single-file archives of an IVF index, to ship a built index as one artifact.
+//!
+//! The archive is a tar of the index file split into members of at most
+//! [MEMBER_SIZE] bytes, named [`INDEX_FILE_NAME`] followed by their position, e.g.
+//! `index.idx.000000`, optionally gzip-compressed, see [`PackageFormat`]. The members
+//! are written with the index file, as soon as they are full, and concatenated in
+//! order they are the index file.
+
+use std::io::Write;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use flate2::{write::GzDecoder, write::GzEncoder, Compression};
+use lance_core::io::{object_store::ObjectStore, ObjectWriter, Reader, Writer};
+use lance_index::vector::ivf::PackageFormat;
+use object_store::path::Path;
+use snafu::{location, Location};
+use tempfile::TempDir;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::index::INDEX_FILE_NAME;
+use crate::{Error, Result};
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// Bytes of the index file in each member of the archive, but the last one.
+const MEMBER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Bytes of a compressed archive decompressed at a time.
+const DECOMPRESS_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Writes the members of the archive, through the gzip encoder if compressed.
+struct PackageWriter {
+    writer: ObjectWriter,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    member_size: usize,
+
+    /// Bytes of the index file not in a member yet.
+    member: Vec<u8>,
+    num_members: usize,
+
+    /// Bytes of the archive not written to `writer` yet, from `pending_pos`.
+    pending: Vec<u8>,
+    pending_pos: usize,
+    finished: bool,
+}
+
+impl PackageWriter {
+    /// Append `bytes` to the archive, to be written by [`Self::poll_drain`].
+    fn push(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.write_all(bytes)?;
+            self.pending.append(encoder.get_mut());
+        } else {
+            self.pending.extend_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    /// Move the buffered bytes of the index file into the next member.
+    fn push_member(&mut self) -> std::io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(format!("{}.{:06}", INDEX_FILE_NAME, self.num_members))?;
+        header.set_size(self.member.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let padding = (TAR_BLOCK_SIZE - self.member.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        let member = std::mem::take(&mut self.member);
+        self.push(header.as_bytes())?;
+        self.push(&member)?;
+        self.push(&vec![0; padding])?;
+        self.member = member;
+        self.member.clear();
+        self.num_members += 1;
+        Ok(())
+    }
+
+    /// Push the last member and the end of the archive, two empty blocks.
+    fn finish(&mut self) -> std::io::Result<()> {
+        if !self.member.is_empty() || self.num_members == 0 {
+            self.push_member()?;
+        }
+        self.push(&[0; 2 * TAR_BLOCK_SIZE])?;
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.try_finish()?;
+            self.pending.append(encoder.get_mut());
+        }
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Write the pending bytes of the archive.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(
+                Pin::new(&mut self.writer).poll_write(cx, &self.pending[self.pending_pos..])
+            )?;
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// [Writer] of the index file, which also writes it into an archive when packaged,
+/// see [`IvfBuildParams::package`](lance_index::vector::ivf::IvfBuildParams::package).
+///
+/// The archive is complete once the writer is shut down.
+pub(super) struct IndexFileWriter {
+    index: ObjectWriter,
+    package: Option<PackageWriter>,
+    index_closed: bool,
+}
+
+impl IndexFileWriter {
+    /// Create the index file at `index_file`, and the `format` archive at `package_file`
+    /// if packaged.
+    pub(super) async fn try_new(
+        object_store: &ObjectStore,
+        index_file: &Path,
+        package: Option<(Path, PackageFormat)>,
+    ) -> Result<Self> {
+        let index = object_store.create(index_file).await?;
+        let package = match package {
+            Some((package_file, format)) => Some(PackageWriter {
+                writer: object_store.create(&package_file).await?,
+                encoder: match format {
+                    PackageFormat::Tar => None,
+                    PackageFormat::TarGz => {
+                        Some(GzEncoder::new(Vec::new(), Compression::default()))
+                    }
+                },
+                member_size: MEMBER_SIZE,
+                member: Vec::new(),
+                num_members: 0,
+                pending: Vec::new(),
+                pending_pos: 0,
+                finished: false,
+            }),
+            None => None,
+        };
+        Ok(Self {
+            index,
+            package,
+            index_closed: false,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_member_size(mut self, member_size: usize) -> Self {
+        if let Some(package) = self.package.as_mut() {
+            package.member_size = member_size;
+        }
+        self
+    }
+}
+
+impl AsyncWrite for IndexFileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(package) = this.package.as_mut() {
+            ready!(package.poll_drain(cx))?;
+        }
+        let n = ready!(Pin::new(&mut this.index).poll_write(cx, buf))?;
+        if let Some(package) = this.package.as_mut() {
+            package.member.extend_from_slice(&buf[..n]);
+            if package.member.len() >= package.member_size {
+                package.push_member()?;
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(package) = this.package.as_mut() {
+            ready!(package.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.index).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.index_closed {
+            ready!(Pin::new(&mut this.index).poll_shutdown(cx))?;
+            this.index_closed = true;
+        }
+        if let Some(package) = this.package.as_mut() {
+            if !package.finished {
+                package.finish()?;
+            }
+            ready!(package.poll_drain(cx))?;
+            ready!(Pin::new(&mut package.writer).poll_shutdown(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl Writer for IndexFileWriter {
+    async fn tell(&mut self) -> Result<usize> {
+        self.index.tell().await
+    }
+}
+
+/// Open the index file in the `format` archive at `package_file`.
+///
+/// The members of an uncompressed tar are read in place, while a gzip-compressed
+/// archive is decompressed into a local temporary file first, one chunk at a time.
+pub(super) async fn open_package(
+    object_store: &ObjectStore,
+    package_file: &Path,
+    format: PackageFormat,
+) -> Result<Arc<dyn Reader>> {
+    let reader: Arc<dyn Reader> = object_store.open(package_file).await?.into();
+    match format {
+        PackageFormat::Tar => MembersReader::try_new(reader, None).await,
+        PackageFormat::TarGz => {
+            let temp_dir = TempDir::new()?;
+            let tar_path = temp_dir.path().join(PackageFormat::Tar.file_name());
+            let mut decoder = GzDecoder::new(Vec::new());
+            let mut file = tokio::fs::File::create(&tar_path).await?;
+            let size = reader.size().await?;
+            let mut offset = 0;
+            while offset < size {
+                let end = std::cmp::min(offset + DECOMPRESS_CHUNK_SIZE, size);
+                decoder.write_all(&reader.get_range(offset..end).await?)?;
+                file.write_all(decoder.get_ref()).await?;
+                decoder.get_mut().clear();
+                offset = end;
+            }
+            decoder.try_finish()?;
+            file.write_all(decoder.get_ref()).await?;
+            file.shutdown().await?;
+
+            let tar_path = Path::from_filesystem_path(&tar_path).map_err(|err| Error::IO {
+                message: format!("Index package {}: {}", package_file, err),
+                location: location!(),
+            })?;
+            let tar_reader = ObjectStore::local().open(&tar_path).await?.into();
+            MembersReader::try_new(tar_reader, Some(temp_dir)).await
+        }
+    }
+}
+
+/// Byte ranges of the members of the index file in an uncompressed tar, in order.
+async fn find_tar_members(reader: &dyn Reader) -> Result<Vec<Range<usize>>> {
+    let size = reader.size().await?;
+    let mut members = vec![];
+    let mut offset = 0;
+    while offset + TAR_BLOCK_SIZE <= size {
+        let block = reader.get_range(offset..offset + TAR_BLOCK_SIZE).await?;
+        if block.iter().all(|b| *b == 0) {
+            break;
+        }
+        let header = tar::Header::from_byte_slice(&block);
+        let entry_size = header.entry_size()? as usize;
+        let start = offset + TAR_BLOCK_SIZE;
+        let path = header.path()?;
+        let position = path
+            .to_str()
+            .and_then(|path| path.strip_prefix(INDEX_FILE_NAME))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|position| position.parse::<usize>().ok());
+        if let Some(position) = position {
+            if start + entry_size > size {
+                return Err(Error::Index {
+                    message: format!(
+                        "Index package {}: member of {} bytes is truncated",
+                        reader.path(),
+                        entry_size
+                    ),
+                    location: location!(),
+                });
+            }
+            if position != members.len() {
+                return Err(Error::Index {
+                    message: format!(
+                        "Index package {}: member {} is out of order, expected {}",
+                        reader.path(),
+                        position,
+                        members.len()
+                    ),
+                    location: location!(),
+                });
+            }
+            members.push(start..start + entry_size);
+        }
+        offset = start + (entry_size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+    }
+    if members.is_empty() {
+        return Err(entry_not_found(reader.path()));
+    }
+    Ok(members)
+}
+
+fn entry_not_found(package_file: &Path) -> Error {
+    Error::Index {
+        message: format!(
+            "Index package {} does not contain {}",
+            package_file, INDEX_FILE_NAME
+        ),
+        location: location!(),
+    }
+}
+
+/// Reads the members of an archive, one after the other, as a whole object.
+struct MembersReader {
+    inner: Arc<dyn Reader>,
+    members: Vec<Range<usize>>,
+
+    /// Offset of each member in the index file.
+    offsets: Vec<usize>,
+    size: usize,
+
+    /// Directory of the decompressed archive, removed with the reader.
+    _temp_dir: Option<TempDir>,
+}
+
+impl MembersReader {
+    async fn try_new(inner: Arc<dyn Reader>, temp_dir: Option<TempDir>) -> Result<Arc<dyn Reader>> {
+        let members = find_tar_members(inner.as_ref()).await?;
+        let mut offsets = Vec::with_capacity(members.len());
+        let mut size = 0;
+        for member in members.iter() {
+            offsets.push(size);
+            size += member.len();
+        }
+        Ok(Arc::new(Self {
+            inner,
+            members,
+            offsets,
+            size,
+            _temp_dir: temp_dir,
+        }))
+    }
+}
+
+#[async_trait]
+impl Reader for MembersReader {
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    async fn size(&self) -> Result<usize> {
+        Ok(self.size)
+    }
+
+    async fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
+        check_range(&range, self.size, self.path())?;
+        if range.is_empty() {
+            return Ok(Bytes::new());
+        }
+        // The last member starting at or before `range.start`.
+        let first = self
+            .offsets
+            .partition_point(|offset| *offset <= range.start)
+            - 1;
+        let mut parts = vec![];
+        for (member, offset) in self.members[first..].iter().zip(&self.offsets[first..]) {
+            if *offset >= range.end {
+                break;
+            }
+            let start = range.start.max(*offset) - offset;
+            let end = range.end.min(offset + member.len()) - offset;
+            parts.push(
+                self.inner
+                    .get_range(member.start + start..member.start + end)
+                    .await?,
+            );
+        }
+        if parts.len() == 1 {
+            return Ok(parts.pop().unwrap());
+        }
+        let mut bytes = BytesMut::with_capacity(range.len());
+        parts.iter().for_each(|part| bytes.extend_from_slice(part));
+        Ok(bytes.freeze())
+    }
+}
+
+fn check_range(range: &Range<usize>, size: usize, path: &Path) -> Result<()> {
+    if range.start > range.end || range.end > size {
+        return Err(Error::IO {
+            message: format!(
+                "Index package {}: range {:?} is out of {} bytes",
+                path, range, size
+            ),
+            location: location!(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_package_members_round_trip() {
+        let object_store = ObjectStore::memory();
+        let index_file = Path::from("index/index.idx");
+        let data = (0..10_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        for format in [PackageFormat::Tar, PackageFormat::TarGz] {
+            let package_file = Path::from(format!("index/{}", format.file_name()));
+            let mut writer = IndexFileWriter::try_new(
+                &object_store,
+                &index_file,
+                Some((package_file.clone(), format)),
+            )
+            .await
+            .unwrap()
+            .with_member_size(1000);
+            for chunk in data.chunks(700) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            assert_eq!(writer.tell().await.unwrap(), data.len());
+            writer.shutdown().await.unwrap();
+
+            let index = object_store.open(&index_file).await.unwrap();
+            assert_eq!(
+                index.get_range(0..data.len()).await.unwrap().as_ref(),
+                data.as_slice()
+            );
+            let reader = open_package(&object_store, &package_file, format)
+                .await
+                .unwrap();
+            assert_eq!(reader.size().await.unwrap(), data.len());
+            // Ranges within a member, across members, and empty.
+            for range in [0..data.len(), 10..20, 900..2500, 1400..1400, 39_000..40_000] {
+                assert_eq!(
+                    reader.get_range(range.clone()).await.unwrap().as_ref(),
+                    &data[range]
+                );
+            }
+            assert!(reader.get_range(0..data.len() + 1).await.is_err());
+        }
+
+        // A plain tar holds several members, readable by any tar reader.
+        let package = object_store
+            .open(&Path::from("index/index.tar"))
+            .await
+            .unwrap();
+        let package = package
+            .get_range(0..package.size().await.unwrap())
+            .await
+            .unwrap();
+        let mut archive = tar::Archive::new(package.as_ref());
+        let mut restored = vec![];
+        for entry in archive.entries().unwrap() {
+            std::io::Read::read_to_end(&mut entry.unwrap(), &mut restored).unwrap();
+        }
+        assert_eq!(restored, data);
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2023 Lance Developers.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage footprint of an IVF_PQ index file, broken down per component.
+
+use byteorder::{ByteOrder, LittleEndian};
+use lance_core::io::{read_message, read_metadata_offset, Reader};
+use lance_index::vector::ivf::{IvfBuildParams, Tier};
+use lance_index::vector::pq::{num_centroids, packed_codes_len, PQBuildParams};
+use prost::Message;
+use serde::Serialize;
+use snafu::{location, Location};
+
+use super::{io::Section, Ivf};
+use crate::index::pb::{self, vector_index_stage::Stage};
+use crate::{Error, Result};
+
+/// Bytes of an IVF_PQ index file attributed to each of its components.
+///
+/// The partitions in a cold tier file are not part of the index file and are not
+/// counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StorageBreakdown {
+    /// Size of the index file.
+    pub total: usize,
+
+    /// PQ codes of the partitions.
+    pub pq_codes: usize,
+
+    /// Row ids of the partitions.
+    pub row_ids: usize,
+
+    /// PCA-reduced vectors of the partitions.
+    pub pca_vectors: usize,
+
+    /// Valid columns of the partitions.
+    pub valid: usize,
+
+    /// Assignment margins of the partitions.
+    pub assignment_margins: usize,
+
+    /// Extra columns added to the partitions by a post-shuffle transform.
+    pub extra_columns: usize,
+
+    /// Bit-packed copies of the PQ codes.
+    pub packed_codes: usize,
+
+    /// Matrices of the transforms, e.g. OPQ rotations.
+    pub transforms: usize,
+
+    /// Offset and length tables of the partitions in the metadata, including the
+    /// offsets of the packed codes and the subgroups.
+    pub offset_tables: usize,
+
+    /// The rest of the metadata, e.g. the centroids and the PQ codebooks.
+    pub metadata: usize,
+
+    /// Position of the metadata, version and magic at the end of the file.
+    pub footer: usize,
+}
+
+impl StorageBreakdown {
+    /// `(name, bytes)` of each component, in file order.
+    pub fn components(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("pq_codes", self.pq_codes),
+            ("row_ids", self.row_ids),
+            ("pca_vectors", self.pca_vectors),
+            ("valid", self.valid),
+            ("assignment_margins", self.assignment_margins),
+            ("extra_columns", self.extra_columns),
+            ("packed_codes", self.packed_codes),
+            ("transforms", self.transforms),
+            ("offset_tables", self.offset_tables),
+            ("metadata", self.metadata),
+            ("footer", self.footer),
+        ]
+    }
+}
+
+/// Parse the IVF_PQ index file of `reader` and attribute its bytes to each component.
+pub async fn storage_breakdown(reader: &dyn Reader) -> Result<StorageBreakdown> {
+    let total = reader.size().await?;
+    let tail = reader
+        .get_range(total.saturating_sub(reader.block_size())..total)
+        .await?;
+    let metadata_pos = read_metadata_offset(&tail)?;
+    let metadata_len = LittleEndian::read_u32(
+        reader
+            .get_range(metadata_pos..metadata_pos + 4)
+            .await?
+            .as_ref(),
+    ) as usize;
+    let proto: pb::Index = read_message(reader, metadata_pos).await?;
+
+    let Some(pb::index::Implementation::VectorIndex(vector_index)) = proto.implementation else {
+        return Err(not_ivf_pq(reader));
+    };
+    let mut ivf_proto = None;
+    let mut num_sub_vectors = None;
+    let mut transforms = 0;
+    for stage in vector_index.stages.iter() {
+        match stage.stage.as_ref() {
+            Some(Stage::Ivf(ivf)) => ivf_proto = Some(ivf),
+            Some(Stage::Pq(pq)) => num_sub_vectors = Some(pq.num_sub_vectors as usize),
+            // The matrices are plain-encoded float32s.
+            Some(Stage::Transform(transform)) => {
+                transforms += transform.shape.iter().product::<u32>() as usize * 4
+            }
+            _ => {}
+        }
+    }
+    let (Some(ivf_proto), Some(num_sub_vectors)) = (ivf_proto, num_sub_vectors) else {
+        return Err(not_ivf_pq(reader));
+    };
+    let ivf = Ivf::try_from(ivf_proto)?;
+
+    let mut breakdown = StorageBreakdown {
+        total,
+        transforms,
+        footer: total - metadata_pos - 4 - metadata_len,
+        ..Default::default()
+    };
+    for (part_id, &length) in ivf.lengths.iter().enumerate() {
+        if ivf.tier(part_id) == Tier::Cold {
+            continue;
+        }
+        let length = length as usize;
+        for section in ivf.partition_sections() {
+            let len = ivf.section_len(section, length, num_sub_vectors)?;
+            match section {
+                Section::PqCodes => breakdown.pq_codes += len,
+                Section::RowIds => breakdown.row_ids += len,
+                Section::PcaVectors => breakdown.pca_vectors += len,
+                Section::Valid => breakdown.valid += len,
+                Section::AssignmentMargins => breakdown.assignment_margins += len,
+                Section::ExtraColumn(_) => breakdown.extra_columns += len,
+            }
+        }
+        if let Some(packed) = ivf.packed_codes.as_ref() {
+            breakdown.packed_codes += packed_codes_len(length * num_sub_vectors, packed.num_bits);
+        }
+    }
+
+    // Each field is encoded on its own, so the tables take the same bytes in the full
+    // message.
+    let tables = pb::Ivf {
+        offsets: ivf_proto.offsets.clone(),
+        lengths: ivf_proto.lengths.clone(),
+        packed_codes: ivf_proto.packed_codes.clone(),
+        partition_subgroups: ivf_proto.partition_subgroups.clone(),
+        ..Default::default()
+    };
+    breakdown.offset_tables = tables.encoded_len();
+    breakdown.metadata = 4 + metadata_len - breakdown.offset_tables;
+    Ok(breakdown)
+}
+
+/// Bytes of the metadata besides the centroids and the codebooks, e.g. the names and
+/// the parameters, in [estimate_index_size].
+const ESTIMATED_METADATA_OVERHEAD: usize = 1024;
+
+/// Estimate the [StorageBreakdown] of an IVF_PQ index of `num_rows` vectors of
+/// `dimension`, built with `ivf_params` and `pq_params`, without building it.
+///
+/// Every row is assumed to be indexed, and the offset tables take the most bytes.
+/// Columns added by a post-shuffle transform and subgroups are not counted.
+pub fn estimate_index_size(
+    num_rows: usize,
+    dimension: usize,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+) -> StorageBreakdown {
+    let num_partitions = ivf_params.num_partitions;
+    let num_sub_vectors = pq_params.num_sub_vectors;
+    let rows = num_rows * ivf_params.multi_assign.max(1);
+    let mut breakdown = StorageBreakdown {
+        pq_codes: rows * num_sub_vectors,
+        row_ids: rows * std::mem::size_of::<u64>(),
+        footer: 16,
+        ..Default::default()
+    };
+    if let Some(pca) = ivf_params.pca.as_ref() {
+        breakdown.pca_vectors = rows * pca.num_components() * std::mem::size_of::<f32>();
+    }
+    // Each partition rounds its bits up to a byte.
+    if ivf_params.valid_column.is_some() {
+        breakdown.valid = arrow_buffer::bit_util::ceil(rows, 8) + num_partitions;
+    }
+    if ivf_params.assignment_margin {
+        breakdown.assignment_margins = rows * std::mem::size_of::<f32>();
+    }
+    // Varint offsets and lengths take at most 10 and 5 bytes.
+    breakdown.offset_tables = num_partitions * 15;
+    if ivf_params.dual_code_layout {
+        breakdown.packed_codes =
+            packed_codes_len(rows * num_sub_vectors, pq_params.num_bits as u32) + num_partitions;
+        breakdown.offset_tables += num_partitions * 10;
+    }
+    if pq_params.use_opq {
+        breakdown.transforms = dimension * dimension * std::mem::size_of::<f32>();
+    }
+    let centroid_width = if ivf_params.bf16_centroids { 2 } else { 4 };
+    let num_codebooks = if ivf_params.per_partition_pq {
+        num_partitions
+    } else {
+        1
+    };
+    breakdown.metadata = num_partitions * dimension * centroid_width
+        + num_codebooks
+            * num_centroids(pq_params.num_bits as u32)
+            * dimension
+            * std::mem::size_of::<f32>()
+        + ESTIMATED_METADATA_OVERHEAD;
+    breakdown.total = breakdown.components().iter().map(|(_, n)| n).sum();
+    breakdown
+}
+
+/// The largest number of sub-vectors, up to `pq_params.num_sub_vectors` and dividing
+/// `dimension`, whose [estimate_index_size] is at most `target_bytes`, see
+/// [`IvfBuildParams::target_index_bytes`].
+pub fn fit_num_sub_vectors(
+    num_rows: usize,
+    dimension: usize,
+    ivf_params: &IvfBuildParams,
+    pq_params: &PQBuildParams,
+    target_bytes: usize,
+) -> Result<usize> {
+    let mut smallest = None;
+    for num_sub_vectors in (1..=pq_params.num_sub_vectors.min(dimension)).rev() {
+        if dimension % num_sub_vectors != 0 {
+            continue;
+        }
+        let params = PQBuildParams {
+            num_sub_vectors,
+            ..pq_params.clone()
+        };
+        let estimate = estimate_index_size(num_rows, dimension, ivf_params, &params).total;
+        if estimate <= target_bytes {
+            return Ok(num_sub_vectors);
+        }
+        smallest = Some(estimate);
+    }
+    Err(Error::Index {
+        message: format!(
+            "No number of sub-vectors up to {} fits an IVF_PQ index of {} rows into {} bytes, the smallest estimate is {} bytes",
+            pq_params.num_sub_vectors,
+            num_rows,
+            target_bytes,
+            smallest.unwrap_or_default()
+        ),
+        location: location!(),
+    })
+}
+
+fn not_ivf_pq(reader: &dyn Reader) -> Error {
+    Error::Index {
+        message: format!("{} is not an IVF_PQ index file", reader.path()),
+        location: location!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use arrow_array::{BooleanArray, FixedSizeListArray, RecordBatch, RecordBatchIterator};
+    use arrow_schema::{DataType, Field, Schema};
+    use lance_arrow::FixedSizeListArrayExt;
+    use lance_index::vector::{ivf::IvfBuildParams, pq::PQBuildParams};
+    use lance_linalg::distance::MetricType;
+    use lance_testing::datagen::generate_random_array;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    use crate::dataset::Dataset;
+    use crate::index::{vector::ivf::build_ivf_pq_index, INDEX_FILE_NAME};
+
+    const DIM: usize = 32;
+    const NUM_ROWS: usize = 1000;
+
+    async fn breakdown_of(
+        dataset: &Dataset,
+        ivf_params: &IvfBuildParams,
+        pq_params: &PQBuildParams,
+    ) -> StorageBreakdown {
+        let uuid = Uuid::new_v4().to_string();
+        build_ivf_pq_index(
+            dataset,
+            "vector",
+            "storage",
+            &uuid,
+            MetricType::L2,
+            ivf_params,
+            pq_params,
+        )
+        .await
+        .unwrap();
+        let path = dataset.indices_dir().child(uuid).child(INDEX_FILE_NAME);
+        let reader = dataset.object_store().open(&path).await.unwrap();
+        storage_breakdown(reader.as_ref()).await.unwrap()
+    }
+
+    async fn write_dataset(uri: &str) -> Dataset {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "vector",
+                DataType::FixedSizeList(
+                    Arc::new(Field::new("item", DataType::Float32, true)),
+                    DIM as i32,
+                ),
+                true,
+            ),
+            Field::new("deleted", DataType::Boolean, true),
+        ]));
+        let vectors = FixedSizeListArray::try_new_from_values(
+            generate_random_array(NUM_ROWS * DIM),
+            DIM as i32,
+        )
+        .unwrap();
+        let deleted = BooleanArray::from_iter((0..NUM_ROWS).map(|i| Some(i % 3 == 0)));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(vectors), Arc::new(deleted)])
+                .unwrap();
+        let batches = RecordBatchIterator::new(vec![batch].into_iter().map(Ok), schema.clone());
+        Dataset::write(batches, uri, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_storage_breakdown_sums_to_file_size() {
+        let test_dir = tempdir().unwrap();
+        let dataset = write_dataset(test_dir.path().to_str().unwrap()).await;
+        let pq_params = PQBuildParams::new(4, 8);
+
+        let breakdown = breakdown_of(&dataset, &IvfBuildParams::new(4), &pq_params).await;
+        assert_eq!(breakdown.pq_codes, NUM_ROWS * 4);
+        assert_eq!(breakdown.row_ids, NUM_ROWS * 8);
+        assert_eq!(breakdown.valid, 0);
+        assert!(breakdown.offset_tables > 0);
+        assert!(breakdown.metadata > 0);
+        assert_eq!(breakdown.footer, 16);
+        let sum = breakdown.components().iter().map(|(_, n)| n).sum::<usize>();
+        assert_eq!(sum, breakdown.total);
+
+        let mut ivf_params = IvfBuildParams::new(4);
+        ivf_params.valid_column = Some("deleted".to_string());
+        ivf_params.assignment_margin = true;
+        ivf_params.dual_code_layout = true;
+        let breakdown = breakdown_of(&dataset, &ivf_params, &pq_params).await;
+        assert!(breakdown.valid >= NUM_ROWS / 8);
+        assert_eq!(breakdown.assignment_margins, NUM_ROWS * 4);
+        assert_eq!(breakdown.packed_codes, NUM_ROWS * 4);
+        let sum = breakdown.components().iter().map(|(_, n)| n).sum::<usize>();
+        assert_eq!(sum, breakdown.total);
+    }
+
+    #[tokio::test]
+    async fn test_target_index_bytes_picks_largest_fitting_sub_vectors() {
+        let test_dir = tempdir().unwrap();
+        let dataset = write_dataset(test_dir.path().to_str().unwrap()).await;
+        let mut ivf_params = IvfBuildParams::new(4);
+        let pq_params = PQBuildParams::new(16, 8);
+        let estimate = |num_sub_vectors| {
+            let pq_params = PQBuildParams::new(num_sub_vectors, 8);
+            estimate_index_size(NUM_ROWS, DIM, &ivf_params, &pq_params).total
+        };
+        assert!(estimate(4) < estimate(8));
+        let target = (estimate(4) + estimate(8)) / 2;
+
+        let num_sub_vectors =
+            fit_num_sub_vectors(NUM_ROWS, DIM, &ivf_params, &pq_params, target).unwrap();
+        assert_eq!(num_sub_vectors, 4);
+        assert!(estimate(num_sub_vectors) <= target);
+        for larger in (num_sub_vectors + 1..=16).filter(|n| DIM % n == 0) {
+            assert!(estimate(larger) > target, "{}", larger);
+        }
+
+        ivf_params.target_index_bytes = Some(target);
+        let breakdown = breakdown_of(&dataset, &ivf_params, &pq_params).await;
+        assert_eq!(breakdown.pq_codes, NUM_ROWS * num_sub_vectors);
+        assert!(
+            breakdown.total <= target,
+            "{} > {}",
+            breakdown.total,
+            target
+        );
+
+        let err = fit_num_sub_vectors(NUM_ROWS, DIM, &ivf_params, &pq_params, 1000).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("fits an IVF_PQ index of 1000 rows into 1000 bytes"));
+    }
+}
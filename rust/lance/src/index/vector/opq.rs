@@ -36,7 +36,7 @@ use crate::index::pb::{Transform, TransformType};
 use crate::index::prefilter::PreFilter;
 use crate::io::{
     object_reader::{read_fixed_stride_array, ObjectReader},
-    object_writer::ObjectWriter,
+    Writer,
 };
 use crate::{arrow::svd::*, index::Index};
 use crate::{Error, Result};
@@ -233,7 +233,7 @@ impl Transformer for OptimizedProductQuantizer {
     }
 
     /// Write the OPQ rotation matrix to disk.
-    async fn save(&self, writer: &mut ObjectWriter) -> Result<Transform> {
+    async fn save(&self, writer: &mut dyn Writer) -> Result<Transform> {
         let mut this = self.clone();
         if this.rotation.is_none() {
             return Err(Error::Index {
@@ -17,7 +17,7 @@ use std::{any::Any, collections::HashMap};
 
 use arrow_array::{
     cast::{as_primitive_array, AsArray},
-    FixedSizeListArray, RecordBatch, UInt64Array, UInt8Array,
+    ArrayRef, FixedSizeListArray, RecordBatch, UInt64Array, UInt8Array,
 };
 use arrow_ord::sort::sort_to_indices;
 use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema};
@@ -31,7 +31,10 @@ use lance_core::{
 };
 pub use lance_index::vector::pq::{PQBuildParams, ProductQuantizerImpl};
 use lance_index::{
-    vector::{pq::ProductQuantizer, Query, DIST_COL},
+    vector::{
+        pq::{transpose_pq_codes, ProductQuantizer},
+        Query, DIST_COL, PQ_CODE_COLUMN,
+    },
     Index, IndexType,
 };
 use lance_linalg::distance::MetricType;
@@ -85,6 +88,32 @@ impl PQIndex {
         }
     }
 
+    /// Convert the loaded PQ codes from sub-vector-major layout to row-major.
+    pub(crate) fn untranspose_codes(&self) -> Result<Self> {
+        let (Some(code), Some(row_ids)) = (self.code.as_ref(), self.row_ids.as_ref()) else {
+            return Err(Error::Index {
+                message: "PQIndex::untranspose_codes: PQ codes are not loaded".to_string(),
+                location: location!(),
+            });
+        };
+        let num_sub_vectors = self.pq.num_sub_vectors();
+        let codes =
+            FixedSizeListArray::try_new_from_values(code.as_ref().clone(), num_sub_vectors as i32)?;
+        let batch = RecordBatch::try_from_iter([(PQ_CODE_COLUMN, Arc::new(codes) as ArrayRef)])?;
+        let batch = transpose_pq_codes(&batch, num_sub_vectors, false)?;
+        let code = batch[PQ_CODE_COLUMN]
+            .as_fixed_size_list()
+            .values()
+            .as_primitive()
+            .clone();
+        Ok(Self {
+            code: Some(Arc::new(code)),
+            row_ids: Some(row_ids.clone()),
+            pq: self.pq.clone(),
+            metric_type: self.metric_type,
+        })
+    }
+
     /// Filter the row id and PQ code arrays based on the pre-filter.
     fn filter_arrays(
         pre_filter: &PreFilter,
@@ -21,7 +21,7 @@ use arrow_array::{types::Float32Type, FixedSizeListArray, RecordBatch};
 use async_trait::async_trait;
 
 use lance_core::{
-    io::{object_writer::ObjectWriter, Reader},
+    io::{Reader, Writer},
     Result,
 };
 use lance_index::{vector::Query, Index};
@@ -97,5 +97,5 @@ pub trait Transformer: std::fmt::Debug + Sync + Send {
     /// Returns a new Matrix instead.
     async fn transform(&self, data: &FixedSizeListArray) -> Result<FixedSizeListArray>;
 
-    async fn save(&self, writer: &mut ObjectWriter) -> Result<Transform>;
+    async fn save(&self, writer: &mut dyn Writer) -> Result<Transform>;
 }
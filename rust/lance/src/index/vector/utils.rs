@@ -12,47 +12,181 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use arrow_array::{cast::AsArray, FixedSizeListArray};
-use arrow_schema::Schema as ArrowSchema;
-use arrow_select::concat::concat_batches;
+use arrow::compute::cast;
+use arrow_array::{
+    cast::AsArray, types::Float32Type, Array, FixedSizeListArray, RecordBatch, UInt32Array,
+};
+use arrow_schema::{DataType, Schema as ArrowSchema};
+use arrow_select::{concat::concat_batches, take::take};
 use futures::stream::TryStreamExt;
+use lance_index::vector::stats::VectorStats;
+use rand::{seq::IteratorRandom, Rng};
 use snafu::{location, Location};
 
 use crate::dataset::Dataset;
 use crate::{Error, Result};
 
+/// Number of uniformly sampled candidates per vector of a density weighted sample of
+/// [maybe_sample_training_data].
+const DENSITY_CANDIDATES_PER_SAMPLE: usize = 16;
+
 /// Maybe sample training data from dataset, specified by column name.
 ///
+/// With a `density_sample_size`, a [density_weighted_sample] of that many vectors is
+/// drawn from a uniform sample of at least [DENSITY_CANDIDATES_PER_SAMPLE] times as many
+/// rows, or `sample_size_hint` if larger, so that the sparse regions of the dataset
+/// can be over-sampled. The samples are drawn with `rng`.
+///
 /// Returns a [FixedSizeListArray], containing the training dataset.
 ///
 pub async fn maybe_sample_training_data(
     dataset: &Dataset,
     column: &str,
     sample_size_hint: usize,
+    density_sample_size: Option<usize>,
+    rng: &mut (impl Rng + Send),
 ) -> Result<FixedSizeListArray> {
+    let batch = sample_rows(
+        dataset,
+        &[column],
+        training_pool_size(sample_size_hint, density_sample_size),
+        rng,
+    )
+    .await?;
+
+    let array = batch.column_by_name(column).ok_or(Error::Index {
+        message: format!(
+            "Sample training data: column {} does not exist in return",
+            column
+        ),
+        location: location!(),
+    })?;
+    match density_sample_size {
+        Some(sample_size) => density_weighted_sample(array.as_fixed_size_list(), sample_size, rng),
+        None => Ok(array.as_fixed_size_list().clone()),
+    }
+}
+
+/// Number of rows to sample for a training sample of `sample_size_hint` rows, see
+/// [maybe_sample_training_data].
+pub fn training_pool_size(sample_size_hint: usize, density_sample_size: Option<usize>) -> usize {
+    match density_sample_size {
+        Some(sample_size) => sample_size_hint.max(sample_size * DENSITY_CANDIDATES_PER_SAMPLE),
+        None => sample_size_hint,
+    }
+}
+
+/// Sample up to `num_samples` rows of the `columns` of `dataset` uniformly with `rng`,
+/// or read all the rows of a smaller dataset.
+pub async fn sample_rows(
+    dataset: &Dataset,
+    columns: &[&str],
+    num_samples: usize,
+    rng: &mut (impl Rng + Send),
+) -> Result<RecordBatch> {
     let num_rows = dataset.count_rows().await?;
-    let projection = dataset.schema().project(&[column])?;
-    let batch = if num_rows > sample_size_hint {
-        dataset.sample(sample_size_hint, &projection).await?
+    let projection = dataset.schema().project(columns)?;
+    if num_rows > num_samples {
+        let ids = (0..num_rows as u64).choose_multiple(rng, num_samples);
+        dataset.take(&ids, &projection).await
     } else {
         let mut scanner = dataset.scan();
-        scanner.project(&[column])?;
+        scanner.project(columns)?;
         let batches = scanner
             .try_into_stream()
             .await?
             .try_collect::<Vec<_>>()
             .await?;
-        concat_batches(&Arc::new(ArrowSchema::from(&projection)), &batches)?
-    };
+        Ok(concat_batches(
+            &Arc::new(ArrowSchema::from(&projection)),
+            &batches,
+        )?)
+    }
+}
 
-    let array = batch.column_by_name(column).ok_or(Error::Index {
-        message: format!(
-            "Sample training data: column {} does not exist in return",
-            column
-        ),
-        location: location!(),
-    })?;
-    Ok(array.as_fixed_size_list().clone())
+/// Number of dimensions of the grid of [density_weighted_sample].
+const DENSITY_GRID_DIMS: usize = 2;
+
+/// Number of bins of each dimension of the grid of [density_weighted_sample].
+const DENSITY_GRID_BINS: usize = 8;
+
+/// Sample `sample_size` vectors of `data`, weighting each vector by the inverse of the
+/// number of vectors in its cell of a coarse grid, so that the occupied cells are
+/// about equally represented and sparse regions are over-sampled.
+///
+/// The grid splits each of the [DENSITY_GRID_DIMS] dimensions of largest variance into
+/// [DENSITY_GRID_BINS] equal bins, between the smallest and the largest value.
+pub fn density_weighted_sample(
+    data: &FixedSizeListArray,
+    sample_size: usize,
+    rng: &mut impl Rng,
+) -> Result<FixedSizeListArray> {
+    if data.len() <= sample_size {
+        return Ok(data.clone());
+    }
+    let dim = data.value_length() as usize;
+    let mut stats = VectorStats::new(dim);
+    stats.update(data)?;
+    let variance = stats.variance();
+    let mut grid_dims = (0..dim).collect::<Vec<_>>();
+    grid_dims.sort_by(|a, b| variance[*b].total_cmp(&variance[*a]));
+    grid_dims.truncate(DENSITY_GRID_DIMS);
+
+    let values = cast(data.values(), &DataType::Float32)?;
+    let values = values.as_primitive::<Float32Type>().values();
+    let bounds = grid_dims
+        .iter()
+        .map(|&d| {
+            values
+                .iter()
+                .skip(d)
+                .step_by(dim)
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+                    (min.min(*v), max.max(*v))
+                })
+        })
+        .collect::<Vec<_>>();
+    let cells = values
+        .chunks_exact(dim)
+        .map(|vector| {
+            grid_dims
+                .iter()
+                .zip(bounds.iter())
+                .fold(0, |cell, (&d, &(min, max))| {
+                    let width = (max - min) / DENSITY_GRID_BINS as f32;
+                    let bin = if width > 0.0 {
+                        (((vector[d] - min) / width) as usize).min(DENSITY_GRID_BINS - 1)
+                    } else {
+                        0
+                    };
+                    cell * DENSITY_GRID_BINS + bin
+                })
+        })
+        .collect::<Vec<_>>();
+    let mut cell_sizes = HashMap::<usize, usize>::new();
+    for cell in cells.iter() {
+        *cell_sizes.entry(*cell).or_default() += 1;
+    }
+
+    // Weighted sampling without replacement, by the largest `ln(u) / weight` keys.
+    let mut keys = cells
+        .iter()
+        .enumerate()
+        .map(|(i, cell)| {
+            let u = rng.gen_range(f64::MIN_POSITIVE..1.0);
+            (u.ln() * cell_sizes[cell] as f64, i as u32)
+        })
+        .collect::<Vec<_>>();
+    keys.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut indices = keys
+        .iter()
+        .take(sample_size)
+        .map(|(_, i)| *i)
+        .collect::<Vec<_>>();
+    indices.sort();
+    let sample = take(data, &UInt32Array::from(indices), None)?;
+    Ok(sample.as_fixed_size_list().clone())
 }
@@ -672,6 +672,71 @@ mod tests {
         assert_eq!(fields, vec![0, 1]);
     }
 
+    #[tokio::test]
+    async fn test_concurrent_replace_index() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let test_uri = test_dir.path().to_str().unwrap();
+
+        let dimension = 16;
+        let schema = Arc::new(ArrowSchema::new(vec![Field::new(
+            "vector",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, true)),
+                dimension,
+            ),
+            false,
+        )]));
+        let float_arr = generate_random_array(512 * dimension as usize);
+        let vectors = Arc::new(
+            <arrow_array::FixedSizeListArray as FixedSizeListArrayExt>::try_new_from_values(
+                float_arr, dimension,
+            )
+            .unwrap(),
+        );
+        let batches = vec![RecordBatch::try_new(schema.clone(), vec![vectors]).unwrap()];
+        let reader = RecordBatchIterator::new(batches.into_iter().map(Ok), schema.clone());
+        let mut dataset = Dataset::write(reader, test_uri, None).await.unwrap();
+        let params = VectorIndexParams::ivf_pq(10, 8, 2, false, MetricType::L2, 50);
+        dataset
+            .create_index(&["vector"], IndexType::Vector, None, &params, false)
+            .await
+            .unwrap();
+
+        // From version 2, concurrently replace the index twice.
+        let futures: Vec<_> = (0..2)
+            .map(|_| {
+                let mut dataset = dataset.clone();
+                let params = params.clone();
+                tokio::spawn(async move {
+                    dataset
+                        .create_index(&["vector"], IndexType::Vector, None, &params, true)
+                        .await
+                })
+            })
+            .collect();
+
+        let results = join_all(futures)
+            .await
+            .into_iter()
+            .map(|result| result.unwrap())
+            .collect::<Vec<_>>();
+        // One replacement wins, the other one conflicts.
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .all(|result| matches!(result, Ok(_) | Err(crate::Error::CommitConflict { .. }))),
+            "{:?}",
+            results
+        );
+
+        let dataset = Dataset::open(test_uri).await.unwrap();
+        assert_eq!(dataset.version().version, 3);
+        let indices = dataset.load_indices().await.unwrap();
+        assert_eq!(indices.len(), 1);
+        assert_eq!(indices[0].dataset_version, 2);
+    }
+
     #[tokio::test]
     async fn test_concurrent_writes() {
         for write_mode in [WriteMode::Append, WriteMode::Overwrite] {